@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE flow_job (
+    flow          uuid          NOT NULL,
+    step          INTEGER       NOT NULL,
+    idx           INTEGER       NOT NULL,
+    job           uuid          NOT NULL,
+
+    PRIMARY KEY (flow, step, idx)
+);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE queue DROP COLUMN flow_step_id;
+ALTER TABLE completed_job DROP COLUMN flow_step_id;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE queue ADD COLUMN flow_step_id VARCHAR(255);
+ALTER TABLE completed_job ADD COLUMN flow_step_id VARCHAR(255);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE queue DROP COLUMN rerun_of;
+ALTER TABLE completed_job DROP COLUMN rerun_of;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE queue ADD COLUMN rerun_of UUID;
+ALTER TABLE completed_job ADD COLUMN rerun_of UUID;
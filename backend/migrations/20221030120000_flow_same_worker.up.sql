@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE workspace_settings ADD COLUMN flow_same_worker BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE resource DROP COLUMN created_at;
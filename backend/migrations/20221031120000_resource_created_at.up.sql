@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- the existing resources are left without a creation date, they are considered old enough
+ALTER TABLE resource ADD COLUMN created_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE resource ALTER COLUMN created_at SET DEFAULT now();
@@ -0,0 +1,13 @@
+-- Add up migration script here
+CREATE TABLE job_blob (
+    id UUID PRIMARY KEY,
+    workspace_id VARCHAR(50) NOT NULL REFERENCES workspace(id),
+    root_job UUID NOT NULL,
+    content BYTEA NOT NULL,
+    created_by VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    expiration TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX job_blob_root_job ON job_blob (root_job);
+CREATE INDEX job_blob_expiration ON job_blob (expiration);
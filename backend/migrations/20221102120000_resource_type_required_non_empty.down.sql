@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE resource_type DROP COLUMN required_non_empty;
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE resource_type ADD COLUMN required_non_empty VARCHAR(255)[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,6 @@
+-- Add down migration script here
+ALTER TABLE completed_job DROP COLUMN trigger_depth;
+ALTER TABLE completed_job DROP COLUMN trigger_chain;
+ALTER TABLE queue DROP COLUMN trigger_depth;
+ALTER TABLE queue DROP COLUMN trigger_chain;
+ALTER TABLE token DROP COLUMN job;
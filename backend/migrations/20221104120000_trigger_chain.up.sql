@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE token ADD COLUMN job UUID;
+ALTER TABLE queue ADD COLUMN trigger_chain UUID;
+ALTER TABLE queue ADD COLUMN trigger_depth INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE completed_job ADD COLUMN trigger_chain UUID;
+ALTER TABLE completed_job ADD COLUMN trigger_depth INTEGER NOT NULL DEFAULT 0;
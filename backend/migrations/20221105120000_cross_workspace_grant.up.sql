@@ -0,0 +1,11 @@
+-- Add up migration script here
+CREATE TABLE cross_workspace_grant (
+    workspace_id VARCHAR(50) NOT NULL REFERENCES workspace(id),
+    resource_path VARCHAR(255) NOT NULL,
+    permissioned_as VARCHAR(55) NOT NULL,
+    grantee_workspace_id VARCHAR(50) NOT NULL REFERENCES workspace(id),
+    grantee_flow_path VARCHAR(255) NOT NULL,
+    granted_by VARCHAR(50) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    PRIMARY KEY (workspace_id, resource_path, grantee_workspace_id, grantee_flow_path)
+);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE queue DROP COLUMN flow_jobs_pushed;
+ALTER TABLE workspace_settings DROP COLUMN max_flow_jobs;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE workspace_settings ADD COLUMN max_flow_jobs INTEGER;
+ALTER TABLE queue ADD COLUMN flow_jobs_pushed INTEGER NOT NULL DEFAULT 0;
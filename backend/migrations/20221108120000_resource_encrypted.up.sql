@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE resource ADD COLUMN encrypted BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE queue DROP COLUMN deferred;
+ALTER TABLE workspace_settings DROP COLUMN max_concurrent_jobs;
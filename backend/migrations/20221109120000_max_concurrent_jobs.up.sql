@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE workspace_settings ADD COLUMN max_concurrent_jobs INTEGER;
+ALTER TABLE queue ADD COLUMN deferred BOOLEAN NOT NULL DEFAULT false;
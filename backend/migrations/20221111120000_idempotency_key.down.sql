@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP INDEX completed_job_idempotency_key;
+DROP INDEX queue_idempotency_key;
+ALTER TABLE completed_job DROP COLUMN idempotency_key;
+ALTER TABLE queue DROP COLUMN idempotency_key;
@@ -0,0 +1,5 @@
+-- Add up migration script here
+ALTER TABLE queue ADD COLUMN idempotency_key VARCHAR(255);
+ALTER TABLE completed_job ADD COLUMN idempotency_key VARCHAR(255);
+CREATE UNIQUE INDEX queue_idempotency_key ON queue (workspace_id, idempotency_key);
+CREATE INDEX completed_job_idempotency_key ON completed_job (workspace_id, idempotency_key);
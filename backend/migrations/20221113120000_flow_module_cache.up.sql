@@ -0,0 +1,15 @@
+-- Add up migration script here
+CREATE TABLE flow_module_cache (
+    workspace_id     VARCHAR(50)   NOT NULL REFERENCES workspace(id),
+    permissioned_as  VARCHAR(55)   NOT NULL,
+    module_hash      CHAR(64)      NOT NULL,
+    args_hash        CHAR(64)      NOT NULL,
+    job              uuid          NOT NULL,
+    result           JSONB         NOT NULL,
+    created_at       TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    expiration       TIMESTAMP WITH TIME ZONE NOT NULL,
+
+    PRIMARY KEY (workspace_id, permissioned_as, module_hash, args_hash)
+);
+
+CREATE INDEX flow_module_cache_expiration ON flow_module_cache (expiration);
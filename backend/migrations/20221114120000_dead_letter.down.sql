@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE dead_letter_job;
+ALTER TABLE workspace_settings DROP COLUMN dead_letter;
@@ -0,0 +1,18 @@
+-- Add up migration script here
+ALTER TABLE workspace_settings ADD COLUMN dead_letter BOOLEAN NOT NULL DEFAULT false;
+
+CREATE TABLE dead_letter_job (
+    id              uuid          PRIMARY KEY,
+    workspace_id    VARCHAR(50)   NOT NULL REFERENCES workspace(id),
+    job_kind        JOB_KIND      NOT NULL,
+    script_path     VARCHAR(255),
+    raw_flow        JSONB,
+    args            JSONB,
+    flow_status     JSONB,
+    error           JSONB,
+    created_by      VARCHAR(255)  NOT NULL,
+    created_at      TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    replayed_as     uuid
+);
+
+CREATE INDEX dead_letter_job_workspace_id ON dead_letter_job (workspace_id, created_at DESC);
@@ -0,0 +1,6 @@
+-- Add down migration script here
+DELETE FROM audit WHERE action_kind = 'read';
+ALTER TYPE ACTION_KIND RENAME TO ACTION_KIND_OLD;
+CREATE TYPE ACTION_KIND AS ENUM ('create', 'update', 'delete', 'execute');
+ALTER TABLE audit ALTER COLUMN action_kind TYPE ACTION_KIND USING action_kind::text::ACTION_KIND;
+DROP TYPE ACTION_KIND_OLD;
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TYPE ACTION_KIND ADD VALUE 'read';
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE schedule ADD COLUMN last_scheduled_for TIMESTAMP WITH TIME ZONE;
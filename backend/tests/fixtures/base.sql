@@ -5,6 +5,8 @@ INSERT INTO workspace
             (id,               name,             owner,       domain)
      VALUES ('test-workspace', 'test-workspace', 'test-user', null);
 
+INSERT INTO workspace_settings (workspace_id) VALUES ('test-workspace');
+
 INSERT INTO usr(workspace_id, email, username, is_admin, role) VALUES
 	('test-workspace', 'test@windmill.dev', 'test-user', true, 'Admin');
 
@@ -3,12 +3,12 @@ use serde_json::json;
 use sqlx::{postgres::PgListener, types::Uuid, Pool, Postgres, Transaction};
 use windmill_api::jobs::{CompletedJob, Job};
 use windmill_common::{
-    flow_status::{FlowStatus, FlowStatusModule},
+    flow_status::{BranchChosen, FailureReason, FlowStatus, FlowStatusModule},
     flows::{FlowModule, FlowModuleValue, FlowValue, InputTransform},
-    scripts::ScriptLang,
+    scripts::{ScriptHash, ScriptLang},
     DEFAULT_SLEEP_QUEUE,
 };
-use windmill_queue::{get_queued_job, JobPayload, RawCode};
+use windmill_queue::{get_queued_job, FlowStorage, JobPayload, RawCode};
 use windmill_worker::WorkerConfig;
 
 async fn initialize_tracing() {
@@ -218,11 +218,15 @@ mod suspend_resume {
                         "last": { "type": "javascript", "expr": "previous_result", },
                         "resume": { "type": "javascript", "expr": "resume", },
                         "resumes": { "type": "javascript", "expr": "resumes", },
+                        "approvals": {
+                            "type": "javascript",
+                            "expr": "approvals.map(a => [a.resume_id, a.approver, a.payload, !isNaN(Date.parse(a.approved_at))])",
+                        },
                     },
                     "value": {
                         "type": "rawscript",
                         "language": "deno",
-                        "content": "export function main(last, resume, resumes) { return { last, resume, resumes } }"
+                        "content": "export function main(last, resume, resumes, approvals) { return { last, resume, resumes, approvals } }"
                     },
                 }],
             }))
@@ -260,7 +264,7 @@ mod suspend_resume {
                 // print_job(second, &db).await;
 
                 let tx = db.begin().await.unwrap();
-                let (tx, token) = windmill_worker::create_token_for_owner(tx, "test-workspace", "u/test-user", "", 100, "").await.unwrap();
+                let (tx, token) = windmill_worker::create_token_for_owner(tx, "test-workspace", "u/test-user", "", 100, "", None).await.unwrap();
                 tx.commit().await.unwrap();
                 let secret = reqwest::get(format!(
                     "http://localhost:{port}/api/w/test-workspace/jobs/job_signature/{second}/0?token={token}&approver=ruben"
@@ -298,6 +302,7 @@ mod suspend_resume {
                 },
                 "resume": "from test",
                 "resumes": ["from test"],
+                "approvals": [[0, "ruben", "from test", true]],
             }),
             result
         );
@@ -331,7 +336,11 @@ mod suspend_resume {
         server.close().await.unwrap();
 
         assert_eq!(
-            json!({"error": "Job canceled: approval request disapproved by ruben" }),
+            json!({
+                "error": "Job canceled: approval request disapproved by ruben",
+                "name": "Error",
+                "message": "Job canceled: approval request disapproved by ruben",
+            }),
             result
         );
     }
@@ -363,7 +372,7 @@ mod suspend_resume {
                 let second = completed.next().await.unwrap();
 
                 let tx = db.begin().await.unwrap();
-                let (tx, token) = windmill_worker::create_token_for_owner(tx, "test-workspace", "u/test-user", "", 100, "").await.unwrap();
+                let (tx, token) = windmill_worker::create_token_for_owner(tx, "test-workspace", "u/test-user", "", 100, "", None).await.unwrap();
                 tx.commit().await.unwrap();
                 let secret = reqwest::get(format!(
                     "http://localhost:{port}/api/w/test-workspace/jobs/job_signature/{second}/0?token={token}"
@@ -393,448 +402,1519 @@ mod suspend_resume {
         let result = completed_job(flow, &db).await.result.unwrap();
 
         assert_eq!(
-            json!({"error": "Job canceled: approval request disapproved by unknown" }),
+            json!({
+                "error": "Job canceled: approval request disapproved by unknown",
+                "name": "Error",
+                "message": "Job canceled: approval request disapproved by unknown",
+            }),
             result
         );
     }
-}
-
-mod retry {
-    use serde_json::json;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    use windmill_common::flows::FlowValue;
-    use windmill_queue::JobPayload;
-
-    use super::*;
-
-    /// test helper provides some external state to help steps fail at specific points
-    struct Server {
-        addr: std::net::SocketAddr,
-        tx: tokio::sync::oneshot::Sender<()>,
-        task: tokio::task::JoinHandle<Vec<u8>>,
-    }
-
-    impl Server {
-        async fn start(responses: Vec<Option<u8>>) -> Self {
-            use tokio::net::TcpListener;
-
-            let (tx, rx) = tokio::sync::oneshot::channel::<()>();
-            let sock = TcpListener::bind("127.0.0.1:0").await.unwrap();
-            let addr = sock.local_addr().unwrap();
-
-            let task = tokio::task::spawn(async move {
-                tokio::pin!(rx);
-                let mut results = vec![];
 
-                for next in responses {
-                    let (mut peer, _) = tokio::select! {
-                        _ = &mut rx => break,
-                        r = sock.accept() => r,
-                    }
+    async fn wait_until_flow_in_grace_period(flow: Uuid, db: &Pool<Postgres>) {
+        loop {
+            let status =
+                query_scalar::<_, serde_json::Value>("SELECT flow_status FROM queue WHERE id = $1")
+                    .bind(flow)
+                    .fetch_one(db)
+                    .await
                     .unwrap();
-
-                    let n = peer.read_u8().await.unwrap();
-                    results.push(n);
-
-                    if let Some(next) = next {
-                        peer.write_u8(next).await.unwrap();
-                    }
-                }
-
-                results
-            });
-
-            return Self { addr, tx, task };
-        }
-
-        async fn close(self) -> Vec<u8> {
-            let Self { task, tx, .. } = self;
-            drop(tx);
-            task.await.unwrap()
+            let status = serde_json::from_value::<FlowStatus>(status).unwrap();
+            if matches!(
+                status.current_step(),
+                Some(FlowStatusModule::WaitingForEvents { in_grace_period: true, .. })
+            ) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
         }
     }
 
-    fn inner_step() -> &'static str {
-        r#"
-export async function main(index, port) {
-    const buf = new Uint8Array([0]);
-    const sock = await Deno.connect({ port });
-    await sock.write(new Uint8Array([index]));
-    if (await sock.read(buf) != 1) throw "read";
-    return buf[0];
-}
-            "#
-    }
+    #[sqlx::test(fixtures("base"))]
+    async fn failure_module_on_timeout(db: Pool<Postgres>) {
+        initialize_tracing().await;
 
-    fn last_step() -> &'static str {
-        r#"
-def main(last, port):
-    with __import__("socket").create_connection((None, port)) as sock:
-        sock.send(b'\xff')
-        return last + [sock.recv(1)[0]]
-"#
-    }
+        let server = ApiServer::start(db.clone()).await;
+        let port = server.addr.port();
 
-    fn flow_forloop_retry() -> FlowValue {
-        serde_json::from_value(serde_json::json!({
+        let flow: FlowValue = serde_json::from_value(json!({
             "modules": [{
                 "value": {
-                    "type": "forloopflow",
-                    "iterator": { "type": "javascript", "expr": "result.items" },
-                    "skip_failures": false,
-                    "modules": [{
-                        "input_transform": {
-                            "index": { "type": "javascript", "expr": "previous_result.iter.index" },
-                            "port": { "type": "javascript", "expr": "flow_input.port" },
-                        },
-                        "value": {
-                            "type": "rawscript",
-                            "language": "deno",
-                            "content": inner_step(),
-                        },
-                    }],
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { return 1 }",
+                },
+                "suspend": {
+                    "required_events": 1,
+                    "timeout": 1,
                 },
-                "retry": { "constant": { "attempts": 2, "seconds": 0 } },
             }, {
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { return 2 }",
+                },
+            }],
+            "failure_module": {
                 "input_transform": {
-                    "last": { "type": "javascript", "expr": "previous_result" },
-                    "port": { "type": "javascript", "expr": "flow_input.port" },
+                    "error": { "type": "javascript", "expr": "previous_result", },
                 },
                 "value": {
                     "type": "rawscript",
-                    "language": "python3",
-                    "content": last_step(),
+                    "language": "deno",
+                    "content": "export function main(error) { return error.reason }",
                 },
-                "retry": { "constant": { "attempts": 2, "seconds": 0 } },
+                "run_on_timeout": true,
+            },
+        }))
+        .unwrap();
+
+        let result = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+            .run_until_complete(&db, port)
+            .await
+            .result;
+        assert_eq!(Some(json!("timeout")), result);
+
+        server.close().await.unwrap();
+    }
+
+    fn conditional_suspend_flow() -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{
+                "value": { "type": "identity" },
+                "suspend": {
+                    "required_events": 1,
+                    "timeout": 60,
+                    "condition": "flow_input.wait",
+                },
+            }, {
+                "value": { "type": "identity" },
             }],
         }))
         .unwrap()
     }
 
     #[sqlx::test(fixtures("base"))]
-    async fn test_pass(db: Pool<Postgres>) {
+    async fn suspend_condition_false(db: Pool<Postgres>) {
         initialize_tracing().await;
 
-        // let server = ApiServer::start(db.clone()).await;
-
-        /* fails twice in the loop, then once on the last step
-         * retry attempts is measured per-step, so it _retries_ at most two times on each step,
-         * which means it may run the step three times in total */
+        let server = ApiServer::start(db.clone()).await;
+        let port = server.addr.port();
 
-        let (attempts, responses) = [
-            /* pass fail */
-            (0, Some(99)),
-            (1, None),
-            /* pass pass fail */
-            (0, Some(99)),
-            (1, Some(99)),
-            (2, None),
-            /* pass pass pass */
-            (0, Some(3)),
-            (1, Some(5)),
-            (2, Some(7)),
-            /* fail the last step once */
-            (0xff, None),
-            (0xff, Some(9)),
-        ]
-        .into_iter()
-        .unzip::<_, _, Vec<_>, Vec<_>>();
-        let server = Server::start(responses).await;
-        let result = RunJob::from(JobPayload::RawFlow { value: flow_forloop_retry(), path: None })
-            .arg("items", json!(["unused", "unused", "unused"]))
-            .arg("port", json!(server.addr.port()))
-            .run_until_complete(&db, server.addr.port())
-            .await
-            .result
-            .unwrap();
+        /* the flow carries on right away, it would otherwise wait for a minute */
+        let cjob =
+            RunJob::from(JobPayload::RawFlow { value: conditional_suspend_flow(), path: None })
+                .arg("wait", json!(false))
+                .run_until_complete(&db, port)
+                .await;
+        assert!(cjob.success);
+        let flow_status = cjob.flow_status.unwrap();
+        assert_eq!(flow_status["modules"][1]["type"], json!("Success"));
+        assert_eq!(flow_status["modules"][0].get("approvers"), None);
 
-        assert_eq!(server.close().await, attempts);
-        assert_eq!(json!([3, 5, 7, 9]), result);
+        server.close().await.unwrap();
     }
 
     #[sqlx::test(fixtures("base"))]
-    async fn test_fail_step_zero(db: Pool<Postgres>) {
+    async fn suspend_condition_true(db: Pool<Postgres>) {
         initialize_tracing().await;
 
-        /* attempt and fail the first step three times and stop */
-        let (attempts, responses) = [
-            /* pass fail x3 */
-            (0, Some(99)),
-            (1, None),
-            (0, Some(99)),
-            (1, None),
-            (0, Some(99)),
-            (1, None),
-        ]
-        .into_iter()
-        .unzip::<_, _, Vec<_>, Vec<_>>();
-        let server = Server::start(responses).await;
-        let result = RunJob::from(JobPayload::RawFlow { value: flow_forloop_retry(), path: None })
-            .arg("items", json!(["unused", "unused", "unused"]))
-            .arg("port", json!(server.addr.port()))
-            .run_until_complete(&db, server.addr.port())
-            .await
-            .result
-            .unwrap();
+        let server = ApiServer::start(db.clone()).await;
+        let port = server.addr.port();
 
-        assert_eq!(server.close().await, attempts);
-        assert!(result["error"]
-            .as_str()
-            .unwrap()
-            .contains(r#"Uncaught (in promise) "read""#));
-    }
+        let flow =
+            RunJob::from(JobPayload::RawFlow { value: conditional_suspend_flow(), path: None })
+                .arg("wait", json!(true))
+                .push(&db)
+                .await;
 
-    #[sqlx::test(fixtures("base"))]
-    async fn test_fail_step_one(db: Pool<Postgres>) {
-        initialize_tracing().await;
+        let queue = listen_for_queue(&db).await;
+        let db_ = db.clone();
 
-        /* attempt and fail the first step three times and stop */
-        let (attempts, responses) = [
-            /* fail once, then pass */
-            (0, None),
-            (0, Some(1)),
-            (1, Some(2)),
-            (2, Some(3)),
-            /* fail three times */
-            (0xff, None),
-            (0xff, None),
-            (0xff, None),
-        ]
-        .into_iter()
-        .unzip::<_, _, Vec<_>, Vec<_>>();
-        let server = Server::start(responses).await;
-        let result = RunJob::from(JobPayload::RawFlow { value: flow_forloop_retry(), path: None })
-            .arg("items", json!(["unused", "unused", "unused"]))
-            .arg("port", json!(server.addr.port()))
-            .run_until_complete(&db, server.addr.port())
-            .await
-            .result
-            .unwrap();
+        in_test_worker(
+            &db,
+            async move {
+                let db = db_;
+                wait_until_flow_suspends(flow, queue, &db).await;
+            },
+            port,
+        )
+        .await;
 
-        assert_eq!(server.close().await, attempts);
-        assert!(result["error"]
-            .as_str()
-            .unwrap()
-            .contains("index out of range"));
+        let (suspend, flow_status): (i32, serde_json::Value) =
+            sqlx::query_as("SELECT suspend, flow_status FROM queue WHERE id = $1")
+                .bind(flow)
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        assert_eq!(suspend, 1);
+        assert_eq!(flow_status["modules"][1]["type"], json!("WaitingForEvents"));
+
+        server.close().await.unwrap();
     }
 
     #[sqlx::test(fixtures("base"))]
-    async fn test_with_failure_module(db: Pool<Postgres>) {
+    async fn suspend_timeout(db: Pool<Postgres>) {
         initialize_tracing().await;
 
-        // let server = ApiServer::start(db.clone()).await;
+        let server = ApiServer::start(db.clone()).await;
+        let port = server.addr.port();
 
-        let value = serde_json::from_value(json!({
-            "modules": [{
-                "id": "a",
-                "value": {
-                    "input_transform": { "port": { "type": "javascript", "expr": "flow_input.port" } },
-                    "type": "rawscript",
-                    "language": "python3",
-                    "content": r#"
-def main(port):
-    with __import__("socket").create_connection((None, port)) as sock:
-        sock.send(b'\x00')
-        return sock.recv(1)[0]"#,
-                },
-                "retry": { "constant": { "attempts": 1, "seconds": 0 } },
-            }],
-            "failure_module": {
-                "value": {
-                    "input_transform": { "error": { "type": "javascript", "expr": "previous_result", },
-                        "port": { "type": "javascript", "expr": "flow_input.port" } },
-                    "type": "rawscript",
-                    "language": "python3",
-                    "content": r#"
-def main(error, port):
-    with __import__("socket").create_connection((None, port)) as sock:
-        sock.send(b'\xff')
-        return { "recv": sock.recv(1)[0], "from failure module": error }"#,
-                },
-                "retry": { "constant": { "attempts": 1, "seconds": 0 } },
-            },
-        }))
-        .unwrap();
-        let (attempts, responses) = [
-            /* fail the first step twice */
-            (0x00, None),
-            (0x00, None),
-            /* and the failure module once */
-            (0xff, None),
-            (0xff, Some(42)),
-        ]
-        .into_iter()
-        .unzip::<_, _, Vec<_>, Vec<_>>();
-        let server = Server::start(responses).await;
-        let cjob = RunJob::from(JobPayload::RawFlow { value, path: None })
-            .arg("port", json!(server.addr.port()))
-            .run_until_complete(&db, server.addr.port())
-            .await;
-        let result = cjob.result.clone().unwrap();
-        let failed_module = get_module(&cjob, "a").unwrap();
-        match failed_module {
-            FlowStatusModule::Failure { .. } => {}
-            _ => panic!("expected failure module"),
-        }
-
-        assert_eq!(server.close().await, attempts);
-        assert_eq!(
-            result,
-            json!({
-                "recv": 42,
-                "from failure module": {
-                    "error": "Error during execution of the script:\n\nTraceback (most recent call last):\n  File \"/tmp/main.py\", line 14, in <module>\n    res = inner_script.main(**kwargs)\n  File \"/tmp/inner.py\", line 5, in main\n    return sock.recv(1)[0]\nIndexError: index out of range",
-                }
-            })
-        );
-    }
-}
-
-#[sqlx::test(fixtures("base"))]
-async fn test_iteration(db: Pool<Postgres>) {
-    initialize_tracing().await;
-
-    let server = ApiServer::start(db.clone()).await;
-
-    let flow: FlowValue = serde_json::from_value(serde_json::json!({
-        "modules": [{
-            "value": {
-                "type": "forloopflow",
-                "iterator": { "type": "javascript", "expr": "result.items" },
-                "skip_failures": false,
+        let flow = |timeout: serde_json::Value| -> FlowValue {
+            serde_json::from_value(json!({
                 "modules": [{
-                    "input_transform": {
-                        "n": {
-                            "type": "javascript",
-                            "expr": "previous_result.iter.value",
-                        },
-                    },
                     "value": {
                         "type": "rawscript",
-                        "language": "python3",
-                        "content": "def main(n):\n    if 1 < n:\n        raise StopIteration(n)",
+                        "language": "deno",
+                        "content": "export function main() { return 1 }",
+                    },
+                    "suspend": {
+                        "required_events": 1,
+                        "timeout": timeout,
                     },
                 }],
-            },
-        }],
-    }))
-    .unwrap();
+            }))
+            .unwrap()
+        };
 
-    let result = RunJob::from(JobPayload::RawFlow { value: flow.clone(), path: None })
-        .arg("items", json!([]))
-        .run_until_complete(&db, server.addr.port())
+        /* the timeout of the step may be given in days, hours, minutes and seconds */
+        let cjob =
+            RunJob::from(JobPayload::RawFlow { value: flow(json!({ "seconds": 1 })), path: None })
+                .run_until_complete(&db, port)
+                .await;
+        assert!(!cjob.success);
+        let error = cjob.result.unwrap()["error"].as_str().unwrap().to_string();
+        assert!(
+            error.starts_with("Timed out waiting to be resumed after "),
+            "{error}"
+        );
+        assert!(error.ends_with(", no one approved"), "{error}");
+
+        /* an empty duration is refused rather than expiring right away */
+        let empty = serde_json::from_value::<windmill_common::flows::Suspend>(
+            json!({ "required_events": 1, "timeout": {} }),
+        );
+        assert!(empty.is_err());
+
+        /* steps without a timeout wait for the default of the workspace */
+        sqlx::query(
+            "UPDATE workspace_settings SET default_suspend_timeout = 1 WHERE workspace_id = 'test-workspace'",
+        )
+        .execute(&db)
         .await
-        .result
         .unwrap();
-    assert_eq!(result, serde_json::json!([]));
+        let cjob = RunJob::from(JobPayload::RawFlow { value: flow(json!(null)), path: None })
+            .run_until_complete(&db, port)
+            .await;
+        assert!(!cjob.success);
+        assert!(cjob.result.unwrap()["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Timed out waiting to be resumed"));
 
-    /* Don't actually test that this does 257 jobs or that will take forever. */
-    let result = RunJob::from(JobPayload::RawFlow { value: flow.clone(), path: None })
-        .arg("items", json!((0..257).collect::<Vec<_>>()))
-        .run_until_complete(&db, server.addr.port())
-        .await
-        .result
+        server.close().await.unwrap();
+    }
+
+    #[sqlx::test(fixtures("base"))]
+    async fn flow_timeout(db: Pool<Postgres>) {
+        initialize_tracing().await;
+
+        let server = ApiServer::start(db.clone()).await;
+        let port = server.addr.port();
+
+        let flow: FlowValue = serde_json::from_value(json!({
+            "modules": [{
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { return 1 }",
+                },
+                "suspend": {
+                    "required_events": 1,
+                    "timeout": 60,
+                },
+            }],
+            "timeout": 1,
+        }))
         .unwrap();
-    assert!(matches!(result, serde_json::Value::Object(_)));
-    assert!(result["error"]
-        .as_str()
-        .unwrap()
-        .contains("StopIteration: 2"));
-}
 
-struct RunJob {
-    payload: JobPayload,
-    args: serde_json::Map<String, serde_json::Value>,
-}
+        let flow = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+            .push(&db)
+            .await;
 
-impl From<JobPayload> for RunJob {
-    fn from(payload: JobPayload) -> Self {
-        Self { payload, args: Default::default() }
-    }
-}
+        let mut completed = listen_for_completed_jobs(&db).await;
+        let queue = listen_for_queue(&db).await;
+        let db_ = db.clone();
 
-impl RunJob {
-    fn arg<S: Into<String>>(mut self, k: S, v: serde_json::Value) -> Self {
-        self.args.insert(k.into(), v);
-        self
-    }
+        in_test_worker(
+            &db,
+            async move {
+                let db = db_;
 
-    async fn push(self, db: &Pool<Postgres>) -> Uuid {
-        let RunJob { payload, args } = self;
-        let tx = db.begin().await.unwrap();
-        let (uuid, tx) = windmill_queue::push(
-            tx,
-            "test-workspace",
-            payload,
-            Some(args),
-            /* user */ "test-user",
-            /* permissioned_as */ "u/admin".to_string(),
-            /* scheduled_for_o */ None,
-            /* schedule_path */ None,
-            /* parent_job */ None,
-            /* is_flow_step */ false,
-            /* running */ false,
+                /* a flow waiting to be resumed past its timeout is canceled by the reaper */
+                wait_until_flow_suspends(flow, queue, &db).await;
+                let _first = completed.next().await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+                windmill_worker::cancel_timed_out_flows(&db).await.unwrap();
+
+                completed.find(&flow).await.unwrap();
+            },
+            port,
         )
-        .await
-        .expect("push has to succeed");
+        .await;
 
-        tx.commit().await.expect("push has to commit");
+        server.close().await.unwrap();
 
-        uuid
+        let cjob = completed_job(flow, &db).await;
+        assert!(!cjob.success);
+        assert!(cjob.result.unwrap()["error"]
+            .as_str()
+            .unwrap()
+            .contains("Job canceled: flow duration > 1s by timeout"));
     }
 
-    /// push the job, spawn a worker, wait until the job is in completed_job
-    async fn run_until_complete(self, db: &Pool<Postgres>, port: u16) -> CompletedJob {
-        let uuid = self.push(db).await;
-        let listener = listen_for_completed_jobs(db).await;
-        in_test_worker(db, listener.find(&uuid), port).await;
-        completed_job(uuid, db).await
-    }
-}
+    #[sqlx::test(fixtures("base"))]
+    async fn trigger_failure_module(db: Pool<Postgres>) {
+        initialize_tracing().await;
 
-async fn run_job_in_new_worker_until_complete(
-    db: &Pool<Postgres>,
-    job: JobPayload,
-    port: u16,
-) -> CompletedJob {
-    RunJob::from(job).run_until_complete(db, port).await
-}
+        let server = ApiServer::start(db.clone()).await;
+        let port = server.addr.port();
 
-/// Start a worker with a timeout and run a future, until the worker quits or we time out.
-///
-/// Cleans up the worker before resolving.
-async fn in_test_worker<Fut: std::future::Future>(
-    db: &Pool<Postgres>,
-    inner: Fut,
-    port: u16,
-) -> <Fut as std::future::Future>::Output {
-    let (quit, worker) = spawn_test_worker(db, port);
-    let worker = tokio::time::timeout(std::time::Duration::from_secs(19), worker);
-    tokio::pin!(worker);
+        let flow: FlowValue = serde_json::from_value(json!({
+            "modules": [{
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { return 1 }",
+                },
+                "suspend": {
+                    "required_events": 1,
+                    "timeout": 60,
+                },
+            }, {
+                "id": "b",
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { return 2 }",
+                },
+            }],
+            "failure_module": {
+                "input_transform": {
+                    "error": { "type": "javascript", "expr": "previous_result", },
+                },
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(error) { return error }",
+                },
+            },
+        }))
+        .unwrap();
 
-    let res = tokio::select! {
-        biased;
-        res = inner => res,
-        res = &mut worker => match
-            res.expect("worker timed out")
-               .expect("worker panicked") {
-            _ => panic!("worker quit early"),
-        },
-    };
+        let flow = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+            .push(&db)
+            .await;
 
-    /* ensure the worker quits before we return */
-    drop(quit);
+        let mut completed = listen_for_completed_jobs(&db).await;
+        let queue = listen_for_queue(&db).await;
+        let db_ = db.clone();
 
-    let _: () = worker
-        .await
-        .expect("worker timed out")
-        .expect("worker panicked");
+        in_test_worker(
+            &db,
+            async move {
+                let db = db_;
 
-    res
-}
+                wait_until_flow_suspends(flow, queue, &db).await;
+                let _first = completed.next().await.unwrap();
 
-fn spawn_test_worker(
-    db: &Pool<Postgres>,
+                windmill_worker::trigger_failure_module(
+                    &db,
+                    flow,
+                    "test-workspace",
+                    "test-user",
+                    json!("synthetic"),
+                )
+                .await
+                .unwrap();
+
+                completed.find(&flow).await.unwrap();
+            },
+            port,
+        )
+        .await;
+
+        server.close().await.unwrap();
+
+        let result = completed_job(flow, &db).await.result;
+        assert_eq!(
+            Some(json!({
+                "error": "synthetic",
+                "reason": "triggered",
+                "name": "Triggered",
+                "message": "synthetic",
+                "step_id": "b",
+            })),
+            result
+        );
+    }
+
+    #[sqlx::test(fixtures("base"))]
+    async fn resume_during_grace_period(db: Pool<Postgres>) {
+        initialize_tracing().await;
+
+        let server = ApiServer::start(db.clone()).await;
+        let port = server.addr.port();
+
+        let flow: FlowValue = serde_json::from_value(json!({
+            "modules": [{
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { return 1 }",
+                },
+                "suspend": {
+                    "required_events": 1,
+                    "timeout": 1,
+                    "grace_period": 60,
+                },
+            }, {
+                "input_transform": {
+                    "resume": { "type": "javascript", "expr": "resume", },
+                },
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(resume) { return resume }",
+                },
+            }],
+        }))
+        .unwrap();
+
+        let flow = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+            .push(&db)
+            .await;
+
+        let mut completed = listen_for_completed_jobs(&db).await;
+        let queue = listen_for_queue(&db).await;
+        let db_ = db.clone();
+
+        in_test_worker(&db, async move {
+                let db = db_;
+
+                wait_until_flow_suspends(flow, queue, &db).await;
+                let first = completed.next().await.unwrap();
+
+                /* the suspend times out after a second, the resume arrives after that but
+                 * within the grace period */
+                wait_until_flow_in_grace_period(flow, &db).await;
+
+                let tx = db.begin().await.unwrap();
+                let (tx, token) = windmill_worker::create_token_for_owner(tx, "test-workspace", "u/test-user", "", 100, "", None).await.unwrap();
+                tx.commit().await.unwrap();
+                let secret = reqwest::get(format!(
+                    "http://localhost:{port}/api/w/test-workspace/jobs/job_signature/{first}/0?token={token}"
+                ))
+                .await
+                .unwrap()
+                .error_for_status()
+                .unwrap()
+                .text().await.unwrap();
+
+                /* ImZyb20gdGVzdCIK = base64 "from test" */
+                reqwest::get(format!(
+                    "http://localhost:{port}/api/w/test-workspace/jobs/resume/{first}/0/{secret}?payload=ImZyb20gdGVzdCIK"
+                ))
+                .await
+                .unwrap()
+                .error_for_status()
+                .unwrap();
+
+                completed.find(&flow).await.unwrap();
+            }, port)
+            .await;
+
+        server.close().await.unwrap();
+
+        let cjob = completed_job(flow, &db).await;
+        assert!(cjob.success);
+        assert_eq!(Some(json!("from test")), cjob.result);
+    }
+
+    #[sqlx::test(fixtures("base"))]
+    async fn resume_schema(db: Pool<Postgres>) {
+        initialize_tracing().await;
+
+        let server = ApiServer::start(db.clone()).await;
+        let port = server.addr.port();
+
+        let flow: FlowValue = serde_json::from_value(json!({
+            "modules": [{
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { return 1 }",
+                },
+                "suspend": {
+                    "required_events": 1,
+                    "resume_schema": {
+                        "type": "object",
+                        "properties": { "approved": { "type": "boolean" } },
+                        "required": ["approved"],
+                    },
+                },
+            }, {
+                "input_transform": {
+                    "resume": { "type": "javascript", "expr": "resume", },
+                },
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(resume) { return resume }",
+                },
+            }],
+        }))
+        .unwrap();
+
+        let flow = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+            .push(&db)
+            .await;
+
+        let mut completed = listen_for_completed_jobs(&db).await;
+        let queue = listen_for_queue(&db).await;
+        let db_ = db.clone();
+
+        in_test_worker(&db, async move {
+                let db = db_;
+
+                wait_until_flow_suspends(flow, queue, &db).await;
+                let first = completed.next().await.unwrap();
+
+                let tx = db.begin().await.unwrap();
+                let (tx, token) = windmill_worker::create_token_for_owner(tx, "test-workspace", "u/test-user", "", 100, "", None).await.unwrap();
+                tx.commit().await.unwrap();
+                let secret = reqwest::get(format!(
+                    "http://localhost:{port}/api/w/test-workspace/jobs/job_signature/{first}/0?token={token}"
+                ))
+                .await
+                .unwrap()
+                .error_for_status()
+                .unwrap()
+                .text().await.unwrap();
+
+                /* ImZyb20gdGVzdCIK = base64 "from test", rejected by the resume schema */
+                let response = reqwest::get(format!(
+                    "http://localhost:{port}/api/w/test-workspace/jobs/resume/{first}/0/{secret}?payload=ImZyb20gdGVzdCIK"
+                ))
+                .await
+                .unwrap();
+                assert_eq!(response.status(), 400);
+                let rejected: i64 = query_scalar("SELECT count(*) FROM resume_job WHERE job = $1")
+                    .bind(first)
+                    .fetch_one(&db)
+                    .await
+                    .unwrap();
+                assert_eq!(rejected, 0);
+
+                /* eyJhcHByb3ZlZCI6IHRydWV9 = base64 {"approved": true} */
+                reqwest::get(format!(
+                    "http://localhost:{port}/api/w/test-workspace/jobs/resume/{first}/0/{secret}?payload=eyJhcHByb3ZlZCI6IHRydWV9"
+                ))
+                .await
+                .unwrap()
+                .error_for_status()
+                .unwrap();
+
+                completed.find(&flow).await.unwrap();
+            }, port)
+            .await;
+
+        server.close().await.unwrap();
+
+        let cjob = completed_job(flow, &db).await;
+        assert!(cjob.success);
+        assert_eq!(Some(json!({ "approved": true })), cjob.result);
+        let logs: String = query_scalar("SELECT logs FROM completed_job WHERE id = $1")
+            .bind(flow)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert!(
+            logs.contains("does not satisfy the resume schema: expected object, found string"),
+            "{logs}"
+        );
+    }
+
+    #[sqlx::test(fixtures("base"))]
+    async fn suspend_details(db: Pool<Postgres>) {
+        initialize_tracing().await;
+
+        let server = ApiServer::start(db.clone()).await;
+        let port = server.addr.port();
+
+        let form_schema = json!({
+            "type": "object",
+            "properties": { "amount": { "type": "integer" } },
+            "required": ["amount"],
+        });
+        let flow: FlowValue = serde_json::from_value(json!({
+            "modules": [{
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { return 1 }",
+                },
+                "suspend": {
+                    "required_events": 1,
+                    "description": "How much should be refunded?",
+                    "form_schema": form_schema,
+                },
+            }, {
+                "input_transform": {
+                    "resume": { "type": "javascript", "expr": "resume", },
+                },
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(resume) { return resume }",
+                },
+            }],
+        }))
+        .unwrap();
+
+        let flow = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+            .push(&db)
+            .await;
+
+        let mut completed = listen_for_completed_jobs(&db).await;
+        let queue = listen_for_queue(&db).await;
+        let db_ = db.clone();
+
+        in_test_worker(&db, async move {
+                let db = db_;
+
+                wait_until_flow_suspends(flow, queue, &db).await;
+                let first = completed.next().await.unwrap();
+
+                let tx = db.begin().await.unwrap();
+                let (tx, token) = windmill_worker::create_token_for_owner(tx, "test-workspace", "u/test-user", "", 100, "", None).await.unwrap();
+                tx.commit().await.unwrap();
+                let secret = reqwest::get(format!(
+                    "http://localhost:{port}/api/w/test-workspace/jobs/job_signature/{first}/0?token={token}"
+                ))
+                .await
+                .unwrap()
+                .error_for_status()
+                .unwrap()
+                .text().await.unwrap();
+
+                /* the approver gets the form to fill, its submission is the resume value */
+                let details = reqwest::get(format!(
+                    "http://localhost:{port}/api/w/test-workspace/jobs/get_suspend/{first}/0/{secret}"
+                ))
+                .await
+                .unwrap()
+                .error_for_status()
+                .unwrap()
+                .json::<serde_json::Value>()
+                .await
+                .unwrap();
+                assert_eq!(
+                    details,
+                    json!({
+                        "description": "How much should be refunded?",
+                        "form_schema": form_schema,
+                        "required_events": 1,
+                        "resume_messages": 0,
+                    })
+                );
+
+                reqwest::Client::new()
+                    .post(format!(
+                        "http://localhost:{port}/api/w/test-workspace/jobs/resume/{first}/0/{secret}"
+                    ))
+                    .json(&json!({ "amount": 20 }))
+                    .send()
+                    .await
+                    .unwrap()
+                    .error_for_status()
+                    .unwrap();
+
+                completed.find(&flow).await.unwrap();
+            }, port)
+            .await;
+
+        server.close().await.unwrap();
+
+        let cjob = completed_job(flow, &db).await;
+        assert!(cjob.success);
+        assert_eq!(Some(json!({ "amount": 20 })), cjob.result);
+    }
+
+    /// The job of a step suspended for `required_events`, in a flow that isn't run.
+    async fn suspended_step(db: &Pool<Postgres>, required_events: u16) -> Uuid {
+        let flow: FlowValue = serde_json::from_value(json!({
+            "modules": [{
+                "id": "a",
+                "value": { "type": "identity" },
+                "suspend": { "required_events": required_events },
+            }, {
+                "id": "b",
+                "value": { "type": "identity" },
+            }],
+        }))
+        .unwrap();
+        let flow = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+            .push(db)
+            .await;
+
+        let tx = db.begin().await.unwrap();
+        let (step, tx) = windmill_queue::push(
+            tx,
+            "test-workspace",
+            JobPayload::Identity,
+            None,
+            "test-user",
+            "u/admin".to_string(),
+            None,
+            None,
+            Some(flow),
+            true,
+            false,
+            None,
+            FlowStorage::default(),
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        sqlx::query(
+            "UPDATE queue SET flow_status = jsonb_set(flow_status, '{modules,0}',
+                    jsonb_build_object('type', 'Success', 'id', 'a', 'job', $2::text))
+              WHERE id = $1",
+        )
+        .bind(flow)
+        .bind(step)
+        .execute(db)
+        .await
+        .unwrap();
+
+        step
+    }
+
+    #[sqlx::test(fixtures("base"))]
+    async fn max_resume_messages(db: Pool<Postgres>) {
+        initialize_tracing().await;
+
+        let server = ApiServer::start(db.clone()).await;
+        let port = server.addr.port();
+        let client = reqwest::Client::new();
+
+        let tx = db.begin().await.unwrap();
+        let (tx, token) = windmill_worker::create_token_for_owner(
+            tx,
+            "test-workspace",
+            "u/test-user",
+            "",
+            100,
+            "",
+            None,
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        sqlx::query(
+            "UPDATE workspace_settings SET max_resume_messages = 2 WHERE workspace_id = 'test-workspace'",
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let resume = |job: Uuid, resume_id: u32| {
+            let client = client.clone();
+            let token = token.clone();
+            async move {
+                let secret = client
+                    .get(format!(
+                        "http://localhost:{port}/api/w/test-workspace/jobs/job_signature/{job}/{resume_id}"
+                    ))
+                    .bearer_auth(&token)
+                    .send()
+                    .await
+                    .unwrap()
+                    .error_for_status()
+                    .unwrap()
+                    .text()
+                    .await
+                    .unwrap();
+                client
+                    .post(format!(
+                        "http://localhost:{port}/api/w/test-workspace/jobs/resume/{job}/{resume_id}/{secret}"
+                    ))
+                    .json(&json!(resume_id))
+                    .send()
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+
+        /* one message more than the cap of the workspace is refused */
+        let step = suspended_step(&db, 1).await;
+        assert_eq!(resume(step, 0).await, reqwest::StatusCode::CREATED);
+        assert_eq!(resume(step, 1).await, reqwest::StatusCode::CREATED);
+        assert_eq!(
+            resume(step, 2).await,
+            reqwest::StatusCode::TOO_MANY_REQUESTS
+        );
+        /* sending a message again doesn't count twice */
+        assert_eq!(resume(step, 1).await, reqwest::StatusCode::CREATED);
+
+        /* a cap below the events required by the step is raised to them */
+        let step = suspended_step(&db, 3).await;
+        for resume_id in 0..3 {
+            assert_eq!(resume(step, resume_id).await, reqwest::StatusCode::CREATED);
+        }
+        assert_eq!(
+            resume(step, 3).await,
+            reqwest::StatusCode::TOO_MANY_REQUESTS
+        );
+
+        let stored = query_scalar::<_, i64>("SELECT count(*) FROM resume_job WHERE job = $1")
+            .bind(step)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(stored, 3);
+
+        server.close().await.unwrap();
+    }
+
+    #[sqlx::test(fixtures("base"))]
+    async fn distinct_approvers(db: Pool<Postgres>) {
+        initialize_tracing().await;
+
+        let server = ApiServer::start(db.clone()).await;
+        let port = server.addr.port();
+
+        let flow: FlowValue = serde_json::from_value(json!({
+            "modules": [{
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { return 1 }",
+                },
+                "suspend": {
+                    "required_events": 2,
+                    "require_distinct_approvers": true,
+                },
+            }, {
+                "input_transform": {
+                    "approvers": { "type": "javascript", "expr": "approvals.map(a => a.approver)", },
+                },
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(approvers) { return approvers }",
+                },
+            }],
+        }))
+        .unwrap();
+
+        let flow = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+            .push(&db)
+            .await;
+
+        let mut completed = listen_for_completed_jobs(&db).await;
+        let queue = listen_for_queue(&db).await;
+        let db_ = db.clone();
+
+        in_test_worker(&db, async move {
+                let db = db_;
+
+                wait_until_flow_suspends(flow, queue, &db).await;
+                let first = completed.next().await.unwrap();
+
+                let tx = db.begin().await.unwrap();
+                let (tx, token) = windmill_worker::create_token_for_owner(tx, "test-workspace", "u/test-user", "", 100, "", None).await.unwrap();
+                tx.commit().await.unwrap();
+                let resume = |resume_id: u32, approver: &'static str| {
+                    let token = token.clone();
+                    async move {
+                        let secret = reqwest::get(format!(
+                            "http://localhost:{port}/api/w/test-workspace/jobs/job_signature/{first}/{resume_id}?token={token}&approver={approver}"
+                        ))
+                        .await
+                        .unwrap()
+                        .error_for_status()
+                        .unwrap()
+                        .text().await.unwrap();
+                        reqwest::get(format!(
+                            "http://localhost:{port}/api/w/test-workspace/jobs/resume/{first}/{resume_id}/{secret}?approver={approver}"
+                        ))
+                        .await
+                        .unwrap()
+                        .error_for_status()
+                        .unwrap();
+                    }
+                };
+
+                /* a second approval from the same approver doesn't resume the flow */
+                resume(0, "ruben").await;
+                resume(1, "ruben").await;
+                let suspend: i32 = query_scalar("SELECT suspend FROM queue WHERE id = $1")
+                    .bind(flow)
+                    .fetch_one(&db)
+                    .await
+                    .unwrap();
+                assert_eq!(suspend, 1);
+
+                resume(2, "alice").await;
+                completed.find(&flow).await.unwrap();
+            }, port)
+            .await;
+
+        server.close().await.unwrap();
+
+        let cjob = completed_job(flow, &db).await;
+        assert!(cjob.success);
+        assert_eq!(Some(json!(["ruben", "alice"])), cjob.result);
+    }
+}
+
+mod retry {
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use windmill_common::flows::FlowValue;
+    use windmill_queue::JobPayload;
+
+    use super::*;
+
+    /// test helper provides some external state to help steps fail at specific points
+    struct Server {
+        addr: std::net::SocketAddr,
+        tx: tokio::sync::oneshot::Sender<()>,
+        task: tokio::task::JoinHandle<Vec<u8>>,
+    }
+
+    impl Server {
+        async fn start(responses: Vec<Option<u8>>) -> Self {
+            use tokio::net::TcpListener;
+
+            let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+            let sock = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = sock.local_addr().unwrap();
+
+            let task = tokio::task::spawn(async move {
+                tokio::pin!(rx);
+                let mut results = vec![];
+
+                for next in responses {
+                    let (mut peer, _) = tokio::select! {
+                        _ = &mut rx => break,
+                        r = sock.accept() => r,
+                    }
+                    .unwrap();
+
+                    let n = peer.read_u8().await.unwrap();
+                    results.push(n);
+
+                    if let Some(next) = next {
+                        peer.write_u8(next).await.unwrap();
+                    }
+                }
+
+                results
+            });
+
+            return Self { addr, tx, task };
+        }
+
+        async fn close(self) -> Vec<u8> {
+            let Self { task, tx, .. } = self;
+            drop(tx);
+            task.await.unwrap()
+        }
+    }
+
+    fn inner_step() -> &'static str {
+        r#"
+export async function main(index, port) {
+    const buf = new Uint8Array([0]);
+    const sock = await Deno.connect({ port });
+    await sock.write(new Uint8Array([index]));
+    if (await sock.read(buf) != 1) throw "read";
+    return buf[0];
+}
+            "#
+    }
+
+    fn last_step() -> &'static str {
+        r#"
+def main(last, port):
+    with __import__("socket").create_connection((None, port)) as sock:
+        sock.send(b'\xff')
+        return last + [sock.recv(1)[0]]
+"#
+    }
+
+    fn flow_forloop_retry() -> FlowValue {
+        serde_json::from_value(serde_json::json!({
+            "modules": [{
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "javascript", "expr": "result.items" },
+                    "skip_failures": false,
+                    "modules": [{
+                        "input_transform": {
+                            "index": { "type": "javascript", "expr": "previous_result.iter.index" },
+                            "port": { "type": "javascript", "expr": "flow_input.port" },
+                        },
+                        "value": {
+                            "type": "rawscript",
+                            "language": "deno",
+                            "content": inner_step(),
+                        },
+                    }],
+                },
+                "retry": { "constant": { "attempts": 2, "seconds": 0 } },
+            }, {
+                "input_transform": {
+                    "last": { "type": "javascript", "expr": "previous_result" },
+                    "port": { "type": "javascript", "expr": "flow_input.port" },
+                },
+                "value": {
+                    "type": "rawscript",
+                    "language": "python3",
+                    "content": last_step(),
+                },
+                "retry": { "constant": { "attempts": 2, "seconds": 0 } },
+            }],
+        }))
+        .unwrap()
+    }
+
+    #[sqlx::test(fixtures("base"))]
+    async fn test_pass(db: Pool<Postgres>) {
+        initialize_tracing().await;
+
+        // let server = ApiServer::start(db.clone()).await;
+
+        /* fails twice in the loop, then once on the last step
+         * retry attempts is measured per-step, so it _retries_ at most two times on each step,
+         * which means it may run the step three times in total */
+
+        let (attempts, responses) = [
+            /* pass fail */
+            (0, Some(99)),
+            (1, None),
+            /* pass pass fail */
+            (0, Some(99)),
+            (1, Some(99)),
+            (2, None),
+            /* pass pass pass */
+            (0, Some(3)),
+            (1, Some(5)),
+            (2, Some(7)),
+            /* fail the last step once */
+            (0xff, None),
+            (0xff, Some(9)),
+        ]
+        .into_iter()
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+        let server = Server::start(responses).await;
+        let result = RunJob::from(JobPayload::RawFlow { value: flow_forloop_retry(), path: None })
+            .arg("items", json!(["unused", "unused", "unused"]))
+            .arg("port", json!(server.addr.port()))
+            .run_until_complete(&db, server.addr.port())
+            .await
+            .result
+            .unwrap();
+
+        assert_eq!(server.close().await, attempts);
+        assert_eq!(json!([3, 5, 7, 9]), result);
+    }
+
+    #[sqlx::test(fixtures("base"))]
+    async fn test_fail_step_zero(db: Pool<Postgres>) {
+        initialize_tracing().await;
+
+        /* attempt and fail the first step three times and stop */
+        let (attempts, responses) = [
+            /* pass fail x3 */
+            (0, Some(99)),
+            (1, None),
+            (0, Some(99)),
+            (1, None),
+            (0, Some(99)),
+            (1, None),
+        ]
+        .into_iter()
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+        let server = Server::start(responses).await;
+        let result = RunJob::from(JobPayload::RawFlow { value: flow_forloop_retry(), path: None })
+            .arg("items", json!(["unused", "unused", "unused"]))
+            .arg("port", json!(server.addr.port()))
+            .run_until_complete(&db, server.addr.port())
+            .await
+            .result
+            .unwrap();
+
+        assert_eq!(server.close().await, attempts);
+        assert!(result["error"]
+            .as_str()
+            .unwrap()
+            .contains(r#"Uncaught (in promise) "read""#));
+    }
+
+    #[sqlx::test(fixtures("base"))]
+    async fn test_fail_step_one(db: Pool<Postgres>) {
+        initialize_tracing().await;
+
+        /* attempt and fail the first step three times and stop */
+        let (attempts, responses) = [
+            /* fail once, then pass */
+            (0, None),
+            (0, Some(1)),
+            (1, Some(2)),
+            (2, Some(3)),
+            /* fail three times */
+            (0xff, None),
+            (0xff, None),
+            (0xff, None),
+        ]
+        .into_iter()
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+        let server = Server::start(responses).await;
+        let result = RunJob::from(JobPayload::RawFlow { value: flow_forloop_retry(), path: None })
+            .arg("items", json!(["unused", "unused", "unused"]))
+            .arg("port", json!(server.addr.port()))
+            .run_until_complete(&db, server.addr.port())
+            .await
+            .result
+            .unwrap();
+
+        assert_eq!(server.close().await, attempts);
+        assert!(result["error"]
+            .as_str()
+            .unwrap()
+            .contains("index out of range"));
+    }
+
+    #[sqlx::test(fixtures("base"))]
+    async fn test_min_seconds_since_first_failure(db: Pool<Postgres>) {
+        initialize_tracing().await;
+
+        let value = serde_json::from_value(json!({
+            "modules": [{
+                "id": "a",
+                "value": {
+                    "input_transform": {
+                        "index": { "type": "static", "value": 0 },
+                        "port": { "type": "javascript", "expr": "flow_input.port" },
+                    },
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": inner_step(),
+                },
+                "retry": {
+                    "constant": { "attempts": 2, "seconds": 0 },
+                    "min_seconds_since_first_failure": 2,
+                },
+            }],
+        }))
+        .unwrap();
+        let (attempts, responses) = [
+            /* fail twice, then pass */
+            (0, None),
+            (0, None),
+            (0, Some(42)),
+        ]
+        .into_iter()
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+        let server = Server::start(responses).await;
+        let cjob = RunJob::from(JobPayload::RawFlow { value, path: None })
+            .arg("port", json!(server.addr.port()))
+            .run_until_complete(&db, server.addr.port())
+            .await;
+
+        assert_eq!(server.close().await, attempts);
+        assert_eq!(cjob.result, Some(json!(42)));
+
+        let runs = sqlx::query_as::<_, (chrono::DateTime<chrono::Utc>, i32)>(
+            "SELECT started_at, duration_ms FROM completed_job WHERE parent_job = $1 ORDER BY started_at",
+        )
+        .bind(cjob.id)
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(runs.len(), 3);
+
+        /* both retries wait for the floor, measured from the first failure */
+        let (first_started_at, first_duration_ms) = runs[0];
+        let first_failure_at =
+            first_started_at + chrono::Duration::milliseconds(first_duration_ms.into());
+        for (started_at, _) in &runs[1..] {
+            assert!(*started_at - first_failure_at >= chrono::Duration::seconds(2));
+        }
+    }
+
+    #[sqlx::test(fixtures("base"))]
+    async fn test_retry_if(db: Pool<Postgres>) {
+        initialize_tracing().await;
+
+        let server = ApiServer::start(db.clone()).await;
+        let port = server.addr.port();
+
+        for (retry_if, attempts) in [
+            /* the error isn't matched, the step fails without being retried */
+            (json!({ "errors": ["Timeout"] }), 1),
+            (json!({ "errors": ["BadRequest"] }), 3),
+            (json!({ "expr": "error.name === 'Timeout'" }), 1),
+            (json!({ "expr": "error.message.includes('status 400')" }), 3),
+            /* an `expr` returning something else than a boolean is coerced */
+            (
+                json!({ "expr": "error.message.match(/status 5\\d\\d/)" }),
+                1,
+            ),
+            (
+                json!({ "expr": "error.message.match(/status 4\\d\\d/)" }),
+                3,
+            ),
+        ] {
+            let value = serde_json::from_value(json!({
+                "modules": [{
+                    "id": "a",
+                    "value": {
+                        "type": "rawscript",
+                        "language": "deno",
+                        "content": r#"
+export function main() {
+    const error = new Error("request failed with status 400");
+    error.name = "BadRequest";
+    throw error;
+}"#,
+                    },
+                    "retry": {
+                        "constant": { "attempts": 2, "seconds": 0 },
+                        "retry_if": retry_if,
+                    },
+                }],
+            }))
+            .unwrap();
+            let cjob = RunJob::from(JobPayload::RawFlow { value, path: None })
+                .run_until_complete(&db, port)
+                .await;
+            assert!(!cjob.success);
+
+            let runs = sqlx::query_scalar::<_, i64>(
+                "SELECT count(*) FROM completed_job WHERE parent_job = $1",
+            )
+            .bind(cjob.id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            assert_eq!(runs, attempts);
+        }
+
+        server.close().await.unwrap();
+    }
+
+    /// The value of the `flow_step_transitions` counter of `transition` for rawscript steps, the
+    /// metrics being shared with the other tests running at the same time.
+    fn rawscript_transitions(transition: &str) -> f64 {
+        prometheus::gather()
+            .iter()
+            .filter(|family| family.get_name() == "flow_step_transitions")
+            .flat_map(|family| family.get_metric())
+            .filter(|metric| {
+                let labels = metric.get_label();
+                labels
+                    .iter()
+                    .any(|l| l.get_name() == "transition" && l.get_value() == transition)
+                    && labels
+                        .iter()
+                        .any(|l| l.get_name() == "module_kind" && l.get_value() == "rawscript")
+            })
+            .map(|metric| metric.get_counter().get_value())
+            .sum()
+    }
+
+    #[sqlx::test(fixtures("base"))]
+    async fn test_flow_step_metrics(db: Pool<Postgres>) {
+        initialize_tracing().await;
+
+        let server = ApiServer::start(db.clone()).await;
+        let port = server.addr.port();
+
+        let value = serde_json::from_value(json!({
+            "modules": [{
+                "id": "a",
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { return 1 }",
+                },
+            }, {
+                "id": "b",
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { throw new Error('failed') }",
+                },
+                "retry": { "constant": { "attempts": 1, "seconds": 0 } },
+            }],
+        }))
+        .unwrap();
+
+        let transitions = ["started", "succeeded", "failed", "retried"];
+        let before = transitions.map(rawscript_transitions);
+        let cjob = RunJob::from(JobPayload::RawFlow { value, path: None })
+            .run_until_complete(&db, port)
+            .await;
+        assert!(!cjob.success);
+        let after = transitions.map(rawscript_transitions);
+
+        /* `a` starts and succeeds, `b` starts, fails, is retried, starts again and fails again */
+        for ((transition, expected), (before, after)) in transitions
+            .iter()
+            .zip([3.0, 1.0, 2.0, 1.0])
+            .zip(before.iter().zip(after))
+        {
+            assert!(
+                after - before >= expected,
+                "{transition}: {before} -> {after}"
+            );
+        }
+
+        server.close().await.unwrap();
+    }
+
+    #[sqlx::test(fixtures("base"))]
+    async fn test_with_failure_module(db: Pool<Postgres>) {
+        initialize_tracing().await;
+
+        // let server = ApiServer::start(db.clone()).await;
+
+        let value = serde_json::from_value(json!({
+            "modules": [{
+                "id": "a",
+                "value": {
+                    "input_transform": { "port": { "type": "javascript", "expr": "flow_input.port" } },
+                    "type": "rawscript",
+                    "language": "python3",
+                    "content": r#"
+def main(port):
+    with __import__("socket").create_connection((None, port)) as sock:
+        sock.send(b'\x00')
+        return sock.recv(1)[0]"#,
+                },
+                "retry": { "constant": { "attempts": 1, "seconds": 0 } },
+            }],
+            "failure_module": {
+                "value": {
+                    "input_transform": { "error": { "type": "javascript", "expr": "previous_result", },
+                        "port": { "type": "javascript", "expr": "flow_input.port" } },
+                    "type": "rawscript",
+                    "language": "python3",
+                    "content": r#"
+def main(error, port):
+    with __import__("socket").create_connection((None, port)) as sock:
+        sock.send(b'\xff')
+        return { "recv": sock.recv(1)[0], "from failure module": error }"#,
+                },
+                "retry": { "constant": { "attempts": 1, "seconds": 0 } },
+            },
+        }))
+        .unwrap();
+        let (attempts, responses) = [
+            /* fail the first step twice */
+            (0x00, None),
+            (0x00, None),
+            /* and the failure module once */
+            (0xff, None),
+            (0xff, Some(42)),
+        ]
+        .into_iter()
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+        let server = Server::start(responses).await;
+        let cjob = RunJob::from(JobPayload::RawFlow { value, path: None })
+            .arg("port", json!(server.addr.port()))
+            .run_until_complete(&db, server.addr.port())
+            .await;
+        let result = cjob.result.clone().unwrap();
+        let failed_module = get_module(&cjob, "a").unwrap();
+        match failed_module {
+            FlowStatusModule::Failure { .. } => {}
+            _ => panic!("expected failure module"),
+        }
+
+        assert_eq!(server.close().await, attempts);
+        assert_eq!(
+            result,
+            json!({
+                "recv": 42,
+                "from failure module": {
+                    "error": "Error during execution of the script:\n\nTraceback (most recent call last):\n  File \"/tmp/main.py\", line 14, in <module>\n    res = inner_script.main(**kwargs)\n  File \"/tmp/inner.py\", line 5, in main\n    return sock.recv(1)[0]\nIndexError: index out of range",
+                }
+            })
+        );
+    }
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_iteration(db: Pool<Postgres>) {
+    initialize_tracing().await;
+
+    let server = ApiServer::start(db.clone()).await;
+
+    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+        "modules": [{
+            "value": {
+                "type": "forloopflow",
+                "iterator": { "type": "javascript", "expr": "result.items" },
+                "skip_failures": false,
+                "modules": [{
+                    "input_transform": {
+                        "n": {
+                            "type": "javascript",
+                            "expr": "previous_result.iter.value",
+                        },
+                    },
+                    "value": {
+                        "type": "rawscript",
+                        "language": "python3",
+                        "content": "def main(n):\n    if 1 < n:\n        raise StopIteration(n)",
+                    },
+                }],
+            },
+        }],
+    }))
+    .unwrap();
+
+    let result = RunJob::from(JobPayload::RawFlow { value: flow.clone(), path: None })
+        .arg("items", json!([]))
+        .run_until_complete(&db, server.addr.port())
+        .await
+        .result
+        .unwrap();
+    assert_eq!(result, serde_json::json!([]));
+
+    /* Don't actually test that this does 257 jobs or that will take forever. */
+    let result = RunJob::from(JobPayload::RawFlow { value: flow.clone(), path: None })
+        .arg("items", json!((0..257).collect::<Vec<_>>()))
+        .run_until_complete(&db, server.addr.port())
+        .await
+        .result
+        .unwrap();
+    assert!(matches!(result, serde_json::Value::Object(_)));
+    assert!(result["error"]
+        .as_str()
+        .unwrap()
+        .contains("StopIteration: 2"));
+}
+
+struct RunJob {
+    payload: JobPayload,
+    args: serde_json::Map<String, serde_json::Value>,
+    flow_storage: FlowStorage,
+}
+
+impl From<JobPayload> for RunJob {
+    fn from(payload: JobPayload) -> Self {
+        Self { payload, args: Default::default(), flow_storage: Default::default() }
+    }
+}
+
+impl RunJob {
+    fn arg<S: Into<String>>(mut self, k: S, v: serde_json::Value) -> Self {
+        self.args.insert(k.into(), v);
+        self
+    }
+
+    fn flow_storage(mut self, flow_storage: FlowStorage) -> Self {
+        self.flow_storage = flow_storage;
+        self
+    }
+
+    async fn push(self, db: &Pool<Postgres>) -> Uuid {
+        let RunJob { payload, args, flow_storage } = self;
+        let tx = db.begin().await.unwrap();
+        let (uuid, tx) = windmill_queue::push(
+            tx,
+            "test-workspace",
+            payload,
+            Some(args),
+            /* user */ "test-user",
+            /* permissioned_as */ "u/admin".to_string(),
+            /* scheduled_for_o */ None,
+            /* schedule_path */ None,
+            /* parent_job */ None,
+            /* is_flow_step */ false,
+            /* running */ false,
+            None,
+            flow_storage,
+        )
+        .await
+        .expect("push has to succeed");
+
+        tx.commit().await.expect("push has to commit");
+
+        uuid
+    }
+
+    /// push the job, spawn a worker, wait until the job is in completed_job
+    async fn run_until_complete(self, db: &Pool<Postgres>, port: u16) -> CompletedJob {
+        let uuid = self.push(db).await;
+        let listener = listen_for_completed_jobs(db).await;
+        in_test_worker(db, listener.find(&uuid), port).await;
+        completed_job(uuid, db).await
+    }
+}
+
+async fn run_job_in_new_worker_until_complete(
+    db: &Pool<Postgres>,
+    job: JobPayload,
+    port: u16,
+) -> CompletedJob {
+    RunJob::from(job).run_until_complete(db, port).await
+}
+
+/// Start a worker with a timeout and run a future, until the worker quits or we time out.
+///
+/// Cleans up the worker before resolving.
+async fn in_test_worker<Fut: std::future::Future>(
+    db: &Pool<Postgres>,
+    inner: Fut,
+    port: u16,
+) -> <Fut as std::future::Future>::Output {
+    let (quit, worker) = spawn_test_worker(db, port);
+    let worker = tokio::time::timeout(std::time::Duration::from_secs(19), worker);
+    tokio::pin!(worker);
+
+    let res = tokio::select! {
+        biased;
+        res = inner => res,
+        res = &mut worker => match
+            res.expect("worker timed out")
+               .expect("worker panicked") {
+            _ => panic!("worker quit early"),
+        },
+    };
+
+    /* ensure the worker quits before we return */
+    drop(quit);
+
+    let _: () = worker
+        .await
+        .expect("worker timed out")
+        .expect("worker panicked");
+
+    res
+}
+
+fn spawn_test_worker(
+    db: &Pool<Postgres>,
     port: u16,
 ) -> (
     tokio::sync::broadcast::Sender<()>,
@@ -866,712 +1946,5267 @@ fn spawn_test_worker(
             .and_then(|x| x.parse::<bool>().ok())
             .unwrap_or(false),
     };
-    let future = async move {
-        windmill_worker::run_worker(
-            &db,
-            timeout,
-            worker_instance,
-            worker_name,
-            i_worker,
-            num_workers,
-            ip,
-            sleep_queue,
-            worker_config,
-            rx,
+    let future = async move {
+        windmill_worker::run_worker(
+            &db,
+            timeout,
+            worker_instance,
+            worker_name,
+            i_worker,
+            num_workers,
+            ip,
+            sleep_queue,
+            worker_config,
+            rx,
+        )
+        .await
+    };
+
+    (tx, tokio::task::spawn(future))
+}
+
+async fn listen_for_completed_jobs(db: &Pool<Postgres>) -> impl Stream<Item = Uuid> + Unpin {
+    listen_for_uuid_on(db, "insert on completed_job").await
+}
+
+async fn listen_for_queue(db: &Pool<Postgres>) -> impl Stream<Item = Uuid> + Unpin {
+    listen_for_uuid_on(db, "queue").await
+}
+
+async fn listen_for_uuid_on(
+    db: &Pool<Postgres>,
+    channel: &'static str,
+) -> impl Stream<Item = Uuid> + Unpin {
+    let mut listener = PgListener::connect_with(db).await.unwrap();
+    listener.listen(channel).await.unwrap();
+
+    Box::pin(stream::unfold(listener, |mut listener| async move {
+        let uuid = listener
+            .try_recv()
+            .await
+            .unwrap()
+            .expect("lost database connection")
+            .payload()
+            .parse::<Uuid>()
+            .expect("invalid uuid");
+        Some((uuid, listener))
+    }))
+}
+
+async fn completed_job(uuid: Uuid, db: &Pool<Postgres>) -> CompletedJob {
+    sqlx::query_as::<_, CompletedJob>("SELECT * FROM completed_job WHERE id = $1")
+        .bind(uuid)
+        .fetch_one(db)
+        .await
+        .unwrap()
+}
+
+#[axum::async_trait(?Send)]
+trait StreamFind: futures::Stream + Unpin + Sized {
+    async fn find(self, item: &Self::Item) -> Option<Self::Item>
+    where
+        for<'l> &'l Self::Item: std::cmp::PartialEq,
+    {
+        use futures::{future::ready, StreamExt};
+
+        self.filter(|i| ready(i == item)).next().await
+    }
+}
+
+impl<T: futures::Stream + Unpin + Sized> StreamFind for T {}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_deno_flow(db: Pool<Postgres>) {
+    initialize_tracing().await;
+
+    let server = ApiServer::start(db.clone()).await;
+
+    let numbers = "export function main() { return [1, 2, 3]; }";
+    let doubles = "export function main(n) { return n * 2; }";
+
+    let flow = {
+        FlowValue {
+            modules: vec![
+                FlowModule {
+                    id: "a".to_string(),
+                    value: FlowModuleValue::RawScript {
+                        input_transforms: Default::default(),
+                        language: ScriptLang::Deno,
+                        content: numbers.to_string(),
+                        path: None,
+                    },
+                    input_transforms: Default::default(),
+                    stop_after_if: Default::default(),
+                    summary: Default::default(),
+                    suspend: Default::default(),
+                    retry: None,
+                    sleep: None,
+                    input_schema: None,
+                    output_schema: None,
+                    partial_result: None,
+                    max_queue_wait: None,
+                    timeout: None,
+                    group: None,
+                    compensation: None,
+                    catch: vec![],
+                    run_on_cancel: false,
+                    run_on_timeout: false,
+                    input_merge: Default::default(),
+                    poll: None,
+                    assert: None,
+                    result_schema: None,
+                    depends_on: vec![],
+                    same_worker: None,
+                    cache_ttl: None,
+                    skip_if: None,
+                },
+                FlowModule {
+                    id: "b".to_string(),
+                    value: FlowModuleValue::ForloopFlow {
+                        iterator: InputTransform::Javascript { expr: "result".to_string() }.into(),
+                        skip_failures: false,
+                        max_failures: None,
+                        order_by: None,
+                        error_if_empty: false,
+                        min_iteration_interval: None,
+                        parallelism: None,
+                        break_if: None,
+                        modules: vec![FlowModule {
+                            id: "c".to_string(),
+                            value: FlowModuleValue::RawScript {
+                                input_transforms: [(
+                                    "n".to_string(),
+                                    InputTransform::Javascript {
+                                        expr: "previous_result.iter.value".to_string(),
+                                    },
+                                )]
+                                .into(),
+                                language: ScriptLang::Deno,
+                                content: doubles.to_string(),
+                                path: None,
+                            },
+                            input_transforms: Default::default(),
+                            stop_after_if: Default::default(),
+                            summary: Default::default(),
+                            suspend: Default::default(),
+                            retry: None,
+                            sleep: None,
+                            input_schema: None,
+                            output_schema: None,
+                            partial_result: None,
+                            max_queue_wait: None,
+                            timeout: None,
+                            group: None,
+                            compensation: None,
+                            catch: vec![],
+                            run_on_cancel: false,
+                            run_on_timeout: false,
+                            input_merge: Default::default(),
+                            poll: None,
+                            assert: None,
+                            result_schema: None,
+                            depends_on: vec![],
+                            same_worker: None,
+                            cache_ttl: None,
+                            skip_if: None,
+                        }],
+                    },
+                    input_transforms: Default::default(),
+                    stop_after_if: Default::default(),
+                    summary: Default::default(),
+                    suspend: Default::default(),
+                    retry: None,
+                    sleep: None,
+                    input_schema: None,
+                    output_schema: None,
+                    partial_result: None,
+                    max_queue_wait: None,
+                    timeout: None,
+                    group: None,
+                    compensation: None,
+                    catch: vec![],
+                    run_on_cancel: false,
+                    run_on_timeout: false,
+                    input_merge: Default::default(),
+                    poll: None,
+                    assert: None,
+                    result_schema: None,
+                    depends_on: vec![],
+                    same_worker: None,
+                    cache_ttl: None,
+                    skip_if: None,
+                },
+            ],
+            same_worker: Some(false),
+            ..Default::default()
+        }
+    };
+
+    let job = JobPayload::RawFlow { value: flow, path: None };
+    let port = server.addr.port();
+
+    for i in 0..50 {
+        println!("deno flow iteration: {}", i);
+        let result = run_job_in_new_worker_until_complete(&db, job.clone(), port)
+            .await
+            .result
+            .unwrap();
+        assert_eq!(result, serde_json::json!([2, 4, 6]), "iteration: {}", i);
+    }
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_deno_flow_same_worker(db: Pool<Postgres>) {
+    initialize_tracing().await;
+
+    let server = ApiServer::start(db.clone()).await;
+
+    let write_file = r#"export async function main(loop: boolean, i: number, path: string) {  
+            await Deno.writeTextFile(`/shared/${path}`, `${loop} ${i}`);
+        }"#
+    .to_string();
+
+    let flow = FlowValue {
+            modules: vec![
+                FlowModule {
+                    id: "a".to_string(),
+                    value: FlowModuleValue::RawScript {
+                        input_transforms: [
+                        (
+                            "loop".to_string(),
+                            InputTransform::Static { value: json!(false) },
+                        ),
+                        ("i".to_string(), InputTransform::Static { value: json!(1) }),
+                        (
+                            "path".to_string(),
+                            InputTransform::Static { value: json!("outer.txt") },
+                        ),
+                    ]
+                    .into(),
+                        language: ScriptLang::Deno,
+                        content: write_file.clone(),
+                        path: None,
+                    },
+                    input_transforms: Default::default(),
+                    stop_after_if: Default::default(),
+                    summary: Default::default(),
+                    suspend: Default::default(),
+                    retry: None,
+                    sleep: None,
+                    input_schema: None,
+                    output_schema: None,
+                    partial_result: None,
+                    max_queue_wait: None,
+                    timeout: None,
+                    group: None,
+                    compensation: None,
+                    catch: vec![],
+                    run_on_cancel: false,
+                    run_on_timeout: false,
+                    input_merge: Default::default(),
+                    poll: None,
+                    assert: None,
+                    result_schema: None,
+                    depends_on: vec![],
+                    same_worker: None,
+                    cache_ttl: None,
+                    skip_if: None,
+                },
+                FlowModule {
+                    id: "b".to_string(),
+                    value: FlowModuleValue::ForloopFlow {
+                        iterator: InputTransform::Static { value: json!([1, 2, 3]) }.into(),
+                        skip_failures: false,
+                        max_failures: None,
+                        order_by: None,
+                        error_if_empty: false,
+                        min_iteration_interval: None,
+                        parallelism: None,
+                        break_if: None,
+                        modules: vec![
+                            FlowModule {
+                                id: "d".to_string(),
+                                input_transforms: [
+                                (
+                                    "i".to_string(),
+                                    InputTransform::Javascript {
+                                        expr: "previous_result.iter.value".to_string(),
+                                    },
+                                ),
+                                (
+                                    "loop".to_string(),
+                                    InputTransform::Static { value: json!(true) },
+                                ),
+                                (
+                                    "path".to_string(),
+                                    InputTransform::Static { value: json!("inner.txt") },
+                                ),
+                            ]
+                            .into(),
+                                value: FlowModuleValue::RawScript {
+                                    input_transforms: [].into(),
+                                    language: ScriptLang::Deno,
+                                    content: write_file,
+                                    path: None,
+                                },
+                                stop_after_if: Default::default(),
+                                summary: Default::default(),
+                                suspend: Default::default(),
+                                retry: None,
+                                sleep: None,
+                                input_schema: None,
+                                output_schema: None,
+                                partial_result: None,
+                                max_queue_wait: None,
+                                timeout: None,
+                                group: None,
+                                compensation: None,
+                                catch: vec![],
+                                run_on_cancel: false,
+                                run_on_timeout: false,
+                                input_merge: Default::default(),
+                                poll: None,
+                                assert: None,
+                                result_schema: None,
+                                depends_on: vec![],
+                                same_worker: None,
+                                cache_ttl: None,
+                                skip_if: None,
+                            },
+                            FlowModule {
+                                id: "e".to_string(),
+                                value: FlowModuleValue::RawScript {
+                                    input_transforms: [(
+                                        "path".to_string(),
+                                        InputTransform::Static { value: json!("inner.txt") },
+                                    ), (
+                                        "path2".to_string(),
+                                        InputTransform::Static { value: json!("outer.txt") },
+                                    )]
+                                    .into(),
+                                    language: ScriptLang::Deno,
+                                    content: r#"export async function main(path: string, path2: string) {  
+                                        return await Deno.readTextFile(`/shared/${path}`) + "," + await Deno.readTextFile(`/shared/${path2}`);
+                                    }"#
+                                    .to_string(),
+                                    path: None,
+                                },
+                                input_transforms: [].into(),
+                                stop_after_if: Default::default(),
+                                summary: Default::default(),
+                                suspend: Default::default(),
+                                retry: None,
+                                sleep: None,
+                                input_schema: None,
+                                output_schema: None,
+                                partial_result: None,
+                                max_queue_wait: None,
+                                timeout: None,
+                                group: None,
+                                compensation: None,
+                                catch: vec![],
+                                run_on_cancel: false,
+                                run_on_timeout: false,
+                                input_merge: Default::default(),
+                                poll: None,
+                                assert: None,
+                                result_schema: None,
+                                depends_on: vec![],
+                                same_worker: None,
+                                cache_ttl: None,
+                                skip_if: None,
+                            },
+                        ],
+                    },
+                    input_transforms: Default::default(),
+                    stop_after_if: Default::default(),
+                    summary: Default::default(),
+                    suspend: Default::default(),
+                    retry: None,
+                    sleep: None,
+                    input_schema: None,
+                    output_schema: None,
+                    partial_result: None,
+                    max_queue_wait: None,
+                    timeout: None,
+                    group: None,
+                    compensation: None,
+                    catch: vec![],
+                    run_on_cancel: false,
+                    run_on_timeout: false,
+                    input_merge: Default::default(),
+                    poll: None,
+                    assert: None,
+                    result_schema: None,
+                    depends_on: vec![],
+                    same_worker: None,
+                    cache_ttl: None,
+                    skip_if: None,
+
+                },
+                FlowModule {
+                    id: "c".to_string(),
+                    value: FlowModuleValue::RawScript {
+                        input_transforms: [
+                        (
+                            "loops".to_string(),
+                            InputTransform::Javascript { expr: "previous_result".to_string() },
+                        ),
+                        (
+                            "path".to_string(),
+                            InputTransform::Static { value: json!("outer.txt") },
+                        ),
+                        (
+                            "path2".to_string(),
+                            InputTransform::Static { value: json!("inner.txt") },
+                        ),
+                    ]
+                    .into(),
+                        language: ScriptLang::Deno,
+                        content: r#"export async function main(path: string, loops: string[], path2: string) {
+                            return await Deno.readTextFile(`/shared/${path}`) + "," + loops + "," + await Deno.readTextFile(`/shared/${path2}`);
+                        }"#
+                        .to_string(),
+                        path: None,
+                    },
+                    input_transforms: [].into(),
+                    stop_after_if: Default::default(),
+                    summary: Default::default(),
+                    suspend: Default::default(),
+                    retry: None,
+                    sleep: None,
+                    input_schema: None,
+                    output_schema: None,
+                    partial_result: None,
+                    max_queue_wait: None,
+                    timeout: None,
+                    group: None,
+                    compensation: None,
+                    catch: vec![],
+                    run_on_cancel: false,
+                    run_on_timeout: false,
+                    input_merge: Default::default(),
+                    poll: None,
+                    assert: None,
+                    result_schema: None,
+                    depends_on: vec![],
+                    same_worker: None,
+                    cache_ttl: None,
+                    skip_if: None,
+                },
+            ],
+            same_worker: Some(true),
+            ..Default::default()
+        };
+
+    let job = JobPayload::RawFlow { value: flow, path: None };
+
+    let result = run_job_in_new_worker_until_complete(&db, job.clone(), server.addr.port())
+        .await
+        .result
+        .unwrap();
+    assert_eq!(
+        result,
+        serde_json::json!("false 1,true 1,false 1,true 2,false 1,true 3,false 1,true 3")
+    );
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_flow_same_worker_per_step(db: Pool<Postgres>) {
+    initialize_tracing().await;
+
+    let server = ApiServer::start(db.clone()).await;
+
+    let read_file = r#"export async function main() {
+        try {
+            return await Deno.readTextFile("/shared/a.txt");
+        } catch {
+            return "not shared";
+        }
+    }"#;
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [
+            {
+                "id": "a",
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": r#"export async function main() {
+                        await Deno.writeTextFile("/shared/a.txt", "from a");
+                    }"#,
+                },
+                "same_worker": true,
+            },
+            {
+                "id": "b",
+                "value": { "type": "rawscript", "language": "deno", "content": read_file },
+                "same_worker": true,
+            },
+            {
+                "id": "c",
+                "input_transforms": {
+                    "b": { "type": "javascript", "expr": "previous_result" },
+                },
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": r#"export async function main(b: string) {
+                        try {
+                            return b + "," + await Deno.readTextFile("/shared/a.txt");
+                        } catch {
+                            return b + ",not shared";
+                        }
+                    }"#,
+                },
+            },
+        ],
+        "same_worker": false,
+    }))
+    .unwrap();
+
+    let result = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, server.addr.port())
+        .await
+        .result
+        .unwrap();
+    assert_eq!(result, json!("from a,not shared"));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_max_flow_jobs(db: Pool<Postgres>) {
+    initialize_tracing().await;
+
+    let server = ApiServer::start(db.clone()).await;
+
+    sqlx::query(
+        "UPDATE workspace_settings SET max_flow_jobs = 3 WHERE workspace_id = 'test-workspace'",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    /* each iteration pushes its flow and the job of its step */
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "value": {
+                "type": "forloopflow",
+                "iterator": { "type": "javascript", "expr": "[1, 2, 3]" },
+                "skip_failures": false,
+                "modules": [{
+                    "value": {
+                        "type": "rawscript",
+                        "language": "deno",
+                        "content": "export function main(){ return 1 }",
+                    },
+                }],
+            },
+        }],
+    }))
+    .unwrap();
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, server.addr.port())
+        .await;
+    assert!(!cjob.success);
+    let error = format!(
+        "Job canceled: flow run {} exceeded the maximum of 3 jobs by windmill",
+        cjob.id
+    );
+    assert_eq!(
+        cjob.result.unwrap(),
+        json!({ "error": error, "name": "Error", "message": error })
+    );
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_flow_timeout(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "id": "a",
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export async function main() { await new Promise(r => setTimeout(r, 2000)); return 1 }",
+            },
+        }, {
+            "id": "b",
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main() { return 2 }",
+            },
+        }],
+        "timeout": 1,
+    }))
+    .unwrap();
+
+    /* the flow is canceled once the step running past its timeout completes */
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+    assert_eq!(
+        cjob.result,
+        Some(json!({
+            "error": "Job canceled: flow duration > 1s by timeout",
+            "name": "Error",
+            "message": "Job canceled: flow duration > 1s by timeout",
+        }))
+    );
+    let steps = sqlx::query_scalar::<_, String>(
+        "SELECT flow_step_id FROM completed_job WHERE parent_job = $1",
+    )
+    .bind(cjob.id)
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(steps, vec!["a".to_string()]);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_on_cancel(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow = |cleanup: &str| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{
+                "id": "a",
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export async function main() { await new Promise(r => setTimeout(r, 2000)); return 1 }",
+                },
+            }],
+            "timeout": 1,
+            "on_cancel": {
+                "modules": [{
+                    "id": "cleanup",
+                    "value": {
+                        "input_transforms": {
+                            "name": { "type": "javascript", "expr": "previous_result.name" },
+                        },
+                        "type": "rawscript",
+                        "language": "deno",
+                        "content": cleanup,
+                    },
+                }],
+                "timeout": 1,
+            },
+        }))
+        .unwrap()
+    };
+
+    /* the cleanup runs with the cancellation error before the flow completes as canceled ... */
+    let cjob = RunJob::from(JobPayload::RawFlow {
+        value: flow("export function main(name) { return `cleaned up after ${name}` }"),
+        path: None,
+    })
+    .run_until_complete(&db, port)
+    .await;
+    assert_eq!(
+        cjob.result,
+        Some(json!({
+            "error": "Job canceled: flow duration > 1s by timeout",
+            "name": "Error",
+            "message": "Job canceled: flow duration > 1s by timeout",
+        }))
+    );
+    let cleanup = sqlx::query_scalar::<_, serde_json::Value>(
+        "SELECT result FROM completed_job WHERE parent_job = $1 AND flow_step_id = 'failure'",
+    )
+    .bind(cjob.id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(cleanup, json!("cleaned up after Canceled"));
+
+    /* ... even when the cleanup is canceled for running past its own timeout */
+    let cjob = RunJob::from(JobPayload::RawFlow {
+        value: flow("export async function main() { await new Promise(r => setTimeout(r, 2000)) }"),
+        path: None,
+    })
+    .run_until_complete(&db, port)
+    .await;
+    assert_eq!(
+        cjob.result,
+        Some(json!({
+            "error": "Job canceled: flow duration > 1s by timeout",
+            "name": "Error",
+            "message": "Job canceled: flow duration > 1s by timeout",
+        }))
+    );
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_module_cache(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "id": "a",
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main(x) { return [x, Math.random()] }",
+                "input_transforms": { "x": { "type": "javascript", "expr": "flow_input.x" } },
+            },
+            "cache_ttl": 60,
+            "stop_after_if": { "expr": "result[0] == 3", "skip_if_stopped": false },
+        }, {
+            "id": "b",
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main(r) { return r }",
+                "input_transforms": { "r": { "type": "javascript", "expr": "previous_result" } },
+            },
+        }],
+    }))
+    .unwrap();
+
+    let run = |x: i32| {
+        RunJob::from(JobPayload::RawFlow { value: flow.clone(), path: None })
+            .arg("x", json!(x))
+            .run_until_complete(&db, port)
+    };
+    let steps = |id: Uuid| {
+        sqlx::query_scalar::<_, String>(
+            "SELECT flow_step_id FROM completed_job WHERE parent_job = $1 ORDER BY started_at",
+        )
+        .bind(id)
+        .fetch_all(&db)
+    };
+
+    let first = run(1).await;
+    assert!(first.success);
+    assert_eq!(steps(first.id).await.unwrap(), vec!["a", "b"]);
+
+    /* the same arguments reuse the result of the first run without running step a */
+    let second = run(1).await;
+    assert!(second.success);
+    assert_eq!(second.result, first.result);
+    assert_eq!(steps(second.id).await.unwrap(), vec!["b"]);
+
+    /* other arguments run it again */
+    let third = run(2).await;
+    assert!(third.success);
+    assert_ne!(third.result, first.result);
+    assert_eq!(steps(third.id).await.unwrap(), vec!["a", "b"]);
+
+    /* stop_after_if is evaluated against the cached result */
+    let fourth = run(3).await;
+    assert_eq!(steps(fourth.id).await.unwrap(), vec!["a"]);
+    let fifth = run(3).await;
+    assert_eq!(fifth.result, fourth.result);
+    assert!(steps(fifth.id).await.unwrap().is_empty());
+
+    /* a result cached for a run as someone else is not used */
+    sqlx::query("UPDATE flow_module_cache SET permissioned_as = 'u/other'")
+        .execute(&db)
+        .await
+        .unwrap();
+    let sixth = run(1).await;
+    assert_ne!(sixth.result, first.result);
+    assert_eq!(steps(sixth.id).await.unwrap(), vec!["a", "b"]);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_max_concurrent_jobs(db: Pool<Postgres>) {
+    initialize_tracing().await;
+
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    sqlx::query(
+        "UPDATE workspace_settings SET max_concurrent_jobs = 1 WHERE workspace_id = 'test-workspace'",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    /* a job already running takes the only slot */
+    let tx = db.begin().await.unwrap();
+    let (running, tx) = windmill_queue::push(
+        tx,
+        "test-workspace",
+        JobPayload::Identity,
+        None,
+        "test-user",
+        "u/test-user".to_string(),
+        None,
+        None,
+        None,
+        false,
+        true,
+        None,
+        FlowStorage::default(),
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let concurrent_jobs = reqwest::Client::new()
+        .get(format!(
+            "http://localhost:{port}/api/w/test-workspace/workspaces/concurrent_jobs"
+        ))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+    assert_eq!(
+        concurrent_jobs,
+        json!({ "count": 1, "deferred": 0, "max_concurrent_jobs": 1 })
+    );
+
+    /* the step is deferred, the flow still completes with its result */
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main(){ return 42 }",
+            },
+        }],
+    }))
+    .unwrap();
+    /* the step is deferred again as long as the slot is taken, the running job is done after
+     * 8 seconds */
+    let release = {
+        let db = db.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_secs(8)).await;
+            sqlx::query("DELETE FROM queue WHERE id = $1")
+                .bind(running)
+                .execute(&db)
+                .await
+                .unwrap();
+        })
+    };
+    let start = std::time::Instant::now();
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(cjob.success);
+    assert_eq!(cjob.result.unwrap(), json!(42));
+    assert!(start.elapsed() >= std::time::Duration::from_secs(8));
+    release.await.unwrap();
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_push_idempotency_key(db: Pool<Postgres>) {
+    initialize_tracing().await;
+
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let push = |key: &'static str| {
+        let db = db.clone();
+        async move {
+            let tx = db.begin().await.unwrap();
+            let (uuid, tx) = windmill_queue::push(
+                tx,
+                "test-workspace",
+                JobPayload::Identity,
+                None,
+                "test-user",
+                "u/test-user".to_string(),
+                None,
+                None,
+                None,
+                false,
+                false,
+                Some(key.to_string()),
+                FlowStorage::default(),
+            )
+            .await
+            .unwrap();
+            tx.commit().await.unwrap();
+            uuid
+        }
+    };
+
+    /* a job pushed again with the same key is the job already pushed */
+    let job = push("key").await;
+    assert_eq!(push("key").await, job);
+    assert_ne!(push("other key").await, job);
+    let queued =
+        sqlx::query_scalar::<_, i64>("SELECT count(*) FROM queue WHERE idempotency_key = 'key'")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    assert_eq!(queued, 1);
+
+    /* ... even once it completed */
+    let listener = listen_for_completed_jobs(&db).await;
+    in_test_worker(&db, listener.find(&job), port).await;
+    assert_eq!(push("key").await, job);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_dead_letter(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    sqlx::query(
+        "UPDATE workspace_settings SET dead_letter = true WHERE workspace_id = 'test-workspace'",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "id": "a",
+            "input_transforms": { "n": { "type": "javascript", "expr": "flow_input.n" } },
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main(n) { if (n < 0) throw Error('negative'); return n }",
+            },
+        }],
+    }))
+    .unwrap();
+    let run = |n: i32| {
+        RunJob::from(JobPayload::RawFlow { value: flow.clone(), path: None })
+            .arg("n", json!(n))
+            .run_until_complete(&db, port)
+    };
+
+    /* a flow that failed for good is kept with its payload and its error */
+    let failed = run(-1).await;
+    assert!(!failed.success);
+    let (args, error) = sqlx::query_as::<_, (serde_json::Value, serde_json::Value)>(
+        "SELECT args, error FROM dead_letter_job WHERE id = $1",
+    )
+    .bind(failed.id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(args, json!({ "n": -1 }));
+    assert!(error["error"].as_str().unwrap().contains("negative"));
+
+    /* ... not one that succeeded */
+    let succeeded = run(1).await;
+    let kept = sqlx::query_scalar::<_, i64>("SELECT count(*) FROM dead_letter_job WHERE id = $1")
+        .bind(succeeded.id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(kept, 0);
+
+    /* a replay runs the flow again with the original args */
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let replayed = reqwest::Client::new()
+        .post(format!(
+            "http://localhost:{port}/api/w/test-workspace/jobs/dead_letter/replay/{}?token={token}",
+            failed.id
+        ))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .text()
+        .await
+        .unwrap()
+        .parse::<Uuid>()
+        .unwrap();
+    let listener = listen_for_completed_jobs(&db).await;
+    in_test_worker(&db, listener.find(&replayed), port).await;
+    let cjob = completed_job(replayed, &db).await;
+    assert!(!cjob.success);
+    assert_eq!(cjob.args, Some(json!({ "n": -1 })));
+    let replayed_as = sqlx::query_scalar::<_, Option<Uuid>>(
+        "SELECT replayed_as FROM dead_letter_job WHERE id = $1",
+    )
+    .bind(failed.id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(replayed_as, Some(replayed));
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_rerun_pin_step_args(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "input_transforms": {
+                "n": { "type": "javascript", "expr": "flow_input.n" },
+            },
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main(n: number) { return n * 10; }",
+            },
+        }],
+    }))
+    .unwrap();
+    let original = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .arg("n", json!(1))
+        .run_until_complete(&db, port)
+        .await;
+    assert_eq!(original.result, Some(json!(10)));
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let rerun = |pin_step_args: bool| {
+        reqwest::Client::new()
+            .post(format!(
+                "http://localhost:{port}/api/w/test-workspace/jobs/rerun/{}",
+                original.id
+            ))
+            .bearer_auth(&token)
+            .json(&json!({ "args": { "n": 2 }, "pin_step_args": pin_step_args }))
+            .send()
+    };
+    let rerun_result = |pin_step_args: bool| {
+        let (db, rerun) = (&db, &rerun);
+        async move {
+            let uuid = rerun(pin_step_args)
+                .await
+                .unwrap()
+                .error_for_status()
+                .unwrap()
+                .text()
+                .await
+                .unwrap()
+                .parse::<Uuid>()
+                .unwrap();
+            let listener = listen_for_completed_jobs(db).await;
+            in_test_worker(db, listener.find(&uuid), port).await;
+            completed_job(uuid, db).await.result
+        }
+    };
+
+    /* the step evaluates its transform with the new args, unless pinned to its original ones */
+    assert_eq!(rerun_result(false).await, Some(json!(20)));
+    assert_eq!(rerun_result(true).await, Some(json!(10)));
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_run_wait_result_flow(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "input_transforms": {
+                "n": { "type": "javascript", "expr": "flow_input.n" },
+            },
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main(n: number) { return n + 1; }",
+            },
+        }],
+    }))
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO flow (workspace_id, path, summary, description, value, edited_by)
+              VALUES ('test-workspace', 'u/test-user/sync', '', '', $1, 'test-user')",
+    )
+    .bind(serde_json::to_value(flow).unwrap())
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let run = |timeout: u64| {
+        reqwest::Client::new()
+            .post(format!(
+                "http://localhost:{port}/api/w/test-workspace/jobs/run_wait_result/f/u/test-user/sync?timeout={timeout}"
+            ))
+            .bearer_auth(&token)
+            .json(&json!({ "n": 41 }))
+            .send()
+    };
+
+    /* without a worker the flow can't complete in time, it is still queued */
+    let response = run(1).await.unwrap();
+    assert_eq!(response.status(), 408);
+    let queued = sqlx::query_scalar::<_, i64>(
+        "SELECT count(*) FROM queue WHERE script_path = 'u/test-user/sync'",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(queued, 1);
+
+    let response = in_test_worker(&db, run(10), port).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.json::<serde_json::Value>().await.unwrap(),
+        json!(42)
+    );
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_flow_statuses(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow = || -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{ "id": "a", "value": { "type": "identity" } }],
+        }))
+        .unwrap()
+    };
+    let completed = run_job_in_new_worker_until_complete(
+        &db,
+        JobPayload::RawFlow { value: flow(), path: None },
+        port,
+    )
+    .await
+    .id;
+    /* pushed after the worker is gone, they stay in the queue */
+    let running = RunJob::from(JobPayload::RawFlow { value: flow(), path: None })
+        .push(&db)
+        .await;
+    let not_a_flow = RunJob::from(JobPayload::Identity).push(&db).await;
+    let unknown = Uuid::new_v4();
+    /* the jobs must be visible to the user asking for them */
+    for table in ["queue", "completed_job"] {
+        sqlx::query(&format!(
+            "UPDATE {table} SET permissioned_as = 'u/test-user' WHERE id = ANY($1)"
+        ))
+        .bind(vec![completed, running, not_a_flow])
+        .execute(&db)
+        .await
+        .unwrap();
+    }
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let flow_statuses = |ids: Vec<Uuid>| {
+        reqwest::Client::new()
+            .post(format!(
+                "http://localhost:{port}/api/w/test-workspace/jobs/flow_statuses"
+            ))
+            .bearer_auth(&token)
+            .json(&ids)
+            .send()
+    };
+
+    /* the statuses come in the order of the ids, the errors along with the id they are about */
+    let statuses = flow_statuses(vec![unknown, completed, not_a_flow, running])
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+    let statuses = statuses.as_array().unwrap();
+    assert_eq!(statuses.len(), 4);
+
+    assert_eq!(
+        statuses[0],
+        json!({ "id": unknown, "error": format!("Flow {unknown} not found") })
+    );
+
+    assert_eq!(statuses[1]["id"], json!(completed));
+    assert_eq!(statuses[1]["status"]["completed"], json!(true));
+    assert_eq!(statuses[1]["status"]["success"], json!(true));
+    assert_eq!(statuses[1]["status"]["step"], json!(1));
+
+    assert_eq!(
+        statuses[2],
+        json!({ "id": not_a_flow, "error": format!("Job {not_a_flow} is not a flow") })
+    );
+
+    assert_eq!(statuses[3]["id"], json!(running));
+    assert_eq!(statuses[3]["status"]["completed"], json!(false));
+    assert_eq!(statuses[3]["status"].get("success"), None);
+    assert_eq!(statuses[3]["status"]["step"], json!(0));
+    assert_eq!(
+        statuses[3]["status"]["module"],
+        json!({ "type": "WaitingForPriorSteps", "id": "a" })
+    );
+
+    /* too many ids are refused as a whole */
+    let response = flow_statuses(vec![running; 101]).await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("at most 100 flow statuses can be requested at once"));
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_stream_flow_steps(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [
+            { "value": { "type": "identity" } },
+            { "value": { "type": "identity" } },
+        ],
+    }))
+    .unwrap();
+    let flow = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .push(&db)
+        .await;
+    /* the flow must be visible to the subscriber */
+    sqlx::query("UPDATE queue SET permissioned_as = 'u/test-user' WHERE id = $1")
+        .bind(flow)
+        .execute(&db)
+        .await
+        .unwrap();
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+
+    /* subscribed while the flow is queued, the stream ends with its last step */
+    let mut response = reqwest::Client::new()
+        .get(format!(
+            "http://localhost:{port}/api/w/test-workspace/jobs/flow_steps/{flow}"
+        ))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let body = in_test_worker(
+        &db,
+        async {
+            let mut body = String::new();
+            while let Some(chunk) = response.chunk().await.unwrap() {
+                body.push_str(&String::from_utf8_lossy(&chunk));
+            }
+            body
+        },
+        port,
+    )
+    .await;
+    let events = body
+        .lines()
+        .filter_map(|l| l.strip_prefix("data:"))
+        .map(|d| serde_json::from_str::<serde_json::Value>(d.trim()).unwrap())
+        .collect::<Vec<_>>();
+    let steps = body
+        .lines()
+        .filter(|l| l.strip_prefix("event:").map(str::trim) == Some("step"))
+        .count();
+    assert_eq!(steps, 2, "{body}");
+    assert_eq!(
+        events.iter().map(|e| e["done"].clone()).collect::<Vec<_>>(),
+        vec![json!(false), json!(true)]
+    );
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_flow_module_timings(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let script =
+        |content: &str| json!({ "type": "rawscript", "language": "deno", "content": content });
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [
+            { "id": "a", "value": script("export function main() { return 1; }") },
+            {
+                "id": "b",
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "static", "value": [1, 2] },
+                    "skip_failures": false,
+                    "modules": [{
+                        "value": script("export async function main() { await new Promise((r) => setTimeout(r, 100)); }"),
+                    }],
+                },
+            },
+            { "id": "c", "value": script("export function main() { throw new Error('c'); }") },
+        ],
+    }))
+    .unwrap();
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+
+    let timings = |id: &str| match get_module(&cjob, id) {
+        Some(
+            FlowStatusModule::Success { started_at: Some(s), ended_at: Some(e), .. }
+            | FlowStatusModule::Failure { started_at: Some(s), ended_at: Some(e), .. },
+        ) => (s, e),
+        m => panic!("expected the timings of {id}, got {m:?}"),
+    };
+    let (a, b, c) = (timings("a"), timings("b"), timings("c"));
+    assert!(a.0 <= a.1 && a.1 <= b.0, "{a:?} {b:?}");
+    /* the loop started with its first iteration and ended with its last one */
+    assert!(b.1 - b.0 >= chrono::Duration::milliseconds(200), "{b:?}");
+    assert!(b.1 <= c.0 && c.0 <= c.1, "{b:?} {c:?}");
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_flow_result_by_id(db: Pool<Postgres>) {
+    initialize_tracing().await;
+
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(json!({
+            "modules": [
+                {
+                    "id": "a",
+                    "value": {
+                        "type": "rawscript",
+                        "language": "deno",
+                        "content": "export function main(){ return 42 }",
+                    }
+                },
+                {
+                    "value": {
+                        "branches": [
+                            {
+                                "modules": [{
+                                    "value": {
+                                        "branches": [{"modules": [                {
+                                            "id": "d",
+                                            "value": {
+                                                "input_transforms": {"v": {"type": "javascript", "expr": "result_by_id(\"a\")"}},
+                                                "type": "rawscript",
+                                                "language": "deno",
+                                                "content": "export function main(v){ return v }",
+                                            }
+
+                                        },]}],
+                                        "type": "branchall",
+                                    }
+                                }],
+                            }],
+                            "type": "branchall",
+                        },
+                    }
+            ],
+        }))
+        .unwrap();
+
+    let job = JobPayload::RawFlow { value: flow, path: None };
+    let result = run_job_in_new_worker_until_complete(&db, job.clone(), port)
+        .await
+        .result
+        .unwrap();
+    assert_eq!(result, serde_json::json!({ "0": { "0": 42 } }));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_stop_after_if(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    // let server = ApiServer::start(db.clone()).await;
+    // let port = server.addr.port();
+
+    let port = 123;
+    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+        "modules": [
+            {
+                "id": "a",
+                "value": {
+                    "input_transforms": { "n": { "type": "javascript", "expr": "flow_input.n" } },
+                    "type": "rawscript",
+                    "language": "python3",
+                    "content": "def main(n): return n",
+                },
+                "stop_after_if": {
+                    "expr": "result < 0",
+                    "skip_if_stopped": false,
+                },
+            },
+            {
+                "id": "b",
+                "value": {
+                    "input_transforms": { "n": { "type": "javascript", "expr": "previous_result" } },
+                    "type": "rawscript",
+                    "language": "python3",
+                    "content": "def main(n): return f'last step saw {n}'",
+                },
+            },
+        ],
+    }))
+    .unwrap();
+    let job = JobPayload::RawFlow { value: flow, path: None };
+
+    let result = RunJob::from(job.clone())
+        .arg("n", json!(123))
+        .run_until_complete(&db, port)
+        .await
+        .result
+        .unwrap();
+    assert_eq!(json!("last step saw 123"), result);
+
+    let cjob = RunJob::from(job.clone())
+        .arg("n", json!(-123))
+        .run_until_complete(&db, port)
+        .await;
+
+    let result = cjob.result.unwrap();
+    assert_eq!(json!(-123), result);
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_stop_after_if_nested(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    // let server = ApiServer::start(db.clone()).await;
+    // let port = server.addr.port();
+
+    let port = 123;
+    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+        "modules": [
+            {
+                "id": "a",
+                "value": {
+                    "branches": [{"modules": [{
+                    "id": "b",
+                    "value": {
+                        "input_transforms": { "n": { "type": "javascript", "expr": "flow_input.n" } },
+                        "type": "rawscript",
+                        "language": "python3",
+                        "content": "def main(n): return n",
+                    },
+                    "stop_after_if": {
+                        "expr": "result < 0",
+                        "skip_if_stopped": false,
+                    }}]}],
+                    "type": "branchall"
+                },
+            },
+            {
+                "id": "c",
+                "value": {
+                    "input_transforms": { "n": { "type": "javascript", "expr": "previous_result" } },
+                    "type": "rawscript",
+                    "language": "python3",
+                    "content": "def main(n): return f'last step saw {n}'",
+                },
+            },
+        ],
+    }))
+    .unwrap();
+    let job = JobPayload::RawFlow { value: flow, path: None };
+
+    let result = RunJob::from(job.clone())
+        .arg("n", json!(123))
+        .run_until_complete(&db, port)
+        .await
+        .result
+        .unwrap();
+    assert_eq!(json!("last step saw {'0': 123}"), result);
+
+    let cjob = RunJob::from(job.clone())
+        .arg("n", json!(-123))
+        .run_until_complete(&db, port)
+        .await;
+
+    let result = cjob.result.unwrap();
+    assert_eq!(json!({ "0": -123 }), result);
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_python_flow(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let numbers = "def main(): return [1, 2, 3]";
+    let doubles = "def main(n): return n * 2";
+
+    let flow: FlowValue = serde_json::from_value(serde_json::json!( {
+        "input_transform": {},
+        "modules": [
+            {
+                "value": {
+                    "type": "rawscript",
+                    "language": "python3",
+                    "content": numbers,
+                },
+            },
+            {
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "javascript", "expr": "result" },
+                    "skip_failures": false,
+                    "modules": [{
+                        "value": {
+                            "type": "rawscript",
+                            "language": "python3",
+                            "content": doubles,
+                        },
+                        "input_transform": {
+                            "n": {
+                                "type": "javascript",
+                                "expr": "previous_result.iter.value",
+                            },
+                        },
+                    }],
+                },
+            },
+        ],
+    }))
+    .unwrap();
+
+    for i in 0..50 {
+        println!("python flow iteration: {}", i);
+        let result = run_job_in_new_worker_until_complete(
+            &db,
+            JobPayload::RawFlow { value: flow.clone(), path: None },
+            port,
+        )
+        .await
+        .result
+        .unwrap();
+
+        assert_eq!(result, serde_json::json!([2, 4, 6]), "iteration: {i}");
+    }
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_python_flow_2(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+            "modules": [
+                {
+                    "value": {
+                        "type": "rawscript",
+                        "content": "import wmill\ndef main():  return \"Hello\"",
+                        "language": "python3"
+                    },
+                    "input_transform": {}
+                }
+            ]
+    }))
+    .unwrap();
+
+    for i in 0..10 {
+        println!("python flow iteration: {}", i);
+        let result = run_job_in_new_worker_until_complete(
+            &db,
+            JobPayload::RawFlow { value: flow.clone(), path: None },
+            port,
+        )
+        .await
+        .result
+        .unwrap();
+
+        assert_eq!(result, serde_json::json!("Hello"), "iteration: {i}");
+    }
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_go_job(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let content = r#"
+import "fmt"
+
+func main(derp string) (string, error) {
+	fmt.Println("Hello, 世界")
+	return fmt.Sprintf("hello %s", derp), nil
+}
+        "#
+    .to_owned();
+
+    let result = RunJob::from(JobPayload::Code(RawCode {
+        content,
+        path: None,
+        language: ScriptLang::Go,
+    }))
+    .arg("derp", json!("world"))
+    .run_until_complete(&db, port)
+    .await
+    .result
+    .unwrap();
+
+    assert_eq!(result, serde_json::json!("hello world"));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_python_job(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let content = r#"
+def main():
+    return "hello world"
+        "#
+    .to_owned();
+
+    let job = JobPayload::Code(RawCode { content, path: None, language: ScriptLang::Python3 });
+
+    let result = run_job_in_new_worker_until_complete(&db, job, port)
+        .await
+        .result
+        .unwrap();
+
+    assert_eq!(result, serde_json::json!("hello world"));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_python_job_heavy_dep(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let content = r#"
+import numpy as np
+
+def main():
+    a = np.arange(15).reshape(3, 5)
+    return len(a)
+        "#
+    .to_owned();
+
+    let job = JobPayload::Code(RawCode { content, path: None, language: ScriptLang::Python3 });
+
+    let result = run_job_in_new_worker_until_complete(&db, job, port)
+        .await
+        .result
+        .unwrap();
+
+    assert_eq!(result, serde_json::json!(3));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_python_job_with_imports(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let content = r#"
+import wmill
+
+def main():
+    return wmill.get_workspace()
+        "#
+    .to_owned();
+
+    let job = JobPayload::Code(RawCode { content, path: None, language: ScriptLang::Python3 });
+
+    let result = run_job_in_new_worker_until_complete(&db, job, port)
+        .await
+        .result
+        .unwrap();
+
+    assert_eq!(result, serde_json::json!("test-workspace"));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_empty_loop(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+        "modules": [
+            {
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "static", "value": [] },
+                    "modules": [
+                        {
+                            "value": {
+                                "input_transform": {
+                                    "n": {
+                                        "type": "javascript",
+                                        "expr": "previous_result.iter.value",
+                                    },
+                                },
+                                "type": "rawscript",
+                                "language": "python3",
+                                "content": "def main(n): return n",
+                            },
+                        }
+                    ],
+                },
+            },
+            {
+                "value": {
+                    "input_transform": {
+                        "items": {
+                            "type": "javascript",
+                            "expr": "previous_result",
+                        },
+                    },
+                    "type": "rawscript",
+                    "language": "python3",
+                    "content": "def main(items): return sum(items)",
+                },
+            },
+        ],
+    }))
+    .unwrap();
+
+    let flow = JobPayload::RawFlow { value: flow, path: None };
+    let result = run_job_in_new_worker_until_complete(&db, flow, port)
+        .await
+        .result
+        .unwrap();
+
+    assert_eq!(result, serde_json::json!(0));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_invalid_first_step(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+        "modules": [
+            {
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "javascript", "expr": "flow_input" },
+                    "modules": [
+                        {
+                            "value": {
+                                "type": "identity",
+                            },
+                        }
+                    ],
+                },
+            },
+            {
+                "value": {
+                    "type": "identity",
+                },
+            },
+        ],
+    }))
+    .unwrap();
+
+    let flow = JobPayload::RawFlow { value: flow, path: None };
+    let job = run_job_in_new_worker_until_complete(&db, flow, port).await;
+
+    assert_eq!(
+        job.result.unwrap(),
+        serde_json::json!({
+            "error": "Expected an array value, found: {}",
+            "name": "Error",
+            "message": "Expected an array value, found: {}",
+        })
+    );
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_empty_loop_2(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+        "modules": [
+            {
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "static", "value": [] },
+                    "modules": [
+                        {
+                            "input_transform": {
+                                "n": {
+                                    "type": "javascript",
+                                    "expr": "previous_result.iter.value",
+                                },
+                            },
+                            "value": {
+                                "type": "rawscript",
+                                "language": "python3",
+                                "content": "def main(n): return n",
+                            },
+                        }
+                    ],
+                },
+            },
+        ],
+    }))
+    .unwrap();
+
+    let flow = JobPayload::RawFlow { value: flow, path: None };
+    let result = run_job_in_new_worker_until_complete(&db, flow, port)
+        .await
+        .result
+        .unwrap();
+
+    assert_eq!(result, serde_json::json!([]));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_step_after_loop(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+        "modules": [
+            {
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "static", "value": [2,3,4] },
+                    "modules": [
+                        {
+                            "input_transform": {
+                                "n": {
+                                    "type": "javascript",
+                                    "expr": "previous_result.iter.value",
+                                },
+                            },
+                            "value": {
+                                "type": "rawscript",
+                                "language": "python3",
+                                "content": "def main(n): return n",
+                            } ,
+                        }
+                    ],
+                },
+            },
+            {
+                "input_transform": {
+                    "items": {
+                        "type": "javascript",
+                        "expr": "previous_result",
+                    },
+                },
+                "value": {
+                    "type": "rawscript",
+                    "language": "python3",
+                    "content": "def main(items): return sum(items)",
+                },
+            },
+        ],
+    }))
+    .unwrap();
+
+    let flow = JobPayload::RawFlow { value: flow, path: None };
+    let result = run_job_in_new_worker_until_complete(&db, flow, port)
+        .await
+        .result
+        .unwrap();
+
+    assert_eq!(result, serde_json::json!(9));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_skipped_steps(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+        "modules": [
+            {
+                "id": "a",
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "static", "value": [] },
+                    "modules": [],
+                },
+            },
+            {
+                "id": "b",
+                "input_transforms": {
+                    "items": { "type": "javascript", "expr": "previous_result" },
+                },
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(items) { return items.length }",
+                },
+            },
+        ],
+    }))
+    .unwrap();
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert_eq!(cjob.result, Some(json!(0)));
+    assert!(matches!(
+        get_module(&cjob, "a"),
+        Some(FlowStatusModule::Success { skipped: true, .. })
+    ));
+    assert!(matches!(
+        get_module(&cjob, "b"),
+        Some(FlowStatusModule::Success { skipped: false, .. })
+    ));
+    assert!(cjob.logs.unwrap_or_default().contains("Step 'a' skipped"));
+
+    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+        "modules": [
+            {
+                "id": "a",
+                "value": {
+                    "type": "branchone",
+                    "branches": [],
+                    "default": [{
+                        "id": "b",
+                        "input_transforms": {
+                            "n": { "type": "javascript", "expr": "flow_input.n" },
+                        },
+                        "value": {
+                            "type": "rawscript",
+                            "language": "deno",
+                            "content": "export function main(n) { return n }",
+                        },
+                        "stop_after_if": { "expr": "result < 0", "skip_if_stopped": true },
+                    }],
+                },
+            },
+        ],
+    }))
+    .unwrap();
+    let job = JobPayload::RawFlow { value: flow, path: None };
+
+    let cjob = RunJob::from(job.clone())
+        .arg("n", json!(-1))
+        .run_until_complete(&db, port)
+        .await;
+    assert_eq!(cjob.result, Some(json!(-1)));
+    assert!(matches!(
+        get_module(&cjob, "a"),
+        Some(FlowStatusModule::Success { skipped: true, .. })
+    ));
+
+    let cjob = RunJob::from(job)
+        .arg("n", json!(1))
+        .run_until_complete(&db, port)
+        .await;
+    assert_eq!(cjob.result, Some(json!(1)));
+    assert!(matches!(
+        get_module(&cjob, "a"),
+        Some(FlowStatusModule::Success { skipped: false, .. })
+    ));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_skip_if(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+        "modules": [
+            {
+                "id": "a",
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "static", "value": [1, 2, 3] },
+                    "modules": [{
+                        "input_transforms": {
+                            "x": { "type": "javascript", "expr": "previous_result.iter.value" },
+                        },
+                        "value": {
+                            "type": "rawscript",
+                            "language": "deno",
+                            "content": "export function main(x) { return x }",
+                        },
+                    }, {
+                        "input_transforms": {
+                            "x": { "type": "javascript", "expr": "previous_result" },
+                        },
+                        "value": {
+                            "type": "rawscript",
+                            "language": "deno",
+                            "content": "export function main(x) { return x * 10 }",
+                        },
+                        "skip_if": { "expr": "previous_result == 2" },
+                    }],
+                },
+            },
+            {
+                "id": "b",
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { return 'not skipped' }",
+                },
+                "skip_if": { "expr": "flow_input.skip" },
+            },
+        ],
+    }))
+    .unwrap();
+    let job = JobPayload::RawFlow { value: flow, path: None };
+
+    /* the skipped step of an iteration passes its previous result through, the last step of
+     * the flow included */
+    let cjob = RunJob::from(job.clone())
+        .arg("skip", json!(true))
+        .run_until_complete(&db, port)
+        .await;
+    assert_eq!(cjob.result, Some(json!([10, 2, 30])));
+    assert!(matches!(
+        get_module(&cjob, "b"),
+        Some(FlowStatusModule::Success { skipped: true, .. })
+    ));
+    assert!(cjob.logs.unwrap_or_default().contains("Step 'b' skipped"));
+
+    let cjob = RunJob::from(job)
+        .arg("skip", json!(false))
+        .run_until_complete(&db, port)
+        .await;
+    assert_eq!(cjob.result, Some(json!("not skipped")));
+    assert!(matches!(
+        get_module(&cjob, "b"),
+        Some(FlowStatusModule::Success { skipped: false, .. })
+    ));
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_partial_result(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+        "modules": [
+            {
+                "id": "a",
+                "value": {
+                    "input_transforms": { "n": { "type": "javascript", "expr": "flow_input.n" } },
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(n) { console.log('PARTIAL:' + JSON.stringify([1, 2])); if (n < 0) { throw Error('rate limited') } else { throw Error('other error') } }",
+                },
+                "partial_result": {
+                    "expr": "result.error.includes('rate limited') ? JSON.parse(result.error.match(/PARTIAL:(.*)/)[1]) : null",
+                },
+            },
+            {
+                "id": "b",
+                "value": {
+                    "input_transforms": { "n": { "type": "javascript", "expr": "previous_result" } },
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(n) { return n.length }",
+                },
+            },
+        ],
+    }))
+    .unwrap();
+    let job = JobPayload::RawFlow { value: flow, path: None };
+
+    let cjob = RunJob::from(job.clone())
+        .arg("n", json!(-1))
+        .run_until_complete(&db, port)
+        .await;
+    assert!(cjob.success);
+    assert_eq!(Some(json!(2)), cjob.result);
+    assert!(matches!(
+        get_module(&cjob, "a"),
+        Some(FlowStatusModule::Success { warning: Some(_), .. })
+    ));
+
+    let cjob = RunJob::from(job.clone())
+        .arg("n", json!(1))
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_collector(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow = |ids: serde_json::Value| -> FlowValue {
+        serde_json::from_value(serde_json::json!({
+            "modules": [
+                {
+                    "id": "a",
+                    "value": {
+                        "type": "rawscript",
+                        "language": "deno",
+                        "content": "export function main() { return 1 }",
+                    },
+                },
+                {
+                    "id": "b",
+                    "value": {
+                        "type": "rawscript",
+                        "language": "deno",
+                        "content": "export function main() { return 2 }",
+                    },
+                },
+                {
+                    "id": "c",
+                    "value": { "type": "collector", "ids": ids },
+                },
+            ],
+        }))
+        .unwrap()
+    };
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow(json!(["b", "a"])), path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(cjob.success);
+    assert_eq!(Some(json!({ "a": 1, "b": 2 })), cjob.result);
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow(json!(["a", "d"])), path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_transactional_group(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let rawscript =
+        |content: &str| json!({ "type": "rawscript", "language": "deno", "content": content });
+    let mut flow = json!({
+        "modules": [
+            {
+                "id": "a",
+                "value": rawscript("export function main() { return 'a' }"),
+                "compensation": { "value": rawscript("export function main() { return 'undo a' }") },
+            },
+            {
+                "id": "b",
+                "group": "g",
+                "value": rawscript("export function main() { return 'b' }"),
+                "compensation": { "value": rawscript("export function main() { return 'undo b' }") },
+            },
+            {
+                "id": "c",
+                "group": "g",
+                "value": rawscript("export function main() { throw Error('c failed') }"),
+            },
+        ],
+        "failure_module": {
+            "input_transform": { "error": { "type": "javascript", "expr": "previous_result" } },
+            "value": rawscript("export function main(error) { return { 'from failure module': error } }"),
+        },
+    });
+
+    let value: FlowValue = serde_json::from_value(flow.clone()).unwrap();
+    let result = RunJob::from(JobPayload::RawFlow { value, path: None })
+        .run_until_complete(&db, port)
+        .await
+        .result
+        .unwrap();
+    assert_eq!(json!("undo b"), result["from failure module"]);
+
+    flow.as_object_mut().unwrap().remove("failure_module");
+    let value: FlowValue = serde_json::from_value(flow).unwrap();
+    let cjob = RunJob::from(JobPayload::RawFlow { value, path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+    assert!(cjob.result.unwrap()["error"]
+        .as_str()
+        .unwrap()
+        .contains("c failed"));
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_input_merge(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    /* the flow input has a `previous_result` colliding with the one injected by the branch */
+    let flow = |input_merge: &str| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [
+                {
+                    "value": {
+                        "type": "rawscript",
+                        "language": "deno",
+                        "content": "export function main() { return 'injected' }",
+                    },
+                },
+                {
+                    "value": {
+                        "type": "branchone",
+                        "branches": [],
+                        "default": [{
+                            "input_transforms": {
+                                "x": { "type": "javascript", "expr": "flow_input.previous_result" },
+                            },
+                            "value": {
+                                "type": "rawscript",
+                                "language": "deno",
+                                "content": "export function main(x) { return x }",
+                            },
+                        }],
+                    },
+                    "input_merge": input_merge,
+                },
+            ],
+        }))
+        .unwrap()
+    };
+
+    for (input_merge, expected) in [("injected", "injected"), ("existing", "existing")] {
+        let cjob = RunJob::from(JobPayload::RawFlow { value: flow(input_merge), path: None })
+            .arg("previous_result", json!("existing"))
+            .run_until_complete(&db, port)
+            .await;
+        assert!(cjob.success);
+        assert_eq!(Some(json!(expected)), cjob.result);
+    }
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow("error"), path: None })
+        .arg("previous_result", json!("existing"))
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_identity_expr(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "id": "a",
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main() { return { first: 'ada', last: 'lovelace' }; }",
+            },
+        }, {
+            "id": "b",
+            "value": {
+                "type": "identity",
+                "expr": "({ name: `${previous_result.first} ${previous_result.last}`, greeting: flow_input.greeting })",
+            },
+        }, {
+            "id": "c",
+            "value": { "type": "identity", "expr": "previous_result.name.length" },
+        }, {
+            "id": "d",
+            "value": { "type": "identity" },
+        }],
+    }))
+    .unwrap();
+
+    /* a value that isn't an object is passed through as previous_result, like without expr */
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .arg("greeting", json!("hi"))
+        .run_until_complete(&db, port)
+        .await;
+    assert!(cjob.success);
+    assert_eq!(cjob.result, Some(json!({ "previous_result": 12 })));
+    let b = completed_job(get_module(&cjob, "b").unwrap().job().unwrap(), &db).await;
+    assert_eq!(
+        b.result,
+        Some(json!({ "name": "ada lovelace", "greeting": "hi" }))
+    );
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_step_summaries(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "id": "a",
+            "summary": "Fetch invoices",
+            "value": { "type": "identity" },
+        }, {
+            "id": "b",
+            "value": { "type": "identity" },
+        }],
+    }))
+    .unwrap();
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, port)
+        .await;
+
+    assert!(cjob.success);
+    assert_eq!(
+        cjob.logs.as_deref(),
+        Some("Step 'Fetch invoices' completed\nStep 'b' completed\nFlow job completed")
+    );
+    assert_eq!(
+        cjob.flow_status.unwrap()["summaries"],
+        json!({ "a": "Fetch invoices" })
+    );
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_log_iterations(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "id": "a",
+            "value": {
+                "type": "forloopflow",
+                "iterator": { "type": "static", "value": [1, 2] },
+                "skip_failures": true,
+                "modules": [{
+                    "value": {
+                        "input_transforms": {
+                            "x": { "type": "javascript", "expr": "previous_result.iter.value" },
+                        },
+                        "type": "rawscript",
+                        "language": "deno",
+                        "content": "export function main(x){ if (x == 2) { throw Error('2') } return x }",
+                    },
+                }],
+            },
+        }],
+        "log_iterations": true,
+    }))
+    .unwrap();
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, port)
+        .await;
+
+    assert!(cjob.success);
+    let jobs = match get_module(&cjob, "a").unwrap() {
+        FlowStatusModule::Success { flow_jobs: Some(jobs), .. } => jobs,
+        m => panic!("unexpected status {m:?}"),
+    };
+    assert_eq!(
+        cjob.logs.as_deref(),
+        Some(
+            format!(
+                "Step 'a' iteration 0 (job {}) completed\n\
+                 Step 'a' iteration 1 (job {}) failed\n\
+                 Step 'a' completed\nFlow job completed",
+                jobs[0], jobs[1]
+            )
+            .as_str()
+        )
+    );
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_resolve_input_resources(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    sqlx::query(
+        "INSERT INTO resource (workspace_id, path, value, resource_type)
+              VALUES ('test-workspace', 'u/test-user/config', '{\"host\": \"db\"}', 'object')",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let flow = |resolve_resources: bool| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{ "id": "a", "value": { "type": "identity" } }],
+            "resolve_resources": resolve_resources,
+        }))
+        .unwrap()
+    };
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow(true), path: None })
+        .arg("config", json!("$res:u/test-user/config"))
+        .arg("configs", json!(["$res:u/test-user/config"]))
+        .run_until_complete(&db, port)
+        .await;
+    assert!(cjob.success);
+    assert_eq!(
+        cjob.result,
+        Some(json!({ "config": { "host": "db" }, "configs": [{ "host": "db" }] }))
+    );
+
+    /* the references are left as is when not opted in */
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow(false), path: None })
+        .arg("config", json!("$res:u/test-user/config"))
+        .run_until_complete(&db, port)
+        .await;
+    assert_eq!(
+        cjob.result,
+        Some(json!({ "config": "$res:u/test-user/config" }))
+    );
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow(true), path: None })
+        .arg("config", json!("$res:u/test-user/missing"))
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_get_resource_values(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    for query in [
+        "INSERT INTO usr (workspace_id, email, username, is_admin, role)
+              VALUES ('test-workspace', 'dev@windmill.dev', 'dev-user', false, 'Developer')",
+        "INSERT INTO resource (workspace_id, path, value, resource_type)
+              VALUES ('test-workspace', 'u/dev-user/a', '{\"host\": \"a\"}', 'object')",
+        "INSERT INTO resource (workspace_id, path, value, resource_type)
+              VALUES ('test-workspace', 'u/test-user/b', '{\"host\": \"b\"}', 'object')",
+    ] {
+        sqlx::query(query).execute(&db).await.unwrap();
+    }
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/dev-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let get_values = |paths: serde_json::Value| {
+        reqwest::Client::new()
+            .post(format!(
+                "http://localhost:{port}/api/w/test-workspace/resources/get_values"
+            ))
+            .bearer_auth(&token)
+            .json(&paths)
+            .send()
+    };
+
+    let values = get_values(json!([
+        "u/dev-user/a",
+        "u/test-user/b",
+        "u/dev-user/missing"
+    ]))
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap()
+    .json::<serde_json::Value>()
+    .await
+    .unwrap();
+    assert_eq!(
+        values,
+        json!({
+            "u/dev-user/a": { "status": "ok", "value": { "host": "a" } },
+            "u/test-user/b": { "status": "forbidden" },
+            "u/dev-user/missing": { "status": "not_found" },
+        })
+    );
+
+    let too_many = (0..101)
+        .map(|i| format!("u/dev-user/{i}"))
+        .collect::<Vec<_>>();
+    let res = get_values(json!(too_many)).await.unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    let batch = reqwest::Client::new()
+        .post(format!(
+            "http://localhost:{port}/api/w/test-workspace/resources/get_value_batch"
+        ))
+        .bearer_auth(&token)
+        .json(&json!([
+            "u/dev-user/a",
+            "u/test-user/b",
+            "u/dev-user/missing"
+        ]))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+    assert_eq!(batch, json!({ "u/dev-user/a": { "host": "a" } }));
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_encrypted_resource(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let client = reqwest::Client::new();
+    let url = |endpoint: &str| format!("http://localhost:{port}/api/w/test-workspace/{endpoint}");
+    let stored_value = || async {
+        sqlx::query_scalar::<_, serde_json::Value>(
+            "SELECT value FROM resource
+              WHERE workspace_id = 'test-workspace' AND path = 'u/test-user/db'",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap()
+    };
+    let get_value = || async {
+        client
+            .get(url("resources/get_value/u/test-user/db"))
+            .bearer_auth(&token)
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap()
+    };
+    let value = json!({ "host": "db.internal", "password": "hunter2" });
+
+    client
+        .post(url("resources/create"))
+        .bearer_auth(&token)
+        .json(&json!({
+            "path": "u/test-user/db",
+            "value": value,
+            "resource_type": "object",
+            "encrypted": true,
+        }))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let stored = stored_value().await;
+    assert!(stored.is_string());
+    assert!(!stored.to_string().contains("hunter2"));
+    assert_eq!(get_value().await, value);
+
+    let resource = client
+        .get(url("resources/get/u/test-user/db"))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+    assert_eq!(resource["value"], value);
+    assert_eq!(resource["encrypted"], json!(true));
+
+    let values = client
+        .post(url("resources/get_values"))
+        .bearer_auth(&token)
+        .json(&json!(["u/test-user/db"]))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+    assert_eq!(values, json!({ "u/test-user/db": value }));
+
+    /* the reads of the encrypted value are audited */
+    let reads = sqlx::query_scalar::<_, String>(
+        "SELECT operation FROM audit
+          WHERE action_kind = 'read' AND resource = 'u/test-user/db' AND username = 'test-user'
+          ORDER BY id",
+    )
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(
+        reads,
+        vec![
+            "resources.get_value",
+            "resources.get",
+            "resources.get_value"
+        ]
+    );
+
+    /* the values are re-encrypted with the new key */
+    let edit_key = |key: &str| {
+        client
+            .post(url("workspaces/edit_encryption_key"))
+            .bearer_auth(&token)
+            .json(&json!({ "key": key }))
+            .send()
+    };
+    let res = edit_key("short").await.unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+    edit_key("a-much-longer-workspace-key")
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    assert_ne!(stored_value().await, stored);
+    assert_eq!(get_value().await, value);
+
+    client
+        .post(url("resources/update/u/test-user/db"))
+        .bearer_auth(&token)
+        .json(&json!({ "encrypted": false }))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    assert_eq!(stored_value().await, value);
+    assert_eq!(get_value().await, value);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_resource_schema_validation(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    sqlx::query(
+        "INSERT INTO resource_type (workspace_id, name, schema)
+              VALUES ('test-workspace', 'postgresql', $1)",
+    )
+    .bind(json!({
+        "type": "object",
+        "required": ["host"],
+        "properties": { "host": { "type": "string" }, "port": { "type": "integer" } },
+    }))
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let client = reqwest::Client::new();
+    let create = |path: &str, value: serde_json::Value, query: &str| {
+        client
+            .post(format!(
+                "http://localhost:{port}/api/w/test-workspace/resources/create{query}"
+            ))
+            .bearer_auth(&token)
+            .json(&json!({ "path": path, "value": value, "resource_type": "postgresql" }))
+            .send()
+    };
+
+    let res = create("u/test-user/invalid", json!({ "port": "5432" }), "")
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+    let error = res.text().await.unwrap();
+    assert!(error.contains("host is required"), "{error}");
+    assert!(error.contains("port expected integer"), "{error}");
+
+    create(
+        "u/test-user/legacy",
+        json!({ "port": "5432" }),
+        "?skip_validation=true",
+    )
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap();
+
+    create("u/test-user/db", json!({ "host": "db.internal" }), "")
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let res = client
+        .post(format!(
+            "http://localhost:{port}/api/w/test-workspace/resources/update/u/test-user/db"
+        ))
+        .bearer_auth(&token)
+        .json(&json!({ "value": { "host": 42 } }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_delete_referenced_resource(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    sqlx::query(
+        "INSERT INTO resource (workspace_id, path, value, resource_type)
+              VALUES ('test-workspace', 'u/test-user/db', '{}', 'postgresql'),
+                     ('test-workspace', 'u/test-user/copy', '{\"of\": \"$res:u/test-user/db\"}', 'object'),
+                     ('test-workspace', 'u/test-user/unused', '{}', 'postgresql')",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let delete = |path: &str, force: bool| {
+        reqwest::Client::new()
+            .delete(format!(
+                "http://localhost:{port}/api/w/test-workspace/resources/delete/{path}"
+            ))
+            .bearer_auth(&token)
+            .query(&[("force", force)])
+            .send()
+    };
+
+    /* a referenced resource is kept, the error listing what references it */
+    let res = delete("u/test-user/db", false).await.unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::CONFLICT);
+    assert!(res
+        .text()
+        .await
+        .unwrap()
+        .contains("referenced by resource u/test-user/copy"));
+
+    /* ... unless forced */
+    delete("u/test-user/db", true)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    delete("u/test-user/unused", false)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let left = sqlx::query_scalar::<_, String>(
+        "SELECT path FROM resource WHERE workspace_id = 'test-workspace' AND path LIKE 'u/test-user/%'",
+    )
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(left, vec!["u/test-user/copy".to_string()]);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_list_orphan_resources(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    sqlx::query(
+        "INSERT INTO resource (workspace_id, path, value, resource_type, created_at)
+              VALUES ('test-workspace', 'u/test-user/by_script', '{}', 'postgresql', now() - interval '2 days'),
+                     ('test-workspace', 'u/test-user/by_schedule', '{}', 'postgresql', now() - interval '2 days'),
+                     ('test-workspace', 'u/test-user/unused', '{}', 'postgresql', now() - interval '2 days'),
+                     ('test-workspace', 'u/test-user/recent', '{}', 'postgresql', now() - interval '1 hour')",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO script
+                (workspace_id, hash, path, summary, description, content, created_by, language)
+         VALUES ('test-workspace', 1, 'u/test-user/query', '', '',
+                 'export async function main(db = \"$res:u/test-user/by_script\") {}', 'test-user', 'deno')",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO schedule
+                (workspace_id, path, schedule, offset_, edited_by, script_path, is_flow, enabled, args)
+         VALUES ('test-workspace', 'u/test-user/nightly', '0 0 0 * * *', 0, 'test-user',
+                 'u/test-user/query', false, false, '{\"db\": \"$res:u/test-user/by_schedule\"}')",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let list = |min_age_secs: Option<i64>| {
+        let mut query = vec![("path_start", "u/test-user/".to_string())];
+        query.extend(min_age_secs.map(|s| ("min_age_secs", s.to_string())));
+        let token = token.clone();
+        async move {
+            reqwest::Client::new()
+                .get(format!(
+                    "http://localhost:{port}/api/w/test-workspace/resources/list_orphans"
+                ))
+                .bearer_auth(token)
+                .query(&query)
+                .send()
+                .await
+                .unwrap()
+                .error_for_status()
+                .unwrap()
+                .json::<Vec<serde_json::Value>>()
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|r| r["path"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        }
+    };
+
+    /* the resources referenced by a script or a schedule aren't orphans, nor by default the
+     * ones created less than a day ago */
+    assert_eq!(list(None).await, vec!["u/test-user/unused".to_string()]);
+    assert_eq!(
+        list(Some(0)).await,
+        vec![
+            "u/test-user/recent".to_string(),
+            "u/test-user/unused".to_string()
+        ]
+    );
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_delete_used_resource_type(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    sqlx::query(
+        "INSERT INTO resource_type (workspace_id, name, schema, description)
+              VALUES ('test-workspace', 'smtp', '{}', ''), ('test-workspace', 'unused', '{}', '')",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO resource (workspace_id, path, value, resource_type)
+              VALUES ('test-workspace', 'u/test-user/mail', '{}', 'smtp'),
+                     ('test-workspace', 'u/test-user/mail2', '{}', 'smtp')",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let delete = |name: &str, force: bool| {
+        reqwest::Client::new()
+            .delete(format!(
+                "http://localhost:{port}/api/w/test-workspace/resources/type/delete/{name}"
+            ))
+            .bearer_auth(&token)
+            .query(&[("force", force)])
+            .send()
+    };
+
+    /* a type resources have is kept */
+    let res = delete("smtp", false).await.unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::CONFLICT);
+    assert!(res.text().await.unwrap().contains("type of 2 resources"));
+    delete("unused", false)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    /* ... unless forced, the resources are then given the placeholder type */
+    delete("smtp", true)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let types = sqlx::query_scalar::<_, String>(
+        "SELECT DISTINCT resource_type FROM resource WHERE path LIKE 'u/test-user/mail%'",
+    )
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(types, vec!["untyped".to_string()]);
+    let audited = sqlx::query_scalar::<_, serde_json::Value>(
+        "SELECT parameters FROM audit WHERE operation = 'resource_types.delete' AND resource = 'smtp'",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(audited["resources"], json!("2"));
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_import_resources(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    sqlx::query(
+        "INSERT INTO resource_type (workspace_id, name, schema, description)
+              VALUES ('test-workspace', 'smtp', '{}', '')",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO resource (workspace_id, path, value, resource_type)
+              VALUES ('test-workspace', 'u/test-user/mail', '{\"host\": \"old\"}', 'smtp')",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let resources = json!([
+        { "path": "u/test-user/mail2", "value": { "host": "a" }, "resource_type": "smtp" },
+        { "path": "u/test-user/mail", "value": { "host": "new" }, "resource_type": "smtp" },
+    ]);
+    let import = |upsert: bool| {
+        reqwest::Client::new()
+            .post(format!(
+                "http://localhost:{port}/api/w/test-workspace/resources/import"
+            ))
+            .bearer_auth(&token)
+            .query(&[("upsert", upsert)])
+            .json(&resources)
+            .send()
+    };
+    let values = || {
+        sqlx::query_as::<_, (String, serde_json::Value)>(
+            "SELECT path, value FROM resource WHERE workspace_id = 'test-workspace' \
+             AND path LIKE 'u/test-user/mail%' ORDER BY path",
+        )
+        .fetch_all(&db)
+    };
+
+    /* the existing path fails the whole import */
+    let res = import(false).await.unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+    assert!(res
+        .text()
+        .await
+        .unwrap()
+        .contains("resource 1 at u/test-user/mail could not be imported"));
+    assert_eq!(
+        values().await.unwrap(),
+        vec![("u/test-user/mail".to_string(), json!({ "host": "old" }))]
+    );
+
+    /* ... unless upserting */
+    let res = import(true).await.unwrap().error_for_status().unwrap();
+    assert_eq!(
+        res.text().await.unwrap(),
+        "1 resources created and 1 updated"
+    );
+    assert_eq!(
+        values().await.unwrap(),
+        vec![
+            ("u/test-user/mail".to_string(), json!({ "host": "new" })),
+            ("u/test-user/mail2".to_string(), json!({ "host": "a" })),
+        ]
+    );
+    let audited = sqlx::query_scalar::<_, i64>(
+        "SELECT count(*) FROM audit WHERE operation = 'resources.import'",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(audited, 1);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_clone_resource(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    sqlx::query(
+        "INSERT INTO resource (workspace_id, path, value, description, resource_type, extra_perms)
+              VALUES ('test-workspace', 'u/test-user/db', '{\"host\": \"db.internal\"}', 'main db',
+                      'object', '{\"u/dev-user\": false}')",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let clone = |destination: &str, copy_extra_perms: bool| {
+        reqwest::Client::new()
+            .post(format!(
+                "http://localhost:{port}/api/w/test-workspace/resources/clone"
+            ))
+            .bearer_auth(&token)
+            .json(&json!({
+                "source": "u/test-user/db",
+                "destination": destination,
+                "copy_extra_perms": copy_extra_perms,
+            }))
+            .send()
+    };
+    let copied = |path: &'static str| {
+        sqlx::query_as::<_, (serde_json::Value, Option<String>, String, serde_json::Value)>(
+            "SELECT value, description, resource_type, extra_perms FROM resource
+              WHERE workspace_id = 'test-workspace' AND path = $1",
+        )
+        .bind(path)
+        .fetch_one(&db)
+    };
+
+    let res = clone("u/test-user/db_copy", false).await.unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+    assert_eq!(
+        copied("u/test-user/db_copy").await.unwrap(),
+        (
+            json!({ "host": "db.internal" }),
+            Some("main db".to_string()),
+            "object".to_string(),
+            json!({})
+        )
+    );
+
+    clone("u/test-user/db_shared", true)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    assert_eq!(
+        copied("u/test-user/db_shared").await.unwrap().3,
+        json!({ "u/dev-user": false })
+    );
+
+    /* the destination must not exist yet */
+    let res = clone("u/test-user/db_copy", false).await.unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    let audited = sqlx::query_scalar::<_, i64>(
+        "SELECT count(*) FROM audit WHERE operation = 'resources.clone'
+            AND parameters->>'source' = 'u/test-user/db'",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(audited, 2);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_search_resources(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    for query in [
+        "INSERT INTO usr (workspace_id, email, username, is_admin, role)
+              VALUES ('test-workspace', 'dev@windmill.dev', 'dev-user', false, 'Developer')",
+        "INSERT INTO resource (workspace_id, path, value, resource_type)
+              VALUES ('test-workspace', 'u/dev-user/db', '{\"host\": \"db.example.com\"}', 'object')",
+        "INSERT INTO resource (workspace_id, path, value, resource_type)
+              VALUES ('test-workspace', 'u/test-user/db', '{\"host\": \"db.example.com\", \"port\": 5432}', 'object'),
+                     ('test-workspace', 'u/test-user/db2', '{\"host\": \"other.com\", \"port\": 5432}', 'object')",
+    ] {
+        sqlx::query(query).execute(&db).await.unwrap();
+    }
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/dev-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let search = |search: serde_json::Value| {
+        reqwest::Client::new()
+            .post(format!(
+                "http://localhost:{port}/api/w/test-workspace/resources/search"
+            ))
+            .bearer_auth(&token)
+            .json(&search)
+            .send()
+    };
+
+    /* only the resources the user can see are searched */
+    let paths = search(json!({ "value_contains": { "host": "db.example.com" } }))
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json::<Vec<String>>()
+        .await
+        .unwrap();
+    assert_eq!(paths, vec!["u/dev-user/db".to_string()]);
+
+    sqlx::query("UPDATE resource SET extra_perms = '{\"u/dev-user\": false}'")
+        .execute(&db)
+        .await
+        .unwrap();
+    let paths = search(json!({ "value_jsonpath": "$.port ? (@ == 5432)" }))
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json::<Vec<String>>()
+        .await
+        .unwrap();
+    assert_eq!(
+        paths,
+        vec!["u/test-user/db".to_string(), "u/test-user/db2".to_string()]
+    );
+
+    let res = search(json!({ "resource_type": "postgres" }))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_list_resources_cursor(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    for i in 1..=5 {
+        sqlx::query(
+            "INSERT INTO resource (workspace_id, path, value, resource_type)
+                  VALUES ('test-workspace', $1, '{}', 'paginated')",
+        )
+        .bind(format!("u/test-user/r{i}"))
+        .execute(&db)
+        .await
+        .unwrap();
+    }
+    /* a starter resource sharing its path with one of the workspace, at the end of a page */
+    sqlx::query(
+        "INSERT INTO resource (workspace_id, path, value, resource_type)
+              VALUES ('starter', 'u/test-user/r4', '{}', 'paginated')",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let list = |cursor: Option<String>| {
+        let mut query = vec![("resource_type", "paginated".to_string())];
+        query.push(("per_page", "2".to_string()));
+        query.extend(cursor.map(|c| ("cursor", c)));
+        reqwest::Client::new()
+            .get(format!(
+                "http://localhost:{port}/api/w/test-workspace/resources/list"
+            ))
+            .bearer_auth(&token)
+            .query(&query)
+            .send()
+    };
+
+    let mut pages = vec![];
+    let mut cursor = Some(String::new());
+    while let Some(c) = cursor {
+        let res = list(Some(c)).await.unwrap().error_for_status().unwrap();
+        assert!(res.headers().get("x-total-count").is_none());
+        cursor = res
+            .headers()
+            .get("x-next-cursor")
+            .map(|c| c.to_str().unwrap().to_string());
+        let page = res.json::<Vec<serde_json::Value>>().await.unwrap();
+        pages.push(
+            page.iter()
+                .map(|r| {
+                    format!(
+                        "{}:{}",
+                        r["workspace_id"].as_str().unwrap(),
+                        r["path"].as_str().unwrap()
+                    )
+                })
+                .collect::<Vec<_>>(),
+        );
+    }
+    assert_eq!(
+        pages,
+        vec![
+            vec![
+                "test-workspace:u/test-user/r5",
+                "test-workspace:u/test-user/r4"
+            ],
+            vec!["starter:u/test-user/r4", "test-workspace:u/test-user/r3"],
+            vec![
+                "test-workspace:u/test-user/r2",
+                "test-workspace:u/test-user/r1"
+            ],
+            /* the last page being full, it still has a next cursor */
+            vec![],
+        ]
+    );
+
+    /* without a cursor, the offset pagination is kept */
+    let res = list(None).await.unwrap().error_for_status().unwrap();
+    assert_eq!(res.headers()["x-total-count"], "6");
+    assert!(res.headers().get("x-next-cursor").is_none());
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_cross_workspace_resource(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    for query in [
+        "INSERT INTO workspace (id, name, owner) VALUES ('other', 'other', 'other-user')",
+        "INSERT INTO usr (workspace_id, email, username, is_admin, role)
+              VALUES ('other', 'other@windmill.dev', 'other-user', true, 'Admin')",
+        "INSERT INTO resource (workspace_id, path, value, resource_type)
+              VALUES ('test-workspace', 'u/test-user/config', '{\"host\": \"db\"}', 'object')",
+        "INSERT INTO resource (workspace_id, path, value, resource_type)
+              VALUES ('other', 'u/other-user/shared', '{\"host\": \"other\"}', 'object')",
+    ] {
+        sqlx::query(query).execute(&db).await.unwrap();
+    }
+
+    let flow = |transform: serde_json::Value| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{
+                "id": "a",
+                "input_transforms": { "config": transform },
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(config) { return config }",
+                },
+            }],
+        }))
+        .unwrap()
+    };
+
+    /* the flow reads the resources of its own workspace as itself */
+    let cjob = RunJob::from(JobPayload::RawFlow {
+        value: flow(json!({ "type": "resource", "path": "u/test-user/config" })),
+        path: None,
+    })
+    .run_until_complete(&db, port)
+    .await;
+    assert_eq!(cjob.result, Some(json!({ "host": "db" })));
+
+    let shared = json!({
+        "type": "resource",
+        "path": "u/other-user/shared",
+        "workspace": "other",
+        "permissioned_as": "u/other-user",
+    });
+    sqlx::query(
+        "INSERT INTO flow (workspace_id, path, summary, description, value, edited_by)
+              VALUES ('test-workspace', 'u/test-user/blessed', '', '', $1, 'test-user')",
+    )
+    .bind(serde_json::to_value(flow(shared.clone())).unwrap())
+    .execute(&db)
+    .await
+    .unwrap();
+
+    /* not without a permissioned_as, nor without a grant */
+    let cjob = RunJob::from(JobPayload::RawFlow {
+        value: flow(
+            json!({ "type": "resource", "path": "u/other-user/shared", "workspace": "other" }),
+        ),
+        path: None,
+    })
+    .run_until_complete(&db, port)
+    .await;
+    assert!(!cjob.success);
+    let cjob = RunJob::from(JobPayload::Flow("u/test-user/blessed".to_string()))
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+
+    sqlx::query(
+        "INSERT INTO cross_workspace_grant
+            (workspace_id, resource_path, permissioned_as, grantee_workspace_id,
+             grantee_flow_path, granted_by)
+         VALUES ('other', 'u/other-user/shared', 'u/other-user', 'test-workspace',
+                 'u/test-user/blessed', 'other-user')",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let cjob = RunJob::from(JobPayload::Flow("u/test-user/blessed".to_string()))
+        .run_until_complete(&db, port)
+        .await;
+    assert_eq!(cjob.result, Some(json!({ "host": "other" })));
+
+    /* the grant is to the deployed flow, not to previews claiming its path */
+    let cjob = RunJob::from(JobPayload::RawFlow {
+        value: flow(shared),
+        path: Some("u/test-user/blessed".to_string()),
+    })
+    .run_until_complete(&db, port)
+    .await;
+    assert!(!cjob.success);
+
+    let audited = sqlx::query_scalar::<_, String>(
+        "SELECT workspace_id FROM audit WHERE operation = 'resources.cross_workspace_get'
+       ORDER BY workspace_id",
+    )
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(
+        audited,
+        vec!["other".to_string(), "test-workspace".to_string()]
+    );
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_output_transform(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow = |expr: &str| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{ "id": "a", "value": { "type": "identity" } }],
+            "output_transform": { "expr": expr, "keep_raw_result": true },
+        }))
+        .unwrap()
+    };
+
+    let cjob = RunJob::from(JobPayload::RawFlow {
+        value: flow("({ doubled: result.x * 2, input: flow_input.x })"),
+        path: None,
+    })
+    .arg("x", json!(21))
+    .run_until_complete(&db, port)
+    .await;
+    assert!(cjob.success);
+    assert_eq!(cjob.result, Some(json!({ "doubled": 42, "input": 21 })));
+    assert_eq!(cjob.flow_status.unwrap()["raw_result"], json!({ "x": 21 }));
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow("result.oops.x"), path: None })
+        .arg("x", json!(21))
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_trigger_chain_depth(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{ "id": "a", "value": { "type": "identity" } }],
+    }))
+    .unwrap();
+    let first = RunJob::from(JobPayload::RawFlow { value: flow.clone(), path: None })
+        .push(&db)
+        .await;
+    /* the token of a run is the one of its owner */
+    sqlx::query("UPDATE queue SET permissioned_as = 'u/test-user' WHERE id = $1")
+        .bind(first)
+        .execute(&db)
+        .await
+        .unwrap();
+
+    /* a run triggered with the token of another run, like a script of a flow would */
+    let trigger = |job: Uuid| {
+        let db = db.clone();
+        let flow = flow.clone();
+        async move {
+            let tx = db.begin().await.unwrap();
+            let (tx, token) = windmill_worker::create_token_for_owner(
+                tx,
+                "test-workspace",
+                "u/test-user",
+                "",
+                100,
+                "",
+                Some(job),
+            )
+            .await
+            .unwrap();
+            tx.commit().await.unwrap();
+            reqwest::Client::new()
+                .post(format!(
+                    "http://localhost:{port}/api/w/test-workspace/jobs/run/preview_flow"
+                ))
+                .bearer_auth(token)
+                .json(&json!({ "value": flow, "args": {} }))
+                .send()
+                .await
+                .unwrap()
+        }
+    };
+
+    let second = trigger(first).await.error_for_status().unwrap();
+    let second: Uuid = second.text().await.unwrap().parse().unwrap();
+    let (chain, depth) = sqlx::query_as::<_, (Option<Uuid>, i32)>(
+        "SELECT trigger_chain, trigger_depth FROM queue WHERE id = $1",
+    )
+    .bind(second)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!((chain, depth), (Some(first), 1));
+
+    sqlx::query("UPDATE queue SET trigger_depth = $1 WHERE id = $2")
+        .bind(*windmill_queue::MAX_TRIGGER_DEPTH)
+        .bind(second)
+        .execute(&db)
+        .await
+        .unwrap();
+    let third = trigger(second).await;
+    assert_eq!(third.status(), reqwest::StatusCode::BAD_REQUEST);
+    assert!(third.text().await.unwrap().contains(&first.to_string()));
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_schedule_pushed_once(db: Pool<Postgres>) {
+    initialize_tracing().await;
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{ "value": { "type": "identity" } }],
+    }))
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO flow (workspace_id, path, summary, description, value, edited_by)
+              VALUES ('test-workspace', 'u/test-user/yearly', '', '', $1, 'test-user')",
+    )
+    .bind(serde_json::to_value(flow).unwrap())
+    .execute(&db)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO schedule
+                (workspace_id, path, schedule, offset_, edited_by, script_path, is_flow, enabled)
+              VALUES ('test-workspace', 'u/test-user/yearly', '0 0 0 1 1 *', 0, 'test-user',
+                      'u/test-user/yearly', true, true)",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let push = || async {
+        let mut tx = db.begin().await.unwrap();
+        let schedule = windmill_queue::schedule::get_schedule_opt(
+            &mut tx,
+            "test-workspace",
+            "u/test-user/yearly",
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        windmill_queue::schedule::push_scheduled_job(tx, schedule)
+            .await
+            .unwrap()
+            .commit()
+            .await
+            .unwrap();
+    };
+    let queued = || async {
+        sqlx::query_scalar::<_, i64>(
+            "SELECT count(*) FROM queue WHERE schedule_path = 'u/test-user/yearly'",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap()
+    };
+
+    push().await;
+    assert_eq!(queued().await, 1);
+
+    /* re-arming the schedule for the same run, after its job already left the queue, is a no-op */
+    sqlx::query("DELETE FROM queue WHERE schedule_path = 'u/test-user/yearly'")
+        .execute(&db)
+        .await
+        .unwrap();
+    push().await;
+    assert_eq!(queued().await, 0);
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_max_flow_depth(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    /* each branchone runs its default branch in a flow nested in the one of the previous */
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "id": "b",
+            "value": {
+                "type": "branchone",
+                "branches": [],
+                "default": [{
+                    "id": "b",
+                    "value": {
+                        "type": "branchone",
+                        "branches": [],
+                        "default": [{ "id": "a", "value": { "type": "identity" } }],
+                    },
+                }],
+            },
+        }],
+    }))
+    .unwrap();
+
+    /* a flow nested that deep couldn't even be decoded, the flow is rather run under a chain of
+     * jobs `depth` long, that are never run */
+    let run_nested_in = |depth: usize| {
+        let db = db.clone();
+        let flow = flow.clone();
+        async move {
+            let mut parent = None;
+            for _ in 0..depth {
+                parent = Some(
+                    sqlx::query_scalar::<_, Uuid>(
+                        "INSERT INTO queue
+                                (id, workspace_id, parent_job, created_by, permissioned_as,
+                                 scheduled_for, job_kind)
+                              VALUES (gen_random_uuid(), 'test-workspace', $1, 'test-user',
+                                      'u/test-user', 'infinity', 'identity')
+                           RETURNING id",
+                    )
+                    .bind(parent)
+                    .fetch_one(&db)
+                    .await
+                    .unwrap(),
+                );
+            }
+
+            let tx = db.begin().await.unwrap();
+            let (id, tx) = windmill_queue::push(
+                tx,
+                "test-workspace",
+                JobPayload::RawFlow { value: flow, path: None },
+                None,
+                "test-user",
+                "u/test-user".to_string(),
+                None,
+                None,
+                parent,
+                false,
+                false,
+                None,
+                FlowStorage::default(),
+            )
+            .await
+            .unwrap();
+            tx.commit().await.unwrap();
+
+            let listener = listen_for_completed_jobs(&db).await;
+            in_test_worker(&db, listener.find(&id), port).await;
+            completed_job(id, &db).await
+        }
+    };
+
+    /* the innermost flow is 100 levels deep */
+    let cjob = run_nested_in(98).await;
+    assert!(cjob.success, "{:?}", cjob.result);
+
+    let cjob = run_nested_in(99).await;
+    assert!(!cjob.success);
+    assert!(cjob
+        .result
+        .unwrap()
+        .to_string()
+        .contains("would nest a flow 101 levels deep, past the maximum of 100 levels"));
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_unparsable_flow_status_needs_intervention(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{ "id": "a", "value": { "type": "identity" } }],
+    }))
+    .unwrap();
+
+    let flow = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .push(&db)
+        .await;
+    sqlx::query("UPDATE queue SET flow_status = '{\"step\": \"oops\"}' WHERE id = $1")
+        .bind(flow)
+        .execute(&db)
+        .await
+        .unwrap();
+
+    let completed = listen_for_completed_jobs(&db).await;
+    let db_ = db.clone();
+    in_test_worker(
+        &db,
+        async move {
+            let db = db_;
+
+            /* the flow is parked instead of failing, with its status left as is */
+            let (flow_status, suspend_until) = loop {
+                let parked = sqlx::query_as::<_, (serde_json::Value, String)>(
+                    "SELECT flow_status, suspend_until::text FROM queue
+                      WHERE id = $1 AND needs_intervention IS NOT NULL",
+                )
+                .bind(flow)
+                .fetch_optional(&db)
+                .await
+                .unwrap();
+                match parked {
+                    Some(parked) => break parked,
+                    None => tokio::time::sleep(std::time::Duration::from_millis(100)).await,
+                }
+            };
+            assert_eq!(flow_status, json!({ "step": "oops" }));
+            assert_eq!(suspend_until, "infinity");
+
+            /* until it is canceled */
+            sqlx::query(
+                "UPDATE queue SET canceled = true, canceled_by = 'test-user', suspend = 0
+                  WHERE id = $1",
+            )
+            .bind(flow)
+            .execute(&db)
+            .await
+            .unwrap();
+            completed.find(&flow).await.unwrap();
+        },
+        port,
+    )
+    .await;
+
+    let cjob = completed_job(flow, &db).await;
+    assert!(!cjob.success);
+    assert!(cjob.canceled);
+
+    server.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_estimate_fan_out() {
+    let script = || json!({ "value": { "type": "identity" } });
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "id": "a",
+            "value": {
+                "type": "forloopflow",
+                "iterator": { "type": "static", "value": [1, 2, 3] },
+                "modules": [script(), script()],
+            },
+        }, {
+            "id": "b",
+            "value": {
+                "type": "forloopflow",
+                "iterator": { "type": "javascript", "expr": "flow_input.items" },
+                "modules": [script()],
+            },
+        }, {
+            "id": "c",
+            "value": {
+                "type": "branchall",
+                "branches": [{ "modules": [script()] }, { "modules": [script(), script()] }],
+            },
+        }, {
+            "id": "d",
+            "value": {
+                "type": "branchone",
+                "branches": [{ "expr": "flow_input.items.length > 5", "modules": [script()] }],
+                "default": [script(), script()],
+            },
+        }, {
+            "id": "e",
+            "value": {
+                "type": "forloopflow",
+                "iterator": { "type": "javascript", "expr": "result" },
+                "modules": [script()],
+            },
+        }, {
+            "id": "f",
+            "value": {
+                "type": "branchone",
+                "branches": [{ "expr": "flow_input.items", "modules": [script()] }],
+                "default": [script(), script()],
+            },
+        }],
+    }))
+    .unwrap();
+
+    let estimate = windmill_common::fan_out::estimate_fan_out(
+        &flow,
+        &json!({ "items": [1, 2] }),
+        &windmill_worker::JsFanOutEval,
+    )
+    .await;
+    assert_eq!(
+        estimate
+            .modules
+            .iter()
+            .map(|m| (m.id.as_str(), m.jobs, m.dynamic))
+            .collect::<Vec<_>>(),
+        vec![
+            ("a", 9, false),
+            ("b", 4, false),
+            ("c", 5, false),
+            ("d", 3, false),
+            /* depends on the result of the prior step */
+            ("e", 0, true),
+            /* a non-empty array is a truthy predicate */
+            ("f", 2, false),
+        ]
+    );
+    assert_eq!(estimate.jobs, 24);
+    assert!(estimate.dynamic);
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_forloop_order_by(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow = |order_by: serde_json::Value| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": {
+                        "type": "static",
+                        "value": [
+                            { "name": "b", "age": 30 },
+                            { "name": "c", "age": 10 },
+                            { "name": "a", "age": 20 },
+                        ],
+                    },
+                    "skip_failures": false,
+                    "order_by": order_by,
+                    "modules": [{
+                        "input_transforms": {
+                            "x": { "type": "javascript", "expr": "flow_input.iter.value.name" },
+                        },
+                        "value": {
+                            "type": "rawscript",
+                            "language": "deno",
+                            "content": "export function main(x) { return x }",
+                        },
+                    }],
+                },
+            }],
+        }))
+        .unwrap()
+    };
+
+    for (order_by, expected) in [
+        (json!({ "expr": "iter.value.age" }), json!(["c", "a", "b"])),
+        (
+            json!({ "expr": "iter.value.age", "desc": true }),
+            json!(["b", "a", "c"]),
+        ),
+        (json!({ "expr": "iter.value.name" }), json!(["a", "b", "c"])),
+        (
+            json!({ "expr": "iter.value.name", "desc": true }),
+            json!(["c", "b", "a"]),
+        ),
+    ] {
+        let result = RunJob::from(JobPayload::RawFlow { value: flow(order_by), path: None })
+            .run_until_complete(&db, port)
+            .await
+            .result
+            .unwrap();
+        assert_eq!(result, expected);
+    }
+
+    /* keys of different types can't be compared */
+    let cjob = RunJob::from(JobPayload::RawFlow {
+        value: flow(json!({ "expr": "iter.index == 0 ? 'first' : iter.index" })),
+        path: None,
+    })
+    .run_until_complete(&db, port)
+    .await;
+    assert!(!cjob.success);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_dag(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let module = |id: &str, depends_on: serde_json::Value, content: &str| {
+        json!({
+            "id": id,
+            "depends_on": depends_on,
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": content,
+                "input_transforms": {
+                    "x": { "type": "javascript", "expr": "previous_result" },
+                },
+            },
+        })
+    };
+    let flow: FlowValue = serde_json::from_value(json!({
+        "dag": true,
+        "modules": [
+            module("c", json!(["a", "b"]), "export function main(x) { return x.a + x.b }"),
+            module("a", json!([]), "export function main(x) { return x.n }"),
+            module("b", json!([]), "export function main(x) { return x.n * 2 }"),
+            module("d", json!(["c"]), "export function main(x) { return x * 10 }"),
+            module("e", json!(["a"]), "export function main(x) { return -x }"),
+        ],
+    }))
+    .unwrap();
+
+    /* the modules no other depends on make the result */
+    let result = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .arg("n", json!(1))
+        .run_until_complete(&db, port)
+        .await
+        .result;
+    assert_eq!(result, Some(json!({ "d": 30, "e": -1 })));
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "dag": true,
+        "modules": [
+            module("a", json!(["b"]), "export function main(x) { return x }"),
+            module("b", json!(["a"]), "export function main(x) { return x }"),
+        ],
+    }))
+    .unwrap();
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+    assert!(cjob.result.unwrap().to_string().contains("form a cycle"));
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_script_hashes_pinned_per_run(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let add_version = |hash: i64| {
+        sqlx::query(
+            "INSERT INTO script
+                    (workspace_id, hash, path, summary, description, content, created_by, language)
+             VALUES ('test-workspace', $1, 'u/test-user/version', '', '', $2, 'test-user', 'deno')",
+        )
+        .bind(hash)
+        .bind(format!("export function main() {{ return {hash} }}"))
+        .execute(&db)
+    };
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "value": {
+                "type": "forloopflow",
+                "iterator": { "type": "static", "value": [1, 2, 3] },
+                "skip_failures": false,
+                "modules": [{
+                    "value": {
+                        "type": "script",
+                        "path": "u/test-user/version",
+                        "input_transforms": {},
+                    },
+                }],
+            },
+        }],
+    }))
+    .unwrap();
+
+    /* all the iterations run the version the run was pinned to when it started ... */
+    add_version(1).await.unwrap();
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow.clone(), path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert_eq!(cjob.result, Some(json!([1, 1, 1])));
+    assert_eq!(
+        cjob.raw_flow
+            .unwrap()
+            .pointer("/modules/0/value/modules/0/value/hash")
+            .cloned(),
+        Some(json!(ScriptHash(1).to_string()))
+    );
+
+    /* ... and the next run picks up the new version */
+    add_version(2).await.unwrap();
+    let result = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, port)
+        .await
+        .result;
+    assert_eq!(result, Some(json!([2, 2, 2])));
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_flow_partial_results(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    /* the last iteration returns the partial results of the loop, the iterations are sub-flows
+     * of the flow */
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "id": "a",
+            "value": {
+                "type": "forloopflow",
+                "iterator": { "type": "static", "value": [1, 2, 3] },
+                "skip_failures": false,
+                "modules": [{
+                    "input_transforms": {
+                        "x": { "type": "javascript", "expr": "previous_result.iter.value" },
+                        "port": { "type": "javascript", "expr": "flow_input.port" },
+                    },
+                    "value": {
+                        "type": "rawscript",
+                        "language": "deno",
+                        "content": r#"export async function main(x: number, port: number) {
+                            if (x < 3) {
+                                return x * 2;
+                            }
+                            const headers = { 'Authorization': `Bearer ${Deno.env.get('WM_TOKEN')}` };
+                            const base = `http://localhost:${port}/api/w/test-workspace/jobs`;
+                            const iteration = await fetch(`${base}/get/${Deno.env.get('WM_FLOW_JOB_ID')}`, { headers });
+                            const flow = (await iteration.json()).parent_job;
+                            const r = await fetch(`${base}/flow_partial_results/${flow}`, { headers });
+                            return await r.json();
+                        }"#,
+                    },
+                }],
+            },
+        }],
+    }))
+    .unwrap();
+
+    let result = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .arg("port", json!(port))
+        .run_until_complete(&db, port)
+        .await
+        .result
+        .unwrap();
+    assert_eq!(
+        result,
+        json!([2, 4, { "step": 0, "id": "a", "results": [2, 4] }])
+    );
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_job_blobs(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    /* the first step uploads its payload as a blob and returns its reference, the next one gets
+     * it back with `blob(ref)` */
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "input_transforms": {
+                "port": { "type": "javascript", "expr": "flow_input.port" },
+            },
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": r#"export async function main(port: number) {
+                    const r = await fetch(
+                        `http://localhost:${port}/api/w/test-workspace/jobs/blobs/upload/${Deno.env.get('WM_JOB_ID')}`,
+                        {
+                            method: 'POST',
+                            headers: { 'Authorization': `Bearer ${Deno.env.get('WM_TOKEN')}` },
+                            body: 'hello blob',
+                        }
+                    );
+                    return await r.json();
+                }"#,
+            },
+        }, {
+            "input_transforms": {
+                "content": { "type": "javascript", "expr": "blob(previous_result)" },
+            },
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main(content: string) { return atob(content) }",
+            },
+        }],
+    }))
+    .unwrap();
+
+    let result = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .arg("port", json!(port))
+        .run_until_complete(&db, port)
+        .await
+        .result;
+    assert_eq!(result, Some(json!("hello blob")));
+
+    /* the blob can't be read without authenticating */
+    let id = sqlx::query_scalar::<_, Uuid>("SELECT id FROM job_blob")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    let res = reqwest::get(format!(
+        "http://localhost:{port}/api/w/test-workspace/jobs/blobs/get/{id}"
+    ))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_forloop_max_failures(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow = |parallelism: u16, max_failures: u32| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{
+                "id": "a",
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "static", "value": [1, -2, 3, -4, 5, -6] },
+                    "skip_failures": true,
+                    "max_failures": max_failures,
+                    "parallelism": parallelism,
+                    "modules": [{
+                        "input_transforms": {
+                            "x": { "type": "javascript", "expr": "previous_result.iter.value" },
+                        },
+                        "value": {
+                            "type": "rawscript",
+                            "language": "deno",
+                            "content": r#"export function main(x: number) {
+                                if (x < 0) {
+                                    throw new Error(`negative ${x}`);
+                                }
+                                return x;
+                            }"#,
+                        },
+                    }],
+                },
+            }],
+        }))
+        .unwrap()
+    };
+
+    /* the first failure is skipped, the loop fails at the second one */
+    for parallelism in [1, 2] {
+        let cjob = RunJob::from(JobPayload::RawFlow { value: flow(parallelism, 2), path: None })
+            .run_until_complete(&db, port)
+            .await;
+        assert!(!cjob.success);
+        let result = cjob.result.unwrap();
+        assert_eq!(result["failures"], json!(2), "{result}");
+        assert!(result["error"].as_str().unwrap().contains("maximum of 2"));
+        let iterations = sqlx::query_scalar::<_, i64>(
+            "SELECT count(*) FROM completed_job WHERE parent_job = $1 AND flow_step_id = 'a'",
         )
+        .bind(cjob.id)
+        .fetch_one(&db)
         .await
-    };
+        .unwrap();
+        assert_eq!(iterations, 4);
+    }
 
-    (tx, tokio::task::spawn(future))
-}
+    /* none of the successful iterations reaches a maximum of 0, the first failure does */
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow(1, 0), path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+    assert_eq!(cjob.result.unwrap()["failures"], json!(1));
 
-async fn listen_for_completed_jobs(db: &Pool<Postgres>) -> impl Stream<Item = Uuid> + Unpin {
-    listen_for_uuid_on(db, "insert on completed_job").await
+    server.close().await.unwrap();
 }
 
-async fn listen_for_queue(db: &Pool<Postgres>) -> impl Stream<Item = Uuid> + Unpin {
-    listen_for_uuid_on(db, "queue").await
+#[sqlx::test(fixtures("base"))]
+async fn test_forloop_empty_iterator(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow = |error_if_empty: bool| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "static", "value": [] },
+                    "skip_failures": false,
+                    "error_if_empty": error_if_empty,
+                    "modules": [{
+                        "value": {
+                            "type": "rawscript",
+                            "language": "deno",
+                            "content": "export function main() { return 1 }",
+                        },
+                    }],
+                },
+            }],
+        }))
+        .unwrap()
+    };
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow(false), path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(cjob.success);
+    assert_eq!(Some(json!([])), cjob.result);
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow(true), path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+
+    server.close().await.unwrap();
 }
 
-async fn listen_for_uuid_on(
-    db: &Pool<Postgres>,
-    channel: &'static str,
-) -> impl Stream<Item = Uuid> + Unpin {
-    let mut listener = PgListener::connect_with(db).await.unwrap();
-    listener.listen(channel).await.unwrap();
+#[sqlx::test(fixtures("base"))]
+async fn test_forloop_min_iteration_interval(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
 
-    Box::pin(stream::unfold(listener, |mut listener| async move {
-        let uuid = listener
-            .try_recv()
-            .await
-            .unwrap()
-            .expect("lost database connection")
-            .payload()
-            .parse::<Uuid>()
-            .expect("invalid uuid");
-        Some((uuid, listener))
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "id": "a",
+            "value": {
+                "type": "forloopflow",
+                "iterator": { "type": "static", "value": [1, 2, 3] },
+                "skip_failures": false,
+                "min_iteration_interval": 1,
+                "modules": [{ "id": "b", "value": { "type": "identity" } }],
+            },
+        }],
     }))
+    .unwrap();
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(cjob.success);
+
+    let starts = sqlx::query_scalar::<_, chrono::DateTime<chrono::Utc>>(
+        "SELECT started_at FROM completed_job
+          WHERE parent_job = $1 AND flow_step_id = 'a'
+          ORDER BY started_at",
+    )
+    .bind(cjob.id)
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(starts.len(), 3);
+    for pair in starts.windows(2) {
+        assert!(
+            pair[1] - pair[0] >= chrono::Duration::seconds(1),
+            "iterations started at {starts:?}"
+        );
+    }
+
+    server.close().await.unwrap();
 }
 
-async fn completed_job(uuid: Uuid, db: &Pool<Postgres>) -> CompletedJob {
-    sqlx::query_as::<_, CompletedJob>("SELECT * FROM completed_job WHERE id = $1")
-        .bind(uuid)
-        .fetch_one(db)
-        .await
+#[sqlx::test(fixtures("base"))]
+async fn test_forloop_parallelism(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow = |iterated: serde_json::Value, parallelism: u16| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{
+                "id": "a",
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "static", "value": iterated },
+                    "skip_failures": false,
+                    "parallelism": parallelism,
+                    "modules": [{
+                        "input_transforms": {
+                            "x": { "type": "javascript", "expr": "previous_result.iter.value" },
+                        },
+                        "value": {
+                            "type": "rawscript",
+                            "language": "deno",
+                            "content": r#"export function main(x: number) {
+                                if (x < 0) {
+                                    throw new Error(`negative ${x}`);
+                                }
+                                return x * 2;
+                            }"#,
+                        },
+                    }],
+                },
+            }],
+        }))
         .unwrap()
-}
+    };
 
-#[axum::async_trait(?Send)]
-trait StreamFind: futures::Stream + Unpin + Sized {
-    async fn find(self, item: &Self::Item) -> Option<Self::Item>
-    where
-        for<'l> &'l Self::Item: std::cmp::PartialEq,
-    {
-        use futures::{future::ready, StreamExt};
+    let cjob =
+        RunJob::from(JobPayload::RawFlow { value: flow(json!([3, 1, 2, 5, 4]), 2), path: None })
+            .run_until_complete(&db, port)
+            .await;
+    assert!(cjob.success);
+    assert_eq!(cjob.result.unwrap(), json!([6, 2, 4, 10, 8]));
+
+    /* the iterations of a window are pushed together, the next window once they completed */
+    let iterations =
+        sqlx::query_as::<_, (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>(
+            "SELECT created_at, started_at FROM completed_job
+          WHERE parent_job = $1 AND flow_step_id = 'a'
+          ORDER BY (args->'iter'->>'index')::int",
+        )
+        .bind(cjob.id)
+        .fetch_all(&db)
+        .await
+        .unwrap();
+    assert_eq!(iterations.len(), 5);
+    assert!(iterations[1].0 <= iterations[0].1, "{iterations:?}");
+    assert!(iterations[2].0 >= iterations[1].1, "{iterations:?}");
+    assert!(iterations[3].0 <= iterations[2].1, "{iterations:?}");
+
+    /* a failure fails the loop once the whole window completed */
+    let cjob =
+        RunJob::from(JobPayload::RawFlow { value: flow(json!([1, -2, 3, 4]), 3), path: None })
+            .run_until_complete(&db, port)
+            .await;
+    assert!(!cjob.success);
+    assert!(
+        cjob.result.unwrap().to_string().contains("negative -2"),
+        "the loop should fail with the error of the failed iteration"
+    );
+    let iterations = sqlx::query_scalar::<_, i64>(
+        "SELECT count(*) FROM completed_job WHERE parent_job = $1 AND flow_step_id = 'a'",
+    )
+    .bind(cjob.id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(iterations, 3);
 
-        self.filter(|i| ready(i == item)).next().await
-    }
+    server.close().await.unwrap();
 }
 
-impl<T: futures::Stream + Unpin + Sized> StreamFind for T {}
-
 #[sqlx::test(fixtures("base"))]
-async fn test_deno_flow(db: Pool<Postgres>) {
+async fn test_forloop_flow_jobs_in_table(db: Pool<Postgres>) {
     initialize_tracing().await;
-
     let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
 
-    let numbers = "export function main() { return [1, 2, 3]; }";
-    let doubles = "export function main(n) { return n * 2; }";
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "value": {
+                "type": "forloopflow",
+                "iterator": { "type": "static", "value": [1, 2, 3] },
+                "skip_failures": false,
+                "modules": [{ "value": { "type": "identity" } }],
+            },
+        }],
+    }))
+    .unwrap();
 
-    let flow = {
-        FlowValue {
-            modules: vec![
-                FlowModule {
-                    id: "a".to_string(),
-                    value: FlowModuleValue::RawScript {
-                        input_transforms: Default::default(),
-                        language: ScriptLang::Deno,
-                        content: numbers.to_string(),
-                        path: None,
-                    },
-                    input_transforms: Default::default(),
-                    stop_after_if: Default::default(),
-                    summary: Default::default(),
-                    suspend: Default::default(),
-                    retry: None,
-                    sleep: None,
-                },
-                FlowModule {
-                    id: "b".to_string(),
-                    value: FlowModuleValue::ForloopFlow {
-                        iterator: InputTransform::Javascript { expr: "result".to_string() },
-                        skip_failures: false,
-                        modules: vec![FlowModule {
-                            id: "c".to_string(),
-                            value: FlowModuleValue::RawScript {
-                                input_transforms: [(
-                                    "n".to_string(),
-                                    InputTransform::Javascript {
-                                        expr: "previous_result.iter.value".to_string(),
-                                    },
-                                )]
-                                .into(),
-                                language: ScriptLang::Deno,
-                                content: doubles.to_string(),
-                                path: None,
-                            },
-                            input_transforms: Default::default(),
-                            stop_after_if: Default::default(),
-                            summary: Default::default(),
-                            suspend: Default::default(),
-                            retry: None,
-                            sleep: None,
-                        }],
-                    },
-                    input_transforms: Default::default(),
-                    stop_after_if: Default::default(),
-                    summary: Default::default(),
-                    suspend: Default::default(),
-                    retry: None,
-                    sleep: None,
-                },
-            ],
-            same_worker: false,
-            ..Default::default()
-        }
-    };
+    let job = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .flow_storage(FlowStorage { flow_jobs_in_table: true, iterators_in_table: true })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(job.success);
+    let values = job.result.as_ref().unwrap().as_array().unwrap();
+    let values = values
+        .iter()
+        .map(|r| r["iter"]["value"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(values, vec![json!(1), json!(2), json!(3)]);
+
+    /* the jobs of the iterations are only in the table ... */
+    let flow_status = job.flow_status.unwrap();
+    assert_eq!(flow_status["flow_jobs_in_table"], json!(true));
+    assert_eq!(flow_status["modules"][0]["flow_jobs"], json!([]));
+    let iterations = sqlx::query_scalar::<_, Uuid>(
+        "SELECT id FROM completed_job WHERE parent_job = $1
+          ORDER BY (args->'iter'->>'index')::int",
+    )
+    .bind(job.id)
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(iterations.len(), 3);
+
+    /* ... and inlined by the API as if they had been kept in the status */
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let fetched = reqwest::Client::new()
+        .get(format!(
+            "http://localhost:{port}/api/w/test-workspace/jobs/get/{}",
+            job.id
+        ))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+    assert_eq!(
+        fetched["flow_status"]["modules"][0]["flow_jobs"],
+        json!(iterations)
+    );
 
-    let job = JobPayload::RawFlow { value: flow, path: None };
-    let port = server.addr.port();
+    /* the values iterated over were in the table only while the flow ran */
+    assert_eq!(flow_status["iterators_in_table"], json!(true));
+    let itered = sqlx::query_scalar::<_, i64>("SELECT count(*) FROM flow_iterator WHERE flow = $1")
+        .bind(job.id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(itered, 0);
 
-    for i in 0..50 {
-        println!("deno flow iteration: {}", i);
-        let result = run_job_in_new_worker_until_complete(&db, job.clone(), port)
-            .await
-            .result
-            .unwrap();
-        assert_eq!(result, serde_json::json!([2, 4, 6]), "iteration: {}", i);
-    }
+    server.close().await.unwrap();
 }
 
+/// Compares a large loop keeping its flow jobs inline and in the table, run with
+/// `cargo test --test worker bench_forloop_flow_jobs_in_table -- --ignored --nocapture`.
+///
+/// Every iteration rewrites the flow status, so its size is what each step writes.
 #[sqlx::test(fixtures("base"))]
-async fn test_deno_flow_same_worker(db: Pool<Postgres>) {
+#[ignore]
+async fn bench_forloop_flow_jobs_in_table(db: Pool<Postgres>) {
     initialize_tracing().await;
-
     let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
 
-    let write_file = r#"export async function main(loop: boolean, i: number, path: string) {  
-            await Deno.writeTextFile(`/shared/${path}`, `${loop} ${i}`);
-        }"#
-    .to_string();
-
-    let flow = FlowValue {
-            modules: vec![
-                FlowModule {
-                    id: "a".to_string(),
-                    value: FlowModuleValue::RawScript {
-                        input_transforms: [
-                        (
-                            "loop".to_string(),
-                            InputTransform::Static { value: json!(false) },
-                        ),
-                        ("i".to_string(), InputTransform::Static { value: json!(1) }),
-                        (
-                            "path".to_string(),
-                            InputTransform::Static { value: json!("outer.txt") },
-                        ),
-                    ]
-                    .into(),
-                        language: ScriptLang::Deno,
-                        content: write_file.clone(),
-                        path: None,
-                    },
-                    input_transforms: Default::default(),
-                    stop_after_if: Default::default(),
-                    summary: Default::default(),
-                    suspend: Default::default(),
-                    retry: None,
-                    sleep: None,
-                },
-                FlowModule {
-                    id: "b".to_string(),
-                    value: FlowModuleValue::ForloopFlow {
-                        iterator: InputTransform::Static { value: json!([1, 2, 3]) },
-                        skip_failures: false,
-                        modules: vec![
-                            FlowModule {
-                                id: "d".to_string(),
-                                input_transforms: [
-                                (
-                                    "i".to_string(),
-                                    InputTransform::Javascript {
-                                        expr: "previous_result.iter.value".to_string(),
-                                    },
-                                ),
-                                (
-                                    "loop".to_string(),
-                                    InputTransform::Static { value: json!(true) },
-                                ),
-                                (
-                                    "path".to_string(),
-                                    InputTransform::Static { value: json!("inner.txt") },
-                                ),
-                            ]
-                            .into(),
-                                value: FlowModuleValue::RawScript {
-                                    input_transforms: [].into(),
-                                    language: ScriptLang::Deno,
-                                    content: write_file,
-                                    path: None,
-                                },
-                                stop_after_if: Default::default(),
-                                summary: Default::default(),
-                                suspend: Default::default(),
-                                retry: None,
-                                sleep: None,
-                            },
-                            FlowModule {
-                                id: "e".to_string(),
-                                value: FlowModuleValue::RawScript {
-                                    input_transforms: [(
-                                        "path".to_string(),
-                                        InputTransform::Static { value: json!("inner.txt") },
-                                    ), (
-                                        "path2".to_string(),
-                                        InputTransform::Static { value: json!("outer.txt") },
-                                    )]
-                                    .into(),
-                                    language: ScriptLang::Deno,
-                                    content: r#"export async function main(path: string, path2: string) {  
-                                        return await Deno.readTextFile(`/shared/${path}`) + "," + await Deno.readTextFile(`/shared/${path2}`);
-                                    }"#
-                                    .to_string(),
-                                    path: None,
-                                },
-                                input_transforms: [].into(),
-                                stop_after_if: Default::default(),
-                                summary: Default::default(),
-                                suspend: Default::default(),
-                                retry: None,
-                                sleep: None,
-                            },
-                        ],
-                    },
-                    input_transforms: Default::default(),
-                    stop_after_if: Default::default(),
-                    summary: Default::default(),
-                    suspend: Default::default(),
-                    retry: None,
-                    sleep: None,
-
-                },
-                FlowModule {
-                    id: "c".to_string(),
-                    value: FlowModuleValue::RawScript {
-                        input_transforms: [
-                        (
-                            "loops".to_string(),
-                            InputTransform::Javascript { expr: "previous_result".to_string() },
-                        ),
-                        (
-                            "path".to_string(),
-                            InputTransform::Static { value: json!("outer.txt") },
-                        ),
-                        (
-                            "path2".to_string(),
-                            InputTransform::Static { value: json!("inner.txt") },
-                        ),
-                    ]
-                    .into(),
-                        language: ScriptLang::Deno,
-                        content: r#"export async function main(path: string, loops: string[], path2: string) {
-                            return await Deno.readTextFile(`/shared/${path}`) + "," + loops + "," + await Deno.readTextFile(`/shared/${path2}`);
-                        }"#
-                        .to_string(),
-                        path: None,
-                    },
-                    input_transforms: [].into(),
-                    stop_after_if: Default::default(),
-                    summary: Default::default(),
-                    suspend: Default::default(),
-                    retry: None,
-                    sleep: None,
+    let iterations = (0..500).collect::<Vec<_>>();
+    let mut sizes = vec![];
+    for flow_jobs_in_table in [false, true] {
+        let flow: FlowValue = serde_json::from_value(json!({
+            "modules": [{
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "static", "value": iterations },
+                    "skip_failures": false,
+                    "modules": [{ "value": { "type": "identity" } }],
                 },
-            ],
-            same_worker: true,
-            ..Default::default()
-        };
+            }],
+        }))
+        .unwrap();
 
-    let job = JobPayload::RawFlow { value: flow, path: None };
+        let start = std::time::Instant::now();
+        let job = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+            .flow_storage(FlowStorage { flow_jobs_in_table, ..Default::default() })
+            .run_until_complete(&db, port)
+            .await;
+        let elapsed = start.elapsed();
+        assert!(job.success);
 
-    let result = run_job_in_new_worker_until_complete(&db, job.clone(), server.addr.port())
+        let size = sqlx::query_scalar::<_, i32>(
+            "SELECT pg_column_size(flow_status) FROM completed_job WHERE id = $1",
+        )
+        .bind(job.id)
+        .fetch_one(&db)
         .await
-        .result
         .unwrap();
-    assert_eq!(
-        result,
-        serde_json::json!("false 1,true 1,false 1,true 2,false 1,true 3,false 1,true 3")
-    );
+        println!(
+            "flow_jobs_in_table: {flow_jobs_in_table}, {} iterations in {elapsed:?}, \
+             flow_status of {size} bytes",
+            iterations.len()
+        );
+        sizes.push(size);
+    }
+    assert!(sizes[1] < sizes[0], "{sizes:?}");
+
+    server.close().await.unwrap();
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_flow_result_by_id(db: Pool<Postgres>) {
+async fn test_forloop_iterators_in_table(db: Pool<Postgres>) {
     initialize_tracing().await;
-
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
 
     let flow: FlowValue = serde_json::from_value(json!({
-            "modules": [
-                {
-                    "id": "a",
+        "modules": [{
+            "id": "a",
+            "value": {
+                "type": "forloopflow",
+                "iterator": { "type": "static", "value": [3, 1, -2, 5, 4] },
+                "skip_failures": true,
+                "parallelism": 2,
+                "modules": [{
+                    "input_transforms": {
+                        "x": { "type": "javascript", "expr": "previous_result.iter.value" },
+                    },
                     "value": {
                         "type": "rawscript",
                         "language": "deno",
-                        "content": "export function main(){ return 42 }",
-                    }
-                },
-                {
-                    "value": {
-                        "branches": [
-                            {
-                                "modules": [{
-                                    "value": {
-                                        "branches": [{"modules": [                {
-                                            "id": "d",
-                                            "value": {
-                                                "input_transforms": {"v": {"type": "javascript", "expr": "result_by_id(\"a\")"}},
-                                                "type": "rawscript",
-                                                "language": "deno",
-                                                "content": "export function main(v){ return v }",
-                                            }
+                        "content": r#"export function main(x: number) {
+                            if (x < 0) {
+                                throw new Error(`negative ${x}`);
+                            }
+                            return x * 2;
+                        }"#,
+                    },
+                }],
+            },
+        }],
+    }))
+    .unwrap();
 
-                                        },]}],
-                                        "type": "branchall",
-                                    }
-                                }],
-                            }],
-                            "type": "branchall",
+    let job = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .flow_storage(FlowStorage { iterators_in_table: true, ..Default::default() })
+        .push(&db)
+        .await;
+
+    let listener = listen_for_completed_jobs(&db).await;
+    in_test_worker(&db, listener.find(&job), port).await;
+    let cjob = completed_job(job, &db).await;
+
+    /* the results keep the order of the iterations, the failed one keeping its error */
+    assert!(cjob.success);
+    let result = cjob.result.clone().unwrap();
+    assert_eq!(result[0], json!(6));
+    assert_eq!(result[1], json!(2));
+    assert!(result[2].to_string().contains("negative -2"), "{result}");
+    assert_eq!(result[3], json!(10));
+    assert_eq!(result[4], json!(8));
+    assert_eq!(
+        cjob.flow_status.as_ref().unwrap()["iterators_in_table"],
+        json!(true)
+    );
+    let indexes = sqlx::query_scalar::<_, i32>(
+        "SELECT (args->'iter'->>'index')::int FROM completed_job
+          WHERE parent_job = $1 AND flow_step_id = 'a' AND success
+          ORDER BY (args->'iter'->>'value')::int",
+    )
+    .bind(job)
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(indexes, vec![1, 0, 4, 3]);
+
+    /* the values are only kept in the table while the flow runs */
+    let rows = sqlx::query_scalar::<_, i64>("SELECT count(*) FROM flow_iterator WHERE flow = $1")
+        .bind(job)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(rows, 0);
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_forloop_iter_length_last(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow = |parallelism: u16| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "static", "value": ["a", "b", "c"] },
+                    "skip_failures": false,
+                    "parallelism": parallelism,
+                    "modules": [{
+                        "input_transforms": {
+                            "x": {
+                                "type": "javascript",
+                                "expr": "`${flow_input.iter.value}${flow_input.iter.index}/${flow_input.iter.length}${flow_input.iter.last ? ' last' : ''}`",
+                            },
                         },
-                    }
-            ],
+                        "value": {
+                            "type": "rawscript",
+                            "language": "deno",
+                            "content": "export function main(x: string) { return x; }",
+                        },
+                    }],
+                },
+            }],
         }))
-        .unwrap();
+        .unwrap()
+    };
 
-    let job = JobPayload::RawFlow { value: flow, path: None };
-    let result = run_job_in_new_worker_until_complete(&db, job.clone(), port)
-        .await
-        .result
-        .unwrap();
-    assert_eq!(result, serde_json::json!([[42]]));
+    /* the first iteration, the following ones and the ones of a window all get them */
+    for parallelism in [1, 2] {
+        let result = RunJob::from(JobPayload::RawFlow { value: flow(parallelism), path: None })
+            .run_until_complete(&db, port)
+            .await
+            .result
+            .unwrap();
+        assert_eq!(result, json!(["a0/3", "b1/3", "c2/3 last"]));
+    }
+
+    server.close().await.unwrap();
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_stop_after_if(db: Pool<Postgres>) {
+async fn test_forloop_iterator_results(db: Pool<Postgres>) {
     initialize_tracing().await;
-    // let server = ApiServer::start(db.clone()).await;
-    // let port = server.addr.port();
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
 
-    let port = 123;
-    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+    let flow: FlowValue = serde_json::from_value(json!({
         "modules": [
             {
                 "id": "a",
                 "value": {
-                    "input_transforms": { "n": { "type": "javascript", "expr": "flow_input.n" } },
                     "type": "rawscript",
-                    "language": "python3",
-                    "content": "def main(n): return n",
-                },
-                "stop_after_if": {
-                    "expr": "result < 0",
-                    "skip_if_stopped": false,
+                    "language": "deno",
+                    "content": "export function main() { return { ids: [1, 2, 3] }; }",
                 },
             },
             {
                 "id": "b",
                 "value": {
-                    "input_transforms": { "n": { "type": "javascript", "expr": "previous_result" } },
                     "type": "rawscript",
-                    "language": "python3",
-                    "content": "def main(n): return f'last step saw {n}'",
+                    "language": "deno",
+                    "content": "export function main() { return 'unrelated'; }",
+                },
+            },
+            {
+                "id": "c",
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "javascript", "expr": "results.a.ids" },
+                    "skip_failures": false,
+                    "modules": [{
+                        "input_transforms": {
+                            "x": { "type": "javascript", "expr": "previous_result.iter.value" },
+                        },
+                        "value": {
+                            "type": "rawscript",
+                            "language": "deno",
+                            "content": "export function main(x: number) { return x * 10; }",
+                        },
+                    }],
                 },
             },
         ],
     }))
     .unwrap();
-    let job = JobPayload::RawFlow { value: flow, path: None };
 
-    let result = RunJob::from(job.clone())
-        .arg("n", json!(123))
+    /* the iterator resolves the result of a step two steps before the loop */
+    let result = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
         .run_until_complete(&db, port)
         .await
         .result
         .unwrap();
-    assert_eq!(json!("last step saw 123"), result);
+    assert_eq!(result, json!([10, 20, 30]));
 
-    let cjob = RunJob::from(job.clone())
-        .arg("n", json!(-123))
-        .run_until_complete(&db, port)
-        .await;
+    server.close().await.unwrap();
+}
 
-    let result = cjob.result.unwrap();
-    assert_eq!(json!(-123), result);
+#[sqlx::test(fixtures("base"))]
+async fn test_truthy_predicates(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let branch = |name: &str| {
+        json!([{
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": format!("export function main() {{ return '{name}'; }}"),
+            },
+        }])
+    };
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "id": "a",
+            "value": {
+                "type": "branchone",
+                "branches": [
+                    { "expr": "flow_input.items", "modules": branch("items") },
+                    { "expr": "flow_input.count", "modules": branch("count") },
+                    { "expr": "flow_input.name", "modules": branch("name") },
+                ],
+                "default": branch("default"),
+            },
+        }],
+    }))
+    .unwrap();
+
+    /* null, 0, "" and [] are false, any other value is true */
+    for (args, expected) in [
+        (json!({ "items": [], "count": 0, "name": "" }), "default"),
+        (json!({ "items": [], "count": 0, "name": "x" }), "name"),
+        (json!({ "items": null, "count": 2 }), "count"),
+        (json!({ "items": [1] }), "items"),
+    ] {
+        let mut job = RunJob::from(JobPayload::RawFlow { value: flow.clone(), path: None });
+        for (k, v) in args.as_object().unwrap() {
+            job = job.arg(k, v.clone());
+        }
+        let result = job.run_until_complete(&db, port).await.result.unwrap();
+        assert_eq!(result, json!(expected));
+    }
+
+    server.close().await.unwrap();
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_stop_after_if_nested(db: Pool<Postgres>) {
+async fn test_input_transform_results(db: Pool<Postgres>) {
     initialize_tracing().await;
-    // let server = ApiServer::start(db.clone()).await;
-    // let port = server.addr.port();
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
 
-    let port = 123;
-    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+    let flow: FlowValue = serde_json::from_value(json!({
         "modules": [
             {
                 "id": "a",
                 "value": {
-                    "branches": [{"modules": [{
-                    "id": "b",
-                    "value": {
-                        "input_transforms": { "n": { "type": "javascript", "expr": "flow_input.n" } },
-                        "type": "rawscript",
-                        "language": "python3",
-                        "content": "def main(n): return n",
-                    },
-                    "stop_after_if": {
-                        "expr": "result < 0",
-                        "skip_if_stopped": false,
-                    }}]}],
-                    "type": "branchall"
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { return { name: 'a' }; }",
+                },
+            },
+            {
+                "id": "b",
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { return 2; }",
                 },
             },
             {
                 "id": "c",
+                "input_transforms": {
+                    "name": { "type": "javascript", "expr": "results.a.name" },
+                    "n": { "type": "javascript", "expr": "results.b" },
+                    "missing": { "type": "javascript", "expr": "results.c ?? 'none'" },
+                },
                 "value": {
-                    "input_transforms": { "n": { "type": "javascript", "expr": "previous_result" } },
                     "type": "rawscript",
-                    "language": "python3",
-                    "content": "def main(n): return f'last step saw {n}'",
+                    "language": "deno",
+                    "content": "export function main(name: string, n: number, missing: string) { return [name, n, missing]; }",
                 },
             },
         ],
     }))
     .unwrap();
-    let job = JobPayload::RawFlow { value: flow, path: None };
 
-    let result = RunJob::from(job.clone())
-        .arg("n", json!(123))
+    /* steps read the results of any earlier step, the ones that have not run are absent */
+    let result = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
         .run_until_complete(&db, port)
         .await
         .result
         .unwrap();
-    assert_eq!(json!("last step saw [123]"), result);
-
-    let cjob = RunJob::from(job.clone())
-        .arg("n", json!(-123))
-        .run_until_complete(&db, port)
-        .await;
+    assert_eq!(result, json!(["a", 2, "none"]));
 
-    let result = cjob.result.unwrap();
-    assert_eq!(json!([-123]), result);
+    server.close().await.unwrap();
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_python_flow(db: Pool<Postgres>) {
+async fn test_forloop_break_if(db: Pool<Postgres>) {
     initialize_tracing().await;
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
 
-    let numbers = "def main(): return [1, 2, 3]";
-    let doubles = "def main(n): return n * 2";
-
-    let flow: FlowValue = serde_json::from_value(serde_json::json!( {
-        "input_transform": {},
-        "modules": [
-            {
-                "value": {
-                    "type": "rawscript",
-                    "language": "python3",
-                    "content": numbers,
-                },
-            },
-            {
+    let flow = |parallelism: u16| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{
+                "id": "a",
                 "value": {
                     "type": "forloopflow",
-                    "iterator": { "type": "javascript", "expr": "result" },
+                    "iterator": { "type": "static", "value": [1, 2, 3, 4, 5] },
                     "skip_failures": false,
+                    "parallelism": parallelism,
+                    "break_if": "result >= 6",
                     "modules": [{
+                        "input_transforms": {
+                            "x": { "type": "javascript", "expr": "previous_result.iter.value" },
+                        },
                         "value": {
                             "type": "rawscript",
-                            "language": "python3",
-                            "content": doubles,
-                        },
-                        "input_transform": {
-                            "n": {
-                                "type": "javascript",
-                                "expr": "previous_result.iter.value",
-                            },
+                            "language": "deno",
+                            "content": "export function main(x: number) { return x * 2; }",
                         },
                     }],
                 },
-            },
-        ],
-    }))
+            }],
+        }))
+        .unwrap()
+    };
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow(1), path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(cjob.success);
+    assert_eq!(cjob.result.unwrap(), json!([2, 4, 6]));
+    let iterations = sqlx::query_scalar::<_, i64>(
+        "SELECT count(*) FROM completed_job WHERE parent_job = $1 AND flow_step_id = 'a'",
+    )
+    .bind(cjob.id)
+    .fetch_one(&db)
+    .await
     .unwrap();
+    assert_eq!(iterations, 3);
 
-    for i in 0..50 {
-        println!("python flow iteration: {}", i);
-        let result = run_job_in_new_worker_until_complete(
-            &db,
-            JobPayload::RawFlow { value: flow.clone(), path: None },
-            port,
-        )
-        .await
-        .result
-        .unwrap();
+    /* the window of the iteration that broke the loop completes, but the iterations past it
+     * are left out of the result */
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow(2), path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(cjob.success);
+    assert_eq!(cjob.result.unwrap(), json!([2, 4, 6]));
 
-        assert_eq!(result, serde_json::json!([2, 4, 6]), "iteration: {i}");
-    }
+    server.close().await.unwrap();
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_python_flow_2(db: Pool<Postgres>) {
+async fn test_step_timeout(db: Pool<Postgres>) {
     initialize_tracing().await;
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
 
-    let flow: FlowValue = serde_json::from_value(serde_json::json!({
-            "modules": [
-                {
-                    "value": {
-                        "type": "rawscript",
-                        "content": "import wmill\ndef main():  return \"Hello\"",
-                        "language": "python3"
-                    },
-                    "input_transform": {}
-                }
-            ]
+    let sleeping = |id: &str, seconds: &str| {
+        json!({
+            "id": id,
+            "input_transforms": {
+                "x": { "type": "javascript", "expr": seconds },
+            },
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": r#"export async function main(x: number) {
+                    await new Promise((resolve) => setTimeout(resolve, x * 1000));
+                    return x;
+                }"#,
+            },
+            "timeout": 1,
+        })
+    };
+
+    let flow: FlowValue =
+        serde_json::from_value(json!({ "modules": [sleeping("a", "5")] })).unwrap();
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+    match get_module(&cjob, "a").unwrap() {
+        FlowStatusModule::Failure { reason, .. } => {
+            assert_eq!(reason, Some(FailureReason::Timeout))
+        }
+        m => panic!("expected a failure, got {m:?}"),
+    }
+    assert_eq!(cjob.result.unwrap()["reason"], json!("timeout"));
+
+    /* a timed out iteration is skipped like any other failure */
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "id": "a",
+            "value": {
+                "type": "forloopflow",
+                "iterator": { "type": "static", "value": [0, 5] },
+                "skip_failures": true,
+                "modules": [sleeping("b", "flow_input.iter.value")],
+            },
+        }],
     }))
     .unwrap();
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(cjob.success);
+    let result = cjob.result.unwrap();
+    assert_eq!(result[0], json!(0));
+    assert_eq!(result[1]["reason"], json!("timeout"));
 
-    for i in 0..10 {
-        println!("python flow iteration: {}", i);
-        let result = run_job_in_new_worker_until_complete(
-            &db,
-            JobPayload::RawFlow { value: flow.clone(), path: None },
-            port,
-        )
-        .await
-        .result
-        .unwrap();
-
-        assert_eq!(result, serde_json::json!("Hello"), "iteration: {i}");
-    }
+    server.close().await.unwrap();
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_go_job(db: Pool<Postgres>) {
+async fn test_whileloop(db: Pool<Postgres>) {
     initialize_tracing().await;
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
 
-    let content = r#"
-import "fmt"
+    let flow = |stop_condition: &str, max_iterations: Option<u32>| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{
+                "id": "a",
+                "value": {
+                    "type": "whileloopflow",
+                    "stop_condition": stop_condition,
+                    "max_iterations": max_iterations,
+                    "modules": [{
+                        "input_transforms": {
+                            "i": { "type": "javascript", "expr": "flow_input.iter.index" },
+                        },
+                        "value": {
+                            "type": "rawscript",
+                            "language": "deno",
+                            "content": "export function main(i: number) { return i * 10; }",
+                        },
+                    }],
+                },
+            }],
+        }))
+        .unwrap()
+    };
 
-func main(derp string) (string, error) {
-	fmt.Println("Hello, 世界")
-	return fmt.Sprintf("hello %s", derp), nil
-}
-        "#
-    .to_owned();
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow("result >= 30", None), path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(cjob.success);
+    assert_eq!(cjob.result.unwrap(), json!([0, 10, 20, 30]));
 
-    let result = RunJob::from(JobPayload::Code(RawCode {
-        content,
-        path: None,
-        language: ScriptLang::Go,
-    }))
-    .arg("derp", json!("world"))
-    .run_until_complete(&db, port)
+    /* the iteration count is available to the condition */
+    let cjob =
+        RunJob::from(JobPayload::RawFlow { value: flow("iter.index == 1", None), path: None })
+            .run_until_complete(&db, port)
+            .await;
+    assert!(cjob.success);
+    assert_eq!(cjob.result.unwrap(), json!([0, 10]));
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow("false", Some(3)), path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+    assert_eq!(
+        cjob.result.unwrap(),
+        json!({ "error": "Whileloop 'a' ran its maximum of 3 iterations without its stop condition being met" })
+    );
+    let passes = sqlx::query_scalar::<_, i64>(
+        "SELECT count(*) FROM completed_job WHERE parent_job = $1 AND flow_step_id = 'a'",
+    )
+    .bind(cjob.id)
+    .fetch_one(&db)
     .await
-    .result
     .unwrap();
+    assert_eq!(passes, 3);
 
-    assert_eq!(result, serde_json::json!("hello world"));
+    server.close().await.unwrap();
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_python_job(db: Pool<Postgres>) {
+async fn test_assert(db: Pool<Postgres>) {
     initialize_tracing().await;
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
 
-    let content = r#"
-def main():
-    return "hello world"
-        "#
-    .to_owned();
-
-    let job = JobPayload::Code(RawCode { content, path: None, language: ScriptLang::Python3 });
+    let flow = |guard: serde_json::Value| -> FlowValue {
+        let mut module = json!({
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main() { return { count: 1 } }",
+            },
+        });
+        module
+            .as_object_mut()
+            .unwrap()
+            .extend(guard.as_object().unwrap().clone());
+        serde_json::from_value(json!({ "modules": [module] })).unwrap()
+    };
 
-    let result = run_job_in_new_worker_until_complete(&db, job, port)
-        .await
-        .result
-        .unwrap();
+    for (guard, success) in [
+        (
+            json!({ "assert": { "type": "javascript", "expr": "result.count == flow_input.n" } }),
+            true,
+        ),
+        (
+            json!({ "assert": { "type": "javascript", "expr": "result.count > flow_input.n" } }),
+            false,
+        ),
+        (
+            json!({ "assert": { "type": "javascript", "expr": "result.count" } }),
+            true,
+        ),
+        (
+            json!({ "assert": { "type": "javascript", "expr": "result.count - flow_input.n" } }),
+            false,
+        ),
+        (
+            json!({ "result_schema": { "type": "object", "required": ["count"] } }),
+            true,
+        ),
+        (
+            json!({ "result_schema": { "type": "object", "required": ["total"] } }),
+            false,
+        ),
+    ] {
+        let cjob = RunJob::from(JobPayload::RawFlow { value: flow(guard.clone()), path: None })
+            .arg("n", json!(1))
+            .run_until_complete(&db, port)
+            .await;
+        assert_eq!(success, cjob.success, "{guard}");
+    }
 
-    assert_eq!(result, serde_json::json!("hello world"));
+    server.close().await.unwrap();
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_python_job_heavy_dep(db: Pool<Postgres>) {
+async fn test_poll(db: Pool<Postgres>) {
     initialize_tracing().await;
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
 
-    let content = r#"
-import numpy as np
-
-def main():
-    a = np.arange(15).reshape(3, 5)
-    return len(a)
-        "#
-    .to_owned();
+    let flow = |timeout: u32| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main() { return Date.now() }",
+                },
+                "poll": {
+                    "condition": "result >= flow_input.ready_at",
+                    "interval": 1,
+                    "timeout": timeout,
+                },
+            }],
+        }))
+        .unwrap()
+    };
+    let ready_at = chrono::Utc::now().timestamp_millis() + 2000;
 
-    let job = JobPayload::Code(RawCode { content, path: None, language: ScriptLang::Python3 });
+    /* the step is run again until its result is past `ready_at` */
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow(60), path: None })
+        .arg("ready_at", json!(ready_at))
+        .run_until_complete(&db, port)
+        .await;
+    assert!(cjob.success);
+    assert!(cjob.result.unwrap().as_i64().unwrap() >= ready_at);
 
-    let result = run_job_in_new_worker_until_complete(&db, job, port)
-        .await
-        .result
-        .unwrap();
+    /* and fails once the poll times out */
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow(1), path: None })
+        .arg("ready_at", json!(i64::MAX))
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
 
-    assert_eq!(result, serde_json::json!(3));
+    server.close().await.unwrap();
 }
 
-#[sqlx::test(fixtures("base"))]
-async fn test_python_job_with_imports(db: Pool<Postgres>) {
-    initialize_tracing().await;
-    let server = ApiServer::start(db.clone()).await;
-    let port = server.addr.port();
-
-    let content = r#"
-import wmill
-
-def main():
-    return wmill.get_workspace()
-        "#
-    .to_owned();
-
-    let job = JobPayload::Code(RawCode { content, path: None, language: ScriptLang::Python3 });
+fn module_add_item_to_list(i: i32) -> serde_json::Value {
+    json!({
+        "input_transform": {
+            "array": {
+                "type": "javascript",
+                "expr": "previous_result",
+            },
+            "i": {
+                "type": "static",
+                "value": json!(i),
+            }
+        },
+        "value": {
+            "type": "rawscript",
+            "language": "deno",
+            "content": "export function main(array, i){ array.push(i); return array }",
+        }
+    })
+}
 
-    let result = run_job_in_new_worker_until_complete(&db, job, port)
-        .await
-        .result
-        .unwrap();
+/// same as `module_add_item_to_list` with the values of the result of a branchall
+fn module_add_item_to_values(i: i32) -> serde_json::Value {
+    let mut module = module_add_item_to_list(i);
+    module["input_transform"]["array"]["expr"] = json!("Object.values(previous_result)");
+    module
+}
 
-    assert_eq!(result, serde_json::json!("test-workspace"));
+fn module_failure() -> serde_json::Value {
+    json!({
+        "input_transform": {},
+        "value": {
+            "type": "rawscript",
+            "language": "deno",
+            "content": "export function main(){ throw Error('failure') }",
+        }
+    })
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_empty_loop(db: Pool<Postgres>) {
+async fn test_sleep_until(db: Pool<Postgres>) {
     initialize_tracing().await;
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
 
-    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+    let flow: FlowValue = serde_json::from_value(json!({
         "modules": [
             {
+                "id": "a",
                 "value": {
-                    "type": "forloopflow",
-                    "iterator": { "type": "static", "value": [] },
-                    "modules": [
-                        {
-                            "value": {
-                                "input_transform": {
-                                    "n": {
-                                        "type": "javascript",
-                                        "expr": "previous_result.iter.value",
-                                    },
-                                },
-                                "type": "rawscript",
-                                "language": "python3",
-                                "content": "def main(n): return n",
-                            },
-                        }
-                    ],
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(){ return new Date(Date.now() + 3000).toISOString() }",
                 },
+                "sleep": { "type": "javascript", "expr": "result" },
             },
             {
+                "id": "b",
                 "value": {
-                    "input_transform": {
-                        "items": {
-                            "type": "javascript",
-                            "expr": "previous_result",
-                        },
-                    },
                     "type": "rawscript",
-                    "language": "python3",
-                    "content": "def main(items): return sum(items)",
+                    "language": "deno",
+                    "content": "export function main(){ return 1 }",
+                },
+                /* already passed, the next step runs right away */
+                "sleep": { "type": "static", "value": "2000-01-01T00:00:00Z" },
+            },
+            {
+                "id": "c",
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(){ return 2 }",
                 },
             },
         ],
@@ -1579,182 +7214,152 @@ async fn test_empty_loop(db: Pool<Postgres>) {
     .unwrap();
 
     let flow = JobPayload::RawFlow { value: flow, path: None };
-    let result = run_job_in_new_worker_until_complete(&db, flow, port)
-        .await
-        .result
-        .unwrap();
+    let cjob = run_job_in_new_worker_until_complete(&db, flow, port).await;
+    assert_eq!(cjob.result, Some(json!(2)));
 
-    assert_eq!(result, serde_json::json!(0));
+    let steps = sqlx::query_as::<_, (String, chrono::DateTime<chrono::Utc>, serde_json::Value)>(
+        "SELECT flow_step_id, started_at, result FROM completed_job WHERE parent_job = $1 \
+         ORDER BY started_at",
+    )
+    .bind(cjob.id)
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    let until = chrono::DateTime::parse_from_rfc3339(steps[0].2.as_str().unwrap()).unwrap();
+    assert_eq!(steps[1].0, "b");
+    assert!(steps[1].1 >= until);
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_invalid_first_step(db: Pool<Postgres>) {
+async fn test_branchone_simple(db: Pool<Postgres>) {
     initialize_tracing().await;
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
 
-    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+    let flow: FlowValue = serde_json::from_value(json!({
         "modules": [
             {
                 "value": {
-                    "type": "forloopflow",
-                    "iterator": { "type": "javascript", "expr": "flow_input" },
-                    "modules": [
-                        {
-                            "value": {
-                                "type": "identity",
-                            },
-                        }
-                    ],
-                },
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(){ return [1] }",
+                }
             },
             {
                 "value": {
-                    "type": "identity",
-                },
+                    "branches": [],
+                    "default": [module_add_item_to_list(2)],
+                    "type": "branchone",
+                }
             },
         ],
     }))
     .unwrap();
 
     let flow = JobPayload::RawFlow { value: flow, path: None };
-    let job = run_job_in_new_worker_until_complete(&db, flow, port).await;
+    let result = run_job_in_new_worker_until_complete(&db, flow, port)
+        .await
+        .result
+        .unwrap();
 
-    assert_eq!(
-        job.result.unwrap(),
-        serde_json::json!({"error":"Expected an array value, found: {}"})
-    );
+    assert_eq!(result, serde_json::json!([1, 2]));
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_empty_loop_2(db: Pool<Postgres>) {
+async fn test_branchone_label(db: Pool<Postgres>) {
     initialize_tracing().await;
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
 
-    let flow: FlowValue = serde_json::from_value(serde_json::json!({
-        "modules": [
-            {
+    let identity = json!({ "value": { "type": "identity" } });
+    for (n, branch_chosen) in [
+        (1, json!({ "type": "branch", "branch": 0 })),
+        (
+            20,
+            json!({ "type": "branch", "branch": 1, "label": "large" }),
+        ),
+        (300, json!({ "type": "default", "label": "default" })),
+    ] {
+        let flow: FlowValue = serde_json::from_value(json!({
+            "modules": [{
+                "id": "b",
                 "value": {
-                    "type": "forloopflow",
-                    "iterator": { "type": "static", "value": [] },
-                    "modules": [
-                        {
-                            "input_transform": {
-                                "n": {
-                                    "type": "javascript",
-                                    "expr": "previous_result.iter.value",
-                                },
-                            },
-                            "value": {
-                                "type": "rawscript",
-                                "language": "python3",
-                                "content": "def main(n): return n",
-                            },
-                        }
+                    "branches": [
+                        { "expr": "flow_input.n < 10", "modules": [identity.clone()] },
+                        { "label": "large", "expr": "flow_input.n < 100", "modules": [identity.clone()] },
                     ],
+                    "default": [identity.clone()],
+                    "type": "branchone",
                 },
-            },
-        ],
-    }))
-    .unwrap();
-
-    let flow = JobPayload::RawFlow { value: flow, path: None };
-    let result = run_job_in_new_worker_until_complete(&db, flow, port)
-        .await
-        .result
+            }],
+        }))
         .unwrap();
 
-    assert_eq!(result, serde_json::json!([]));
+        let cjob = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+            .arg("n", json!(n))
+            .run_until_complete(&db, port)
+            .await;
+        assert_eq!(
+            cjob.flow_status.unwrap()["modules"][0]["branch_chosen"],
+            branch_chosen
+        );
+    }
+
+    server.close().await.unwrap();
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_step_after_loop(db: Pool<Postgres>) {
+async fn test_branchone_match_all(db: Pool<Postgres>) {
     initialize_tracing().await;
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
-    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+
+    let flow: FlowValue = serde_json::from_value(json!({
         "modules": [
             {
                 "value": {
-                    "type": "forloopflow",
-                    "iterator": { "type": "static", "value": [2,3,4] },
-                    "modules": [
-                        {
-                            "input_transform": {
-                                "n": {
-                                    "type": "javascript",
-                                    "expr": "previous_result.iter.value",
-                                },
-                            },
-                            "value": {
-                                "type": "rawscript",
-                                "language": "python3",
-                                "content": "def main(n): return n",
-                            } ,
-                        }
-                    ],
-                },
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(){ return [1] }",
+                }
             },
             {
-                "input_transform": {
-                    "items": {
-                        "type": "javascript",
-                        "expr": "previous_result",
-                    },
-                },
+                "id": "b",
                 "value": {
-                    "type": "rawscript",
-                    "language": "python3",
-                    "content": "def main(items): return sum(items)",
-                },
+                    "branches": [
+                        { "expr": "true", "modules": [module_add_item_to_list(2)] },
+                        { "expr": "false", "modules": [module_add_item_to_list(3)] },
+                        { "expr": "previous_result.length == 1", "modules": [module_add_item_to_list(4)] },
+                    ],
+                    "default": [module_add_item_to_list(5)],
+                    "match_all": true,
+                    "type": "branchone",
+                }
             },
         ],
     }))
     .unwrap();
 
     let flow = JobPayload::RawFlow { value: flow, path: None };
-    let result = run_job_in_new_worker_until_complete(&db, flow, port)
-        .await
-        .result
-        .unwrap();
-
-    assert_eq!(result, serde_json::json!(9));
-}
-
-fn module_add_item_to_list(i: i32) -> serde_json::Value {
-    json!({
-        "input_transform": {
-            "array": {
-                "type": "javascript",
-                "expr": "previous_result",
-            },
-            "i": {
-                "type": "static",
-                "value": json!(i),
-            }
-        },
-        "value": {
-            "type": "rawscript",
-            "language": "deno",
-            "content": "export function main(array, i){ array.push(i); return array }",
-        }
-    })
-}
+    let cjob = run_job_in_new_worker_until_complete(&db, flow, port).await;
 
-fn module_failure() -> serde_json::Value {
-    json!({
-        "input_transform": {},
-        "value": {
-            "type": "rawscript",
-            "language": "deno",
-            "content": "export function main(){ throw Error('failure') }",
-        }
-    })
+    assert_eq!(
+        cjob.result.unwrap(),
+        json!({
+            "results": { "0": [1, 2], "2": [1, 4] },
+            "matched": [0, 2],
+            "skipped": [1],
+        })
+    );
+    let status = cjob.flow_status.unwrap();
+    assert_eq!(
+        status["modules"][1]["branch_chosen"],
+        json!({ "type": "branches", "branches": [0, 2] })
+    );
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_branchone_simple(db: Pool<Postgres>) {
+async fn test_branchall_simple(db: Pool<Postgres>) {
     initialize_tracing().await;
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
@@ -1770,9 +7375,20 @@ async fn test_branchone_simple(db: Pool<Postgres>) {
             },
             {
                 "value": {
-                    "branches": [],
-                    "default": [module_add_item_to_list(2)],
-                    "type": "branchone",
+                    "branches": [
+                        {"modules": [module_add_item_to_list(2)], "label": "two"},
+                        {"modules": [{
+                            "input_transform": {
+                                "branch": { "type": "javascript", "expr": "flow_input.branch" },
+                            },
+                            "value": {
+                                "type": "rawscript",
+                                "language": "deno",
+                                "content": "export function main(branch){ return branch }",
+                            }
+                        }]},
+                        {"modules": [module_add_item_to_list(3)]}],
+                    "type": "branchall",
                 }
             },
         ],
@@ -1785,11 +7401,71 @@ async fn test_branchone_simple(db: Pool<Postgres>) {
         .result
         .unwrap();
 
-    assert_eq!(result, serde_json::json!([1, 2]));
+    /* the results of the branches are keyed by their label, or their index */
+    assert_eq!(
+        result,
+        serde_json::json!({
+            "two": [1, 2],
+            "1": { "index": 1, "label": null },
+            "2": [1, 3],
+        })
+    );
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{ "value": { "branches": [], "type": "branchall" } }],
+    }))
+    .unwrap();
+
+    let flow = JobPayload::RawFlow { value: flow, path: None };
+    let result = run_job_in_new_worker_until_complete(&db, flow, port)
+        .await
+        .result
+        .unwrap();
+
+    assert_eq!(result, serde_json::json!({}));
+
+    /* a label colliding with the index of an unlabeled branch is refused */
+    let tx = db.begin().await.unwrap();
+    let (tx, token) = windmill_worker::create_token_for_owner(
+        tx,
+        "test-workspace",
+        "u/test-user",
+        "",
+        100,
+        "",
+        None,
+    )
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://localhost:{port}/api/w/test-workspace/jobs/run/preview_flow"
+        ))
+        .bearer_auth(&token)
+        .json(&json!({
+            "value": {
+                "modules": [{
+                    "id": "a",
+                    "value": {
+                        "branches": [
+                            {"modules": [module_add_item_to_list(2)]},
+                            {"modules": [module_add_item_to_list(3)], "label": "0"}],
+                        "type": "branchall",
+                    }
+                }],
+            },
+            "args": {},
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert!(response.text().await.unwrap().contains("a: `0`"));
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_branchall_simple(db: Pool<Postgres>) {
+async fn test_branchall_stop_at_first_success(db: Pool<Postgres>) {
     initialize_tracing().await;
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
@@ -1804,11 +7480,14 @@ async fn test_branchall_simple(db: Pool<Postgres>) {
                 }
             },
             {
+                "id": "b",
                 "value": {
                     "branches": [
+                        {"modules": [module_failure()]},
                         {"modules": [module_add_item_to_list(2)]},
                         {"modules": [module_add_item_to_list(3)]}],
                     "type": "branchall",
+                    "stop_at_first_success": true,
                 }
             },
         ],
@@ -1816,12 +7495,34 @@ async fn test_branchall_simple(db: Pool<Postgres>) {
     .unwrap();
 
     let flow = JobPayload::RawFlow { value: flow, path: None };
-    let result = run_job_in_new_worker_until_complete(&db, flow, port)
-        .await
-        .result
-        .unwrap();
+    let cjob = run_job_in_new_worker_until_complete(&db, flow, port).await;
+    assert_eq!(cjob.result, Some(serde_json::json!([1, 2])));
+    assert!(matches!(
+        get_module(&cjob, "b"),
+        Some(FlowStatusModule::Success {
+            branch_chosen: Some(BranchChosen::Branch { branch: 1, .. }),
+            ..
+        })
+    ));
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [
+            {
+                "value": {
+                    "branches": [
+                        {"modules": [module_failure()]},
+                        {"modules": [module_failure()]}],
+                    "type": "branchall",
+                    "stop_at_first_success": true,
+                }
+            },
+        ],
+    }))
+    .unwrap();
 
-    assert_eq!(result, serde_json::json!([[1, 2], [1, 3]]));
+    let flow = JobPayload::RawFlow { value: flow, path: None };
+    let cjob = run_job_in_new_worker_until_complete(&db, flow, port).await;
+    assert!(!cjob.success);
 }
 
 #[sqlx::test(fixtures("base"))]
@@ -1859,7 +7560,12 @@ async fn test_branchall_skip_failure(db: Pool<Postgres>) {
 
     assert_eq!(
         result,
-        serde_json::json!({"error": "Error during execution of the script:\n\nerror: Uncaught (in promise) Error: failure\nexport function main(){ throw Error('failure') }\n                              ^\n    at main (file:///tmp/inner.ts:1:31)\n    at run (file:///tmp/main.ts:9:26)\n    at file:///tmp/main.ts:14:1"})
+        serde_json::json!({
+            "error": "Error during execution of the script:\n\nerror: Uncaught (in promise) Error: failure\nexport function main(){ throw Error('failure') }\n                              ^\n    at main (file:///tmp/inner.ts:1:31)\n    at run (file:///tmp/main.ts:9:26)\n    at file:///tmp/main.ts:14:1",
+            "name": "Error",
+            "message": "failure",
+            "stack": "at main (file:///tmp/inner.ts:1:31)\nat run (file:///tmp/main.ts:9:26)\nat file:///tmp/main.ts:14:1",
+        })
     );
 
     let flow: FlowValue = serde_json::from_value(json!({
@@ -1892,10 +7598,95 @@ async fn test_branchall_skip_failure(db: Pool<Postgres>) {
 
     assert_eq!(
         result,
-        serde_json::json!([{"error": "Error during execution of the script:\n\nerror: Uncaught (in promise) Error: failure\nexport function main(){ throw Error('failure') }\n                              ^\n    at main (file:///tmp/inner.ts:1:31)\n    at run (file:///tmp/main.ts:9:26)\n    at file:///tmp/main.ts:14:1"}, [1, 2]])
+        serde_json::json!({
+            "0": {
+                "error": "Error during execution of the script:\n\nerror: Uncaught (in promise) Error: failure\nexport function main(){ throw Error('failure') }\n                              ^\n    at main (file:///tmp/inner.ts:1:31)\n    at run (file:///tmp/main.ts:9:26)\n    at file:///tmp/main.ts:14:1",
+                "name": "Error",
+                "message": "failure",
+                "stack": "at main (file:///tmp/inner.ts:1:31)\nat run (file:///tmp/main.ts:9:26)\nat file:///tmp/main.ts:14:1",
+            },
+            "1": [1, 2],
+        })
     );
 }
 
+#[sqlx::test(fixtures("base"))]
+async fn test_branchall_parallel(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow = |skip_failure: bool| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [
+                {
+                    "value": {
+                        "type": "rawscript",
+                        "language": "deno",
+                        "content": "export function main(){ return [1] }",
+                    }
+                },
+                {
+                    "id": "b",
+                    "value": {
+                        "branches": [
+                            {"modules": [module_add_item_to_list(2)]},
+                            {"modules": [module_failure()], "skip_failure": skip_failure},
+                            {"modules": [module_add_item_to_list(3)]}
+                        ],
+                        "type": "branchall",
+                        "parallel": true,
+                    }
+                },
+            ],
+        }))
+        .unwrap()
+    };
+
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow(true), path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(cjob.success);
+    let result = cjob.result.unwrap();
+    assert_eq!(result["0"], json!([1, 2]));
+    assert!(result["1"]["error"].as_str().unwrap().contains("failure"));
+    assert_eq!(result["2"], json!([1, 3]));
+
+    /* the jobs of all the branches are pushed before any of them starts */
+    let branches =
+        sqlx::query_as::<_, (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>(
+            "SELECT created_at, started_at FROM completed_job
+          WHERE parent_job = $1 AND flow_step_id = 'b'
+          ORDER BY created_at",
+        )
+        .bind(cjob.id)
+        .fetch_all(&db)
+        .await
+        .unwrap();
+    assert_eq!(branches.len(), 3);
+    assert!(branches[2].0 <= branches[0].1, "{branches:?}");
+
+    /* a failure that isn't skipped fails the step once all the branches completed */
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow(false), path: None })
+        .run_until_complete(&db, port)
+        .await;
+    assert!(!cjob.success);
+    assert!(cjob.result.unwrap()["error"]
+        .as_str()
+        .unwrap()
+        .contains("failure"));
+    let branches = sqlx::query_scalar::<_, i64>(
+        "SELECT count(*) FROM completed_job WHERE parent_job = $1 AND flow_step_id = 'b'",
+    )
+    .bind(cjob.id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(branches, 3);
+
+    server.close().await.unwrap();
+}
+
 #[sqlx::test(fixtures("base"))]
 async fn test_branchone_nested(db: Pool<Postgres>) {
     initialize_tracing().await;
@@ -1981,14 +7772,15 @@ async fn test_branchall_nested(db: Pool<Postgres>) {
                             }, {
                                 "value": {
                                     "branches": [
-                                        {"modules": [module_add_item_to_list(4)]},
-                                        {"modules": [module_add_item_to_list(5)]}],
+                                        {"modules": [module_add_item_to_values(4)]},
+                                        {"modules": [module_add_item_to_values(5)]}],
                                     "type": "branchall",
                                 }
                             }
-                                    ]
+                                    ],
+                            "label": "nested"
                         },
-                        {"modules": [module_add_item_to_list(6)]}],
+                        {"modules": [module_add_item_to_list(6)], "label": "flat"}],
                     "type": "branchall",
                 }
             },
@@ -2004,7 +7796,10 @@ async fn test_branchall_nested(db: Pool<Postgres>) {
 
     assert_eq!(
         result,
-        serde_json::json!([[[[1, 2], [1, 3], 4], [[1, 2], [1, 3], 5]], [1, 6]])
+        serde_json::json!({
+            "nested": { "0": [[1, 2], [1, 3], 4], "1": [[1, 2], [1, 3], 5] },
+            "flat": [1, 6],
+        })
     );
 }
 
@@ -2099,6 +7894,153 @@ async fn test_failure_module(db: Pool<Postgres>) {
     assert_eq!(json!({ "l": [0, 1, 2] }), result);
 }
 
+#[sqlx::test(fixtures("base"))]
+async fn test_failure_module_step_error(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow = |language: &str, content: &str| -> FlowValue {
+        serde_json::from_value(json!({
+            "modules": [{
+                "id": "a",
+                "value": { "type": "rawscript", "language": language, "content": content },
+            }],
+            "failure_module": {
+                "input_transforms": {
+                    "error": { "type": "javascript", "expr": "previous_result" },
+                },
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(error) { return { name: error.name, message: error.message, step_id: error.step_id, stack: !!error.stack } }",
+                },
+            },
+        }))
+        .unwrap()
+    };
+
+    let result = RunJob::from(JobPayload::RawFlow {
+        value: flow(
+            "deno",
+            "export function main() { throw new TypeError('bad input') }",
+        ),
+        path: None,
+    })
+    .run_until_complete(&db, port)
+    .await
+    .result
+    .unwrap();
+    assert_eq!(
+        json!({ "name": "TypeError", "message": "bad input", "step_id": "a", "stack": true }),
+        result
+    );
+
+    let result = RunJob::from(JobPayload::RawFlow {
+        value: flow("python3", "def main():\n    raise KeyError('x')"),
+        path: None,
+    })
+    .run_until_complete(&db, port)
+    .await
+    .result
+    .unwrap();
+    assert_eq!(
+        json!({ "name": "KeyError", "message": "'x'", "step_id": "a", "stack": true }),
+        result
+    );
+
+    server.close().await.unwrap();
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_catch(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "id": "a",
+            "input_transforms": {
+                "n": { "type": "javascript", "expr": "flow_input.n" },
+            },
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main(n) { if (n > 0) throw Error(`step ${n}`); return 'ok' }",
+            },
+            "catch": [{
+                "input_transforms": {
+                    "e": { "type": "javascript", "expr": "previous_result.error" },
+                    "n": { "type": "javascript", "expr": "flow_input.n" },
+                },
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(e, n) { if (n > 1) throw Error('catch'); return { caught: e.includes(`step ${n}`) } }",
+                },
+            }],
+        }, {
+            "input_transforms": {
+                "x": { "type": "javascript", "expr": "previous_result" },
+            },
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main(x) { return { next: x } }",
+            },
+        }],
+        "failure_module": {
+            "input_transforms": {
+                "error": { "type": "javascript", "expr": "previous_result" },
+            },
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main(error) { return { 'from failure module': error } }",
+            },
+        },
+    }))
+    .unwrap();
+
+    let result = RunJob::from(JobPayload::RawFlow { value: flow.clone(), path: None })
+        .arg("n", json!(0))
+        .run_until_complete(&db, port)
+        .await
+        .result
+        .unwrap();
+    assert_eq!(result, json!({ "next": "ok" }));
+
+    /* the result of the catch modules goes on to the next step */
+    let cjob = RunJob::from(JobPayload::RawFlow { value: flow.clone(), path: None })
+        .arg("n", json!(1))
+        .run_until_complete(&db, port)
+        .await;
+    assert!(cjob.success);
+    assert_eq!(
+        cjob.result.clone().unwrap(),
+        json!({ "next": { "caught": true } })
+    );
+    assert!(matches!(
+        get_module(&cjob, "a"),
+        Some(FlowStatusModule::Success { warning: Some(_), .. })
+    ));
+
+    /* the failure module runs when the catch modules fail too */
+    let result = RunJob::from(JobPayload::RawFlow { value: flow.clone(), path: None })
+        .arg("n", json!(2))
+        .run_until_complete(&db, port)
+        .await
+        .result
+        .unwrap();
+    assert!(result["from failure module"]["error"]
+        .as_str()
+        .unwrap()
+        .contains("catch"));
+
+    server.close().await.unwrap();
+}
+
 // #[cfg(test)]
 // mod client_test {
 //     use windmill_common::error::to_anyhow;
@@ -7,6 +7,7 @@
  */
 
 use reqwest::Client;
+use serde::Deserialize;
 use sql_builder::prelude::*;
 
 use axum::{
@@ -19,12 +20,15 @@ use sqlx::{Postgres, Transaction};
 use windmill_audit::{audit_log, ActionKind};
 use windmill_common::{
     error::{self, to_anyhow, Error, JsonResult, Result},
-    flows::{Flow, ListFlowQuery, NewFlow},
+    fan_out::{estimate_fan_out, FanOutEstimate},
+    flows::{Flow, FlowValue, ListFlowQuery, NewFlow, SchemaIncompatibility},
     utils::{
         http_get_from_hub, list_elems_from_hub, not_found_if_none, paginate, Pagination, StripPath,
     },
 };
 
+use windmill_worker::JsFanOutEval;
+
 use crate::{
     db::{UserDB, DB},
     users::Authed,
@@ -38,6 +42,8 @@ pub fn workspaced_service() -> Router {
         .route("/archive/*path", post(archive_flow_by_path))
         .route("/get/*path", get(get_flow_by_path))
         .route("/exists/*path", get(exists_flow_by_path))
+        .route("/check_schemas", post(check_schemas))
+        .route("/estimate_fan_out", post(estimate_flow_fan_out))
 }
 
 pub fn global_service() -> Router {
@@ -131,13 +137,110 @@ pub async fn get_hub_flow_by_id(
     Ok(Json(value))
 }
 
+#[derive(Deserialize)]
+struct CheckSchemasQuery {
+    check_schemas: Option<bool>,
+}
+
+async fn check_schemas(Json(value): Json<FlowValue>) -> JsonResult<Vec<SchemaIncompatibility>> {
+    Ok(Json(value.schema_incompatibilities()))
+}
+
+#[derive(Deserialize)]
+struct EstimateFanOut {
+    path: Option<String>,
+    value: Option<FlowValue>,
+    args: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+async fn estimate_flow_fan_out(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path(w_id): Path<String>,
+    Json(EstimateFanOut { path, value, args }): Json<EstimateFanOut>,
+) -> JsonResult<FanOutEstimate> {
+    let value = match (path, value) {
+        (Some(path), None) => {
+            let mut tx = user_db.begin(&authed).await?;
+            let value_o = sqlx::query_scalar!(
+                "SELECT value FROM flow WHERE path = $1 AND (workspace_id = $2 OR workspace_id = \
+                 'starter')",
+                &path,
+                &w_id
+            )
+            .fetch_optional(&mut tx)
+            .await?;
+            tx.commit().await?;
+            let value = not_found_if_none(value_o, "Flow", &path)?;
+            serde_json::from_value::<FlowValue>(value)
+                .map_err(|e| Error::InternalErr(format!("invalid flow value at {path}: {e}")))?
+        }
+        (None, Some(value)) => value,
+        _ => Err(Error::BadRequest(
+            "exactly one of path and value must be given".to_string(),
+        ))?,
+    };
+
+    let flow_input = serde_json::Value::Object(args.unwrap_or_default());
+    Ok(Json(
+        estimate_fan_out(&value, &flow_input, &JsFanOutEval).await,
+    ))
+}
+
+/// refuses to save a flow whose steps declare incompatible schemas when `check_schemas` is set
+fn check_flow_schemas(nf: &NewFlow, query: CheckSchemasQuery) -> Result<()> {
+    if !query.check_schemas.unwrap_or(false) {
+        return Ok(());
+    }
+    let value = serde_json::from_value::<FlowValue>(nf.value.clone())
+        .map_err(|e| Error::BadRequest(format!("invalid flow value: {e}")))?;
+    let incompatibilities = value.schema_incompatibilities();
+    if incompatibilities.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(format!(
+            "incompatible step schemas:\n{}",
+            incompatibilities
+                .iter()
+                .map(|i| format!(
+                    "{} -> {}: `{}` {}",
+                    i.previous_module, i.module, i.property, i.reason
+                ))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )))
+    }
+}
+
+/// refuses a flow with a branchall whose branches would have their results keyed the same
+pub(crate) fn check_branch_keys(value: &FlowValue) -> Result<()> {
+    let collisions = value.branch_key_collisions();
+    if collisions.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(format!(
+            "branches keyed the same:\n{}",
+            collisions
+                .iter()
+                .map(|(module, key)| format!("{module}: `{key}`"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )))
+    }
+}
+
 async fn create_flow(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Path(w_id): Path<String>,
+    Query(query): Query<CheckSchemasQuery>,
     Json(nf): Json<NewFlow>,
 ) -> Result<String> {
     // cron::Schedule::from_str(&ns.schedule).map_err(|e| error::Error::BadRequest(e.to_string()))?;
+    check_flow_schemas(&nf, query)?;
+    if let Ok(value) = serde_json::from_value::<FlowValue>(nf.value.clone()) {
+        check_branch_keys(&value)?;
+    }
     let mut tx = user_db.begin(&authed).await?;
 
     check_schedule_conflict(&mut tx, &w_id, &nf.path).await?;
@@ -203,8 +306,13 @@ async fn update_flow(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Path((w_id, flow_path)): Path<(String, StripPath)>,
+    Query(query): Query<CheckSchemasQuery>,
     Json(nf): Json<NewFlow>,
 ) -> Result<String> {
+    check_flow_schemas(&nf, query)?;
+    if let Ok(value) = serde_json::from_value::<FlowValue>(nf.value.clone()) {
+        check_branch_keys(&value)?;
+    }
     let mut tx = user_db.begin(&authed).await?;
 
     let flow_path = flow_path.to_path();
@@ -324,9 +432,10 @@ mod tests {
     use std::{collections::HashMap, time::Duration};
 
     use windmill_common::{
+        flow_status::MAX_RETRY_ATTEMPTS,
         flows::{
-            ConstantDelay, ExponentialDelay, FlowModule, FlowModuleValue, FlowValue,
-            InputTransform, Retry, StopAfterIf,
+            ConstantDelay, CronIterator, ExponentialDelay, FlowModule, FlowModuleValue, FlowValue,
+            InputMerge, InputTransform, LoopIterator, Retry, ScheduleDelay, StopAfterIf,
         },
         scripts,
     };
@@ -342,6 +451,7 @@ mod tests {
                     input_transforms: [].into(),
                     value: FlowModuleValue::Script {
                         path: "test".to_string(),
+                        hash: None,
                         input_transforms: [(
                             "test".to_string(),
                             InputTransform::Static { value: serde_json::json!("test2") },
@@ -353,6 +463,24 @@ mod tests {
                     suspend: Default::default(),
                     retry: None,
                     sleep: None,
+                    input_schema: None,
+                    output_schema: None,
+                    partial_result: None,
+                    max_queue_wait: None,
+                    timeout: None,
+                    group: None,
+                    compensation: None,
+                    catch: vec![],
+                    run_on_cancel: false,
+                    run_on_timeout: false,
+                    input_merge: Default::default(),
+                    poll: None,
+                    assert: None,
+                    result_schema: None,
+                    depends_on: vec![],
+                    same_worker: None,
+                    cache_ttl: None,
+                    skip_if: None,
                 },
                 FlowModule {
                     id: "b".to_string(),
@@ -371,14 +499,39 @@ mod tests {
                     suspend: Default::default(),
                     retry: None,
                     sleep: None,
+                    input_schema: None,
+                    output_schema: None,
+                    partial_result: None,
+                    max_queue_wait: None,
+                    timeout: None,
+                    group: None,
+                    compensation: None,
+                    catch: vec![],
+                    run_on_cancel: false,
+                    run_on_timeout: false,
+                    input_merge: Default::default(),
+                    poll: None,
+                    assert: None,
+                    result_schema: None,
+                    depends_on: vec![],
+                    same_worker: None,
+                    cache_ttl: None,
+                    skip_if: None,
                 },
                 FlowModule {
                     id: "c".to_string(),
                     input_transforms: HashMap::new(),
                     value: FlowModuleValue::ForloopFlow {
-                        iterator: InputTransform::Static { value: serde_json::json!([1, 2, 3]) },
+                        iterator: InputTransform::Static { value: serde_json::json!([1, 2, 3]) }
+                            .into(),
                         modules: vec![],
                         skip_failures: true,
+                        max_failures: None,
+                        order_by: None,
+                        error_if_empty: false,
+                        min_iteration_interval: None,
+                        parallelism: None,
+                        break_if: None,
                     },
                     stop_after_if: Some(StopAfterIf {
                         expr: "previous.isEmpty()".to_string(),
@@ -388,6 +541,24 @@ mod tests {
                     suspend: Default::default(),
                     retry: None,
                     sleep: None,
+                    input_schema: None,
+                    output_schema: None,
+                    partial_result: None,
+                    max_queue_wait: None,
+                    timeout: None,
+                    group: None,
+                    compensation: None,
+                    catch: vec![],
+                    run_on_cancel: false,
+                    run_on_timeout: false,
+                    input_merge: Default::default(),
+                    poll: None,
+                    assert: None,
+                    result_schema: None,
+                    depends_on: vec![],
+                    same_worker: None,
+                    cache_ttl: None,
+                    skip_if: None,
                 },
             ],
             failure_module: Some(FlowModule {
@@ -395,6 +566,7 @@ mod tests {
                 input_transforms: HashMap::new(),
                 value: FlowModuleValue::Script {
                     path: "test".to_string(),
+                    hash: None,
                     input_transforms: HashMap::new(),
                 },
                 stop_after_if: Some(StopAfterIf {
@@ -405,8 +577,32 @@ mod tests {
                 suspend: Default::default(),
                 retry: None,
                 sleep: None,
+                input_schema: None,
+                output_schema: None,
+                partial_result: None,
+                max_queue_wait: None,
+                timeout: None,
+                group: None,
+                compensation: None,
+                catch: vec![],
+                run_on_cancel: false,
+                run_on_timeout: false,
+                input_merge: Default::default(),
+                poll: None,
+                assert: None,
+                result_schema: None,
+                depends_on: vec![],
+                same_worker: None,
+                cache_ttl: None,
+                skip_if: None,
             }),
-            same_worker: false,
+            same_worker: None,
+            resolve_resources: false,
+            output_transform: None,
+            dag: false,
+            timeout: None,
+            log_iterations: false,
+            on_cancel: None,
         };
         let expect = serde_json::json!({
           "modules": [
@@ -508,6 +704,102 @@ mod tests {
         );
     }
 
+    #[test]
+    fn cron_iterator_serde() {
+        let s = r#"
+        {
+            "type": "forloopflow",
+            "modules": [],
+            "iterator": {
+                "type": "cron",
+                "schedule": "0 0 9 * * *",
+                "start": { "type": "javascript", "expr": "flow_input.start" },
+                "end": { "type": "static", "value": "2022-11-01T00:00:00Z" }
+            }
+        }
+        "#;
+        let value: FlowModuleValue = serde_json::from_str(s).unwrap();
+        let iterator = match &value {
+            FlowModuleValue::ForloopFlow { iterator, .. } => iterator,
+            _ => panic!("expected a forloop, got {value:?}"),
+        };
+        assert!(matches!(
+            iterator,
+            LoopIterator::Cron(CronIterator { schedule, start: InputTransform::Javascript { .. }, .. })
+                if schedule == "0 0 9 * * *"
+        ));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(s).unwrap()["iterator"],
+            serde_json::json!(iterator)
+        );
+    }
+
+    #[test]
+    fn schema_incompatibilities() {
+        let module = |id: &str, input_schema, output_schema| FlowModule {
+            id: id.to_string(),
+            input_transforms: HashMap::new(),
+            value: FlowModuleValue::Identity { expr: None },
+            stop_after_if: None,
+            summary: None,
+            suspend: None,
+            retry: None,
+            sleep: None,
+            input_schema,
+            output_schema,
+            partial_result: None,
+            max_queue_wait: None,
+            timeout: None,
+            group: None,
+            compensation: None,
+            catch: vec![],
+            run_on_cancel: false,
+            run_on_timeout: false,
+            input_merge: Default::default(),
+            poll: None,
+            assert: None,
+            result_schema: None,
+            depends_on: vec![],
+            same_worker: None,
+            cache_ttl: None,
+            skip_if: None,
+        };
+        let fv = FlowValue {
+            modules: vec![
+                module(
+                    "a",
+                    None,
+                    Some(serde_json::json!({
+                        "type": "object",
+                        "properties": { "name": { "type": "string" }, "count": { "type": "string" } }
+                    })),
+                ),
+                module(
+                    "b",
+                    Some(serde_json::json!({
+                        "type": "object",
+                        "properties": { "name": { "type": "string" }, "count": { "type": "integer" } },
+                        "required": ["name", "count", "email"]
+                    })),
+                    None,
+                ),
+                module("c", Some(serde_json::json!({ "required": ["foo"] })), None),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            fv.schema_incompatibilities()
+                .into_iter()
+                .map(|i| (i.previous_module, i.module, i.property))
+                .collect::<Vec<_>>(),
+            vec![
+                ("a".to_string(), "b".to_string(), "count".to_string()),
+                ("a".to_string(), "b".to_string(), "email".to_string()),
+            ]
+        );
+    }
+
     #[test]
     fn retry_serde() {
         assert_eq!(Retry::default(), serde_json::from_str(r#"{}"#).unwrap());
@@ -533,7 +825,15 @@ mod tests {
         assert_eq!(
             Retry {
                 constant: Default::default(),
-                exponential: ExponentialDelay { attempts: 0, multiplier: 1, seconds: 123 }
+                exponential: ExponentialDelay {
+                    attempts: 0,
+                    multiplier: 1,
+                    seconds: 123,
+                    random_factor: None
+                },
+                schedule: None,
+                min_seconds_since_first_failure: None,
+                retry_if: None,
             },
             serde_json::from_str(
                 r#"
@@ -551,7 +851,15 @@ mod tests {
     fn retry_exponential() {
         let retry = Retry {
             constant: ConstantDelay::default(),
-            exponential: ExponentialDelay { attempts: 3, multiplier: 4, seconds: 3 },
+            exponential: ExponentialDelay {
+                attempts: 3,
+                multiplier: 4,
+                seconds: 3,
+                random_factor: None,
+            },
+            schedule: None,
+            min_seconds_since_first_failure: None,
+            retry_if: None,
         };
         assert_eq!(
             vec![
@@ -568,11 +876,50 @@ mod tests {
         assert_eq!(Some(108 * SECOND), retry.max_interval());
     }
 
+    #[test]
+    fn retry_exponential_jitter() {
+        let retry = Retry {
+            constant: ConstantDelay { attempts: 1, seconds: 10 },
+            exponential: ExponentialDelay {
+                attempts: 2,
+                multiplier: 1,
+                seconds: 10,
+                random_factor: Some(50),
+            },
+            schedule: None,
+            min_seconds_since_first_failure: None,
+            retry_if: None,
+        };
+        for _ in 0..100 {
+            /* the constant retries have no jitter */
+            assert_eq!(10 * SECOND, retry.jitter(0, 10 * SECOND));
+            let interval = retry.jitter(2, 1000 * SECOND);
+            assert!(
+                (1000 * SECOND..=1500 * SECOND).contains(&interval),
+                "{interval:?}"
+            );
+        }
+
+        let retry = Retry {
+            exponential: ExponentialDelay { random_factor: None, ..retry.exponential },
+            ..retry
+        };
+        assert_eq!(1000 * SECOND, retry.jitter(2, 1000 * SECOND));
+    }
+
     #[test]
     fn retry_both() {
         let retry = Retry {
             constant: ConstantDelay { attempts: 2, seconds: 4 },
-            exponential: ExponentialDelay { attempts: 2, multiplier: 1, seconds: 3 },
+            exponential: ExponentialDelay {
+                attempts: 2,
+                multiplier: 1,
+                seconds: 3,
+                random_factor: None,
+            },
+            schedule: None,
+            min_seconds_since_first_failure: None,
+            retry_if: None,
         };
         assert_eq!(
             vec![
@@ -589,4 +936,117 @@ mod tests {
 
         assert_eq!(Some(81 * SECOND), retry.max_interval());
     }
+
+    #[test]
+    fn retry_min_seconds_since_first_failure() {
+        let retry = Retry {
+            constant: ConstantDelay { attempts: 3, seconds: 2 },
+            min_seconds_since_first_failure: Some(10),
+            ..Default::default()
+        };
+        /* every attempt failing right away waits for the floor, measured from the first failure */
+        assert_eq!(
+            vec![Some(10 * SECOND), Some(8 * SECOND), Some(2 * SECOND), None],
+            [(0, 0), (1, 2), (2, 12), (3, 12)]
+                .into_iter()
+                .map(|(previous_attempts, elapsed)| {
+                    retry.interval_since_first_failure(previous_attempts, elapsed * SECOND)
+                })
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(Some(10 * SECOND), retry.max_interval());
+    }
+
+    #[test]
+    fn input_merge_collisions() {
+        let args = || serde_json::json!({ "iter": { "index": 0 }, "a": 1 });
+        let injected = || {
+            serde_json::json!({ "iter": { "index": 1 }, "a": 1, "b": 2 })
+                .as_object()
+                .unwrap()
+                .clone()
+        };
+
+        let mut merged = args().as_object().unwrap().clone();
+        let collisions = InputMerge::Injected.merge(&mut merged, injected()).unwrap();
+        assert_eq!(vec!["iter".to_string()], collisions);
+        assert_eq!(
+            serde_json::json!({ "iter": { "index": 1 }, "a": 1, "b": 2 }),
+            serde_json::json!(merged)
+        );
+
+        let mut merged = args().as_object().unwrap().clone();
+        let collisions = InputMerge::Existing.merge(&mut merged, injected()).unwrap();
+        assert_eq!(vec!["iter".to_string()], collisions);
+        assert_eq!(
+            serde_json::json!({ "iter": { "index": 0 }, "a": 1, "b": 2 }),
+            serde_json::json!(merged)
+        );
+
+        let mut merged = args().as_object().unwrap().clone();
+        assert!(InputMerge::Error.merge(&mut merged, injected()).is_err());
+
+        /* the same value isn't a collision */
+        let mut merged = args().as_object().unwrap().clone();
+        assert!(InputMerge::Error
+            .merge(&mut merged, args().as_object().unwrap().clone())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn retry_schedule() {
+        let retry = Retry {
+            constant: ConstantDelay { attempts: 2, seconds: 4 },
+            exponential: ExponentialDelay::default(),
+            schedule: Some(ScheduleDelay { intervals: vec![5, 30, 120, 10], repeat_last: false }),
+            min_seconds_since_first_failure: None,
+            retry_if: None,
+        };
+        assert_eq!(
+            vec![
+                Some(5 * SECOND),
+                Some(30 * SECOND),
+                Some(120 * SECOND),
+                Some(10 * SECOND),
+                None,
+            ],
+            (0..5)
+                .map(|previous_attempts| retry.interval(previous_attempts))
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(4, retry.max_attempts());
+        assert_eq!(Some(120 * SECOND), retry.max_interval());
+    }
+
+    #[test]
+    fn retry_schedule_repeat_last() {
+        let retry = Retry {
+            schedule: Some(ScheduleDelay { intervals: vec![5, 30], repeat_last: true }),
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![
+                Some(5 * SECOND),
+                Some(30 * SECOND),
+                Some(30 * SECOND),
+                Some(30 * SECOND)
+            ],
+            (0..4)
+                .map(|previous_attempts| retry.interval(previous_attempts))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(30 * SECOND), retry.interval(MAX_RETRY_ATTEMPTS - 1));
+        assert_eq!(None, retry.interval(MAX_RETRY_ATTEMPTS));
+        assert_eq!(MAX_RETRY_ATTEMPTS, retry.max_attempts());
+
+        let retry = Retry {
+            schedule: Some(ScheduleDelay { intervals: vec![], repeat_last: true }),
+            ..Default::default()
+        };
+        assert_eq!(None, retry.interval(0));
+        assert!(!retry.has_attempts());
+    }
 }
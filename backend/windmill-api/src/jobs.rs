@@ -6,34 +6,52 @@
  * LICENSE-AGPL for a copy of the license.
  */
 
+use std::{collections::HashMap, sync::Arc};
+
 use anyhow::Context;
 use axum::{
+    body::Bytes,
     extract::{FromRequest, Path, Query},
-    response::{IntoResponse, Response},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
     Extension, Json, Router,
 };
+use futures::{future, stream, Stream, StreamExt};
 use hmac::Mac;
-use hyper::StatusCode;
+use hyper::{header, StatusCode};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sql_builder::{prelude::*, quote, SqlBuilder};
-use sqlx::{query_scalar, types::Uuid, Postgres, Transaction};
+use sqlx::{postgres::PgListener, query_scalar, types::Uuid, Postgres, Transaction};
+use tokio::sync::broadcast;
 use windmill_audit::{audit_log, ActionKind};
 use windmill_common::{
+    blobs::{blob_ttl_secs, BlobRef},
     error::{self, to_anyhow, Error},
-    flow_status::{Approval, FlowStatus, FlowStatusModule},
-    flows::FlowValue,
+    flow_status::{
+        Approval, FlowStatus, FlowStatusDiff, FlowStatusModule, FlowStepEvent, FLOW_STEP_CHANNEL,
+    },
+    flows::{FlowModuleValue, FlowValue, InputTransform, Suspend},
+    json_schema::{validate_value, Violation},
     oauth2::HmacSha256,
     scripts::{ScriptHash, ScriptLang},
     users::owner_to_token_owner,
     utils::{not_found_if_none, now_from_db, paginate, require_admin, Pagination, StripPath},
 };
-use windmill_queue::{get_queued_job, push, JobKind, JobPayload, QueuedJob, RawCode};
+use windmill_queue::{
+    chain_trigger,
+    flow_jobs::{flow_jobs_results, get_flow_jobs, inline_flow_jobs},
+    get_queued_job, push, FlowStorage, JobKind, JobPayload, QueuedJob, RawCode,
+};
 
 use crate::{
     db::{UserDB, DB},
-    users::Authed,
+    flows::check_branch_keys,
+    users::{Authed, Tokened},
     variables::get_workspace_key,
+    TestActions,
 };
 
 pub fn workspaced_service() -> Router {
@@ -48,18 +66,39 @@ pub fn workspaced_service() -> Router {
             "/run_wait_result/h/:hash",
             post(run_wait_result_job_by_hash),
         )
+        .route(
+            "/run_wait_result/f/*script_path",
+            post(run_wait_result_flow_by_path),
+        )
         .route("/run/h/:hash", post(run_job_by_hash))
         .route("/run/preview", post(run_preview_job))
         .route("/run/preview_flow", post(run_preview_flow_job))
+        .route("/rerun/:id", post(rerun_flow_job))
         .route("/list", get(list_jobs))
         .route("/queue/list", get(list_queue_jobs))
         .route("/queue/cancel/:id", post(cancel_job_api))
+        .route(
+            "/queue/trigger_failure_module/:id",
+            post(trigger_failure_module_api),
+        )
         .route("/completed/list", get(list_completed_jobs))
         .route("/completed/get/:id", get(get_completed_job))
         .route("/completed/get_result/:id", get(get_completed_job_result))
         .route("/completed/delete/:id", post(delete_completed_job))
+        .route("/dead_letter/list", get(list_dead_letter_jobs))
+        .route("/dead_letter/get/:id", get(get_dead_letter_job))
+        .route("/dead_letter/replay/:id", post(replay_dead_letter_job))
+        .route("/dead_letter/delete/:id", post(delete_dead_letter_job))
         .route("/get/:id", get(get_job))
         .route("/getupdate/:id", get(get_job_update))
+        .route("/flow_steps/:id", get(stream_flow_steps))
+        .route("/flow_statuses", post(get_flow_statuses))
+        .route("/flow_status/get/:id", get(get_flow_status))
+        .route("/flow_status/diff", post(diff_flow_statuses))
+        .route("/flow_partial_results/:id", get(get_flow_partial_results))
+        .route("/blobs/upload/:job_id", post(upload_job_blob))
+        .route("/blobs/get/:id", get(get_job_blob))
+        .route("/blobs/get_base64/:id", get(get_job_blob_base64))
         .route(
             "/job_signature/:job_id/:resume_id",
             get(create_job_signature),
@@ -69,6 +108,10 @@ pub fn workspaced_service() -> Router {
             "/get_flow/:job_id/:resume_id/:secret",
             get(get_suspended_job_flow),
         )
+        .route(
+            "/get_suspend/:job_id/:resume_id/:secret",
+            get(get_suspend_details),
+        )
 }
 
 pub fn global_service() -> Router {
@@ -100,6 +143,120 @@ async fn get_result_by_id(
     Ok(Json(res))
 }
 
+async fn upload_job_blob(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, job_id)): Path<(String, Uuid)>,
+    content: Bytes,
+) -> error::JsonResult<BlobRef> {
+    let mut tx = user_db.begin(&authed).await?;
+
+    let root_job = sqlx::query_scalar!(
+        "SELECT COALESCE(root_job, id) FROM queue WHERE id = $1 AND workspace_id = $2",
+        job_id,
+        &w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .flatten();
+    let root_job = not_found_if_none(root_job, "Queued Job", job_id.to_string())?;
+
+    let id = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO job_blob (id, workspace_id, root_job, content, created_by, expiration)
+        VALUES ($1, $2, $3, $4, $5, now() + ($6 || ' seconds')::interval)",
+        id,
+        &w_id,
+        root_job,
+        content.as_ref(),
+        &authed.username,
+        blob_ttl_secs().to_string()
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(BlobRef { id }))
+}
+
+/// The content of the blob `id`, for the users who can see the flow it is attached to.
+async fn get_job_blob_content(
+    authed: &Authed,
+    user_db: UserDB,
+    w_id: &str,
+    id: Uuid,
+) -> error::Result<Vec<u8>> {
+    let mut tx = user_db.begin(authed).await?;
+    let content = sqlx::query_scalar!(
+        "SELECT b.content FROM job_blob b
+          WHERE b.id = $1 AND b.workspace_id = $2 AND b.expiration > now()
+            AND (EXISTS (SELECT 1 FROM queue q
+                          WHERE q.id = b.root_job AND q.workspace_id = b.workspace_id)
+                 OR EXISTS (SELECT 1 FROM completed_job c
+                             WHERE c.id = b.root_job AND c.workspace_id = b.workspace_id))",
+        id,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    tx.commit().await?;
+    not_found_if_none(content, "Blob", id.to_string())
+}
+
+async fn get_job_blob(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, id)): Path<(String, Uuid)>,
+) -> error::Result<impl IntoResponse> {
+    let content = get_job_blob_content(&authed, user_db, &w_id, id).await?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/octet-stream")],
+        content,
+    ))
+}
+
+async fn get_job_blob_base64(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, id)): Path<(String, Uuid)>,
+) -> error::JsonResult<String> {
+    let content = get_job_blob_content(&authed, user_db, &w_id, id).await?;
+    Ok(Json(base64::encode(content)))
+}
+
+/// Test action, only available with `ENABLE_TEST_ACTIONS`: runs the failure module of a running
+/// flow as if its current step had failed with `error`.
+async fn trigger_failure_module_api(
+    authed: Authed,
+    Extension(db): Extension<DB>,
+    Extension(test_actions): Extension<Arc<TestActions>>,
+    Path((w_id, id)): Path<(String, Uuid)>,
+    Json(TriggerFailureModule { error }): Json<TriggerFailureModule>,
+) -> error::Result<String> {
+    if !test_actions.0 {
+        return Err(Error::BadRequest(
+            "test actions are disabled, set ENABLE_TEST_ACTIONS to enable them".to_string(),
+        ));
+    }
+    require_admin(authed.is_admin, &authed.username)?;
+
+    windmill_worker::trigger_failure_module(&db, id, &w_id, &authed.username, error).await?;
+
+    let mut tx = db.begin().await?;
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "jobs.flow.trigger_failure_module",
+        ActionKind::Execute,
+        &w_id,
+        Some(&id.to_string()),
+        Some([("test_action", "true")].into()),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(id.to_string())
+}
+
 async fn cancel_job_api(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
@@ -187,13 +344,11 @@ pub async fn get_job_by_id<'c>(
     .bind(w_id)
     .fetch_optional(&mut tx)
     .await?;
-    let job_option = match cjob_option {
+    let mut job_option = match cjob_option {
         Some(job) => Some(Job::CompletedJob(job)),
         None => get_queued_job(id, w_id, &mut tx).await?.map(Job::QueuedJob),
     };
-    if job_option.is_some() {
-        Ok((job_option, tx))
-    } else {
+    if job_option.is_none() {
         // check if a job had been moved in-between queries
         let cjob_option = sqlx::query_as::<_, CompletedJob>(
             "SELECT * FROM completed_job WHERE id = $1 AND workspace_id = $2",
@@ -202,8 +357,12 @@ pub async fn get_job_by_id<'c>(
         .bind(w_id)
         .fetch_optional(&mut tx)
         .await?;
-        Ok((cjob_option.map(Job::CompletedJob), tx))
+        job_option = cjob_option.map(Job::CompletedJob);
     }
+    if let Some(flow_status) = job_option.as_mut().and_then(|j| j.flow_status_mut()) {
+        inline_flow_jobs(&mut tx, id, flow_status).await?;
+    }
+    Ok((job_option, tx))
 }
 
 #[derive(Debug, sqlx::FromRow, Serialize)]
@@ -234,6 +393,13 @@ pub struct CompletedJob {
     pub is_flow_step: bool,
     pub language: Option<ScriptLang>,
     pub is_skipped: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flow_step_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rerun_of: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trigger_chain: Option<Uuid>,
+    pub trigger_depth: i32,
 }
 
 #[derive(Deserialize, Clone, Copy)]
@@ -374,6 +540,11 @@ async fn list_jobs(
             "is_flow_step",
             "language",
             "false as is_skipped",
+            "flow_step_id",
+            "rerun_of",
+            "needs_intervention",
+            "trigger_chain",
+            "trigger_depth",
         ],
     );
     let sqlc = list_completed_jobs_query(
@@ -406,6 +577,11 @@ async fn list_jobs(
             "is_flow_step",
             "language",
             "is_skipped",
+            "flow_step_id",
+            "rerun_of",
+            "null as needs_intervention",
+            "trigger_chain",
+            "trigger_depth",
         ],
     );
     let sql = format!(
@@ -441,7 +617,7 @@ pub async fn resume_suspended_job(
         .map_err(|_| anyhow::anyhow!("Invalid signature"))?;
     let flow = sqlx::query!(
         r#"
-        SELECT id, flow_status, suspend
+        SELECT id, flow_status, suspend, raw_flow
         FROM queue
         WHERE id = ( SELECT parent_job FROM queue WHERE id = $1 UNION ALL SELECT parent_job FROM completed_job WHERE id = $1)
         FOR UPDATE
@@ -452,6 +628,71 @@ pub async fn resume_suspended_job(
     .await?
     .ok_or_else(|| anyhow::anyhow!("parent flow job not found"))?;
 
+    let status = serde_json::from_value::<FlowStatus>(flow.flow_status.context("no flow status")?)
+        .context("deserialize flow status")?;
+
+    let suspend = get_suspend(&status, flow.raw_flow, job_id)?;
+    if let Some(schema) = suspend.as_ref().and_then(|s| s.resume_schema.as_ref()) {
+        let mut violations = vec![];
+        validate_value(&mut violations, "", schema, &value);
+        if !violations.is_empty() {
+            let violations = violations
+                .into_iter()
+                .map(|Violation { key, message }| {
+                    if key.is_empty() {
+                        message
+                    } else {
+                        format!("`{key}` {message}")
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            let reason = format!(
+                "resume message {resume_id} of job {job_id} rejected, its value does not satisfy the resume schema: {violations}"
+            );
+            sqlx::query!(
+                "UPDATE queue SET logs = concat(logs, $1::text) WHERE id = $2",
+                format!("{reason}\n"),
+                flow.id
+            )
+            .execute(&mut tx)
+            .await?;
+            tx.commit().await?;
+            return Err(Error::BadRequest(reason));
+        }
+    }
+
+    let max_resume_messages = get_max_resume_messages(&mut tx, &w_id, suspend.as_ref()).await?;
+    let resume_messages = sqlx::query_scalar!(
+        "SELECT count(*) FROM resume_job WHERE job = $1 AND resume_id != $2",
+        job_id,
+        resume_id as i32,
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .unwrap_or(0);
+    if resume_messages >= max_resume_messages {
+        return Err(Error::TooManyRequests(format!(
+            "job {job_id} already received the maximum of {max_resume_messages} resume messages"
+        )));
+    }
+
+    /* the worker only counts the first approval of each approver when the step requires them
+     * to be distinct, so must the suspend column */
+    let approved_before = match suspend.as_ref() {
+        Some(suspend) if suspend.require_distinct_approvers => sqlx::query_scalar!(
+            "SELECT EXISTS(SELECT 1 FROM resume_job WHERE job = $1 AND resume_id != $2
+                                 AND COALESCE(approver, 'unknown') = $3)",
+            job_id,
+            resume_id as i32,
+            approver.approver.as_deref().unwrap_or("unknown"),
+        )
+        .fetch_one(&mut tx)
+        .await?
+        .unwrap_or(false),
+        _ => false,
+    };
+
     sqlx::query!(
         r#"
         INSERT INTO resume_job
@@ -478,10 +719,7 @@ pub async fn resume_suspended_job(
      * entering WaitingForEvents.  Then this message arrives but the job isn't in WaitingForEvents
      * yet so the suspend counter isn't updated.  Then the job enters WaitingForEvents expecting
      * one event to arrive based on the count that is no longer correct. */
-    if let Some(suspend) = (0 < flow.suspend).then(|| flow.suspend - 1) {
-        let status =
-            serde_json::from_value::<FlowStatus>(flow.flow_status.context("no flow status")?)
-                .context("deserialize flow status")?;
+    if let Some(suspend) = (0 < flow.suspend && !approved_before).then(|| flow.suspend - 1) {
         if matches!(status.current_step(), Some(FlowStatusModule::WaitingForEvents { job, .. }) if job == &job_id)
         {
             sqlx::query!(
@@ -498,6 +736,52 @@ pub async fn resume_suspended_job(
     Ok(StatusCode::CREATED)
 }
 
+/// Resume messages stored per job when the workspace doesn't set `max_resume_messages`
+const DEFAULT_MAX_RESUME_MESSAGES: i64 = 100;
+
+/// The `suspend` of the step of the flow, with `status` and `raw_flow`, whose job is `job`.
+fn get_suspend(
+    status: &FlowStatus,
+    raw_flow: Option<serde_json::Value>,
+    job: Uuid,
+) -> error::Result<Option<Suspend>> {
+    Ok(raw_flow
+        .map(serde_json::from_value::<FlowValue>)
+        .transpose()
+        .context("deserialize raw flow")?
+        .and_then(|flow| {
+            status
+                .modules
+                .iter()
+                .position(|m| m.job() == Some(job))
+                .and_then(|i| flow.modules.into_iter().nth(i))
+        })
+        .and_then(|module| module.suspend))
+}
+
+/// The number of resume messages a suspended job with `suspend` may store. That is the workspace
+/// setting, but never less than the `required_events` of the step or the step couldn't resume.
+async fn get_max_resume_messages<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    suspend: Option<&Suspend>,
+) -> error::Result<i64> {
+    let max_resume_messages = sqlx::query_scalar!(
+        "SELECT max_resume_messages FROM workspace_settings WHERE workspace_id = $1",
+        w_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .flatten()
+    .map_or(DEFAULT_MAX_RESUME_MESSAGES, i64::from);
+
+    let required_events = suspend
+        .and_then(|suspend| suspend.required_events)
+        .unwrap_or(1);
+
+    Ok(max_resume_messages.max(required_events.into()))
+}
+
 pub async fn cancel_suspended_job(
     /* unauthed */
     Extension(db): Extension<DB>,
@@ -622,7 +906,7 @@ pub async fn get_suspended_job_flow(
     let approvers = if approvers_from_status.is_empty() {
         sqlx::query!(
             r#"
-            SELECT resume_id, approver
+            SELECT resume_id, approver, created_at
             FROM resume_job
             WHERE job = $1
             "#,
@@ -634,6 +918,7 @@ pub async fn get_suspended_job_flow(
         .map(|x| Approval {
             resume_id: x.resume_id as u16,
             approver: x.approver.unwrap_or_else(|| "anonymous".to_string()),
+            approved_at: Some(x.created_at),
         })
         .collect()
     } else {
@@ -643,6 +928,69 @@ pub async fn get_suspended_job_flow(
     Ok(Json(SuspendedJobFlow { job: flow, approvers }))
 }
 
+#[derive(Serialize)]
+pub struct SuspendDetails {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// the `resume_schema` of the suspend, the value of the resume message must satisfy it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub form_schema: Option<serde_json::Value>,
+    pub required_events: u32,
+    /// the resume messages the job already received
+    pub resume_messages: i64,
+}
+
+/// What the approver of a suspended job is asked: the description of the suspend and the schema
+/// of the form to render, whose submission is then given to `resume_suspended_job`.
+pub async fn get_suspend_details(
+    /* unauthed */
+    Extension(db): Extension<DB>,
+    Path((w_id, job, resume_id, secret)): Path<(String, Uuid, u32, String)>,
+    Query(approver): Query<QueryApprover>,
+) -> error::JsonResult<SuspendDetails> {
+    let mut tx = db.begin().await?;
+    let key = get_workspace_key(&w_id, &mut tx).await?;
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).map_err(to_anyhow)?;
+    mac.update(job.as_bytes());
+    mac.update(resume_id.to_be_bytes().as_ref());
+    if let Some(approver) = approver.approver {
+        mac.update(approver.as_bytes());
+    }
+    mac.verify_slice(hex::decode(secret)?.as_ref())
+        .map_err(|_| anyhow::anyhow!("Invalid signature"))?;
+    let flow = sqlx::query!(
+        r#"
+        SELECT flow_status, raw_flow
+        FROM queue
+        WHERE id = ( SELECT parent_job FROM queue WHERE id = $1 UNION ALL SELECT parent_job FROM completed_job WHERE id = $1)
+          AND workspace_id = $2
+        "#,
+        job,
+        w_id,
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or_else(|| Error::NotFound(format!("no running flow is suspended by job {job}")))?;
+
+    let status = serde_json::from_value::<FlowStatus>(flow.flow_status.context("no flow status")?)
+        .context("deserialize flow status")?;
+    let suspend = get_suspend(&status, flow.raw_flow, job)?
+        .ok_or_else(|| Error::NotFound(format!("the step of job {job} has no suspend")))?;
+    let resume_messages =
+        sqlx::query_scalar!("SELECT count(*) FROM resume_job WHERE job = $1", job)
+            .fetch_one(&mut tx)
+            .await?
+            .unwrap_or(0);
+    tx.commit().await?;
+
+    Ok(Json(SuspendDetails {
+        description: suspend.description,
+        form_schema: suspend.resume_schema,
+        required_events: suspend.required_events.unwrap_or(1),
+        resume_messages,
+    }))
+}
+
 pub async fn create_job_signature(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
@@ -682,6 +1030,12 @@ impl Job {
         };
         value.map(|v| serde_json::from_value(v).ok()).flatten()
     }
+    fn flow_status_mut(&mut self) -> Option<&mut serde_json::Value> {
+        match self {
+            Job::QueuedJob(job) => job.flow_status.as_mut(),
+            Job::CompletedJob(job) => job.flow_status.as_mut(),
+        }
+    }
 }
 
 #[derive(sqlx::FromRow)]
@@ -710,6 +1064,11 @@ struct UnifiedJob {
     is_flow_step: bool,
     language: Option<ScriptLang>,
     is_skipped: bool,
+    flow_step_id: Option<String>,
+    rerun_of: Option<Uuid>,
+    needs_intervention: Option<String>,
+    trigger_chain: Option<Uuid>,
+    trigger_depth: i32,
 }
 
 impl From<UnifiedJob> for Job {
@@ -742,6 +1101,10 @@ impl From<UnifiedJob> for Job {
                 is_flow_step: uj.is_flow_step,
                 language: uj.language,
                 is_skipped: uj.is_skipped,
+                flow_step_id: uj.flow_step_id,
+                rerun_of: uj.rerun_of,
+                trigger_chain: uj.trigger_chain,
+                trigger_depth: uj.trigger_depth,
             }),
             "QueuedJob" => Job::QueuedJob(QueuedJob {
                 workspace_id: uj.workspace_id,
@@ -769,6 +1132,15 @@ impl From<UnifiedJob> for Job {
                 is_flow_step: uj.is_flow_step,
                 language: uj.language,
                 same_worker: false,
+                root_job: None,
+                flow_step_id: uj.flow_step_id,
+                rerun_of: uj.rerun_of,
+                needs_intervention: uj.needs_intervention,
+                trigger_chain: uj.trigger_chain,
+                trigger_depth: uj.trigger_depth,
+                timeout: None,
+                deferred: false,
+                idempotency_key: None,
             }),
             t => panic!("job type {} not valid", t),
         }
@@ -779,6 +1151,11 @@ struct CancelJob {
     reason: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct TriggerFailureModule {
+    error: serde_json::Value,
+}
+
 #[derive(Deserialize)]
 struct Preview {
     content: String,
@@ -794,6 +1171,19 @@ struct PreviewFlow {
     args: Option<serde_json::Map<String, serde_json::Value>>,
 }
 
+#[derive(Deserialize)]
+struct RerunFlow {
+    /// args replacing the ones of the original run, the others are kept
+    args: Option<serde_json::Map<String, serde_json::Value>>,
+    /// run the latest version of the flow instead of the one the original run used
+    #[serde(default)]
+    use_latest: bool,
+    /// run the script steps with the args they ran with in the original run, instead of
+    /// evaluating their input transforms again
+    #[serde(default)]
+    pin_step_args: bool,
+}
+
 pub struct QueryOrBody<D>(pub Option<D>);
 
 #[axum::async_trait]
@@ -841,6 +1231,7 @@ where
 }
 pub async fn run_flow_by_path(
     authed: Authed,
+    Tokened { token }: Tokened,
     Extension(user_db): Extension<UserDB>,
     Path((w_id, flow_path)): Path<(String, StripPath)>,
     axum::Json(args): axum::Json<Option<serde_json::Map<String, serde_json::Value>>>,
@@ -849,7 +1240,7 @@ pub async fn run_flow_by_path(
     let flow_path = flow_path.to_path();
     let mut tx = user_db.begin(&authed).await?;
     let scheduled_for = run_query.get_scheduled_for(&mut tx).await?;
-    let (uuid, tx) = push(
+    let (uuid, mut tx) = push(
         tx,
         &w_id,
         JobPayload::Flow(flow_path.to_string()),
@@ -861,14 +1252,18 @@ pub async fn run_flow_by_path(
         run_query.parent_job,
         false,
         false,
+        None,
+        FlowStorage::default(),
     )
     .await?;
+    chain_trigger(&mut tx, uuid, &token).await?;
     tx.commit().await?;
     Ok((StatusCode::CREATED, uuid.to_string()))
 }
 
 pub async fn run_job_by_path(
     authed: Authed,
+    Tokened { token }: Tokened,
     Extension(user_db): Extension<UserDB>,
     Path((w_id, script_path)): Path<(String, StripPath)>,
     axum::Json(args): axum::Json<Option<serde_json::Map<String, serde_json::Value>>>,
@@ -879,7 +1274,7 @@ pub async fn run_job_by_path(
     let job_payload = script_path_to_payload(script_path, &mut tx, &w_id).await?;
     let scheduled_for = run_query.get_scheduled_for(&mut tx).await?;
 
-    let (uuid, tx) = push(
+    let (uuid, mut tx) = push(
         tx,
         &w_id,
         job_payload,
@@ -891,8 +1286,11 @@ pub async fn run_job_by_path(
         run_query.parent_job,
         false,
         false,
+        None,
+        FlowStorage::default(),
     )
     .await?;
+    chain_trigger(&mut tx, uuid, &token).await?;
     tx.commit().await?;
     Ok((StatusCode::CREATED, uuid.to_string()))
 }
@@ -931,6 +1329,7 @@ async fn run_wait_result<T>(
 
 pub async fn run_wait_result_job_by_path(
     authed: Authed,
+    Tokened { token }: Tokened,
     Extension(user_db): Extension<UserDB>,
     Path((w_id, script_path)): Path<(String, StripPath)>,
     axum::Json(args): axum::Json<Option<serde_json::Map<String, serde_json::Value>>>,
@@ -941,7 +1340,7 @@ pub async fn run_wait_result_job_by_path(
     let job_payload = script_path_to_payload(script_path, &mut tx, &w_id).await?;
     let scheduled_for = run_query.get_scheduled_for(&mut tx).await?;
 
-    let (uuid, tx) = push(
+    let (uuid, mut tx) = push(
         tx,
         &w_id,
         job_payload,
@@ -953,8 +1352,11 @@ pub async fn run_wait_result_job_by_path(
         run_query.parent_job,
         false,
         false,
+        None,
+        FlowStorage::default(),
     )
     .await?;
+    chain_trigger(&mut tx, uuid, &token).await?;
     tx.commit().await?;
 
     run_wait_result(authed, Extension(user_db), uuid, Path((w_id, script_path))).await
@@ -962,6 +1364,7 @@ pub async fn run_wait_result_job_by_path(
 
 pub async fn run_wait_result_job_by_hash(
     authed: Authed,
+    Tokened { token }: Tokened,
     Extension(user_db): Extension<UserDB>,
     Path((w_id, script_hash)): Path<(String, ScriptHash)>,
     axum::Json(args): axum::Json<Option<serde_json::Map<String, serde_json::Value>>>,
@@ -972,7 +1375,7 @@ pub async fn run_wait_result_job_by_hash(
     let path = get_path_for_hash(&mut tx, &w_id, hash).await?;
     let scheduled_for = run_query.get_scheduled_for(&mut tx).await?;
 
-    let (uuid, tx) = push(
+    let (uuid, mut tx) = push(
         tx,
         &w_id,
         JobPayload::ScriptHash { hash: ScriptHash(hash), path },
@@ -984,13 +1387,199 @@ pub async fn run_wait_result_job_by_hash(
         run_query.parent_job,
         false,
         false,
+        None,
+        FlowStorage::default(),
     )
     .await?;
+    chain_trigger(&mut tx, uuid, &token).await?;
     tx.commit().await?;
 
     run_wait_result(authed, Extension(user_db), uuid, Path((w_id, script_hash))).await
 }
 
+const DEFAULT_WAIT_RESULT_TIMEOUT_SECS: u64 = 20;
+const MAX_WAIT_RESULT_TIMEOUT_SECS: u64 = 600;
+
+/// The events buffered for the subscribers of `FlowStepEvents` that fall behind.
+const FLOW_STEP_EVENTS_CAPACITY: usize = 1024;
+
+/// What the subscribers of `FlowStepEvents` receive: an event, or the notice that the events
+/// notified since the previous one may have been missed.
+#[derive(Clone, Debug)]
+pub enum FlowStepUpdate {
+    Event(FlowStepEvent),
+    Missed,
+}
+
+/// Fans out the `FlowStepEvent`s notified on `FLOW_STEP_CHANNEL` to the requests of this server
+/// waiting on flows, so that they all share a single postgres connection.
+pub struct FlowStepEvents {
+    sender: broadcast::Sender<FlowStepUpdate>,
+    db: DB,
+}
+
+impl FlowStepEvents {
+    pub fn new(db: DB) -> Self {
+        FlowStepEvents { sender: broadcast::channel(FLOW_STEP_EVENTS_CAPACITY).0, db }
+    }
+
+    /// The events notified from now on, subscribe before the action whose events are awaited.
+    pub fn subscribe(&self) -> broadcast::Receiver<FlowStepUpdate> {
+        self.sender.subscribe()
+    }
+
+    /// Forwards the notifications to the subscribers, listening again when the connection is
+    /// lost. The events notified in between are missed, which the subscribers are told once
+    /// listening again.
+    pub async fn monitor(&self) {
+        let mut reconnecting = false;
+        loop {
+            let listener = async {
+                let mut listener = PgListener::connect_with(&self.db).await?;
+                listener.listen(FLOW_STEP_CHANNEL).await?;
+                Ok::<_, sqlx::Error>(listener)
+            };
+            match listener.await {
+                Ok(mut listener) => loop {
+                    if reconnecting {
+                        /* there may be no subscriber */
+                        let _ = self.sender.send(FlowStepUpdate::Missed);
+                        reconnecting = false;
+                    }
+                    match listener.recv().await {
+                        Ok(n) => {
+                            if let Ok(e) = serde_json::from_str::<FlowStepEvent>(n.payload()) {
+                                let _ = self.sender.send(FlowStepUpdate::Event(e));
+                            }
+                        }
+                        Err(err) => {
+                            tracing::error!("error receiving flow step events: {err}");
+                            break;
+                        }
+                    }
+                },
+                Err(err) => tracing::error!("error listening to flow step events: {err}"),
+            }
+            reconnecting = true;
+            tokio::time::sleep(core::time::Duration::from_secs(1)).await;
+        }
+    }
+}
+
+/// The next update of `rx`, `Missed` when it lagged behind on some events.
+async fn recv_flow_step(rx: &mut broadcast::Receiver<FlowStepUpdate>) -> Option<FlowStepUpdate> {
+    match rx.recv().await {
+        Ok(u) => Some(u),
+        Err(broadcast::error::RecvError::Lagged(_)) => Some(FlowStepUpdate::Missed),
+        Err(broadcast::error::RecvError::Closed) => None,
+    }
+}
+
+/// Whether the flow `id` is still in the queue, as seen by `authed`.
+async fn is_flow_queued(
+    user_db: UserDB,
+    authed: &Authed,
+    w_id: &str,
+    id: Uuid,
+) -> error::Result<bool> {
+    let mut tx = user_db.begin(authed).await?;
+    let queued = query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM queue WHERE id = $1 AND workspace_id = $2)",
+        id,
+        w_id
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .unwrap_or(false);
+    tx.commit().await?;
+    Ok(queued)
+}
+
+#[derive(Deserialize)]
+pub struct WaitResultQuery {
+    timeout: Option<u64>,
+}
+
+/// Runs the flow at `flow_path` and answers with its result once it completed, or with a 408
+/// after `timeout` seconds. The flow keeps running when the caller timed out, its result can
+/// then be fetched with its id.
+pub async fn run_wait_result_flow_by_path(
+    authed: Authed,
+    Tokened { token }: Tokened,
+    Extension(flow_step_events): Extension<Arc<FlowStepEvents>>,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, flow_path)): Path<(String, StripPath)>,
+    axum::Json(args): axum::Json<Option<serde_json::Map<String, serde_json::Value>>>,
+    Query(run_query): Query<RunJobQuery>,
+    Query(WaitResultQuery { timeout }): Query<WaitResultQuery>,
+) -> error::JsonResult<serde_json::Value> {
+    let timeout = timeout
+        .unwrap_or(DEFAULT_WAIT_RESULT_TIMEOUT_SECS)
+        .min(MAX_WAIT_RESULT_TIMEOUT_SECS);
+
+    /* subscribe before pushing the flow to not miss its completion */
+    let mut events = flow_step_events.subscribe();
+
+    let flow_path = flow_path.to_path();
+    let mut tx = user_db.clone().begin(&authed).await?;
+    let scheduled_for = run_query.get_scheduled_for(&mut tx).await?;
+    let (uuid, mut tx) = push(
+        tx,
+        &w_id,
+        JobPayload::Flow(flow_path.to_string()),
+        args,
+        &authed.username,
+        owner_to_token_owner(&authed.username, false),
+        scheduled_for,
+        None,
+        run_query.parent_job,
+        false,
+        false,
+        None,
+        FlowStorage::default(),
+    )
+    .await?;
+    chain_trigger(&mut tx, uuid, &token).await?;
+    tx.commit().await?;
+
+    let deadline = tokio::time::Instant::now() + core::time::Duration::from_secs(timeout);
+    loop {
+        let mut tx = user_db.clone().begin(&authed).await?;
+        let result = sqlx::query_scalar!(
+            "SELECT result FROM completed_job WHERE id = $1 AND workspace_id = $2",
+            uuid,
+            &w_id
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        tx.commit().await?;
+        if let Some(result) = result {
+            return Ok(Json(result.unwrap_or(serde_json::Value::Null)));
+        }
+
+        let now = tokio::time::Instant::now();
+        if now >= deadline {
+            return Err(Error::RequestTimeout(format!(
+                "flow {uuid} did not complete within {timeout}s, it keeps running"
+            )));
+        }
+        /* the completion of the flow is notified along with its last step, the result is also
+         * checked every second in case the notification was missed */
+        let done = async {
+            while let Some(u) = recv_flow_step(&mut events).await {
+                match u {
+                    FlowStepUpdate::Event(e) if e.flow == uuid && e.done => return,
+                    FlowStepUpdate::Missed => return,
+                    _ => (),
+                }
+            }
+            future::pending::<()>().await
+        };
+        let wait = (deadline - now).min(core::time::Duration::from_secs(1));
+        let _ = tokio::time::timeout(wait, done).await;
+    }
+}
+
 // a similar function exists on the worker
 pub async fn script_path_to_payload<'c>(
     script_path: &str,
@@ -1008,6 +1597,7 @@ pub async fn script_path_to_payload<'c>(
 
 async fn run_preview_job(
     authed: Authed,
+    Tokened { token }: Tokened,
     Extension(user_db): Extension<UserDB>,
     Path(w_id): Path<String>,
     Json(preview): Json<Preview>,
@@ -1016,7 +1606,7 @@ async fn run_preview_job(
     let mut tx = user_db.begin(&authed).await?;
     let scheduled_for = sch_query.get_scheduled_for(&mut tx).await?;
 
-    let (uuid, tx) = push(
+    let (uuid, mut tx) = push(
         tx,
         &w_id,
         JobPayload::Code(RawCode {
@@ -1032,22 +1622,27 @@ async fn run_preview_job(
         None,
         false,
         false,
+        None,
+        FlowStorage::default(),
     )
     .await?;
+    chain_trigger(&mut tx, uuid, &token).await?;
     tx.commit().await?;
     Ok((StatusCode::CREATED, uuid.to_string()))
 }
 
 async fn run_preview_flow_job(
     authed: Authed,
+    Tokened { token }: Tokened,
     Extension(user_db): Extension<UserDB>,
     Path(w_id): Path<String>,
     Json(raw_flow): Json<PreviewFlow>,
     Query(sch_query): Query<RunJobQuery>,
 ) -> error::Result<(StatusCode, String)> {
+    check_branch_keys(&raw_flow.value)?;
     let mut tx = user_db.begin(&authed).await?;
     let scheduled_for = sch_query.get_scheduled_for(&mut tx).await?;
-    let (uuid, tx) = push(
+    let (uuid, mut tx) = push(
         tx,
         &w_id,
         JobPayload::RawFlow { value: raw_flow.value, path: raw_flow.path },
@@ -1059,14 +1654,155 @@ async fn run_preview_flow_job(
         None,
         false,
         false,
+        None,
+        FlowStorage::default(),
     )
     .await?;
+    chain_trigger(&mut tx, uuid, &token).await?;
     tx.commit().await?;
     Ok((StatusCode::CREATED, uuid.to_string()))
 }
 
+async fn rerun_flow_job(
+    authed: Authed,
+    Tokened { token }: Tokened,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, id)): Path<(String, Uuid)>,
+    Json(rerun): Json<RerunFlow>,
+) -> error::Result<(StatusCode, String)> {
+    let mut tx = user_db.begin(&authed).await?;
+    let job_o = sqlx::query!(
+        "SELECT job_kind AS \"job_kind: JobKind\", script_path, args, raw_flow, flow_status FROM \
+         completed_job WHERE id = $1 AND workspace_id = $2",
+        id,
+        &w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let job = not_found_if_none(job_o, "Completed Job", id.to_string())?;
+
+    if job.job_kind != JobKind::Flow && job.job_kind != JobKind::FlowPreview {
+        return Err(Error::BadRequest(format!("job {id} is not a flow")));
+    }
+
+    let mut args = match job.args {
+        Some(serde_json::Value::Object(args)) => args,
+        _ => serde_json::Map::new(),
+    };
+    args.extend(rerun.args.unwrap_or_default());
+
+    if rerun.pin_step_args && rerun.use_latest {
+        return Err(Error::BadRequest(
+            "the args of the steps can only be pinned with the version of the flow of the \
+             original run"
+                .to_string(),
+        ));
+    }
+
+    let job_payload = match (rerun.use_latest, job.script_path) {
+        (true, Some(path)) if job.job_kind == JobKind::Flow => JobPayload::Flow(path),
+        (true, _) => {
+            return Err(Error::BadRequest(format!(
+                "job {id} is a flow preview, it has no latest version"
+            )))
+        }
+        (false, path) => {
+            let raw_flow = not_found_if_none(job.raw_flow, "Flow of job", id.to_string())?;
+            let mut value = serde_json::from_value::<FlowValue>(raw_flow).map_err(|err| {
+                Error::InternalErr(format!("could not convert json to flow for {id}: {err:?}"))
+            })?;
+            if rerun.pin_step_args {
+                let status = job
+                    .flow_status
+                    .and_then(|status| serde_json::from_value::<FlowStatus>(status).ok())
+                    .ok_or_else(|| {
+                        Error::BadRequest(format!("job {id} has no flow status to pin args from"))
+                    })?;
+                pin_step_args(&mut tx, &w_id, &mut value, &status).await?;
+            }
+            JobPayload::RawFlow { value, path }
+        }
+    };
+
+    /* pushed without the schedule of the original run, its completion doesn't re-arm it */
+    let (uuid, mut tx) = push(
+        tx,
+        &w_id,
+        job_payload,
+        Some(args),
+        &authed.username,
+        owner_to_token_owner(&authed.username, false),
+        None,
+        None,
+        None,
+        false,
+        false,
+        None,
+        FlowStorage::default(),
+    )
+    .await?;
+    chain_trigger(&mut tx, uuid, &token).await?;
+
+    /* a snapshot is pushed as a raw flow, it keeps the kind of the original run nonetheless */
+    sqlx::query!(
+        "UPDATE queue SET rerun_of = $1, job_kind = $2 WHERE id = $3",
+        id,
+        job.job_kind: JobKind,
+        uuid
+    )
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok((StatusCode::CREATED, uuid.to_string()))
+}
+
+/// Replaces the input transforms of the script steps of `flow` by static ones, of the args
+/// their job ran with in the run of `status`. The other steps, and the ones that did not run, are
+/// left as they are.
+async fn pin_step_args<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    flow: &mut FlowValue,
+    status: &FlowStatus,
+) -> error::Result<()> {
+    let jobs = status
+        .modules
+        .iter()
+        .filter_map(|module| module.job())
+        .collect::<Vec<_>>();
+    let args = sqlx::query_as::<_, (Uuid, Option<serde_json::Value>)>(
+        "SELECT id, args FROM completed_job WHERE id = ANY($1) AND workspace_id = $2",
+    )
+    .bind(&jobs)
+    .bind(w_id)
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .collect::<HashMap<_, _>>();
+
+    for (module, module_status) in flow.modules.iter_mut().zip(status.modules.iter()) {
+        if !matches!(
+            module.value,
+            FlowModuleValue::Script { .. } | FlowModuleValue::RawScript { .. }
+        ) {
+            continue;
+        }
+        if let Some(Some(serde_json::Value::Object(args))) =
+            module_status.job().and_then(|job| args.get(&job))
+        {
+            module.input_transforms = args
+                .iter()
+                .map(|(k, v)| (k.clone(), InputTransform::Static { value: v.clone() }))
+                .collect();
+        }
+    }
+    Ok(())
+}
+
 pub async fn run_job_by_hash(
     authed: Authed,
+    Tokened { token }: Tokened,
     Extension(user_db): Extension<UserDB>,
     Path((w_id, script_hash)): Path<(String, ScriptHash)>,
     axum::Json(args): axum::Json<Option<serde_json::Map<String, serde_json::Value>>>,
@@ -1077,7 +1813,7 @@ pub async fn run_job_by_hash(
     let path = get_path_for_hash(&mut tx, &w_id, hash).await?;
     let scheduled_for = run_query.get_scheduled_for(&mut tx).await?;
 
-    let (uuid, tx) = push(
+    let (uuid, mut tx) = push(
         tx,
         &w_id,
         JobPayload::ScriptHash { hash: ScriptHash(hash), path },
@@ -1089,8 +1825,11 @@ pub async fn run_job_by_hash(
         run_query.parent_job,
         false,
         false,
+        None,
+        FlowStorage::default(),
     )
     .await?;
+    chain_trigger(&mut tx, uuid, &token).await?;
     tx.commit().await?;
     Ok((StatusCode::CREATED, uuid.to_string()))
 }
@@ -1151,6 +1890,218 @@ async fn get_job_update(
     }
 }
 
+const MAX_FLOW_STATUSES: usize = 100;
+
+#[derive(Serialize)]
+pub struct FlowStatusSummary {
+    pub completed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub success: Option<bool>,
+    pub step: i32,
+    /// status of the module at `step`, or of the failure module
+    pub module: FlowStatusModule,
+}
+
+#[derive(Serialize)]
+pub struct FlowStatusOrError {
+    pub id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<FlowStatusSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The whole status of a flow as it is now, to be compared to a later snapshot with
+/// `diff_flow_statuses`. Doesn't affect the flow in any way.
+async fn get_flow_status(
+    Extension(db): Extension<DB>,
+    Path((w_id, id)): Path<(String, Uuid)>,
+) -> error::JsonResult<serde_json::Value> {
+    let tx = db.begin().await?;
+    let (job_o, tx) = get_job_by_id(tx, &w_id, id).await?;
+    tx.commit().await?;
+
+    let mut job = not_found_if_none(job_o, "Job", id.to_string())?;
+    let flow_status = job
+        .flow_status_mut()
+        .map(|flow_status| flow_status.take())
+        .ok_or_else(|| Error::BadRequest(format!("Job {id} is not a flow")))?;
+    Ok(Json(flow_status))
+}
+
+#[derive(Serialize)]
+struct FlowPartialResults {
+    step: i32,
+    id: String,
+    results: Option<serde_json::Value>,
+}
+
+/// The results collected so far by the forloop or branchall the running flow is at, in the same
+/// shape and order as the result the step will complete with. `results` is `None` when the
+/// current step doesn't spawn several jobs.
+async fn get_flow_partial_results(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, id)): Path<(String, Uuid)>,
+) -> error::JsonResult<FlowPartialResults> {
+    let mut tx = user_db.begin(&authed).await?;
+    let job = get_queued_job(id, &w_id, &mut tx).await?;
+    let job = not_found_if_none(job, "Running flow", id.to_string())?;
+    let status = job
+        .parse_flow_status()
+        .ok_or_else(|| Error::BadRequest(format!("Job {id} is not a flow")))?;
+
+    let module = status.current_step().unwrap_or(&status.failure_module);
+    let results = match module {
+        FlowStatusModule::InProgress { flow_jobs: Some(flow_jobs), branchall, .. } => {
+            let jobs = get_flow_jobs(&mut tx, &status, id, status.step, flow_jobs).await?;
+            Some(flow_jobs_results(&mut tx, &w_id, &jobs, branchall.is_some()).await?)
+        }
+        _ => None,
+    };
+    tx.commit().await?;
+
+    Ok(Json(FlowPartialResults {
+        step: status.step,
+        id: module.id(),
+        results,
+    }))
+}
+
+#[derive(Deserialize)]
+struct DiffFlowStatuses {
+    old: serde_json::Value,
+    new: serde_json::Value,
+}
+
+async fn diff_flow_statuses(
+    Json(DiffFlowStatuses { old, new }): Json<DiffFlowStatuses>,
+) -> error::JsonResult<FlowStatusDiff> {
+    let parse = |status: serde_json::Value, name: &str| {
+        serde_json::from_value::<FlowStatus>(status)
+            .map_err(|e| Error::BadRequest(format!("invalid {name} flow status: {e}")))
+    };
+    let (old, new) = (parse(old, "old")?, parse(new, "new")?);
+    Ok(Json(FlowStatusDiff::new(&old, &new)))
+}
+
+/// Statuses of many flows at once, in the order of `ids`. Flows that can't be found, or that
+/// the user can't see, are reported as such instead of failing the whole request.
+async fn get_flow_statuses(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path(w_id): Path<String>,
+    Json(ids): Json<Vec<Uuid>>,
+) -> error::JsonResult<Vec<FlowStatusOrError>> {
+    if ids.len() > MAX_FLOW_STATUSES {
+        return Err(Error::BadRequest(format!(
+            "at most {MAX_FLOW_STATUSES} flow statuses can be requested at once"
+        )));
+    }
+
+    let mut tx = user_db.begin(&authed).await?;
+    let rows = sqlx::query!(
+        "SELECT id, flow_status, NULL::bool AS success FROM queue
+          WHERE id = ANY($1) AND workspace_id = $2
+          UNION ALL
+         SELECT id, flow_status, success FROM completed_job
+          WHERE id = ANY($1) AND workspace_id = $2",
+        &ids,
+        &w_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    let mut statuses = rows
+        .into_iter()
+        .filter_map(|r| Some((r.id?, (r.flow_status, r.success))))
+        .collect::<HashMap<_, _>>();
+
+    let res = ids
+        .into_iter()
+        .map(|id| {
+            let status = match statuses.remove(&id) {
+                None => Err(format!("Flow {id} not found")),
+                Some((None, _)) => Err(format!("Job {id} is not a flow")),
+                Some((Some(flow_status), success)) => {
+                    serde_json::from_value::<FlowStatus>(flow_status)
+                        .map_err(|e| format!("Flow {id} has an invalid status: {e}"))
+                        .map(|status| FlowStatusSummary {
+                            completed: success.is_some(),
+                            success,
+                            step: status.step,
+                            module: status
+                                .current_step()
+                                .cloned()
+                                .unwrap_or(status.failure_module),
+                        })
+                }
+            };
+            match status {
+                Ok(status) => FlowStatusOrError { id, status: Some(status), error: None },
+                Err(error) => FlowStatusOrError { id, status: None, error: Some(error) },
+            }
+        })
+        .collect();
+    Ok(Json(res))
+}
+
+#[derive(Deserialize)]
+struct FlowStepsQuery {
+    nested: Option<bool>,
+}
+
+/// Streams a `FlowStepEvent` every time a module of the flow `id` completes, including the
+/// modules of its sub-flows with `nested`, until the flow itself is done. A slow or gone
+/// subscriber never holds back the flow as the events go through postgres notifications.
+async fn stream_flow_steps(
+    authed: Authed,
+    Extension(flow_step_events): Extension<Arc<FlowStepEvents>>,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, id)): Path<(String, Uuid)>,
+    Query(FlowStepsQuery { nested }): Query<FlowStepsQuery>,
+) -> error::Result<Sse<impl Stream<Item = Result<Event, serde_json::Error>>>> {
+    /* subscribe before checking that the flow is running to not miss its last step */
+    let events = flow_step_events.subscribe();
+
+    if !is_flow_queued(user_db.clone(), &authed, &w_id, id).await? {
+        return Err(Error::NotFound(format!("Flow {id} is not running")));
+    }
+
+    let nested = nested.unwrap_or(false);
+    let events = stream::unfold((events, false), move |(mut events, done)| {
+        let (user_db, authed, w_id) = (user_db.clone(), authed.clone(), w_id.clone());
+        async move {
+            if done {
+                return None;
+            }
+            loop {
+                match recv_flow_step(&mut events).await? {
+                    FlowStepUpdate::Event(e) if e.flow == id || (nested && e.root == id) => {
+                        let done = e.flow == id && e.done;
+                        return Some((e, (events, done)));
+                    }
+                    FlowStepUpdate::Event(_) => (),
+                    /* the last event may be among the missed ones, the stream then ends if the
+                     * flow already completed */
+                    FlowStepUpdate::Missed => {
+                        if !is_flow_queued(user_db.clone(), &authed, &w_id, id)
+                            .await
+                            .unwrap_or(false)
+                        {
+                            return None;
+                        }
+                    }
+                }
+            }
+        }
+    })
+    .map(|e| Event::default().event("step").json_data(e));
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
 fn list_completed_jobs_query(
     w_id: &str,
     per_page: usize,
@@ -1260,6 +2211,10 @@ async fn list_completed_jobs(
             "is_flow_step",
             "language",
             "is_skipped",
+            "flow_step_id",
+            "rerun_of",
+            "trigger_chain",
+            "trigger_depth",
         ],
     )
     .sql()?;
@@ -1334,3 +2289,291 @@ async fn delete_completed_job(
     tx.commit().await?;
     Ok(Json(job))
 }
+
+/// A top-level flow that failed for good in a workspace with `dead_letter` set
+#[derive(sqlx::FromRow, Serialize)]
+struct DeadLetterJob {
+    id: Uuid,
+    workspace_id: String,
+    job_kind: JobKind,
+    script_path: Option<String>,
+    raw_flow: Option<serde_json::Value>,
+    args: Option<serde_json::Value>,
+    flow_status: Option<serde_json::Value>,
+    error: Option<serde_json::Value>,
+    created_by: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+    /// the last job replaying this one
+    replayed_as: Option<Uuid>,
+}
+
+async fn list_dead_letter_jobs(
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    Query(pagination): Query<Pagination>,
+) -> error::JsonResult<Vec<DeadLetterJob>> {
+    let (per_page, offset) = paginate(pagination);
+    let jobs = sqlx::query_as::<_, DeadLetterJob>(
+        "SELECT * FROM dead_letter_job WHERE workspace_id = $1 \
+         ORDER BY created_at DESC LIMIT $2 OFFSET $3",
+    )
+    .bind(&w_id)
+    .bind(per_page as i64)
+    .bind(offset as i64)
+    .fetch_all(&db)
+    .await?;
+    Ok(Json(jobs))
+}
+
+async fn get_dead_letter_job(
+    Extension(db): Extension<DB>,
+    Path((w_id, id)): Path<(String, Uuid)>,
+) -> error::JsonResult<DeadLetterJob> {
+    let job_o = sqlx::query_as::<_, DeadLetterJob>(
+        "SELECT * FROM dead_letter_job WHERE id = $1 AND workspace_id = $2",
+    )
+    .bind(id)
+    .bind(&w_id)
+    .fetch_optional(&db)
+    .await?;
+    let job = not_found_if_none(job_o, "Dead letter job", id.to_string())?;
+    Ok(Json(job))
+}
+
+/// Pushes the flow of a dead letter job again, with its original args, as a rerun of it.
+async fn replay_dead_letter_job(
+    authed: Authed,
+    Tokened { token }: Tokened,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, id)): Path<(String, Uuid)>,
+) -> error::Result<(StatusCode, String)> {
+    let mut tx = user_db.begin(&authed).await?;
+    let job_o = sqlx::query_as::<_, DeadLetterJob>(
+        "SELECT * FROM dead_letter_job WHERE id = $1 AND workspace_id = $2 FOR UPDATE",
+    )
+    .bind(id)
+    .bind(&w_id)
+    .fetch_optional(&mut tx)
+    .await?;
+    let job = not_found_if_none(job_o, "Dead letter job", id.to_string())?;
+
+    let raw_flow = not_found_if_none(job.raw_flow, "Flow of job", id.to_string())?;
+    let value = serde_json::from_value::<FlowValue>(raw_flow).map_err(|err| {
+        Error::InternalErr(format!("could not convert json to flow for {id}: {err:?}"))
+    })?;
+    let args = match job.args {
+        Some(serde_json::Value::Object(args)) => Some(args),
+        _ => None,
+    };
+
+    let (uuid, mut tx) = push(
+        tx,
+        &w_id,
+        JobPayload::RawFlow { value, path: job.script_path },
+        args,
+        &authed.username,
+        owner_to_token_owner(&authed.username, false),
+        None,
+        None,
+        None,
+        false,
+        false,
+        None,
+        FlowStorage::default(),
+    )
+    .await?;
+    chain_trigger(&mut tx, uuid, &token).await?;
+
+    sqlx::query("UPDATE queue SET rerun_of = $1, job_kind = $2 WHERE id = $3")
+        .bind(id)
+        .bind(job.job_kind)
+        .bind(uuid)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query("UPDATE dead_letter_job SET replayed_as = $1 WHERE id = $2")
+        .bind(uuid)
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "jobs.dead_letter.replay",
+        ActionKind::Execute,
+        &w_id,
+        Some(&id.to_string()),
+        Some([("replayed_as", uuid.to_string().as_str())].into()),
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok((StatusCode::CREATED, uuid.to_string()))
+}
+
+async fn delete_dead_letter_job(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, id)): Path<(String, Uuid)>,
+) -> error::Result<String> {
+    let mut tx = user_db.begin(&authed).await?;
+
+    require_admin(authed.is_admin, &authed.username)?;
+    let deleted = sqlx::query_scalar::<_, Uuid>(
+        "DELETE FROM dead_letter_job WHERE id = $1 AND workspace_id = $2 RETURNING id",
+    )
+    .bind(id)
+    .bind(&w_id)
+    .fetch_optional(&mut tx)
+    .await?;
+    not_found_if_none(deleted, "Dead letter job", id.to_string())?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "jobs.dead_letter.delete",
+        ActionKind::Delete,
+        &w_id,
+        Some(&id.to_string()),
+        None,
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(format!("dead letter job {id} deleted"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use windmill_common::flow_status::{FieldChange, ModuleTransition, StepChange};
+
+    use super::*;
+
+    #[test]
+    fn diff_flow_status_snapshots() {
+        let job = "00000000-0000-0000-0000-000000000001";
+        let old: FlowStatus = serde_json::from_value(json!({
+            "step": 0,
+            "modules": [
+                { "type": "InProgress", "id": "a", "job": job },
+                { "type": "WaitingForPriorSteps", "id": "b" },
+            ],
+            "failure_module": { "type": "WaitingForPriorSteps", "id": "failure" },
+            "retry": { "fail_count": 1, "failed_jobs": [job] },
+        }))
+        .unwrap();
+        let new: FlowStatus = serde_json::from_value(json!({
+            "step": 1,
+            "modules": [
+                { "type": "Success", "id": "a", "job": job },
+                { "type": "WaitingForPriorSteps", "id": "b" },
+            ],
+            "failure_module": { "type": "WaitingForPriorSteps", "id": "failure" },
+        }))
+        .unwrap();
+
+        assert!(FlowStatusDiff::new(&old, &old).is_empty());
+        assert_eq!(
+            FlowStatusDiff {
+                step: Some(StepChange { old: 0, new: 1 }),
+                modules: vec![ModuleTransition {
+                    index: Some(0),
+                    id: "a".to_string(),
+                    from: "InProgress".to_string(),
+                    to: "Success".to_string(),
+                    changes: vec![],
+                }],
+                failure_module: None,
+                retry: vec![
+                    FieldChange {
+                        field: "fail_count".to_string(),
+                        old: Some(json!(1)),
+                        new: Some(json!(0))
+                    },
+                    FieldChange {
+                        field: "failed_jobs".to_string(),
+                        old: Some(json!([job])),
+                        new: Some(json!([])),
+                    },
+                ],
+            },
+            FlowStatusDiff::new(&old, &new)
+        );
+    }
+
+    #[test]
+    fn diff_skipped_step() {
+        let job = "00000000-0000-0000-0000-000000000001";
+        let status = |module: serde_json::Value| -> FlowStatus {
+            serde_json::from_value(json!({
+                "step": 0,
+                "modules": [module],
+                "failure_module": { "type": "WaitingForPriorSteps", "id": "failure" },
+            }))
+            .unwrap()
+        };
+        let old = status(json!({ "type": "WaitingForPriorSteps", "id": "a" }));
+        let new = status(json!({ "type": "Success", "id": "a", "job": job, "skipped": true }));
+
+        assert_eq!(
+            vec![ModuleTransition {
+                index: Some(0),
+                id: "a".to_string(),
+                from: "WaitingForPriorSteps".to_string(),
+                to: "Skipped".to_string(),
+                changes: vec![FieldChange {
+                    field: "job".to_string(),
+                    old: None,
+                    new: Some(json!(job)),
+                }],
+            }],
+            FlowStatusDiff::new(&old, &new).modules
+        );
+    }
+
+    #[test]
+    fn repair_corrupted_flow_status() {
+        let job = "00000000-0000-0000-0000-000000000001";
+        let flow: FlowValue = serde_json::from_value(json!({
+            "modules": [
+                { "id": "a", "value": { "type": "identity" } },
+                { "id": "b", "value": { "type": "identity" } },
+            ],
+        }))
+        .unwrap();
+
+        /* the module not reached yet, the failure module and the retry status are reset */
+        let repaired = FlowStatus::repair(
+            &json!({
+                "step": 0,
+                "modules": [{ "type": "InProgress", "id": "a", "job": job }, 42],
+                "retry": "oops",
+            }),
+            &flow,
+        )
+        .unwrap();
+        assert_eq!(
+            json!(repaired),
+            json!({
+                "step": 0,
+                "modules": [
+                    { "type": "InProgress", "id": "a", "job": job },
+                    { "type": "WaitingForPriorSteps", "id": "b" },
+                ],
+                "failure_module": { "type": "WaitingForPriorSteps", "id": "failure" },
+            })
+        );
+
+        /* there is no telling what happened to a module already reached */
+        assert!(FlowStatus::repair(
+            &json!({
+                "step": 1,
+                "modules": [{ "type": "Success", "id": "oops", "job": job }],
+            }),
+            &flow,
+        )
+        .is_none());
+        assert!(FlowStatus::repair(&json!({ "step": "oops" }), &flow).is_none());
+    }
+}
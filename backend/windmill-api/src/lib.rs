@@ -47,6 +47,7 @@ const GIT_VERSION: &str = git_version!(args = ["--tag", "--always"], fallback =
 struct BaseUrl(String);
 struct IsSecure(bool);
 struct CloudHosted(bool);
+struct TestActions(bool);
 
 pub use users::delete_expired_items_perdiodically;
 
@@ -59,6 +60,7 @@ pub async fn run_server(
     let user_db = UserDB::new(db.clone());
 
     let auth_cache = Arc::new(users::AuthCache::new(db.clone()));
+    let flow_step_events = Arc::new(jobs::FlowStepEvents::new(db.clone()));
     let argon2 = Arc::new(Argon2::default());
     let basic_clients = Arc::new(build_oauth_clients(&base_url).await?);
     let slack_verifier = Arc::new(
@@ -80,11 +82,18 @@ pub async fn run_server(
         .layer(Extension(db.clone()))
         .layer(Extension(user_db))
         .layer(Extension(auth_cache.clone()))
+        .layer(Extension(flow_step_events.clone()))
         .layer(Extension(basic_clients))
         .layer(Extension(Arc::new(BaseUrl(base_url.to_string()))))
         .layer(Extension(Arc::new(CloudHosted(
             std::env::var("CLOUD_HOSTED").is_ok(),
         ))))
+        .layer(Extension(Arc::new(TestActions(
+            std::env::var("ENABLE_TEST_ACTIONS")
+                .ok()
+                .and_then(|x| x.parse::<bool>().ok())
+                .unwrap_or(false),
+        ))))
         .layer(Extension(Arc::new(IsSecure(
             base_url.starts_with("https://"),
         ))))
@@ -153,6 +162,7 @@ pub async fn run_server(
         });
 
     tokio::spawn(async move { auth_cache.monitor().await });
+    tokio::spawn(async move { flow_step_events.monitor().await });
 
     server.await?;
     Ok(())
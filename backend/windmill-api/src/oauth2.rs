@@ -698,6 +698,8 @@ async fn slack_command(
                 None,
                 false,
                 false,
+                None,
+                windmill_queue::FlowStorage::default(),
             )
             .await?;
             tx.commit().await?;
@@ -6,9 +6,12 @@
  * LICENSE-AGPL for a copy of the license.
  */
 
+use std::collections::{HashMap, HashSet};
+
 use crate::{
     db::{UserDB, DB},
     users::Authed,
+    variables::{build_crypt, encrypt},
 };
 use axum::{
     extract::{Extension, Path, Query},
@@ -16,24 +19,34 @@ use axum::{
     Json, Router,
 };
 use hyper::StatusCode;
+use magic_crypt::{MagicCrypt256, MagicCryptTrait};
 use serde::{Deserialize, Serialize};
 use sql_builder::{bind::Bind, SqlBuilder};
-use sqlx::FromRow;
+use sqlx::{FromRow, Postgres, Transaction};
 use windmill_audit::{audit_log, ActionKind};
 use windmill_common::{
     error::{Error, JsonResult, Result},
+    json_schema::{validate_value, Violation},
     utils::{not_found_if_none, paginate, require_admin, Pagination, StripPath},
 };
 
 pub fn workspaced_service() -> Router {
     Router::new()
         .route("/list", get(list_resources))
+        .route("/list_orphans", get(list_orphan_resources))
+        .route("/search", post(search_resources))
         .route("/get/*path", get(get_resource))
         .route("/exists/*path", get(exists_resource))
         .route("/get_value/*path", get(get_resource_value))
+        .route("/get_values", post(get_resource_values))
+        .route("/get_value_batch", post(get_resource_value_batch))
         .route("/update/*path", post(update_resource))
         .route("/delete/*path", delete(delete_resource))
         .route("/create", post(create_resource))
+        .route("/import", post(import_resources))
+        .route("/clone", post(clone_resource))
+        .route("/diff", post(diff_resources))
+        .route("/validate", post(validate_resource))
         .route("/type/list", get(list_resource_types))
         .route("/type/listnames", get(list_resource_types_names))
         .route("/type/get/:name", get(get_resource_type))
@@ -49,6 +62,8 @@ pub struct ResourceType {
     pub name: String,
     pub schema: Option<serde_json::Value>,
     pub description: Option<String>,
+    /// fields the values of the resources of this type must have, and not empty
+    pub required_non_empty: Vec<String>,
 }
 
 #[derive(Deserialize)]
@@ -56,12 +71,15 @@ pub struct CreateResourceType {
     pub name: String,
     pub schema: Option<serde_json::Value>,
     pub description: Option<String>,
+    #[serde(default)]
+    pub required_non_empty: Vec<String>,
 }
 
 #[derive(Deserialize)]
 pub struct EditResourceType {
     pub schema: Option<serde_json::Value>,
     pub description: Option<String>,
+    pub required_non_empty: Option<Vec<String>>,
 }
 
 #[derive(FromRow, Serialize, Deserialize)]
@@ -73,6 +91,11 @@ pub struct Resource {
     pub resource_type: String,
     pub extra_perms: serde_json::Value,
     pub is_oauth: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// the value is stored encrypted with the key of the workspace, and decrypted when read
+    #[serde(default)]
+    pub encrypted: bool,
 }
 
 #[derive(Deserialize)]
@@ -82,28 +105,185 @@ pub struct CreateResource {
     pub description: Option<String>,
     pub resource_type: String,
     pub is_oauth: Option<bool>,
+    #[serde(default)]
+    pub encrypted: bool,
 }
 #[derive(Deserialize)]
 struct EditResource {
     path: Option<String>,
     description: Option<String>,
     value: Option<serde_json::Value>,
+    /// the current value is re-stored, encrypted or in plain, when only this changes
+    encrypted: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct WriteResourceQuery {
+    /// only check the required non-empty fields of the value, not the schema of its type
+    skip_validation: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct CloneResource {
+    source: String,
+    destination: String,
+    /// also give the destination the permissions shared on the source
+    #[serde(default)]
+    copy_extra_perms: bool,
+}
+
+#[derive(Deserialize)]
+struct ImportResourcesQuery {
+    /// only check the required non-empty fields of the values, not the schemas of their types
+    skip_validation: Option<bool>,
+    /// update the resources already at the path of an imported one instead of failing
+    upsert: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct DiffResources {
+    path: String,
+    other_path: Option<String>,
+    value: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ValueChange {
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ResourceDiff {
+    pub added: Vec<ValueChange>,
+    pub removed: Vec<ValueChange>,
+    pub changed: Vec<ValueChange>,
+}
+
+#[derive(Deserialize)]
+struct ValidateResource {
+    resource_type: Option<String>,
+    schema: Option<serde_json::Value>,
+    value: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct GetResourceValueQuery {
+    /// substitute the `${path}` placeholders of the string values with the variable at `path`
+    interpolate_vars: Option<bool>,
+    /// fail instead of leaving verbatim the placeholders referencing no readable variable
+    strict: Option<bool>,
+}
+
+const MAX_RESOURCE_VALUES: usize = 100;
+
+/// The value of a resource of a batch, or why it couldn't be read.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ResourceValueOrError {
+    Ok {
+        value: Option<serde_json::Value>,
+    },
+    NotFound,
+    /// the resource exists but the user isn't allowed to see it
+    Forbidden,
 }
 
 #[derive(Deserialize)]
 pub struct ListResourceQuery {
     resource_type: Option<String>,
+    min_perm: Option<MinPerm>,
+    /// lists the page after the resource it designates instead of the `page`-th one, empty for
+    /// the first page, see `list_resources`
+    cursor: Option<String>,
+}
+
+/// The permission levels on a resource, each one implying the ones before it. Those shared with
+/// `extra_perms` are granted `read` when the entry of the user or of one of their groups is
+/// `false`, `write` when it is `true`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MinPerm {
+    /// seeing the resource, which is all the row level security lets through
+    Read,
+    /// using its value in scripts and flows, granted along with `read` since the value of a
+    /// resource can be read by anyone who sees it
+    Use,
+    /// updating or deleting it: owning its path (`u/<user>`, or `g/<group>` for one of their
+    /// groups), having it shared with `true`, or being a workspace admin
+    Write,
 }
+
+/// The condition on the `resource` rows the user of `authed` can write, see `MinPerm::Write`.
+fn write_perm_filter(authed: &Authed) -> String {
+    if authed.is_admin {
+        return "workspace_id <> 'starter'".to_string();
+    }
+    /* the owners of paths are also the keys of extra_perms */
+    let owners = std::iter::once(format!("u/{}", authed.username))
+        .chain(authed.groups.iter().map(|g| format!("g/{g}")))
+        .map(sql_builder::quote)
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "workspace_id <> 'starter' AND (
+            SPLIT_PART(path, '/', 1) || '/' || SPLIT_PART(path, '/', 2) = ANY(ARRAY[{owners}])
+            OR EXISTS(SELECT 1 FROM jsonb_each_text(extra_perms)
+                       WHERE key = ANY(ARRAY[{owners}]) AND value::boolean))"
+    )
+}
+
 async fn list_resources(
     authed: Authed,
     Query(lq): Query<ListResourceQuery>,
     Query(pagination): Query<Pagination>,
     Extension(user_db): Extension<UserDB>,
     Path(w_id): Path<String>,
-) -> JsonResult<Vec<Resource>> {
+) -> Result<(
+    Option<[(&'static str, String); 1]>,
+    Option<[(&'static str, String); 1]>,
+    Json<Vec<Resource>>,
+)> {
     let (per_page, offset) = paginate(pagination);
 
     let mut sqlb = SqlBuilder::select_from("resource")
+        .and_where("workspace_id = ? OR workspace_id = 'starter'".bind(&w_id))
+        .clone();
+    if let Some(rt) = &lq.resource_type {
+        sqlb.and_where_eq("resource_type", "?".bind(rt));
+    }
+    if lq.min_perm == Some(MinPerm::Write) {
+        sqlb.and_where(write_perm_filter(&authed));
+    }
+
+    /* counted with the same filters as the listed rows, without the pagination */
+    let count_sql = sqlb
+        .clone()
+        .field("count(*)")
+        .sql()
+        .map_err(|e| Error::InternalErr(e.to_string()))?;
+    /* with a cursor, the page starts right after it in the order of the list instead of at an
+     * offset postgres would have to scan up to. A resource of the workspace and one of `starter`
+     * can share a path, so the cursor is the path and the workspace of the last resource. */
+    match lq.cursor.as_deref() {
+        Some("") => (),
+        Some(cursor) => {
+            let (path, workspace_id) = serde_json::from_str::<(String, String)>(cursor)
+                .map_err(|e| Error::BadRequest(format!("invalid cursor {cursor}: {e}")))?;
+            sqlb.and_where(
+                "(path, workspace_id) < (?, ?)"
+                    .bind(&path)
+                    .bind(&workspace_id),
+            );
+        }
+        None => {
+            sqlb.offset(offset);
+        }
+    }
+    let sql = sqlb
         .fields(&[
             "workspace_id",
             "path",
@@ -112,24 +292,154 @@ async fn list_resources(
             "resource_type",
             "extra_perms",
             "is_oauth",
+            "created_at",
+            "encrypted",
         ])
         .order_by("path", true)
-        .and_where("workspace_id = ? OR workspace_id = 'starter'".bind(&w_id))
-        .offset(offset)
+        .order_by("workspace_id", true)
         .limit(per_page)
-        .clone();
-    if let Some(rt) = &lq.resource_type {
-        sqlb.and_where_eq("resource_type", "?".bind(rt));
-    }
-
-    let sql = sqlb.sql().map_err(|e| Error::InternalErr(e.to_string()))?;
+        .sql()
+        .map_err(|e| Error::InternalErr(e.to_string()))?;
     let mut tx = user_db.begin(&authed).await?;
     let rows = sqlx::query_as::<_, Resource>(&sql)
         .fetch_all(&mut tx)
         .await?;
+    /* counting would scan all the rows the cursor is there to avoid */
+    let total = if lq.cursor.is_none() {
+        let total: i64 = sqlx::query_scalar(&count_sql).fetch_one(&mut tx).await?;
+        Some([("x-total-count", total.to_string())])
+    } else {
+        None
+    };
+
+    tx.commit().await?;
+
+    let next_cursor = rows
+        .last()
+        .filter(|_| lq.cursor.is_some() && rows.len() == per_page)
+        .map(|r| {
+            [(
+                "x-next-cursor",
+                serde_json::json!((&r.path, &r.workspace_id)).to_string(),
+            )]
+        });
+    Ok((total, next_cursor, Json(rows)))
+}
+
+#[derive(Deserialize)]
+struct SearchResources {
+    /// the value must contain this json, e.g. `{"host": "db.example.com"}`
+    value_contains: Option<serde_json::Value>,
+    /// the value must have an item matching this jsonpath, e.g.
+    /// `$.host ? (@ like_regex "example")`
+    value_jsonpath: Option<String>,
+    resource_type: Option<String>,
+}
+
+/// The paths of the resources of the workspace whose value matches all the matchers of
+/// `search`. Only the resources the user can see are searched, as their values are readable by
+/// them anyway, and the values themselves are never returned. The encrypted ones can't be
+/// matched in the database and are left out.
+async fn search_resources(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path(w_id): Path<String>,
+    Query(pagination): Query<Pagination>,
+    Json(search): Json<SearchResources>,
+) -> JsonResult<Vec<String>> {
+    if search.value_contains.is_none() && search.value_jsonpath.is_none() {
+        return Err(Error::BadRequest(
+            "a search needs value_contains or value_jsonpath".to_string(),
+        ));
+    }
+    let (per_page, offset) = paginate(pagination);
 
+    let mut tx = user_db.begin(&authed).await?;
+    let paths = sqlx::query_scalar!(
+        "SELECT path FROM resource
+          WHERE workspace_id = $1 AND NOT encrypted
+            AND ($2::JSONB IS NULL OR value @> $2)
+            AND ($3::TEXT IS NULL OR jsonb_path_exists(value, $3::TEXT::JSONPATH))
+            AND ($4::TEXT IS NULL OR resource_type = $4)
+          ORDER BY path LIMIT $5 OFFSET $6",
+        w_id,
+        search.value_contains,
+        search.value_jsonpath,
+        search.resource_type,
+        per_page as i64,
+        offset as i64
+    )
+    .fetch_all(&mut tx)
+    .await?;
     tx.commit().await?;
 
+    Ok(Json(paths))
+}
+
+#[derive(Deserialize)]
+struct ListOrphanResourcesQuery {
+    resource_type: Option<String>,
+    path_start: Option<String>,
+    /// resources created less than this many seconds ago are not listed, they may not be
+    /// referenced yet
+    min_age_secs: Option<i64>,
+}
+
+#[derive(FromRow, Serialize)]
+struct OrphanResource {
+    path: String,
+    resource_type: String,
+    description: Option<String>,
+    created_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+const DEFAULT_ORPHAN_MIN_AGE_SECS: i64 = 24 * 60 * 60;
+
+/// Lists the resources of the workspace that nothing references, as candidates for cleanup.
+///
+/// The matching is textual and best-effort: a resource is referenced when its path appears
+/// anywhere in the content of a script that isn't archived, the value of a flow that isn't
+/// archived, the args of a schedule or the value of another resource. A path that is a prefix
+/// of another referenced path counts as referenced too, and references built dynamically are
+/// missed, as are references stored in encrypted resource values, only their ciphertext being
+/// searched. Resources created before their creation date was recorded are considered old enough.
+async fn list_orphan_resources(
+    authed: Authed,
+    Query(lq): Query<ListOrphanResourcesQuery>,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+) -> JsonResult<Vec<OrphanResource>> {
+    require_admin(authed.is_admin, &authed.username)?;
+
+    let rows = sqlx::query_as!(
+        OrphanResource,
+        "SELECT r.path as \"path!\", r.resource_type, r.description, r.created_at
+           FROM resource r
+          WHERE r.workspace_id = $1
+            AND ($2::TEXT IS NULL OR r.resource_type = $2)
+            AND ($3::TEXT IS NULL OR starts_with(r.path, $3))
+            AND (r.created_at IS NULL OR r.created_at <= now() - make_interval(secs => $4))
+            AND NOT EXISTS (SELECT 1 FROM script s
+                             WHERE s.workspace_id = r.workspace_id AND NOT s.archived
+                               AND strpos(s.content, r.path) > 0)
+            AND NOT EXISTS (SELECT 1 FROM flow f
+                             WHERE f.workspace_id = r.workspace_id AND NOT f.archived
+                               AND strpos(f.value::TEXT, r.path) > 0)
+            AND NOT EXISTS (SELECT 1 FROM schedule sc
+                             WHERE sc.workspace_id = r.workspace_id
+                               AND strpos(sc.args::TEXT, r.path) > 0)
+            AND NOT EXISTS (SELECT 1 FROM resource o
+                             WHERE o.workspace_id = r.workspace_id AND o.path != r.path
+                               AND strpos(o.value::TEXT, r.path) > 0)
+       ORDER BY r.path",
+        &w_id,
+        lq.resource_type,
+        lq.path_start,
+        lq.min_age_secs.unwrap_or(DEFAULT_ORPHAN_MIN_AGE_SECS) as f64,
+    )
+    .fetch_all(&db)
+    .await?;
+
     Ok(Json(rows))
 }
 
@@ -150,9 +460,30 @@ async fn get_resource(
     )
     .fetch_optional(&mut tx)
     .await?;
+
+    let mut resource = not_found_if_none(resource_o, "Resource", path)?;
+    if resource.is_oauth || resource.encrypted {
+        audit_log(
+            &mut tx,
+            &authed.username,
+            "resources.get",
+            ActionKind::Read,
+            &w_id,
+            Some(path),
+            None,
+        )
+        .await?;
+    }
+    resource.value = read_value(
+        &mut tx,
+        &resource.workspace_id,
+        path,
+        resource.value.take(),
+        resource.encrypted,
+    )
+    .await?;
     tx.commit().await?;
 
-    let resource = not_found_if_none(resource_o, "Resource", path)?;
     Ok(Json(resource))
 }
 
@@ -178,42 +509,689 @@ async fn get_resource_value(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Path((w_id, path)): Path<(String, StripPath)>,
+    Query(q): Query<GetResourceValueQuery>,
 ) -> JsonResult<Option<serde_json::Value>> {
     let path = path.to_path();
     let mut tx = user_db.begin(&authed).await?;
 
-    let value_o = sqlx::query_scalar!(
-        "SELECT value from resource WHERE path = $1 AND (workspace_id = $2 OR workspace_id = \
-         'starter')",
+    let resource_o = sqlx::query!(
+        "SELECT workspace_id, value, encrypted, is_oauth from resource WHERE path = $1 AND \
+         (workspace_id = $2 OR workspace_id = 'starter')",
         path.to_owned(),
         &w_id
     )
     .fetch_optional(&mut tx)
     .await?;
+
+    let resource = not_found_if_none(resource_o, "Resource", path)?;
+    /* only the reads of sensitive values are audited, the others would flood the log */
+    if resource.is_oauth || resource.encrypted {
+        audit_log(
+            &mut tx,
+            &authed.username,
+            "resources.get_value",
+            ActionKind::Read,
+            &w_id,
+            Some(path),
+            None,
+        )
+        .await?;
+    }
+    let mut value = read_value(
+        &mut tx,
+        &resource.workspace_id,
+        path,
+        resource.value,
+        resource.encrypted,
+    )
+    .await?;
+    if let (Some(value), true) = (&mut value, q.interpolate_vars.unwrap_or(false)) {
+        let vars = get_interpolated_vars(
+            &mut tx,
+            &authed.username,
+            &w_id,
+            path,
+            value,
+            q.strict.unwrap_or(false),
+        )
+        .await?;
+        visit_strings(value, &mut |s| *s = interpolate(s, &vars));
+    }
     tx.commit().await?;
 
-    let value = not_found_if_none(value_o, "Resource", path)?;
     Ok(Json(value))
 }
 
+/// Values of many resources at once, keyed by path. They are all read in the same transaction,
+/// with the permissions of the user, so they are a consistent snapshot. Resources that can't be
+/// found, or that the user can't see, are reported as such instead of failing the whole request.
+async fn get_resource_values(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    Query(q): Query<GetResourceValueQuery>,
+    Json(paths): Json<Vec<String>>,
+) -> JsonResult<HashMap<String, ResourceValueOrError>> {
+    let mut tx = user_db.begin(&authed).await?;
+    let mut res = read_resource_values(&mut tx, &authed, &w_id, &paths, &q)
+        .await?
+        .into_iter()
+        .map(|(path, value)| (path, ResourceValueOrError::Ok { value }))
+        .collect::<HashMap<_, _>>();
+    tx.commit().await?;
+
+    /* the row level security hides the resources the user can't see */
+    let missing = paths
+        .into_iter()
+        .filter(|p| !res.contains_key(p))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        let hidden = sqlx::query_scalar!(
+            "SELECT path FROM resource WHERE path = ANY($1) AND workspace_id = $2",
+            &missing,
+            &w_id
+        )
+        .fetch_all(&db)
+        .await?;
+        for path in missing {
+            let status = if hidden.contains(&path) {
+                ResourceValueOrError::Forbidden
+            } else {
+                ResourceValueOrError::NotFound
+            };
+            res.insert(path, status);
+        }
+    }
+
+    Ok(Json(res))
+}
+
+/// Like `get_resource_values` but only the values are returned, the resources that can't be
+/// found or that the user can't see being left out of the map.
+async fn get_resource_value_batch(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path(w_id): Path<String>,
+    Query(q): Query<GetResourceValueQuery>,
+    Json(paths): Json<Vec<String>>,
+) -> JsonResult<HashMap<String, Option<serde_json::Value>>> {
+    let mut tx = user_db.begin(&authed).await?;
+    let res = read_resource_values(&mut tx, &authed, &w_id, &paths, &q).await?;
+    tx.commit().await?;
+
+    Ok(Json(res))
+}
+
+/// The values of the resources at `paths` the user of `tx` can see, with the same fallback on
+/// the starter workspace as `get_resource_value`.
+async fn read_resource_values<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    authed: &Authed,
+    w_id: &str,
+    paths: &[String],
+    q: &GetResourceValueQuery,
+) -> Result<HashMap<String, Option<serde_json::Value>>> {
+    if paths.len() > MAX_RESOURCE_VALUES {
+        return Err(Error::BadRequest(format!(
+            "at most {MAX_RESOURCE_VALUES} resource values can be requested at once"
+        )));
+    }
+
+    /* the resources of the workspace take precedence over the starter ones */
+    let rows = sqlx::query!(
+        "SELECT workspace_id, path, value, encrypted, is_oauth from resource WHERE path = \
+         ANY($1) AND (workspace_id = $2 OR workspace_id = 'starter') ORDER BY workspace_id = \
+         'starter' DESC",
+        paths,
+        w_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut resources = rows
+        .into_iter()
+        .map(|r| (r.path.clone(), r))
+        .collect::<HashMap<_, _>>();
+
+    let mut res = HashMap::new();
+    for path in paths {
+        let resource = match resources.remove(path) {
+            Some(resource) => resource,
+            None => continue,
+        };
+        /* audited one by one, like the reads of a single value */
+        if resource.is_oauth || resource.encrypted {
+            audit_log(
+                &mut *tx,
+                &authed.username,
+                "resources.get_value",
+                ActionKind::Read,
+                w_id,
+                Some(path),
+                None,
+            )
+            .await?;
+        }
+        let mut value = read_value(
+            tx,
+            &resource.workspace_id,
+            path,
+            resource.value,
+            resource.encrypted,
+        )
+        .await?;
+        if let (Some(value), true) = (&mut value, q.interpolate_vars.unwrap_or(false)) {
+            let vars = get_interpolated_vars(
+                tx,
+                &authed.username,
+                w_id,
+                path,
+                value,
+                q.strict.unwrap_or(false),
+            )
+            .await?;
+            visit_strings(value, &mut |s| *s = interpolate(s, &vars));
+        }
+        res.insert(path.clone(), value);
+    }
+
+    Ok(res)
+}
+
+/// The values of the variables referenced by the placeholders of `value`. The variables are read
+/// with the permissions of the caller, the secret ones are decrypted and audited like when read
+/// directly.
+async fn get_interpolated_vars<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    username: &str,
+    w_id: &str,
+    path: &str,
+    value: &mut serde_json::Value,
+    strict: bool,
+) -> Result<HashMap<String, String>> {
+    let mut var_paths = HashSet::new();
+    visit_strings(value, &mut |s| {
+        var_paths.extend(placeholders(s).into_iter().map(str::to_string))
+    });
+
+    let mut vars = HashMap::new();
+    let mut mc = None;
+    for var_path in var_paths {
+        let variable = sqlx::query!(
+            "SELECT value, is_secret FROM variable WHERE path = $1 AND (workspace_id = $2 OR \
+             (is_secret IS NOT TRUE AND workspace_id = 'starter')) LIMIT 1",
+            &var_path,
+            w_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let var_value = match variable {
+            Some(variable) if variable.is_secret => {
+                audit_log(
+                    &mut *tx,
+                    username,
+                    "variables.decrypt_secret",
+                    ActionKind::Execute,
+                    w_id,
+                    Some(&var_path),
+                    Some([("resource", path)].into()),
+                )
+                .await?;
+                if mc.is_none() {
+                    mc = Some(build_crypt(&mut *tx, w_id).await?);
+                }
+                mc.as_ref()
+                    .unwrap()
+                    .decrypt_base64_to_string(variable.value)
+                    .map_err(|e| Error::InternalErr(e.to_string()))?
+            }
+            Some(variable) => variable.value,
+            None if strict => {
+                return Err(Error::NotFound(format!(
+                    "Variable {var_path} referenced by resource {path}"
+                )))
+            }
+            None => continue,
+        };
+        vars.insert(var_path, var_value);
+    }
+    Ok(vars)
+}
+
+fn visit_strings(value: &mut serde_json::Value, f: &mut dyn FnMut(&mut String)) {
+    match value {
+        serde_json::Value::String(s) => f(s),
+        serde_json::Value::Array(values) => values.iter_mut().for_each(|v| visit_strings(v, f)),
+        serde_json::Value::Object(values) => values.values_mut().for_each(|v| visit_strings(v, f)),
+        _ => (),
+    }
+}
+
+/// The paths referenced by the `${path}` placeholders of `s`
+fn placeholders(s: &str) -> Vec<&str> {
+    let mut paths = vec![];
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        rest = &rest[start + 2..];
+        match rest.find('}') {
+            Some(end) => {
+                paths.push(&rest[..end]);
+                rest = &rest[end + 1..];
+            }
+            None => break,
+        }
+    }
+    paths
+}
+
+/// Substitutes the placeholders of `s` referencing `vars`, the others are left verbatim
+fn interpolate(s: &str, vars: &HashMap<String, String>) -> String {
+    let mut res = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        let (before, placeholder) = rest.split_at(start);
+        let end = match placeholder.find('}') {
+            Some(end) => end,
+            None => break,
+        };
+        res.push_str(before);
+        match vars.get(&placeholder[2..end]) {
+            Some(var) => res.push_str(var),
+            None => res.push_str(&placeholder[..=end]),
+        }
+        rest = &placeholder[end + 1..];
+    }
+    res.push_str(rest);
+    res
+}
+
+/// Diffs the value of the resource at `path` with either the value of the resource at
+/// `other_path` or a proposed `value`, e.g. to review an update before applying it.
+async fn diff_resources(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path(w_id): Path<String>,
+    Json(diff): Json<DiffResources>,
+) -> JsonResult<ResourceDiff> {
+    let mut tx = user_db.begin(&authed).await?;
+
+    let old = get_value(&mut tx, &w_id, &diff.path).await?;
+    let new = match (diff.other_path, diff.value) {
+        (Some(other_path), None) => get_value(&mut tx, &w_id, &other_path).await?,
+        (None, Some(value)) => value,
+        _ => {
+            return Err(Error::BadRequest(
+                "exactly one of other_path and value must be given".to_string(),
+            ))
+        }
+    };
+    tx.commit().await?;
+
+    let mut res = ResourceDiff::default();
+    diff_values(&mut res, "", false, &old, &new);
+    Ok(Json(res))
+}
+
+async fn get_value<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    w_id: &str,
+    path: &str,
+) -> Result<serde_json::Value> {
+    let resource_o = sqlx::query!(
+        "SELECT workspace_id, value, encrypted from resource WHERE path = $1 AND (workspace_id = \
+         $2 OR workspace_id = 'starter')",
+        path,
+        w_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let resource = not_found_if_none(resource_o, "Resource", path)?;
+    let value = read_value(
+        tx,
+        &resource.workspace_id,
+        path,
+        resource.value,
+        resource.encrypted,
+    )
+    .await?;
+    Ok(value.unwrap_or(serde_json::Value::Null))
+}
+
+/// The key of the workspace encrypting the values of its encrypted resources.
+async fn resource_crypt<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+) -> Result<MagicCrypt256> {
+    build_crypt(tx, w_id).await.map_err(|e| {
+        Error::InternalErr(format!(
+            "no encryption key to encrypt or decrypt the resource {path} with: {e}"
+        ))
+    })
+}
+
+/// `value` as stored in the `resource` table: encrypted when `encrypted`, as is otherwise.
+async fn stored_value<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+    value: Option<serde_json::Value>,
+    encrypted: bool,
+) -> Result<Option<serde_json::Value>> {
+    match value {
+        Some(value) if encrypted => {
+            let mc = resource_crypt(tx, w_id, path).await?;
+            Ok(Some(encrypt_resource_value(&mc, &value)))
+        }
+        value => Ok(value),
+    }
+}
+
+/// The value of a resource of `w_id` from its `stored_value`.
+async fn read_value<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+    value: Option<serde_json::Value>,
+    encrypted: bool,
+) -> Result<Option<serde_json::Value>> {
+    match value {
+        Some(value) if encrypted => {
+            let mc = resource_crypt(tx, w_id, path).await?;
+            Ok(Some(decrypt_resource_value(&mc, path, value)?))
+        }
+        value => Ok(value),
+    }
+}
+
+/// An encrypted value is stored as a json string, the encrypted json text of the value.
+pub fn encrypt_resource_value(mc: &MagicCrypt256, value: &serde_json::Value) -> serde_json::Value {
+    serde_json::Value::String(encrypt(mc, &value.to_string()))
+}
+
+pub fn decrypt_resource_value(
+    mc: &MagicCrypt256,
+    path: &str,
+    value: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let encrypted = value.as_str().ok_or_else(|| {
+        Error::InternalErr(format!(
+            "the value of the encrypted resource {path} is not an encrypted string"
+        ))
+    })?;
+    let decrypted = mc.decrypt_base64_to_string(encrypted).map_err(|e| {
+        Error::InternalErr(format!("decrypting the value of the resource {path}: {e}"))
+    })?;
+    serde_json::from_str(&decrypted).map_err(|e| {
+        Error::InternalErr(format!(
+            "the decrypted value of the resource {path} is not json: {e}"
+        ))
+    })
+}
+
+const REDACTED: &str = "<redacted>";
+
+/// Keys whose values are never returned by a diff, only the fact that they changed.
+fn is_secret_key(key: &str) -> bool {
+    let key = key.to_lowercase();
+    [
+        "password",
+        "secret",
+        "token",
+        "private_key",
+        "api_key",
+        "apikey",
+    ]
+    .iter()
+    .any(|s| key.contains(s))
+}
+
+/// Objects are compared key by key, any other value as a whole. Keys of nested values are
+/// joined with dots.
+fn diff_values(
+    res: &mut ResourceDiff,
+    key: &str,
+    secret: bool,
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+) {
+    use serde_json::Value;
+
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let mut keys = old.keys().chain(new.keys()).collect::<Vec<_>>();
+            keys.sort();
+            keys.dedup();
+            for k in keys {
+                let nkey = if key.is_empty() {
+                    k.to_string()
+                } else {
+                    format!("{key}.{k}")
+                };
+                let nsecret = secret || is_secret_key(k);
+                match (old.get(k), new.get(k)) {
+                    (Some(o), Some(n)) => diff_values(res, &nkey, nsecret, o, n),
+                    (Some(o), None) => res.removed.push(ValueChange {
+                        key: nkey,
+                        old: Some(redact(nsecret, o)),
+                        new: None,
+                    }),
+                    (None, Some(n)) => res.added.push(ValueChange {
+                        key: nkey,
+                        old: None,
+                        new: Some(redact(nsecret, n)),
+                    }),
+                    (None, None) => (),
+                }
+            }
+        }
+        (o, n) if o != n => res.changed.push(ValueChange {
+            key: key.to_string(),
+            old: Some(redact(secret, o)),
+            new: Some(redact(secret, n)),
+        }),
+        _ => (),
+    }
+}
+
+fn redact(secret: bool, value: &serde_json::Value) -> serde_json::Value {
+    if secret {
+        serde_json::Value::String(REDACTED.to_string())
+    } else {
+        value.clone()
+    }
+}
+
+async fn validate_resource(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path(w_id): Path<String>,
+    Json(validate): Json<ValidateResource>,
+) -> JsonResult<Vec<Violation>> {
+    let (schema, required_non_empty) = match (validate.resource_type, validate.schema) {
+        (Some(resource_type), None) => {
+            let mut tx = user_db.begin(&authed).await?;
+            let schema = get_resource_type_schema(&mut tx, &w_id, &resource_type).await?;
+            let required_non_empty =
+                get_resource_type_required_non_empty(&mut tx, &w_id, &resource_type).await?;
+            tx.commit().await?;
+            (schema, required_non_empty)
+        }
+        (None, Some(schema)) => (Some(schema), vec![]),
+        _ => {
+            return Err(Error::BadRequest(
+                "exactly one of resource_type and schema must be given".to_string(),
+            ))
+        }
+    };
+
+    Ok(Json(resource_value_violations(
+        &required_non_empty,
+        schema.as_ref(),
+        &validate.value,
+    )))
+}
+
+/// Checks `value` against the required non-empty fields of a resource type and then against its
+/// schema, violations of the schema on fields already reported aren't repeated.
+fn resource_value_violations(
+    required_non_empty: &[String],
+    schema: Option<&serde_json::Value>,
+    value: &serde_json::Value,
+) -> Vec<Violation> {
+    let mut violations = vec![];
+    check_required_non_empty(&mut violations, required_non_empty, value);
+    if let Some(schema) = schema {
+        let mut schema_violations = vec![];
+        validate_value(&mut schema_violations, "", schema, value);
+        for violation in schema_violations {
+            if !violations.iter().any(|v| v.key == violation.key) {
+                violations.push(violation);
+            }
+        }
+    }
+    violations
+}
+
+fn check_required_non_empty(
+    violations: &mut Vec<Violation>,
+    required_non_empty: &[String],
+    value: &serde_json::Value,
+) {
+    use serde_json::Value;
+
+    for field in required_non_empty {
+        let message = match value.get(field) {
+            None | Some(Value::Null) => "is required",
+            Some(Value::String(s)) if s.trim().is_empty() => "must not be empty",
+            Some(Value::Array(a)) if a.is_empty() => "must not be empty",
+            Some(Value::Object(o)) if o.is_empty() => "must not be empty",
+            Some(_) => continue,
+        };
+        violations.push(Violation { key: field.clone(), message: message.to_string() });
+    }
+}
+
+/// Refuses the value of a resource of type `resource_type` missing some of the fields the type
+/// requires to be non-empty or, unless `skip_validation`, not satisfying the schema of the type.
+/// A resource without value is only checked for the required fields.
+async fn require_valid_value<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    resource_type: &str,
+    value: Option<&serde_json::Value>,
+    skip_validation: bool,
+) -> Result<()> {
+    let required_non_empty = get_resource_type_required_non_empty(tx, w_id, resource_type).await?;
+    let schema = match value {
+        Some(_) if !skip_validation => find_resource_type_schema(tx, w_id, resource_type)
+            .await?
+            .flatten(),
+        _ => None,
+    };
+    let violations = resource_value_violations(
+        &required_non_empty,
+        schema.as_ref(),
+        value.unwrap_or(&serde_json::Value::Null),
+    );
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(format!(
+            "invalid value for a resource of type {resource_type}: {}",
+            violations
+                .iter()
+                .map(|v| if v.key.is_empty() {
+                    v.message.clone()
+                } else {
+                    format!("{} {}", v.key, v.message)
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        )))
+    }
+}
+
+/// An unknown resource type requires no field.
+async fn get_resource_type_required_non_empty<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    name: &str,
+) -> Result<Vec<String>> {
+    let required_non_empty = sqlx::query_scalar!(
+        "SELECT required_non_empty from resource_type WHERE name = $1 AND (workspace_id = $2 OR \
+         workspace_id = 'starter')",
+        name,
+        w_id
+    )
+    .fetch_optional(tx)
+    .await?;
+    Ok(required_non_empty.unwrap_or_default())
+}
+
+async fn get_resource_type_schema<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    w_id: &str,
+    name: &str,
+) -> Result<Option<serde_json::Value>> {
+    let schema_o = find_resource_type_schema(tx, w_id, name).await?;
+    not_found_if_none(schema_o, "ResourceType", name)
+}
+
+/// `None` for an unknown resource type.
+async fn find_resource_type_schema<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    w_id: &str,
+    name: &str,
+) -> Result<Option<Option<serde_json::Value>>> {
+    let schema_o = sqlx::query_scalar!(
+        "SELECT schema from resource_type WHERE name = $1 AND (workspace_id = $2 OR workspace_id \
+         = 'starter')",
+        name,
+        w_id
+    )
+    .fetch_optional(tx)
+    .await?;
+    Ok(schema_o)
+}
+
 async fn create_resource(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Path(w_id): Path<String>,
+    Query(q): Query<WriteResourceQuery>,
     Json(resource): Json<CreateResource>,
 ) -> Result<(StatusCode, String)> {
     let mut tx = user_db.begin(&authed).await?;
 
+    require_valid_value(
+        &mut tx,
+        &w_id,
+        &resource.resource_type,
+        resource.value.as_ref(),
+        q.skip_validation.unwrap_or(false),
+    )
+    .await?;
+    let value = stored_value(
+        &mut tx,
+        &w_id,
+        &resource.path,
+        resource.value,
+        resource.encrypted,
+    )
+    .await?;
+
     sqlx::query!(
         "INSERT INTO resource
-            (workspace_id, path, value, description, resource_type, is_oauth)
-            VALUES ($1, $2, $3, $4, $5, $6)",
+            (workspace_id, path, value, description, resource_type, is_oauth, encrypted)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
         w_id,
         resource.path,
-        resource.value,
+        value,
         resource.description,
         resource.resource_type,
-        resource.is_oauth.unwrap_or(false)
+        resource.is_oauth.unwrap_or(false),
+        resource.encrypted
     )
     .execute(&mut tx)
     .await?;
@@ -235,12 +1213,254 @@ async fn create_resource(
     ))
 }
 
+/// Copies the resource at `source`, of the workspace or of the starter one like [`get_resource`],
+/// to `destination` in the workspace, which must not exist yet.
+async fn clone_resource(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path(w_id): Path<String>,
+    Json(clone): Json<CloneResource>,
+) -> Result<(StatusCode, String)> {
+    let CloneResource { source, destination, copy_extra_perms } = clone;
+    let mut tx = user_db.begin(&authed).await?;
+
+    let resource_o = sqlx::query_as!(
+        Resource,
+        "SELECT * from resource WHERE path = $1 AND (workspace_id = $2 OR workspace_id = \
+         'starter')",
+        &source,
+        &w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let resource = not_found_if_none(resource_o, "Resource", &source)?;
+
+    let exists = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM resource WHERE path = $1 AND workspace_id = $2)",
+        &destination,
+        &w_id
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .unwrap_or(false);
+    if exists {
+        return Err(Error::BadRequest(format!(
+            "resource {destination} already exists"
+        )));
+    }
+
+    /* an encrypted value is re-encrypted for its new path */
+    let value = read_value(
+        &mut tx,
+        &resource.workspace_id,
+        &source,
+        resource.value,
+        resource.encrypted,
+    )
+    .await?;
+    let value = stored_value(&mut tx, &w_id, &destination, value, resource.encrypted).await?;
+
+    sqlx::query!(
+        "INSERT INTO resource
+            (workspace_id, path, value, description, resource_type, is_oauth, encrypted,
+             extra_perms)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        w_id,
+        destination,
+        value,
+        resource.description,
+        resource.resource_type,
+        resource.is_oauth,
+        resource.encrypted,
+        if copy_extra_perms {
+            resource.extra_perms
+        } else {
+            serde_json::json!({})
+        }
+    )
+    .execute(&mut tx)
+    .await?;
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "resources.clone",
+        ActionKind::Create,
+        &w_id,
+        Some(&destination),
+        Some([("source", source.as_str())].into()),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok((
+        StatusCode::CREATED,
+        format!("resource {source} cloned to {destination}"),
+    ))
+}
+
+/// Creates all the `resources` in a single transaction, e.g. to migrate them from another
+/// workspace. Nothing is imported when one of them can't be, the error naming which.
+async fn import_resources(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path(w_id): Path<String>,
+    Query(q): Query<ImportResourcesQuery>,
+    Json(resources): Json<Vec<CreateResource>>,
+) -> Result<(StatusCode, String)> {
+    let mut tx = user_db.begin(&authed).await?;
+
+    let (mut created, mut updated) = (0, 0);
+    for (i, resource) in resources.into_iter().enumerate() {
+        let path = resource.path.clone();
+        let inserted = import_resource(&mut tx, &w_id, resource, &q)
+            .await
+            .map_err(|e| {
+                Error::BadRequest(format!(
+                    "resource {i} at {path} could not be imported, none were: {e}"
+                ))
+            })?;
+        if inserted {
+            created += 1;
+        } else {
+            updated += 1;
+        }
+    }
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "resources.import",
+        ActionKind::Create,
+        &w_id,
+        None,
+        Some(
+            [
+                ("created", created.to_string().as_str()),
+                ("updated", updated.to_string().as_str()),
+            ]
+            .into(),
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok((
+        StatusCode::CREATED,
+        format!("{created} resources created and {updated} updated"),
+    ))
+}
+
+/// Stores one of the resources of `import_resources`, returning whether it was created rather
+/// than updated.
+async fn import_resource<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    resource: CreateResource,
+    q: &ImportResourcesQuery,
+) -> Result<bool> {
+    require_valid_value(
+        tx,
+        w_id,
+        &resource.resource_type,
+        resource.value.as_ref(),
+        q.skip_validation.unwrap_or(false),
+    )
+    .await?;
+    let value = stored_value(tx, w_id, &resource.path, resource.value, resource.encrypted).await?;
+
+    /* xmax is only set on the rows updated by the upsert */
+    let inserted = if q.upsert.unwrap_or(false) {
+        sqlx::query_scalar!(
+            r#"INSERT INTO resource
+                (workspace_id, path, value, description, resource_type, is_oauth, encrypted)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                ON CONFLICT (workspace_id, path) DO UPDATE
+                SET value = EXCLUDED.value, description = EXCLUDED.description,
+                    resource_type = EXCLUDED.resource_type, is_oauth = EXCLUDED.is_oauth,
+                    encrypted = EXCLUDED.encrypted
+                RETURNING xmax = 0 AS "inserted!""#,
+            w_id,
+            resource.path,
+            value,
+            resource.description,
+            resource.resource_type,
+            resource.is_oauth.unwrap_or(false),
+            resource.encrypted
+        )
+        .fetch_one(&mut *tx)
+        .await?
+    } else {
+        sqlx::query!(
+            "INSERT INTO resource
+                (workspace_id, path, value, description, resource_type, is_oauth, encrypted)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            w_id,
+            resource.path,
+            value,
+            resource.description,
+            resource.resource_type,
+            resource.is_oauth.unwrap_or(false),
+            resource.encrypted
+        )
+        .execute(&mut *tx)
+        .await?;
+        true
+    };
+
+    Ok(inserted)
+}
+
+#[derive(Deserialize)]
+struct DeleteResourceQuery {
+    /// delete the resource even if scripts, flows, schedules or other resources reference it
+    force: Option<bool>,
+}
+
+/// The scripts, flows, schedules and other resources referencing the resource at `path`, matched
+/// like in [`list_orphan_resources`], as `<kind> <path>`. The resources referencing it from an
+/// encrypted value are never found.
+async fn resource_dependents(db: &DB, w_id: &str, path: &str) -> Result<Vec<String>> {
+    let dependents = sqlx::query_scalar!(
+        "SELECT DISTINCT 'script ' || s.path FROM script s
+          WHERE s.workspace_id = $1 AND NOT s.archived AND strpos(s.content, $2) > 0
+         UNION
+         SELECT 'flow ' || f.path FROM flow f
+          WHERE f.workspace_id = $1 AND NOT f.archived AND strpos(f.value::TEXT, $2) > 0
+         UNION
+         SELECT 'schedule ' || sc.path FROM schedule sc
+          WHERE sc.workspace_id = $1 AND strpos(sc.args::TEXT, $2) > 0
+         UNION
+         SELECT 'resource ' || o.path FROM resource o
+          WHERE o.workspace_id = $1 AND o.path != $2 AND strpos(o.value::TEXT, $2) > 0
+         ORDER BY 1",
+        w_id,
+        path
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(dependents.into_iter().flatten().collect())
+}
+
 async fn delete_resource(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
+    Extension(db): Extension<DB>,
     Path((w_id, path)): Path<(String, StripPath)>,
+    Query(q): Query<DeleteResourceQuery>,
 ) -> Result<String> {
     let path = path.to_path();
+
+    /* unless forced, the dependents are looked up among all the items of the workspace, the
+     * ones the user can't see would break all the same */
+    if !q.force.unwrap_or(false) {
+        let dependents = resource_dependents(&db, &w_id, path).await?;
+        if !dependents.is_empty() {
+            return Err(Error::Conflict(format!(
+                "resource {path} is referenced by {}, delete it with force=true to delete it anyway",
+                dependents.join(", ")
+            )));
+        }
+    }
+
     let mut tx = user_db.begin(&authed).await?;
 
     sqlx::query!(
@@ -269,6 +1489,7 @@ async fn update_resource(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Path((w_id, path)): Path<(String, StripPath)>,
+    Query(q): Query<WriteResourceQuery>,
     Json(ns): Json<EditResource>,
 ) -> Result<String> {
     use sql_builder::prelude::*;
@@ -282,9 +1503,6 @@ async fn update_resource(
     if let Some(npath) = &ns.path {
         sqlb.set_str("path", npath);
     }
-    if let Some(nvalue) = ns.value {
-        sqlb.set_str("value", nvalue.to_string());
-    }
     if let Some(ndesc) = ns.description {
         sqlb.set_str("description", ndesc);
     }
@@ -293,6 +1511,37 @@ async fn update_resource(
 
     let mut tx = user_db.begin(&authed).await?;
 
+    if ns.value.is_some() || ns.encrypted.is_some() {
+        let resource_o = sqlx::query!(
+            "SELECT resource_type, value, encrypted FROM resource WHERE path = $1 AND \
+             workspace_id = $2",
+            path,
+            &w_id
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let resource = not_found_if_none(resource_o, "Resource", path)?;
+        let encrypted = ns.encrypted.unwrap_or(resource.encrypted);
+        let nvalue = match ns.value {
+            Some(nvalue) => {
+                require_valid_value(
+                    &mut tx,
+                    &w_id,
+                    &resource.resource_type,
+                    Some(&nvalue),
+                    q.skip_validation.unwrap_or(false),
+                )
+                .await?;
+                Some(nvalue)
+            }
+            None => read_value(&mut tx, &w_id, path, resource.value, resource.encrypted).await?,
+        };
+        if let Some(nvalue) = stored_value(&mut tx, &w_id, path, nvalue, encrypted).await? {
+            sqlb.set_str("value", nvalue.to_string());
+        }
+        sqlb.set("encrypted", encrypted);
+    }
+
     let sql = sqlb.sql().map_err(|e| Error::InternalErr(e.to_string()))?;
     let npath_o: Option<String> = sqlx::query_scalar(&sql).fetch_optional(&mut tx).await?;
 
@@ -392,12 +1641,13 @@ async fn create_resource_type(
 
     sqlx::query!(
         "INSERT INTO resource_type
-            (workspace_id, name, schema, description)
-            VALUES ($1, $2, $3, $4)",
+            (workspace_id, name, schema, description, required_non_empty)
+            VALUES ($1, $2, $3, $4, $5)",
         w_id,
         resource_type.name,
         resource_type.schema,
         resource_type.description,
+        &resource_type.required_non_empty,
     )
     .execute(&mut tx)
     .await?;
@@ -419,13 +1669,43 @@ async fn create_resource_type(
     ))
 }
 
+/// The type the resources of a deleted resource type are given, unknown types not being validated
+const PLACEHOLDER_RESOURCE_TYPE: &str = "untyped";
+
+#[derive(Deserialize)]
+struct DeleteResourceTypeQuery {
+    /// delete the resource type even if resources have it, they are given the placeholder type
+    force: Option<bool>,
+}
+
 async fn delete_resource_type(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
+    Extension(db): Extension<DB>,
     Path((w_id, name)): Path<(String, String)>,
+    Query(q): Query<DeleteResourceTypeQuery>,
 ) -> Result<String> {
     require_admin(authed.is_admin, &authed.username)?;
 
+    /* the resources are counted among all the ones of the workspace, like the dependents of a
+     * deleted resource. They keep a type when a starter type has the same name. */
+    let resources = sqlx::query_scalar!(
+        "SELECT count(*) FROM resource WHERE resource_type = $1 AND workspace_id = $2
+            AND EXISTS(SELECT 1 FROM resource_type WHERE name = $1 AND workspace_id = $2)
+            AND NOT EXISTS(SELECT 1 FROM resource_type WHERE name = $1 AND workspace_id = 'starter')",
+        name,
+        w_id
+    )
+    .fetch_one(&db)
+    .await?
+    .unwrap_or(0);
+    if resources > 0 && !q.force.unwrap_or(false) {
+        return Err(Error::Conflict(format!(
+            "resource_type {name} is the type of {resources} resources, delete it with force=true \
+             to delete it anyway, they are then given the type {PLACEHOLDER_RESOURCE_TYPE}"
+        )));
+    }
+
     let mut tx = user_db.begin(&authed).await?;
 
     sqlx::query!(
@@ -435,6 +1715,16 @@ async fn delete_resource_type(
     )
     .execute(&mut tx)
     .await?;
+    if resources > 0 {
+        sqlx::query!(
+            "UPDATE resource SET resource_type = $1 WHERE resource_type = $2 AND workspace_id = $3",
+            PLACEHOLDER_RESOURCE_TYPE,
+            name,
+            w_id
+        )
+        .execute(&mut tx)
+        .await?;
+    }
     audit_log(
         &mut tx,
         &authed.username,
@@ -442,7 +1732,7 @@ async fn delete_resource_type(
         ActionKind::Delete,
         &w_id,
         Some(&name),
-        None,
+        Some([("resources", resources.to_string().as_str())].into()),
     )
     .await?;
     tx.commit().await?;
@@ -467,6 +1757,19 @@ async fn update_resource_type(
     if let Some(ndesc) = ns.description {
         sqlb.set_str("description", ndesc);
     }
+    if let Some(nrequired) = ns.required_non_empty {
+        sqlb.set(
+            "required_non_empty",
+            format!(
+                "ARRAY[{}]::VARCHAR(255)[]",
+                nrequired
+                    .iter()
+                    .map(|f| sql_builder::quote(f))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        );
+    }
     let sql = sqlb.sql().map_err(|e| Error::InternalErr(e.to_string()))?;
     let mut tx = user_db.begin(&authed).await?;
 
@@ -485,3 +1788,202 @@ async fn update_resource_type(
 
     Ok(format!("resource_type {} updated", name))
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn interpolate_placeholders() {
+        let s = "https://${u/admin/region}.example.com/${f/app/missing}?${ unterminated";
+        assert_eq!(vec!["u/admin/region", "f/app/missing"], placeholders(s));
+
+        let vars = HashMap::from([("u/admin/region".to_string(), "eu-west-1".to_string())]);
+        assert_eq!(
+            "https://eu-west-1.example.com/${f/app/missing}?${ unterminated",
+            interpolate(s, &vars)
+        );
+        assert_eq!("no placeholder", interpolate("no placeholder", &vars));
+    }
+
+    #[test]
+    fn write_perm_filter_owners() {
+        let authed = |is_admin: bool| Authed {
+            email: None,
+            username: "o'brien".to_string(),
+            is_admin,
+            groups: vec!["ops".to_string()],
+        };
+        assert_eq!(
+            "workspace_id <> 'starter'",
+            write_perm_filter(&authed(true))
+        );
+
+        let filter = write_perm_filter(&authed(false));
+        assert_eq!(2, filter.matches("ARRAY['u/o''brien', 'g/ops']").count());
+        assert!(filter.contains("value::boolean"));
+    }
+
+    #[test]
+    fn validate_against_schema() {
+        let schema = json!({
+            "type": "object",
+            "required": ["host", "port"],
+            "properties": {
+                "host": { "type": "string" },
+                "port": { "type": "integer" },
+                "sslmode": { "type": "string", "enum": ["disable", "require"] },
+                "replicas": { "type": "array", "items": { "type": "string" } },
+            },
+        });
+        let violation = |key: &str, message: &str| Violation {
+            key: key.to_string(),
+            message: message.to_string(),
+        };
+
+        let mut violations = vec![];
+        validate_value(
+            &mut violations,
+            "",
+            &schema,
+            &json!({ "host": "localhost", "port": 5432, "replicas": ["a"], "extra": 1 }),
+        );
+        assert_eq!(Vec::<Violation>::new(), violations);
+
+        let mut violations = vec![];
+        validate_value(
+            &mut violations,
+            "",
+            &schema,
+            &json!({ "port": 54.32, "sslmode": "allow", "replicas": ["a", 2] }),
+        );
+        assert_eq!(
+            vec![
+                violation("host", "is required"),
+                violation("port", "expected integer, found number"),
+                violation("sslmode", "expected one of [\"disable\",\"require\"]"),
+                violation("replicas.1", "expected string, found number"),
+            ],
+            violations
+        );
+
+        let mut violations = vec![];
+        validate_value(&mut violations, "", &schema, &json!("localhost"));
+        assert_eq!(
+            vec![violation("", "expected object, found string")],
+            violations
+        );
+    }
+
+    #[test]
+    fn validate_required_non_empty() {
+        let required = vec![
+            "host".to_string(),
+            "password".to_string(),
+            "tags".to_string(),
+        ];
+        let schema = json!({
+            "type": "object",
+            "required": ["host"],
+            "properties": { "host": { "type": "string" }, "port": { "type": "integer" } },
+        });
+        let violation = |key: &str, message: &str| Violation {
+            key: key.to_string(),
+            message: message.to_string(),
+        };
+
+        assert_eq!(
+            Vec::<Violation>::new(),
+            resource_value_violations(
+                &required,
+                Some(&schema),
+                &json!({ "host": "localhost", "password": "hunter2", "tags": ["a"] })
+            )
+        );
+
+        /* the missing host is only reported once */
+        assert_eq!(
+            vec![
+                violation("host", "is required"),
+                violation("password", "must not be empty"),
+                violation("tags", "must not be empty"),
+                violation("port", "expected integer, found string"),
+            ],
+            resource_value_violations(
+                &required,
+                Some(&schema),
+                &json!({ "password": " ", "tags": [], "port": "5432" })
+            )
+        );
+
+        assert_eq!(
+            vec![
+                violation("host", "is required"),
+                violation("password", "is required"),
+                violation("tags", "is required"),
+            ],
+            resource_value_violations(&required, None, &json!(null))
+        );
+    }
+
+    #[test]
+    fn diff_redacts_secrets() {
+        let old = json!({
+            "host": "localhost",
+            "port": 5432,
+            "password": "hunter2",
+            "tls": { "enabled": false, "private_key": "abc" },
+            "user": "admin",
+        });
+        let new = json!({
+            "host": "db.internal",
+            "port": 5432,
+            "password": "hunter3",
+            "tls": { "enabled": true, "private_key": "def" },
+            "dbname": "prod",
+        });
+
+        let mut res = ResourceDiff::default();
+        diff_values(&mut res, "", false, &old, &new);
+
+        let change = |key: &str, old: Option<serde_json::Value>, new| ValueChange {
+            key: key.to_string(),
+            old,
+            new,
+        };
+        let redacted = Some(json!(REDACTED));
+        assert_eq!(
+            ResourceDiff {
+                added: vec![change("dbname", None, Some(json!("prod")))],
+                removed: vec![change("user", Some(json!("admin")), None)],
+                changed: vec![
+                    change("host", Some(json!("localhost")), Some(json!("db.internal"))),
+                    change("password", redacted.clone(), redacted.clone()),
+                    change("tls.enabled", Some(json!(false)), Some(json!(true))),
+                    change("tls.private_key", redacted.clone(), redacted),
+                ],
+            },
+            res
+        );
+    }
+
+    #[test]
+    fn encrypted_value_roundtrip() {
+        let mc = magic_crypt::new_magic_crypt!("workspace-key", 256);
+        let value = json!({ "host": "db.internal", "password": "hunter2" });
+
+        let stored = encrypt_resource_value(&mc, &value);
+        assert!(stored.is_string());
+        assert!(!stored.to_string().contains("hunter2"));
+        assert_eq!(
+            value,
+            decrypt_resource_value(&mc, "u/test/db", stored.clone()).unwrap()
+        );
+
+        let other = magic_crypt::new_magic_crypt!("other-key", 256);
+        assert!(decrypt_resource_value(&other, "u/test/db", stored).is_err());
+        assert!(decrypt_resource_value(&mc, "u/test/db", value).is_err());
+    }
+}
@@ -327,6 +327,8 @@ async fn create_script(
             None,
             false,
             false,
+            None,
+            windmill_queue::FlowStorage::default(),
         )
         .await?;
         tx
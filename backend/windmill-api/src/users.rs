@@ -1447,6 +1447,26 @@ pub async fn delete_expired_items_perdiodically(
             Err(e) => tracing::error!("Error deleting token: {}", e.to_string()),
         }
 
+        let blobs_deleted_r: std::result::Result<Vec<sqlx::types::Uuid>, _> =
+            sqlx::query_scalar("DELETE FROM job_blob WHERE expiration <= now() RETURNING id")
+                .fetch_all(db)
+                .await;
+
+        match blobs_deleted_r {
+            Ok(blobs) => tracing::debug!("deleted {} blobs: {:?}", blobs.len(), blobs),
+            Err(e) => tracing::error!("Error deleting blob: {}", e.to_string()),
+        }
+
+        let cached_deleted_r =
+            sqlx::query("DELETE FROM flow_module_cache WHERE expiration <= now()")
+                .execute(db)
+                .await;
+
+        match cached_deleted_r {
+            Ok(r) => tracing::debug!("deleted {} cached step results", r.rows_affected()),
+            Err(e) => tracing::error!("Error deleting cached step result: {}", e.to_string()),
+        }
+
         tokio::select! {
             _ = tokio::time::sleep(Duration::from_secs(600))     => (),
             _ = rx. recv() => {
@@ -8,9 +8,10 @@
 
 use crate::{
     db::{UserDB, DB},
-    resources::{Resource, ResourceType},
+    resources::{decrypt_resource_value, encrypt_resource_value, Resource, ResourceType},
     users::{Authed, WorkspaceInvite},
     utils::require_super_admin,
+    variables::encrypt,
 };
 use axum::{
     body::StreamBody,
@@ -30,6 +31,7 @@ use windmill_common::{
 };
 
 use hyper::{header, StatusCode};
+use magic_crypt::MagicCryptTrait;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use tempfile::TempDir;
@@ -45,6 +47,29 @@ pub fn workspaced_service() -> Router {
         .route("/delete_invite", post(delete_invite))
         .route("/get_settings", get(get_settings))
         .route("/edit_slack_command", post(edit_slack_command))
+        .route("/edit_max_resume_messages", post(edit_max_resume_messages))
+        .route("/edit_flow_same_worker", post(edit_flow_same_worker))
+        .route("/edit_max_flow_jobs", post(edit_max_flow_jobs))
+        .route("/edit_max_concurrent_jobs", post(edit_max_concurrent_jobs))
+        .route("/concurrent_jobs", get(get_concurrent_jobs))
+        .route(
+            "/edit_default_suspend_timeout",
+            post(edit_default_suspend_timeout),
+        )
+        .route("/edit_dead_letter", post(edit_dead_letter))
+        .route("/edit_encryption_key", post(edit_encryption_key))
+        .route(
+            "/list_cross_workspace_grants",
+            get(list_cross_workspace_grants),
+        )
+        .route(
+            "/add_cross_workspace_grant",
+            post(add_cross_workspace_grant),
+        )
+        .route(
+            "/delete_cross_workspace_grant",
+            post(delete_cross_workspace_grant),
+        )
         .route("/tarball", get(tarball_workspace))
 }
 
@@ -74,6 +99,12 @@ pub struct WorkspaceSettings {
     pub slack_team_id: Option<String>,
     pub slack_name: Option<String>,
     pub slack_command_script: Option<String>,
+    pub max_resume_messages: Option<i32>,
+    pub flow_same_worker: bool,
+    pub max_flow_jobs: Option<i32>,
+    pub max_concurrent_jobs: Option<i32>,
+    pub default_suspend_timeout: Option<i32>,
+    pub dead_letter: bool,
 }
 
 #[derive(sqlx::Type, Serialize, Deserialize, Debug)]
@@ -86,6 +117,64 @@ pub enum WorkspaceKeyKind {
 struct EditCommandScript {
     slack_command_script: Option<String>,
 }
+#[derive(Deserialize)]
+struct EditMaxResumeMessages {
+    max_resume_messages: Option<i32>,
+}
+#[derive(Deserialize)]
+struct EditFlowSameWorker {
+    flow_same_worker: bool,
+}
+#[derive(Deserialize)]
+struct EditMaxFlowJobs {
+    max_flow_jobs: Option<i32>,
+}
+#[derive(Deserialize)]
+struct EditMaxConcurrentJobs {
+    max_concurrent_jobs: Option<i32>,
+}
+#[derive(Deserialize)]
+struct EditDefaultSuspendTimeout {
+    default_suspend_timeout: Option<i32>,
+}
+#[derive(Deserialize)]
+struct EditDeadLetter {
+    dead_letter: bool,
+}
+#[derive(Serialize)]
+struct ConcurrentJobs {
+    /// the running jobs counted against `max_concurrent_jobs`
+    count: i64,
+    deferred: i64,
+    max_concurrent_jobs: Option<i32>,
+}
+#[derive(Deserialize)]
+struct EditEncryptionKey {
+    key: String,
+}
+
+const MIN_ENCRYPTION_KEY_LEN: usize = 16;
+const MAX_ENCRYPTION_KEY_LEN: usize = 255;
+/// Allows the deployed flow at `grantee_flow_path` of `grantee_workspace_id` to read the
+/// resource at `resource_path` of this workspace as `permissioned_as`.
+#[derive(Deserialize)]
+struct NewCrossWorkspaceGrant {
+    resource_path: String,
+    permissioned_as: String,
+    grantee_workspace_id: String,
+    grantee_flow_path: String,
+}
+
+#[derive(FromRow, Serialize)]
+struct CrossWorkspaceGrant {
+    resource_path: String,
+    permissioned_as: String,
+    grantee_workspace_id: String,
+    grantee_flow_path: String,
+    granted_by: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
 #[derive(Deserialize)]
 struct CreateWorkspace {
     id: String,
@@ -242,6 +331,393 @@ async fn edit_slack_command(
     Ok(format!("Edit command script {}", &w_id))
 }
 
+async fn edit_max_resume_messages(
+    authed: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    Authed { is_admin, username, .. }: Authed,
+    Json(em): Json<EditMaxResumeMessages>,
+) -> Result<String> {
+    require_admin(is_admin, &username)?;
+    if em.max_resume_messages.map_or(false, |max| max < 1) {
+        return Err(Error::BadRequest(
+            "max_resume_messages must be at least 1".to_string(),
+        ));
+    }
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        "UPDATE workspace_settings SET max_resume_messages = $1 WHERE workspace_id = $2",
+        em.max_resume_messages,
+        &w_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "workspaces.edit_max_resume_messages",
+        ActionKind::Update,
+        &w_id,
+        Some(&authed.email.unwrap()),
+        Some(
+            [(
+                "max_resume_messages",
+                em.max_resume_messages
+                    .map(|max| max.to_string())
+                    .unwrap_or("DEFAULT".to_string())
+                    .as_str(),
+            )]
+            .into(),
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!("Edit max resume messages {}", &w_id))
+}
+
+async fn edit_flow_same_worker(
+    authed: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    Authed { is_admin, username, .. }: Authed,
+    Json(ef): Json<EditFlowSameWorker>,
+) -> Result<String> {
+    require_admin(is_admin, &username)?;
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        "UPDATE workspace_settings SET flow_same_worker = $1 WHERE workspace_id = $2",
+        ef.flow_same_worker,
+        &w_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "workspaces.edit_flow_same_worker",
+        ActionKind::Update,
+        &w_id,
+        Some(&authed.email.unwrap()),
+        Some([("flow_same_worker", ef.flow_same_worker.to_string().as_str())].into()),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!("Edit flow same worker {}", &w_id))
+}
+
+async fn edit_max_flow_jobs(
+    authed: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    Authed { is_admin, username, .. }: Authed,
+    Json(em): Json<EditMaxFlowJobs>,
+) -> Result<String> {
+    require_admin(is_admin, &username)?;
+    if em.max_flow_jobs.map_or(false, |max| max < 1) {
+        return Err(Error::BadRequest(
+            "max_flow_jobs must be at least 1".to_string(),
+        ));
+    }
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        "UPDATE workspace_settings SET max_flow_jobs = $1 WHERE workspace_id = $2",
+        em.max_flow_jobs,
+        &w_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "workspaces.edit_max_flow_jobs",
+        ActionKind::Update,
+        &w_id,
+        Some(&authed.email.unwrap()),
+        Some(
+            [(
+                "max_flow_jobs",
+                em.max_flow_jobs
+                    .map(|max| max.to_string())
+                    .unwrap_or("DEFAULT".to_string())
+                    .as_str(),
+            )]
+            .into(),
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!("Edit max flow jobs {}", &w_id))
+}
+
+async fn edit_max_concurrent_jobs(
+    authed: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    Authed { is_admin, username, .. }: Authed,
+    Json(em): Json<EditMaxConcurrentJobs>,
+) -> Result<String> {
+    require_admin(is_admin, &username)?;
+    if em.max_concurrent_jobs.map_or(false, |max| max < 1) {
+        return Err(Error::BadRequest(
+            "max_concurrent_jobs must be at least 1".to_string(),
+        ));
+    }
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        "UPDATE workspace_settings SET max_concurrent_jobs = $1 WHERE workspace_id = $2",
+        em.max_concurrent_jobs,
+        &w_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "workspaces.edit_max_concurrent_jobs",
+        ActionKind::Update,
+        &w_id,
+        authed.email.as_deref(),
+        Some(
+            [(
+                "max_concurrent_jobs",
+                em.max_concurrent_jobs
+                    .map(|max| max.to_string())
+                    .unwrap_or("NONE".to_string())
+                    .as_str(),
+            )]
+            .into(),
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!("Edit max concurrent jobs {}", &w_id))
+}
+
+async fn edit_default_suspend_timeout(
+    authed: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    Authed { is_admin, username, .. }: Authed,
+    Json(et): Json<EditDefaultSuspendTimeout>,
+) -> Result<String> {
+    require_admin(is_admin, &username)?;
+    if et
+        .default_suspend_timeout
+        .map_or(false, |timeout| timeout < 1)
+    {
+        return Err(Error::BadRequest(
+            "default_suspend_timeout must be at least 1 second".to_string(),
+        ));
+    }
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        "UPDATE workspace_settings SET default_suspend_timeout = $1 WHERE workspace_id = $2",
+        et.default_suspend_timeout,
+        &w_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "workspaces.edit_default_suspend_timeout",
+        ActionKind::Update,
+        &w_id,
+        authed.email.as_deref(),
+        Some(
+            [(
+                "default_suspend_timeout",
+                et.default_suspend_timeout
+                    .map(|timeout| timeout.to_string())
+                    .unwrap_or("NONE".to_string())
+                    .as_str(),
+            )]
+            .into(),
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!("Edit default suspend timeout {}", &w_id))
+}
+
+async fn edit_dead_letter(
+    authed: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    Authed { is_admin, username, .. }: Authed,
+    Json(ed): Json<EditDeadLetter>,
+) -> Result<String> {
+    require_admin(is_admin, &username)?;
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        "UPDATE workspace_settings SET dead_letter = $1 WHERE workspace_id = $2",
+        ed.dead_letter,
+        &w_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "workspaces.edit_dead_letter",
+        ActionKind::Update,
+        &w_id,
+        authed.email.as_deref(),
+        Some([("dead_letter", ed.dead_letter.to_string().as_str())].into()),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!("Edit dead letter {}", &w_id))
+}
+
+/// The jobs of the workspace currently counted against its `max_concurrent_jobs`, to monitor how
+/// close it is to the limit.
+async fn get_concurrent_jobs(
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    Authed { is_admin, username, .. }: Authed,
+) -> JsonResult<ConcurrentJobs> {
+    require_admin(is_admin, &username)?;
+    let mut tx = db.begin().await?;
+    let count = windmill_queue::count_concurrent_jobs(&mut tx, &w_id).await?;
+    let deferred = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM queue WHERE workspace_id = $1 AND deferred AND scheduled_for > now()",
+        &w_id
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .unwrap_or(0);
+    let max_concurrent_jobs = sqlx::query_scalar!(
+        "SELECT max_concurrent_jobs FROM workspace_settings WHERE workspace_id = $1",
+        &w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .flatten();
+    tx.commit().await?;
+
+    Ok(Json(ConcurrentJobs {
+        count,
+        deferred,
+        max_concurrent_jobs,
+    }))
+}
+
+/// Replaces the key encrypting the secret variables and the encrypted resources of the
+/// workspace, re-encrypting them all with the new key in the same transaction. The key is set
+/// even when the workspace has none yet, as long as nothing needs to be re-encrypted.
+async fn edit_encryption_key(
+    authed: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    Authed { is_admin, username, .. }: Authed,
+    Json(ek): Json<EditEncryptionKey>,
+) -> Result<String> {
+    require_admin(is_admin, &username)?;
+    if !(MIN_ENCRYPTION_KEY_LEN..=MAX_ENCRYPTION_KEY_LEN).contains(&ek.key.len()) {
+        return Err(Error::BadRequest(format!(
+            "the encryption key must be between {MIN_ENCRYPTION_KEY_LEN} and \
+             {MAX_ENCRYPTION_KEY_LEN} characters long"
+        )));
+    }
+    let mut tx = db.begin().await?;
+
+    let old_key = sqlx::query_scalar!(
+        "SELECT key FROM workspace_key WHERE workspace_id = $1 AND kind = 'cloud'",
+        &w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let old_mc = old_key.map(|key| magic_crypt::new_magic_crypt!(key, 256));
+    let require_old_mc = |what: &str| {
+        old_mc.as_ref().ok_or_else(|| {
+            Error::InternalErr(format!(
+                "{what} can't be re-encrypted, the workspace has no encryption key"
+            ))
+        })
+    };
+    let new_mc = magic_crypt::new_magic_crypt!(&ek.key, 256);
+
+    let variables = sqlx::query!(
+        "SELECT path, value FROM variable WHERE workspace_id = $1 AND is_secret",
+        &w_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    for variable in variables {
+        let value = require_old_mc(&format!("the variable {}", variable.path))?
+            .decrypt_base64_to_string(variable.value)
+            .map_err(|e| {
+                Error::InternalErr(format!("decrypting the variable {}: {e}", variable.path))
+            })?;
+        sqlx::query!(
+            "UPDATE variable SET value = $1 WHERE workspace_id = $2 AND path = $3",
+            encrypt(&new_mc, &value),
+            &w_id,
+            variable.path
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+
+    let resources = sqlx::query!(
+        "SELECT path, value as \"value!\" FROM resource
+          WHERE workspace_id = $1 AND encrypted AND value IS NOT NULL",
+        &w_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    for resource in resources {
+        let old_mc = require_old_mc(&format!("the resource {}", resource.path))?;
+        let value = decrypt_resource_value(old_mc, &resource.path, resource.value)?;
+        sqlx::query!(
+            "UPDATE resource SET value = $1 WHERE workspace_id = $2 AND path = $3",
+            encrypt_resource_value(&new_mc, &value),
+            &w_id,
+            resource.path
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+
+    sqlx::query!(
+        "INSERT INTO workspace_key
+            (workspace_id, kind, key)
+            VALUES ($1, 'cloud', $2)
+            ON CONFLICT (workspace_id, kind) DO UPDATE SET key = EXCLUDED.key",
+        &w_id,
+        &ek.key
+    )
+    .execute(&mut tx)
+    .await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "workspaces.edit_encryption_key",
+        ActionKind::Update,
+        &w_id,
+        authed.email.as_deref(),
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!("Edit encryption key {}", &w_id))
+}
+
 async fn list_workspaces_as_super_admin(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
@@ -504,6 +980,147 @@ async fn delete_invite(
     ))
 }
 
+async fn list_cross_workspace_grants(
+    Authed { username, is_admin, .. }: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+) -> JsonResult<Vec<CrossWorkspaceGrant>> {
+    require_admin(is_admin, &username)?;
+    let rows = sqlx::query_as!(
+        CrossWorkspaceGrant,
+        "SELECT resource_path, permissioned_as, grantee_workspace_id, grantee_flow_path,
+                granted_by, created_at
+           FROM cross_workspace_grant
+          WHERE workspace_id = $1
+       ORDER BY resource_path, grantee_workspace_id, grantee_flow_path",
+        w_id
+    )
+    .fetch_all(&db)
+    .await?;
+    Ok(Json(rows))
+}
+
+async fn add_cross_workspace_grant(
+    Authed { username, is_admin, .. }: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    Json(ng): Json<NewCrossWorkspaceGrant>,
+) -> Result<(StatusCode, String)> {
+    require_admin(is_admin, &username)?;
+    if !ng.permissioned_as.starts_with("u/") && !ng.permissioned_as.starts_with("g/") {
+        return Err(Error::BadRequest(format!(
+            "permissioned_as must be a user (u/) or a group (g/), got {}",
+            ng.permissioned_as
+        )));
+    }
+
+    let mut tx = db.begin().await?;
+    let grantee_exists = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM workspace WHERE id = $1 AND deleted = false)",
+        ng.grantee_workspace_id
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .unwrap_or(false);
+    if !grantee_exists {
+        return Err(Error::NotFound(format!(
+            "workspace {} not found",
+            ng.grantee_workspace_id
+        )));
+    }
+
+    sqlx::query!(
+        "INSERT INTO cross_workspace_grant
+            (workspace_id, resource_path, permissioned_as, grantee_workspace_id,
+             grantee_flow_path, granted_by)
+            VALUES ($1, $2, $3, $4, $5, $6)
+         ON CONFLICT (workspace_id, resource_path, grantee_workspace_id, grantee_flow_path)
+         DO UPDATE SET permissioned_as = $3, granted_by = $6, created_at = now()",
+        &w_id,
+        ng.resource_path,
+        ng.permissioned_as,
+        ng.grantee_workspace_id,
+        ng.grantee_flow_path,
+        &username
+    )
+    .execute(&mut tx)
+    .await?;
+
+    audit_log(
+        &mut tx,
+        &username,
+        "workspaces.add_cross_workspace_grant",
+        ActionKind::Create,
+        &w_id,
+        Some(&ng.resource_path),
+        Some(
+            [
+                ("permissioned_as", ng.permissioned_as.as_str()),
+                ("grantee_workspace_id", ng.grantee_workspace_id.as_str()),
+                ("grantee_flow_path", ng.grantee_flow_path.as_str()),
+            ]
+            .into(),
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok((
+        StatusCode::CREATED,
+        format!(
+            "flow {} of workspace {} granted to read {} as {}",
+            ng.grantee_flow_path, ng.grantee_workspace_id, ng.resource_path, ng.permissioned_as
+        ),
+    ))
+}
+
+async fn delete_cross_workspace_grant(
+    Authed { username, is_admin, .. }: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    Json(ng): Json<NewCrossWorkspaceGrant>,
+) -> Result<String> {
+    require_admin(is_admin, &username)?;
+
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        "DELETE FROM cross_workspace_grant
+          WHERE workspace_id = $1 AND resource_path = $2 AND permissioned_as = $3
+            AND grantee_workspace_id = $4 AND grantee_flow_path = $5",
+        &w_id,
+        ng.resource_path,
+        ng.permissioned_as,
+        ng.grantee_workspace_id,
+        ng.grantee_flow_path
+    )
+    .execute(&mut tx)
+    .await?;
+
+    audit_log(
+        &mut tx,
+        &username,
+        "workspaces.delete_cross_workspace_grant",
+        ActionKind::Delete,
+        &w_id,
+        Some(&ng.resource_path),
+        Some(
+            [
+                ("permissioned_as", ng.permissioned_as.as_str()),
+                ("grantee_workspace_id", ng.grantee_workspace_id.as_str()),
+                ("grantee_flow_path", ng.grantee_flow_path.as_str()),
+            ]
+            .into(),
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!(
+        "grant of {} to flow {} of workspace {} deleted",
+        ng.resource_path, ng.grantee_flow_path, ng.grantee_workspace_id
+    ))
+}
+
 async fn exists_username(
     Extension(db): Extension<DB>,
     Json(vu): Json<ValidateUsername>,
@@ -602,7 +1219,12 @@ async fn tarball_workspace(
         .fetch_all(&db)
         .await?;
 
-        for resource in resources {
+        for mut resource in resources {
+            /* the values of encrypted resources are left out of the export, the resources
+             * themselves are kept with their `encrypted` flag so that it shows what it lacks */
+            if resource.encrypted {
+                resource.value = None;
+            }
             let resource_str = serde_json::to_string_pretty(&resource).unwrap();
             write_to_archive(
                 resource_str,
@@ -26,6 +26,7 @@ pub enum ActionKind {
     Update,
     Delete,
     Execute,
+    Read,
 }
 
 #[derive(FromRow, Serialize, Deserialize)]
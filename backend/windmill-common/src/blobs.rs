@@ -0,0 +1,38 @@
+/*
+ * Author: Ruben Fiszel
+ * Copyright: Windmill Labs, Inc 2022
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+//! Large or binary payloads are passed between the steps of a flow as references to blobs
+//! instead of being inlined in the results, which end up in `flow_status` and `completed_job`.
+//!
+//! A step uploads its payload to `jobs/blobs/upload/{job}` and returns the `BlobRef` it gets
+//! back (as is or nested in its result). The reference is a plain json value: it goes through
+//! `last_result`, the input transforms and the args of the next jobs like any other, and is
+//! only resolved by whoever needs the payload, either with `jobs/blobs/get/{id}` or with the
+//! `blob(ref)` function of the input transforms which returns it base64 encoded.
+//!
+//! Blobs belong to the root flow of the job that uploaded them and are deleted once they
+//! expire, `BLOB_TTL_SECS` seconds after their upload (7 days by default), whether or not the
+//! flow completed. A reference outliving its blob resolves to a not found error.
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const DEFAULT_BLOB_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+
+pub fn blob_ttl_secs() -> i64 {
+    std::env::var("BLOB_TTL_SECS")
+        .ok()
+        .and_then(|x| x.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_BLOB_TTL_SECS)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BlobRef {
+    #[serde(rename = "$blob")]
+    pub id: Uuid,
+}
@@ -45,6 +45,12 @@ pub enum Error {
     SqlErr(#[from] sqlx::Error),
     #[error("Bad request: {0}")]
     BadRequest(String),
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Request timeout: {0}")]
+    RequestTimeout(String),
     #[error("Internal: {0}")]
     InternalErr(String),
     #[error("Hexadecimal decoding error: {0}")]
@@ -54,6 +60,8 @@ pub enum Error {
     DatabaseMigration(#[from] MigrateError),
     #[error("Non-zero exit status: {0}")]
     ExitStatus(i32),
+    #[error("Job process killed after its timeout of {0}s")]
+    ExecutionTimeout(u64),
     #[error(transparent)]
     Anyhow(#[from] anyhow::Error),
 }
@@ -78,6 +86,9 @@ impl IntoResponse for Error {
             Self::NotFound(_) => axum::http::StatusCode::NOT_FOUND,
             Self::NotAuthorized(_) => axum::http::StatusCode::UNAUTHORIZED,
             Self::SqlErr(_) | Self::BadRequest(_) => axum::http::StatusCode::BAD_REQUEST,
+            Self::TooManyRequests(_) => axum::http::StatusCode::TOO_MANY_REQUESTS,
+            Self::Conflict(_) => axum::http::StatusCode::CONFLICT,
+            Self::RequestTimeout(_) => axum::http::StatusCode::REQUEST_TIMEOUT,
             _ => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
         };
         tracing::error!(error = e.to_string());
@@ -0,0 +1,196 @@
+/*
+ * Author: Ruben Fiszel
+ * Copyright: Windmill Labs, Inc 2022
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+//! Estimates how many jobs a flow spawns before running it, so that flows with large loops can
+//! be gated before they flood the queue.
+//!
+//! Nothing is run: forloop iterators and branchone predicates are evaluated against the flow
+//! input only. The ones depending on anything else (the results of prior steps, the iterated
+//! value of an enclosing loop, ...) can't be evaluated ahead of time and make the estimate
+//! `dynamic`, its count then being a lower bound. How the iterators and the predicates are
+//! evaluated is up to a `FanOutEval`, the workers evaluating the javascript ones.
+
+use async_recursion::async_recursion;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::flows::{FlowModule, FlowModuleValue, FlowValue, LoopIterator};
+
+/// Evaluates the iterators and the predicates of a flow against its input. `flow_input` is
+/// `None` in sub-flows, whose input is only known at runtime.
+pub trait FanOutEval: Sync {
+    /// the values a forloop iterates over
+    fn iterate<'a>(
+        &'a self,
+        iterator: &'a LoopIterator,
+        flow_input: Option<&'a Value>,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<Value>>>;
+
+    /// whether the branch with the predicate `expr` is chosen
+    fn predicate<'a>(
+        &'a self,
+        expr: &'a str,
+        flow_input: Option<&'a Value>,
+    ) -> BoxFuture<'a, anyhow::Result<bool>>;
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct FanOutEstimate {
+    /// the flow job itself included
+    pub jobs: usize,
+    pub dynamic: bool,
+    pub modules: Vec<ModuleFanOut>,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct ModuleFanOut {
+    pub id: String,
+    /// the jobs of the nested modules included
+    pub jobs: usize,
+    pub dynamic: bool,
+}
+
+impl ModuleFanOut {
+    fn add(&mut self, other: &ModuleFanOut) {
+        self.jobs = self.jobs.saturating_add(other.jobs);
+        self.dynamic |= other.dynamic;
+    }
+}
+
+/// Walks `flow` and counts the jobs running it with `flow_input` would spawn. Retries and the
+/// failure module are left out since they only run on failures, polled modules are counted once
+/// and marked `dynamic`.
+pub async fn estimate_fan_out(
+    flow: &FlowValue,
+    flow_input: &Value,
+    eval: &dyn FanOutEval,
+) -> FanOutEstimate {
+    let mut modules = Vec::with_capacity(flow.modules.len());
+    for module in &flow.modules {
+        modules.push(estimate_module(module, Some(flow_input), eval).await);
+    }
+    let mut total = ModuleFanOut { jobs: 1, ..Default::default() };
+    modules.iter().for_each(|m| total.add(m));
+    FanOutEstimate { jobs: total.jobs, dynamic: total.dynamic, modules }
+}
+
+/// `flow_input` is `None` in sub-flows, whose input is only known at runtime.
+#[async_recursion]
+async fn estimate_modules(
+    modules: &[FlowModule],
+    flow_input: Option<&Value>,
+    eval: &dyn FanOutEval,
+) -> ModuleFanOut {
+    let mut estimate = ModuleFanOut::default();
+    for module in modules {
+        estimate.add(&estimate_module(module, flow_input, eval).await);
+    }
+    estimate
+}
+
+async fn estimate_module(
+    module: &FlowModule,
+    flow_input: Option<&Value>,
+    eval: &dyn FanOutEval,
+) -> ModuleFanOut {
+    let mut estimate = ModuleFanOut {
+        id: module.id.clone(),
+        dynamic: module.poll.is_some(),
+        ..Default::default()
+    };
+
+    match &module.value {
+        FlowModuleValue::Script { .. }
+        | FlowModuleValue::RawScript { .. }
+        | FlowModuleValue::Identity { .. }
+        | FlowModuleValue::Collector { .. } => estimate.jobs = 1,
+        /* every iteration runs the loop body in a sub-flow */
+        FlowModuleValue::ForloopFlow { iterator, modules, .. } => {
+            let body = estimate_modules(modules, None, eval).await;
+            let iterations = eval.iterate(iterator, flow_input).await;
+            match iterations {
+                Ok(itered) => {
+                    estimate.jobs = body.jobs.saturating_add(1).saturating_mul(itered.len());
+                    estimate.dynamic |= body.dynamic && !itered.is_empty();
+                }
+                Err(_) => estimate.dynamic = true,
+            }
+        }
+        /* the number of passes is only known once the stop condition is met, at least one runs */
+        FlowModuleValue::WhileloopFlow { modules, .. } => {
+            let body = estimate_modules(modules, None, eval).await;
+            estimate.jobs = body.jobs.saturating_add(1);
+            estimate.dynamic = true;
+        }
+        /* the chosen branch runs in a sub-flow, the largest of the branches that may be chosen is
+         * counted when the predicates can't be evaluated */
+        /* with `match_all`, the matching branches all run, each in its own sub-flow within the
+         * sub-flow of the step. The ones whose predicate can't be evaluated may run too */
+        FlowModuleValue::BranchOne { branches, default, match_all: true } => {
+            let mut matched = ModuleFanOut::default();
+            let mut undecided = false;
+            for branch in branches {
+                let pred = eval.predicate(&branch.expr, flow_input).await;
+                match pred {
+                    Ok(false) => (),
+                    Ok(true) => {
+                        let mut branch = estimate_modules(&branch.modules, None, eval).await;
+                        branch.jobs = branch.jobs.saturating_add(1);
+                        matched.add(&branch);
+                    }
+                    _ => undecided = true,
+                }
+            }
+            if matched.jobs == 0 && !undecided {
+                matched = estimate_modules(default, None, eval).await;
+            }
+            matched.dynamic |= undecided;
+            estimate.jobs = matched.jobs.saturating_add(1);
+            estimate.dynamic |= matched.dynamic;
+        }
+        FlowModuleValue::BranchOne { branches, default, .. } => {
+            let mut candidates = vec![];
+            let mut decided = false;
+            for branch in branches {
+                let pred = eval.predicate(&branch.expr, flow_input).await;
+                match pred {
+                    Ok(false) => (),
+                    Ok(true) => {
+                        candidates.push(&branch.modules);
+                        decided = true;
+                        break;
+                    }
+                    _ => candidates.push(&branch.modules),
+                }
+            }
+            if !decided {
+                candidates.push(default);
+            }
+
+            let mut largest = ModuleFanOut { dynamic: candidates.len() > 1, ..Default::default() };
+            for modules in candidates {
+                let branch = estimate_modules(modules, None, eval).await;
+                largest.jobs = largest.jobs.max(branch.jobs);
+                largest.dynamic |= branch.dynamic;
+            }
+            estimate.jobs = largest.jobs.saturating_add(1);
+            estimate.dynamic |= largest.dynamic;
+        }
+        /* every branch runs in its own sub-flow */
+        FlowModuleValue::BranchAll { branches, .. } => {
+            for branch in branches {
+                let mut branch = estimate_modules(&branch.modules, None, eval).await;
+                branch.jobs = branch.jobs.saturating_add(1);
+                estimate.add(&branch);
+            }
+        }
+    }
+
+    estimate
+}
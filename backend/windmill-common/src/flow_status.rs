@@ -6,7 +6,7 @@
  * LICENSE-AGPL for a copy of the license.
  */
 
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -27,6 +27,46 @@ pub struct FlowStatus {
     #[serde(default)]
     #[serde(skip_serializing_if = "is_default")]
     pub retry: RetryStatus,
+    /// when set, the `flow_jobs` of the modules are kept in the `flow_job` table
+    /// instead of inline, see `windmill_queue::flow_jobs`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub flow_jobs_in_table: bool,
+    /// when set, the values iterated over by the forloops are kept in the `flow_iterator` table
+    /// instead of inline, see `windmill_queue::flow_iterators`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub iterators_in_table: bool,
+    /// the summaries of the modules that have one, keyed by module id
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub summaries: HashMap<String, String>,
+    /// the result of the flow before its `output_transform`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_result: Option<serde_json::Value>,
+}
+
+/// Postgres channel on which a `FlowStepEvent` is notified every time a module of a flow
+/// completes.
+pub const FLOW_STEP_CHANNEL: &str = "flow_step";
+
+/// Notifications are limited to 8000 bytes, bigger results are left out.
+pub const MAX_FLOW_STEP_EVENT_RESULT_SIZE: usize = 6000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FlowStepEvent {
+    pub flow: Uuid,
+    /// the top-level flow, see `QueuedJob::trace_id`
+    pub root: Uuid,
+    pub module_id: String,
+    pub job: Uuid,
+    pub success: bool,
+    pub result: Option<serde_json::Value>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub result_truncated: bool,
+    /// the flow has no more steps to run
+    pub done: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
@@ -35,12 +75,46 @@ pub struct RetryStatus {
     pub fail_count: u16,
     pub previous_result: Option<serde_json::Value>,
     pub failed_jobs: Vec<Uuid>,
+    /// when the current step first failed, see `Retry::min_seconds_since_first_failure`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_failure_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// when the current step stops being polled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll_until: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Iterator {
     pub index: usize,
     pub itered: Vec<serde_json::Value>,
+    /// how many values are iterated over when they are kept in the `flow_iterator` table,
+    /// `itered` then being empty
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub itered_len: Option<usize>,
+    /// the jobs of the window of iterations of a parallel loop that haven't completed yet,
+    /// `index` being the last iteration of the window
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub running: Vec<Uuid>,
+    /// the first job of the window of a parallel loop that failed, the loop fails with it once
+    /// the whole window completed unless failures are skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed: Option<Uuid>,
+    /// the lowest index of the iterations of the window whose result met the `break_if` of the
+    /// loop, the loop stops after it once the whole window completed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub break_at: Option<usize>,
+    /// the passes a whileloop may run without its stop condition being met, read from the
+    /// module when the loop starts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_iterations: Option<u32>,
+}
+
+impl Iterator {
+    /// The number of values iterated over, wherever they are kept.
+    pub fn length(&self) -> usize {
+        self.itered_len.unwrap_or(self.itered.len())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -48,6 +122,15 @@ pub struct BranchAllStatus {
     pub branch: usize,
     pub previous_result: serde_json::Value,
     pub len: usize,
+    /// the jobs of the branches of a parallel branchall that haven't completed yet, `branch`
+    /// being the last branch
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub running: Vec<Uuid>,
+    /// the first job of a branch of a parallel branchall that failed without skipping its
+    /// failure, the step fails with it once all the branches completed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed: Option<Uuid>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -56,14 +139,51 @@ pub struct BranchAllStatus {
     rename_all(serialize = "lowercase", deserialize = "lowercase")
 )]
 pub enum BranchChosen {
-    Default,
-    Branch { branch: usize },
+    Default {
+        /// always `default`, for the branch taken to read the same whichever it is
+        #[serde(default = "default_branch_label")]
+        label: String,
+    },
+    Branch {
+        branch: usize,
+        /// the label of the branch, if it has one
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        label: Option<String>,
+    },
+    /// with `match_all`, the branches whose predicate was true, all run
+    Branches { branches: Vec<usize> },
+}
+
+pub const DEFAULT_BRANCH_LABEL: &str = "default";
+
+fn default_branch_label() -> String {
+    DEFAULT_BRANCH_LABEL.to_string()
+}
+
+impl BranchChosen {
+    pub fn default_branch() -> Self {
+        Self::Default { label: default_branch_label() }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Approval {
     pub resume_id: u16,
     pub approver: String,
+    /// when the resume message was received, missing from the approvals of older flows
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub approved_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// An approval of a suspended step along with the payload submitted with it. The step resumed
+/// by them gets them, in the order they were received, as `approvals` in its input transforms.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApprovalResult {
+    #[serde(flatten)]
+    pub approval: Approval,
+    pub payload: serde_json::Value,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -76,6 +196,19 @@ pub enum FlowStatusModule {
         id: String,
         count: u16,
         job: Uuid,
+        /// the suspend timed out and the flow waits for the `grace_period` of the suspend
+        #[serde(default)]
+        #[serde(skip_serializing_if = "is_default")]
+        in_grace_period: bool,
+        /// when the flow started waiting for the resume messages
+        #[serde(skip_serializing_if = "Option::is_none")]
+        suspended_at: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    /// the `poll` condition wasn't met by the result of `job`, the step is run again once the
+    /// poll interval elapsed
+    WaitingForCondition {
+        id: String,
+        job: Uuid,
     },
     WaitingForExecutor {
         id: String,
@@ -92,6 +225,12 @@ pub enum FlowStatusModule {
         branch_chosen: Option<BranchChosen>,
         #[serde(skip_serializing_if = "Option::is_none")]
         branchall: Option<BranchAllStatus>,
+        /// the job of the step that failed, `job` running the catch modules of the step
+        #[serde(skip_serializing_if = "Option::is_none")]
+        catching: Option<Uuid>,
+        /// when the first job of the step started
+        #[serde(skip_serializing_if = "Option::is_none")]
+        started_at: Option<chrono::DateTime<chrono::Utc>>,
     },
     Success {
         id: String,
@@ -103,6 +242,19 @@ pub enum FlowStatusModule {
         #[serde(default)]
         #[serde(skip_serializing_if = "Vec::is_empty")]
         approvers: Vec<Approval>,
+        /// set when the step failed but its `partial_result` was used as its output
+        #[serde(skip_serializing_if = "Option::is_none")]
+        warning: Option<String>,
+        /// set when the step was bypassed rather than run, e.g. a loop over nothing or a
+        /// sub-flow stopped early with `skip_if_stopped`. Its result is still passed through.
+        #[serde(default)]
+        #[serde(skip_serializing_if = "is_default")]
+        skipped: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        started_at: Option<chrono::DateTime<chrono::Utc>>,
+        /// when the step completed
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ended_at: Option<chrono::DateTime<chrono::Utc>>,
     },
     Failure {
         id: String,
@@ -111,14 +263,146 @@ pub enum FlowStatusModule {
         flow_jobs: Option<Vec<Uuid>>,
         #[serde(skip_serializing_if = "Option::is_none")]
         branch_chosen: Option<BranchChosen>,
+        /// why the step failed, when it's not only the error of its job
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<FailureReason>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        started_at: Option<chrono::DateTime<chrono::Utc>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ended_at: Option<chrono::DateTime<chrono::Utc>>,
     },
 }
 
+/// Why a step failed, given as the `reason` of the error result of its job
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureReason {
+    /// the job ran for longer than the `timeout` of its module
+    Timeout,
+    /// the catch modules of the step failed too
+    Catch,
+}
+
+impl FailureReason {
+    pub fn of_result(result: &serde_json::Value) -> Option<Self> {
+        serde_json::from_value(result.get("reason")?.clone()).ok()
+    }
+}
+
+/// The error a step failed with, set on the `previous_result` of its failure module and of its
+/// catch modules so that they can switch on its `name` whatever the language of the step
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StepError {
+    /// the class of the error thrown by the script of the step (`Error`, `TypeError`,
+    /// `IndexError`...), `Canceled`, `Timeout` or `Triggered` when it didn't fail on its own
+    pub name: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stack: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step_id: Option<String>,
+}
+
+const SCRIPT_ERROR_PREFIX: &str = "Error during execution of the script:";
+
+impl StepError {
+    /// Extracts the error from the result of the failed job of a step, be it the logs of a deno
+    /// or python script, an error returned by the script or one of the flow itself.
+    pub fn of_result(result: &serde_json::Value, step_id: Option<String>) -> Self {
+        let error = result.get("error").unwrap_or(result);
+        let (name, message, stack) = match error {
+            serde_json::Value::Object(error) => (
+                error
+                    .get("name")
+                    .and_then(|n| n.as_str())
+                    .map(str::to_string),
+                error
+                    .get("message")
+                    .and_then(|m| m.as_str())
+                    .map(str::to_string)
+                    .unwrap_or_else(|| serde_json::Value::Object(error.clone()).to_string()),
+                error
+                    .get("stack")
+                    .and_then(|s| s.as_str())
+                    .map(str::to_string),
+            ),
+            serde_json::Value::String(error) => parse_script_error(error),
+            error => (None, error.to_string(), None),
+        };
+        let name = match result.get("reason").and_then(|r| r.as_str()) {
+            Some("canceled") => "Canceled".to_string(),
+            Some("timeout") => "Timeout".to_string(),
+            Some("triggered") => "Triggered".to_string(),
+            _ => name.unwrap_or_else(|| "Error".to_string()),
+        };
+        Self { name, message, stack, step_id }
+    }
+
+    /// `result` with the fields of the error set, its `error` and `reason` being kept as is.
+    pub fn into_result(self, result: serde_json::Value) -> serde_json::Value {
+        let mut result = match result {
+            serde_json::Value::Object(result) => result,
+            error => serde_json::Map::from_iter([("error".to_string(), error)]),
+        };
+        if let serde_json::Value::Object(error) = serde_json::json!(self) {
+            result.extend(error);
+        }
+        serde_json::Value::Object(result)
+    }
+}
+
+/// The name, message and stack of the uncaught error of a deno or python script from its logs,
+/// the whole error being the message of an `Error` otherwise.
+fn parse_script_error(error: &str) -> (Option<String>, String, Option<String>) {
+    let error = error.trim_start_matches(SCRIPT_ERROR_PREFIX).trim();
+    let lines = error.lines().collect::<Vec<_>>();
+    let name_and_message = |line: &str| match line.split_once(": ") {
+        Some((name, message))
+            if !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') =>
+        {
+            (Some(name.to_string()), message.to_string())
+        }
+        _ => (None, line.to_string()),
+    };
+
+    /* deno: `error: Uncaught (in promise) Error: message` followed by the stack */
+    if let Some(uncaught) = lines
+        .iter()
+        .find_map(|l| l.strip_prefix("error: Uncaught "))
+    {
+        let uncaught = uncaught.trim_start_matches("(in promise) ");
+        let (name, message) = name_and_message(uncaught);
+        let stack = lines
+            .iter()
+            .filter(|l| l.trim_start().starts_with("at "))
+            .map(|l| l.trim())
+            .collect::<Vec<_>>();
+        return (name, message, (!stack.is_empty()).then(|| stack.join("\n")));
+    }
+
+    /* python: the traceback followed by `Name: message` */
+    if let Some(start) = lines
+        .iter()
+        .position(|l| l.starts_with("Traceback (most recent call last):"))
+    {
+        if let Some((last, stack)) = lines[start..].split_last() {
+            let (name, message) = name_and_message(last);
+            return (name, message, Some(stack.join("\n")));
+        }
+    }
+
+    (None, error.to_string(), None)
+}
+
 impl FlowStatusModule {
     pub fn job(&self) -> Option<Uuid> {
         match self {
             FlowStatusModule::WaitingForPriorSteps { .. } => None,
             FlowStatusModule::WaitingForEvents { job, .. } => Some(*job),
+            FlowStatusModule::WaitingForCondition { job, .. } => Some(*job),
             FlowStatusModule::WaitingForExecutor { job, .. } => Some(*job),
             FlowStatusModule::InProgress { job, .. } => Some(*job),
             FlowStatusModule::Success { job, .. } => Some(*job),
@@ -130,12 +414,27 @@ impl FlowStatusModule {
         match self {
             FlowStatusModule::WaitingForPriorSteps { id, .. } => id.clone(),
             FlowStatusModule::WaitingForEvents { id, .. } => id.clone(),
+            FlowStatusModule::WaitingForCondition { id, .. } => id.clone(),
             FlowStatusModule::WaitingForExecutor { id, .. } => id.clone(),
             FlowStatusModule::InProgress { id, .. } => id.clone(),
             FlowStatusModule::Success { id, .. } => id.clone(),
             FlowStatusModule::Failure { id, .. } => id.clone(),
         }
     }
+
+    /// When the first job of the step started, for the steps that started running.
+    pub fn started_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        match self {
+            FlowStatusModule::InProgress { started_at, .. }
+            | FlowStatusModule::Success { started_at, .. }
+            | FlowStatusModule::Failure { started_at, .. } => *started_at,
+            _ => None,
+        }
+    }
+
+    pub fn is_skipped(&self) -> bool {
+        matches!(self, FlowStatusModule::Success { skipped: true, .. })
+    }
 }
 
 impl FlowStatus {
@@ -154,13 +453,220 @@ impl FlowStatus {
                     .map(|x| x.id.clone())
                     .unwrap_or_else(|| "failure".to_string()),
             },
-            retry: RetryStatus { fail_count: 0, previous_result: None, failed_jobs: vec![] },
+            retry: RetryStatus {
+                fail_count: 0,
+                previous_result: None,
+                failed_jobs: vec![],
+                first_failure_at: None,
+                poll_until: None,
+            },
+            flow_jobs_in_table: false,
+            iterators_in_table: false,
+            summaries: f
+                .modules
+                .iter()
+                .chain(f.failure_module.as_ref())
+                .filter_map(|m| Some((m.id.clone(), m.summary.clone()?)))
+                .filter(|(_, summary)| !summary.is_empty())
+                .collect(),
+            raw_result: None,
         }
     }
 
+    /// How the module with the given id is referred to in logs and errors: its summary, or its
+    /// id when it has none.
+    pub fn step_name<'a>(&'a self, id: &'a str) -> &'a str {
+        self.summaries.get(id).map(String::as_str).unwrap_or(id)
+    }
+
     /// current module status ... excluding failure_module
     pub fn current_step(&self) -> Option<&FlowStatusModule> {
         let i = usize::try_from(self.step).ok()?;
         self.modules.get(i)
     }
+
+    /// Rebuilds the status of `flow` from a `raw` status that failed to parse, keeping what can
+    /// still be parsed of it. Modules that haven't been reached yet and a missing retry status are
+    /// reset to their initial status. Returns `None` when the step or the status of a module that
+    /// has already been reached are lost, in which case there is no telling where the flow was.
+    pub fn repair(raw: &serde_json::Value, flow: &FlowValue) -> Option<Self> {
+        let mut status = FlowStatus::new(flow);
+        status.step = i32::try_from(raw.get("step")?.as_i64()?).ok()?;
+        let step = usize::try_from(status.step).unwrap_or(0);
+
+        let parse_module = |raw: Option<&serde_json::Value>, id: &str| {
+            raw.and_then(|m| serde_json::from_value::<FlowStatusModule>(m.clone()).ok())
+                .filter(|m| m.id() == id)
+        };
+        let raw_modules = raw.get("modules").and_then(|m| m.as_array());
+        for (i, module) in status.modules.iter_mut().enumerate() {
+            match parse_module(raw_modules.and_then(|m| m.get(i)), &module.id()) {
+                Some(parsed) => *module = parsed,
+                None if i <= step => return None,
+                None => (),
+            }
+        }
+        match parse_module(raw.get("failure_module"), &status.failure_module.id()) {
+            Some(parsed) => status.failure_module = parsed,
+            None if step >= status.modules.len() => return None,
+            None => (),
+        }
+
+        if let Some(retry) = raw
+            .get("retry")
+            .and_then(|r| serde_json::from_value::<RetryStatus>(r.clone()).ok())
+        {
+            status.retry = retry;
+        }
+        /* guessing wrong where the flow jobs are kept would lose them */
+        status.flow_jobs_in_table = match raw.get("flow_jobs_in_table") {
+            None => false,
+            Some(in_table) => in_table.as_bool()?,
+        };
+        status.iterators_in_table = match raw.get("iterators_in_table") {
+            None => false,
+            Some(in_table) => in_table.as_bool()?,
+        };
+        Some(status)
+    }
+}
+
+/// A field that changed between two snapshots of a flow status, the keys of nested fields are
+/// joined with dots. `old` or `new` is missing when the field was added or removed.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ModuleTransition {
+    /// index of the module, absent for the failure module
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    pub id: String,
+    /// type of the status of the module, e.g. `InProgress`
+    pub from: String,
+    pub to: String,
+    pub changes: Vec<FieldChange>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct StepChange {
+    pub old: i32,
+    pub new: i32,
+}
+
+/// What changed between two snapshots of the status of a flow.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct FlowStatusDiff {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<StepChange>,
+    pub modules: Vec<ModuleTransition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_module: Option<ModuleTransition>,
+    pub retry: Vec<FieldChange>,
+}
+
+impl FlowStatusDiff {
+    pub fn new(old: &FlowStatus, new: &FlowStatus) -> Self {
+        let step = (old.step != new.step).then(|| StepChange { old: old.step, new: new.step });
+        let modules = old
+            .modules
+            .iter()
+            .zip(new.modules.iter())
+            .enumerate()
+            .filter_map(|(i, (old, new))| module_transition(Some(i), old, new))
+            .collect();
+        let failure_module = module_transition(None, &old.failure_module, &new.failure_module);
+        let mut retry = vec![];
+        diff_fields(&mut retry, "", &json_of(&old.retry), &json_of(&new.retry));
+        Self { step, modules, failure_module, retry }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+fn json_of<T: Serialize>(value: &T) -> serde_json::Value {
+    serde_json::to_value(value).unwrap_or(serde_json::Value::Null)
+}
+
+fn module_transition(
+    index: Option<usize>,
+    old: &FlowStatusModule,
+    new: &FlowStatusModule,
+) -> Option<ModuleTransition> {
+    /* a skipped step is reported as a type of its own rather than as a success */
+    let type_of = |m: &FlowStatusModule, v: &mut serde_json::Value| {
+        let o = v.as_object_mut();
+        let type_name = o
+            .and_then(|o| {
+                o.remove("skipped");
+                o.remove("type")
+            })
+            .and_then(|t| t.as_str().map(str::to_string))
+            .unwrap_or_default();
+        if m.is_skipped() {
+            "Skipped".to_string()
+        } else {
+            type_name
+        }
+    };
+    let (mut old_json, mut new_json) = (json_of(old), json_of(new));
+    let (from, to) = (type_of(old, &mut old_json), type_of(new, &mut new_json));
+    let mut changes = vec![];
+    diff_fields(&mut changes, "", &old_json, &new_json);
+    (from != to || !changes.is_empty()).then(|| ModuleTransition {
+        index,
+        id: new.id(),
+        from,
+        to,
+        changes,
+    })
+}
+
+fn diff_fields(
+    changes: &mut Vec<FieldChange>,
+    key: &str,
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+) {
+    use serde_json::Value;
+
+    let nkey = |k: &str| {
+        if key.is_empty() {
+            k.to_string()
+        } else {
+            format!("{key}.{k}")
+        }
+    };
+
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (k, o) in old {
+                match new.get(k) {
+                    Some(n) => diff_fields(changes, &nkey(k), o, n),
+                    None => changes.push(FieldChange {
+                        field: nkey(k),
+                        old: Some(o.clone()),
+                        new: None,
+                    }),
+                }
+            }
+            for (k, n) in new.iter().filter(|(k, _)| !old.contains_key(*k)) {
+                changes.push(FieldChange { field: nkey(k), old: None, new: Some(n.clone()) });
+            }
+        }
+        (old, new) if old != new => changes.push(FieldChange {
+            field: key.to_string(),
+            old: Some(old.clone()),
+            new: Some(new.clone()),
+        }),
+        _ => (),
+    }
 }
@@ -6,13 +6,18 @@
  * LICENSE-AGPL for a copy of the license.
  */
 
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
 use serde::{self, Deserialize, Serialize};
 
 use crate::{
+    error::Error,
+    flow_status::MAX_RETRY_ATTEMPTS,
     more_serde::{default_id, default_true, is_default},
-    scripts::{Schema, ScriptLang},
+    scripts::{Schema, ScriptHash, ScriptLang},
 };
 
 #[derive(Serialize)]
@@ -45,9 +50,65 @@ pub struct FlowValue {
     pub modules: Vec<FlowModule>,
     #[serde(default)]
     pub failure_module: Option<FlowModule>,
+    /// run all the steps of the flow on the worker that started it, sharing its directory.
+    /// Flows that don't set it use the `flow_same_worker` setting of their workspace, it's
+    /// resolved when the flow is pushed. Modules may override it with their own `same_worker`.
+    /// Either way, the step following a module with a `suspend`, a `sleep` or a `poll` is not
+    /// continued on the same worker.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub same_worker: Option<bool>,
+    /// replace the `$res:<path>` strings of the flow input by the value of the resource at that
+    /// path before running the flow, with the permissions of the flow
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub resolve_resources: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_transform: Option<OutputTransform>,
+    /// run the modules as soon as the modules of their `depends_on` have succeeded, the
+    /// independent ones concurrently, instead of one after the other
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub dag: bool,
+    /// seconds the flow may run for once started, it is then canceled with its in-flight jobs
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u32>,
+    /// log each iteration of the loops of the flow, with its index, its job and whether it
+    /// succeeded, in the logs of the flow as it completes. Inherited by the nested flows
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub log_iterations: bool,
+    /// run when the flow is canceled, before it completes as canceled
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_cancel: Option<OnCancel>,
+}
+
+/// Cleans up after a canceled flow, e.g. releasing locks or deleting temporary resources. The
+/// modules run in a sub-flow in place of the failure module, which follows them when it runs on
+/// cancel too, with the cancellation error as `previous_result`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OnCancel {
+    pub modules: Vec<FlowModule>,
+    /// seconds the cleanup may run for before being canceled in turn, so that it can't hold
+    /// up the cancellation, `DEFAULT_ON_CANCEL_TIMEOUT` when missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u32>,
+}
+
+pub const DEFAULT_ON_CANCEL_TIMEOUT: u32 = 300;
+
+/// Shapes the output of a flow that succeeded: `expr` is evaluated with the flow `result` and
+/// `flow_input`, and its value is stored as the result of the flow instead. External consumers
+/// of the flow only ever see that value.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct OutputTransform {
+    pub expr: String,
+    /// keep the result before the transform in the `raw_result` of the flow status
     #[serde(default)]
     #[serde(skip_serializing_if = "is_default")]
-    pub same_worker: bool,
+    pub keep_raw_result: bool,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -56,11 +117,48 @@ pub struct StopAfterIf {
     pub skip_if_stopped: bool,
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SkipIf {
+    pub expr: String,
+}
+
+/// Lets a step that failed with an error carrying usable data (e.g. some of the records fetched
+/// before hitting a rate limit) be treated as a success. `expr` is evaluated with the error as
+/// `result` and returns the partial result, or `null` when the error isn't of the expected shape.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PartialResult {
+    pub expr: String,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
 #[serde(default)]
 pub struct Retry {
     pub constant: ConstantDelay,
     pub exponential: ExponentialDelay,
+    /// when set, replaces `constant` and `exponential`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<ScheduleDelay>,
+    /// no retry runs until this many seconds have elapsed since the first failure of the step,
+    /// whatever the interval of the attempt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_seconds_since_first_failure: Option<u32>,
+    /// when set, only the failures it matches are retried, the others fail the step right away
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_if: Option<RetryIf>,
+}
+
+/// Restricts the failures of a step that are retried, e.g. to the transient errors of an API.
+/// A failure is retried when the `name` of its error is one of `errors` or when `expr`, evaluated
+/// with the error of the step as `error` and `flow_input`, returns true. Like other predicates,
+/// `expr` may return a value that isn't a boolean, which is then coerced unless
+/// `STRICT_PREDICATES` is set.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct RetryIf {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expr: Option<String>,
 }
 
 impl Retry {
@@ -68,9 +166,11 @@ impl Retry {
     ///
     /// May return [`Duration::ZERO`] to retry immediately.
     pub fn interval(&self, previous_attempts: u16) -> Option<Duration> {
-        let Self { constant, exponential } = self;
+        let Self { constant, exponential, schedule, .. } = self;
 
-        if previous_attempts < constant.attempts {
+        if let Some(schedule) = schedule {
+            schedule.interval(previous_attempts)
+        } else if previous_attempts < constant.attempts {
             Some(Duration::from_secs(constant.seconds as u64))
         } else if previous_attempts - constant.attempts < exponential.attempts {
             let exp = previous_attempts.saturating_add(1) as u32;
@@ -81,20 +181,96 @@ impl Retry {
         }
     }
 
+    /// Like [`Retry::interval`] but waits at least until `min_seconds_since_first_failure` have
+    /// elapsed since the first failure, `since_first_failure` ago.
+    pub fn interval_since_first_failure(
+        &self,
+        previous_attempts: u16,
+        since_first_failure: Duration,
+    ) -> Option<Duration> {
+        let interval = self.interval(previous_attempts)?;
+        let floor = self
+            .min_seconds_since_first_failure
+            .map(|s| Duration::from_secs(s.into()).saturating_sub(since_first_failure))
+            .unwrap_or_default();
+        Some(interval.max(floor))
+    }
+
+    /// Adds a random part of up to `random_factor` percent of `interval`, the interval until the
+    /// retry following `previous_attempts`, when that retry is an exponential one.
+    pub fn jitter(&self, previous_attempts: u16, interval: Duration) -> Duration {
+        match self.exponential.random_factor {
+            Some(random_factor)
+                if self.schedule.is_none() && previous_attempts >= self.constant.attempts =>
+            {
+                let max_jitter = interval.mul_f64(f64::from(random_factor) / 100.0);
+                interval + max_jitter.mul_f64(rand::random::<f64>())
+            }
+            _ => interval,
+        }
+    }
+
     pub fn has_attempts(&self) -> bool {
-        self.constant.attempts != 0 || self.exponential.attempts != 0
+        self.max_attempts() != 0
     }
 
     pub fn max_attempts(&self) -> u16 {
-        self.constant
-            .attempts
-            .saturating_add(self.exponential.attempts)
+        if let Some(schedule) = &self.schedule {
+            schedule.max_attempts()
+        } else {
+            self.constant
+                .attempts
+                .saturating_add(self.exponential.attempts)
+        }
     }
 
     pub fn max_interval(&self) -> Option<Duration> {
-        self.max_attempts()
-            .checked_sub(1)
-            .and_then(|p| self.interval(p))
+        if let Some(schedule) = &self.schedule {
+            schedule
+                .intervals
+                .iter()
+                .max()
+                .map(|&s| Duration::from_secs(s.into()))
+        } else {
+            self.max_attempts()
+                .checked_sub(1)
+                .and_then(|p| self.interval(p))
+        }
+        .map(|interval| {
+            let floor = self.min_seconds_since_first_failure.unwrap_or(0);
+            interval.max(Duration::from_secs(floor.into()))
+        })
+    }
+}
+
+/// The nth retry waits `intervals[n]` seconds. Once they are exhausted, the retries either stop
+/// or, with `repeat_last`, go on with the last interval up to `MAX_RETRY_ATTEMPTS` retries.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ScheduleDelay {
+    pub intervals: Vec<u32>,
+    #[serde(skip_serializing_if = "is_default")]
+    pub repeat_last: bool,
+}
+
+impl ScheduleDelay {
+    fn interval(&self, previous_attempts: u16) -> Option<Duration> {
+        let seconds = match self.intervals.get(usize::from(previous_attempts)) {
+            Some(seconds) => Some(seconds),
+            None if self.repeat_last && previous_attempts < MAX_RETRY_ATTEMPTS => {
+                self.intervals.last()
+            }
+            None => None,
+        };
+        seconds.map(|&s| Duration::from_secs(s.into()))
+    }
+
+    fn max_attempts(&self) -> u16 {
+        if self.repeat_last && !self.intervals.is_empty() {
+            MAX_RETRY_ATTEMPTS
+        } else {
+            u16::try_from(self.intervals.len()).unwrap_or(u16::MAX)
+        }
     }
 }
 
@@ -112,11 +288,15 @@ pub struct ExponentialDelay {
     pub attempts: u16,
     pub multiplier: u16,
     pub seconds: u16,
+    /// up to this percentage of the interval is added to it at random, so that the
+    /// steps of many flows failing together don't all retry at the same time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub random_factor: Option<u8>,
 }
 
 impl Default for ExponentialDelay {
     fn default() -> Self {
-        Self { attempts: 0, multiplier: 1, seconds: 0 }
+        Self { attempts: 0, multiplier: 1, seconds: 0, random_factor: None }
     }
 }
 
@@ -124,6 +304,111 @@ impl Default for ExponentialDelay {
 pub struct Suspend {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub required_events: Option<u32>,
+    /// how long to wait for the resume messages, the `default_suspend_timeout` of the workspace
+    /// or 30 minutes when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<SuspendTimeout>,
+    /// when set, only suspend if this expression, evaluated against the result of the step,
+    /// returns true
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub condition: Option<String>,
+    /// seconds to keep waiting for late resume messages once `timeout` is reached, before
+    /// failing the flow
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grace_period: Option<u32>,
+    /// json schema the value of the resume messages must satisfy, messages that don't are
+    /// rejected and not counted towards `required_events`. Approval UIs render it as the form
+    /// whose submission is the value of the resume message.
+    #[serde(alias = "form_schema")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resume_schema: Option<serde_json::Value>,
+    /// what the approvers are asked, shown along with the form
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// when set, further resume messages from an approver that already resumed the step don't
+    /// count towards `required_events`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub require_distinct_approvers: bool,
+}
+
+/// A duration to wait for resume messages, in seconds or in days, hours, minutes and seconds.
+/// A duration that is empty or zero, e.g. `{}`, is refused rather than read as expiring right
+/// away.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum SuspendTimeout {
+    Seconds(u32),
+    Duration {
+        #[serde(skip_serializing_if = "is_default")]
+        days: u32,
+        #[serde(skip_serializing_if = "is_default")]
+        hours: u32,
+        #[serde(skip_serializing_if = "is_default")]
+        minutes: u32,
+        #[serde(skip_serializing_if = "is_default")]
+        seconds: u32,
+    },
+}
+
+impl<'de> Deserialize<'de> for SuspendTimeout {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<SuspendTimeout, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Seconds(u32),
+            Duration {
+                #[serde(default)]
+                days: u32,
+                #[serde(default)]
+                hours: u32,
+                #[serde(default)]
+                minutes: u32,
+                #[serde(default)]
+                seconds: u32,
+            },
+        }
+
+        let timeout = match Repr::deserialize(deserializer)? {
+            Repr::Seconds(seconds) => return Ok(SuspendTimeout::Seconds(seconds)),
+            Repr::Duration { days, hours, minutes, seconds } => {
+                SuspendTimeout::Duration { days, hours, minutes, seconds }
+            }
+        };
+        if timeout.as_secs() == 0 {
+            return Err(serde::de::Error::custom(
+                "a suspend timeout given as a duration needs some days, hours, minutes or seconds",
+            ));
+        }
+        Ok(timeout)
+    }
+}
+
+impl SuspendTimeout {
+    pub fn as_secs(&self) -> u64 {
+        match *self {
+            SuspendTimeout::Seconds(seconds) => seconds.into(),
+            SuspendTimeout::Duration { days, hours, minutes, seconds } => {
+                u64::from(days) * 24 * 60 * 60
+                    + u64::from(hours) * 60 * 60
+                    + u64::from(minutes) * 60
+                    + u64::from(seconds)
+            }
+        }
+    }
+}
+
+/// Waits for an external system to reach a desired state without holding a worker: the step is
+/// run again every `interval` seconds, the flow being suspended in between, until `condition`
+/// evaluated against its result returns true. The step fails once `timeout` seconds elapsed
+/// since its first run.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Poll {
+    pub condition: String,
+    pub interval: u32,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub timeout: Option<u32>,
 }
@@ -137,6 +422,10 @@ pub struct FlowModule {
     pub input_transforms: HashMap<String, InputTransform>,
     pub value: FlowModuleValue,
     pub stop_after_if: Option<StopAfterIf>,
+    /// evaluated with `previous_result` and `flow_input` before the step runs. When true, the
+    /// step is skipped and the previous result passed through to the next one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_if: Option<SkipIf>,
     pub summary: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub suspend: Option<Suspend>,
@@ -144,6 +433,262 @@ pub struct FlowModule {
     pub retry: Option<Retry>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sleep: Option<InputTransform>,
+    /// json schema of the args expected by this step, provided by the flow author
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_schema: Option<serde_json::Value>,
+    /// json schema of the result of this step, provided by the flow author
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_schema: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partial_result: Option<PartialResult>,
+    /// seconds the job of this step may wait in the queue before being picked up by a worker,
+    /// after which it fails instead of running late
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_queue_wait: Option<u64>,
+    /// seconds the job of this step may run for, after which it is killed and the step fails
+    /// with a timeout like any other failure. Never more than the timeout of the workers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u32>,
+    /// consecutive modules of the same group are transactional: when one of them fails for good,
+    /// the `compensation` of the modules of the group that succeeded before it run in reverse
+    /// order, followed by the failure module. Groups are scoped to the list of modules they are
+    /// declared in, a failure in the modules of a loop or a branch only compensates the group it
+    /// belongs to there and then fails the loop or branch module like any other failure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// the module undoing the effects of this one when a later module of its `group` fails
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compensation: Option<Box<FlowModule>>,
+    /// modules run in a sub-flow when this step fails past its retries, with its error as
+    /// `previous_result`. The result of the sub-flow is the result of the step, which then
+    /// succeeds, and the failure module only runs if the sub-flow fails too
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub catch: Vec<FlowModule>,
+    /// for the failure module, also run it when the flow is canceled, the flow still completing
+    /// as canceled
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub run_on_cancel: bool,
+    /// for the failure module, also run it when a suspended step times out waiting to be resumed
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub run_on_timeout: bool,
+    /// how the arguments injected by forloops and branches collide with the other arguments
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub input_merge: InputMerge,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll: Option<Poll>,
+    /// post-condition on the result of the step, evaluated with `result` and `flow_input`. The
+    /// step fails (and may be retried) when it evaluates to false
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assert: Option<InputTransform>,
+    /// json schema the result of the step must satisfy, the step fails (and may be retried)
+    /// when it doesn't. Unlike `output_schema`, it's checked at runtime
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_schema: Option<serde_json::Value>,
+    /// in dag flows, ids of the modules whose success this module waits for
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+    /// overrides the `same_worker` of the flow for the jobs of this step. The directory of the
+    /// flow is shared by consecutive steps continued on the same worker, and removed from it once
+    /// the flow moves on to another worker.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub same_worker: Option<bool>,
+    /// for script steps, seconds the result of the step is reused by later runs of the step with
+    /// the same script and the same resolved arguments, instead of running its job again
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_ttl: Option<u32>,
+}
+
+/// How the arguments injected by loops (`iter`) and branches (`previous_result`) are merged into
+/// arguments that already have the same key, e.g. the `iter` of an outer loop
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InputMerge {
+    /// the injected value replaces the existing one
+    Injected,
+    /// the existing value is kept
+    Existing,
+    /// the step fails
+    Error,
+}
+
+impl Default for InputMerge {
+    fn default() -> Self {
+        Self::Injected
+    }
+}
+
+impl InputMerge {
+    /// Merges `injected` into `args` and returns the keys that collided with a different value.
+    pub fn merge(
+        self,
+        args: &mut serde_json::Map<String, serde_json::Value>,
+        injected: serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Vec<String>, Error> {
+        let mut collisions = vec![];
+        for (key, value) in injected {
+            match args.get(&key) {
+                Some(existing) if existing != &value => match self {
+                    Self::Injected => {
+                        args.insert(key.clone(), value);
+                    }
+                    Self::Existing => (),
+                    Self::Error => {
+                        return Err(Error::ExecutionErr(format!(
+                            "The injected argument `{key}` collides with an existing argument"
+                        )))
+                    }
+                },
+                Some(_) => continue,
+                None => {
+                    args.insert(key, value);
+                    continue;
+                }
+            }
+            collisions.push(key);
+        }
+        Ok(collisions)
+    }
+}
+
+/// A property required by the `input_schema` of a step that the `output_schema` of the step
+/// before it doesn't provide (or provides with another type).
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SchemaIncompatibility {
+    pub previous_module: String,
+    pub module: String,
+    pub property: String,
+    pub reason: String,
+}
+
+impl FlowValue {
+    /// Checks the declared `output_schema` of every step against the declared `input_schema` of
+    /// the step that follows it, including the steps of loops and branches.
+    /// Steps without a declared schema are not checked.
+    pub fn schema_incompatibilities(&self) -> Vec<SchemaIncompatibility> {
+        let mut incompatibilities = vec![];
+        check_modules_schemas(&self.modules, &mut incompatibilities);
+        incompatibilities
+    }
+}
+
+impl FlowValue {
+    /// The keys shared by several branches of a branchall, including the branchalls nested in
+    /// other modules, as `(module id, key)`. The result of a branch is keyed by its label, or by its
+    /// index when unlabeled, so a label can also collide with the index of another branch.
+    pub fn branch_key_collisions(&self) -> Vec<(String, String)> {
+        let mut collisions = vec![];
+        check_modules_branch_keys(&self.modules, &mut collisions);
+        if let Some(failure_module) = &self.failure_module {
+            check_modules_branch_keys(std::slice::from_ref(failure_module), &mut collisions);
+        }
+        collisions
+    }
+}
+
+fn check_modules_branch_keys(modules: &[FlowModule], acc: &mut Vec<(String, String)>) {
+    for module in modules {
+        check_modules_branch_keys(&module.catch, acc);
+        if let Some(compensation) = &module.compensation {
+            check_modules_branch_keys(std::slice::from_ref(compensation), acc);
+        }
+        match &module.value {
+            FlowModuleValue::ForloopFlow { modules, .. }
+            | FlowModuleValue::WhileloopFlow { modules, .. } => {
+                check_modules_branch_keys(modules, acc)
+            }
+            FlowModuleValue::BranchOne { branches, default, .. } => {
+                branches
+                    .iter()
+                    .for_each(|b| check_modules_branch_keys(&b.modules, acc));
+                check_modules_branch_keys(default, acc);
+            }
+            FlowModuleValue::BranchAll { branches, .. } => {
+                let mut keys = HashSet::new();
+                for (i, branch) in branches.iter().enumerate() {
+                    let key = branch.label.clone().unwrap_or_else(|| i.to_string());
+                    if !keys.insert(key.clone()) {
+                        acc.push((module.id.clone(), key));
+                    }
+                    check_modules_branch_keys(&branch.modules, acc);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn check_modules_schemas(modules: &[FlowModule], acc: &mut Vec<SchemaIncompatibility>) {
+    for (previous, module) in modules.iter().zip(modules.iter().skip(1)) {
+        if let (Some(output), Some(input)) = (&previous.output_schema, &module.input_schema) {
+            acc.extend(schemas_incompatibilities(output, input).into_iter().map(
+                |(property, reason)| SchemaIncompatibility {
+                    previous_module: previous.id.clone(),
+                    module: module.id.clone(),
+                    property,
+                    reason,
+                },
+            ));
+        }
+    }
+    for module in modules {
+        match &module.value {
+            FlowModuleValue::ForloopFlow { modules, .. }
+            | FlowModuleValue::WhileloopFlow { modules, .. } => check_modules_schemas(modules, acc),
+            FlowModuleValue::BranchOne { branches, default, .. } => {
+                branches
+                    .iter()
+                    .for_each(|b| check_modules_schemas(&b.modules, acc));
+                check_modules_schemas(default, acc);
+            }
+            FlowModuleValue::BranchAll { branches, .. } => branches
+                .iter()
+                .for_each(|b| check_modules_schemas(&b.modules, acc)),
+            _ => (),
+        }
+    }
+}
+
+/// returns the (property, reason) of every required property of `input` not satisfied by `output`
+fn schemas_incompatibilities(
+    output: &serde_json::Value,
+    input: &serde_json::Value,
+) -> Vec<(String, String)> {
+    let required = input
+        .get("required")
+        .and_then(|r| r.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|p| p.as_str());
+    let output_properties = output.get("properties").and_then(|p| p.as_object());
+
+    required
+        .filter_map(|property| {
+            let expected = input
+                .get("properties")
+                .and_then(|p| p.get(property))
+                .and_then(|p| p.get("type"));
+            match output_properties.and_then(|p| p.get(property)) {
+                None => Some((
+                    property.to_string(),
+                    "not produced by the previous step".to_string(),
+                )),
+                Some(produced) => match (expected, produced.get("type")) {
+                    (Some(expected), Some(produced)) if expected != produced => Some((
+                        property.to_string(),
+                        format!(
+                            "expected type {expected} but the previous step produces {produced}"
+                        ),
+                    )),
+                    _ => None,
+                },
+            }
+        })
+        .collect()
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
@@ -152,14 +697,72 @@ pub struct FlowModule {
     rename_all(serialize = "lowercase", deserialize = "lowercase")
 )]
 pub enum InputTransform {
-    Static { value: serde_json::Value },
-    Javascript { expr: String },
+    Static {
+        value: serde_json::Value,
+    },
+    Javascript {
+        expr: String,
+    },
+    /// The value of the resource at `path`, only supported as the input of a step. It is read as
+    /// the flow, in its own workspace, unless `permissioned_as` is set: the resource is then read
+    /// as `permissioned_as` in `workspace` (the flow's own by default), which the admins of that
+    /// workspace must have granted to the flow.
+    Resource {
+        path: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        workspace: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        permissioned_as: Option<String>,
+    },
+}
+
+/// What a forloop iterates over: the array an `InputTransform` evaluates to, or the occurrences
+/// of a cron schedule.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum LoopIterator {
+    Transform(InputTransform),
+    Cron(CronIterator),
+}
+
+impl From<InputTransform> for LoopIterator {
+    fn from(transform: InputTransform) -> Self {
+        LoopIterator::Transform(transform)
+    }
+}
+
+/// The most occurrences a `CronIterator` may expand to.
+pub const MAX_CRON_ITERATIONS: usize = 1000;
+
+/// Iterates over the occurrences of the cron `schedule` between `start` and `end` (both
+/// included), which must evaluate to RFC 3339 datetimes. Each occurrence is given to its
+/// iteration as `iter.value`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename = "cron")]
+pub struct CronIterator {
+    pub schedule: String,
+    pub start: InputTransform,
+    pub end: InputTransform,
+}
+
+/// Sorts the iterations of a forloop by the value `expr` evaluates to for each of them, given as
+/// `iter.value`. The values must either all be numbers or all be strings, iterations with equal
+/// values keep the order of the iterator.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OrderBy {
+    pub expr: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default")]
+    pub desc: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BranchOneModules {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub summary: Option<String>,
+    /// recorded along with the index of the branch in the status of the step when it's taken
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
     pub expr: String,
     pub modules: Vec<FlowModule>,
 }
@@ -168,6 +771,9 @@ pub struct BranchOneModules {
 pub struct BranchAllModules {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub summary: Option<String>,
+    /// key of the result of the branch in the result of the step, its index when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
     pub modules: Vec<FlowModule>,
     #[serde(default = "default_true")]
     pub skip_failure: bool,
@@ -184,19 +790,73 @@ pub enum FlowModuleValue {
         #[serde(alias = "input_transform")]
         input_transforms: HashMap<String, InputTransform>,
         path: String,
+        /// the version of the script at `path` to run, set to its latest one when the flow
+        /// run starts if missing
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hash: Option<ScriptHash>,
     },
     ForloopFlow {
-        iterator: InputTransform,
+        iterator: LoopIterator,
         modules: Vec<FlowModule>,
         #[serde(default = "default_true")]
         skip_failures: bool,
+        /// with `skip_failures`, the loop fails once this many of its iterations failed
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_failures: Option<u32>,
+        /// the iterations, and so the results of the loop, are in this order instead of the
+        /// order of the iterator
+        #[serde(skip_serializing_if = "Option::is_none")]
+        order_by: Option<OrderBy>,
+        /// fail instead of succeeding with an empty array when there is nothing to iterate over
+        #[serde(default)]
+        #[serde(skip_serializing_if = "is_default")]
+        error_if_empty: bool,
+        /// minimum number of seconds between the starts of two consecutive iterations
+        #[serde(skip_serializing_if = "Option::is_none")]
+        min_iteration_interval: Option<u32>,
+        /// number of iterations pushed at once, the next ones waiting for all of them to
+        /// complete, 1 when missing
+        #[serde(skip_serializing_if = "Option::is_none")]
+        parallelism: Option<u16>,
+        /// evaluated against the result of each iteration, with `iter.index` its index, the loop
+        /// stops after the first iteration for which it returns true
+        #[serde(skip_serializing_if = "Option::is_none")]
+        break_if: Option<String>,
+    },
+    /// runs its modules again, one pass after the other, until `stop_condition` is true
+    WhileloopFlow {
+        modules: Vec<FlowModule>,
+        /// evaluated after each pass with its result as `result` and `previous_result`, and
+        /// `iter.index` the index of the pass, starting at 0
+        stop_condition: String,
+        /// the loop fails once this many passes ran without the stop condition being met,
+        /// 1000 when missing
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_iterations: Option<u32>,
     },
     BranchOne {
         branches: Vec<BranchOneModules>,
         default: Vec<FlowModule>,
+        /// run every branch whose `expr` is true, one after the other, instead of only the first
+        /// one. The result of the step is then the results of the branches keyed by their label,
+        /// or their index, along with the indexes of the branches that `matched` and were
+        /// `skipped`
+        #[serde(default)]
+        #[serde(skip_serializing_if = "is_default")]
+        match_all: bool,
     },
     BranchAll {
         branches: Vec<BranchAllModules>,
+        /// try the branches in order and use the result of the first one that succeeds instead
+        /// of the results of all of them, only fails if they all do
+        #[serde(default)]
+        #[serde(skip_serializing_if = "is_default")]
+        stop_at_first_success: bool,
+        /// push the jobs of all the branches at once instead of one after the other, the step
+        /// completing once they all did. Ignored with `stop_at_first_success`
+        #[serde(default)]
+        #[serde(skip_serializing_if = "is_default")]
+        parallel: bool,
     },
     RawScript {
         #[serde(default)]
@@ -206,7 +866,31 @@ pub enum FlowModuleValue {
         path: Option<String>,
         language: ScriptLang,
     },
-    Identity,
+    /// passes the result of the previous module through, reshaped by `expr` when given
+    Identity {
+        /// evaluated with `previous_result` and `flow_input`, its value is passed through instead
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expr: Option<String>,
+    },
+    /// returns the results of the prior modules with the given ids, keyed by module id
+    Collector { ids: Vec<String> },
+}
+
+impl FlowModuleValue {
+    /// The `type` of the module, as it is serialized
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FlowModuleValue::Script { .. } => "script",
+            FlowModuleValue::ForloopFlow { .. } => "forloopflow",
+            FlowModuleValue::WhileloopFlow { .. } => "whileloopflow",
+            FlowModuleValue::BranchOne { .. } => "branchone",
+            FlowModuleValue::BranchAll { .. } => "branchall",
+            FlowModuleValue::RawScript { .. } => "rawscript",
+            FlowModuleValue::Identity { .. } => "identity",
+            FlowModuleValue::Collector { .. } => "collector",
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -0,0 +1,110 @@
+/*
+ * Author: Ruben Fiszel
+ * Copyright: Windmill Labs, Inc 2022
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+use serde::Serialize;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Violation {
+    pub key: String,
+    pub message: String,
+}
+
+/// Validates `value` against the subset of json schema used by resource types: `type`, `enum`,
+/// `required`, `properties` and `items`, other keywords are ignored. Keys of nested values are
+/// joined with dots.
+pub fn validate_value(
+    violations: &mut Vec<Violation>,
+    key: &str,
+    schema: &serde_json::Value,
+    value: &serde_json::Value,
+) {
+    use serde_json::Value;
+
+    let nkey = |k: &str| {
+        if key.is_empty() {
+            k.to_string()
+        } else {
+            format!("{key}.{k}")
+        }
+    };
+
+    let types = match schema.get("type") {
+        Some(Value::String(t)) => vec![t.as_str()],
+        Some(Value::Array(ts)) => ts.iter().filter_map(|t| t.as_str()).collect(),
+        _ => vec![],
+    };
+    if !types.is_empty() && !types.iter().any(|t| has_type(t, value)) {
+        violations.push(Violation {
+            key: key.to_string(),
+            message: format!(
+                "expected {}, found {}",
+                types.join(" or "),
+                type_name(value)
+            ),
+        });
+        return;
+    }
+
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            violations.push(Violation {
+                key: key.to_string(),
+                message: format!("expected one of {}", Value::Array(allowed.clone())),
+            });
+        }
+    }
+
+    match value {
+        Value::Object(o) => {
+            let required = schema
+                .get("required")
+                .and_then(|r| r.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|r| r.as_str());
+            for k in required.filter(|k| !o.contains_key(*k)) {
+                violations.push(Violation { key: nkey(k), message: "is required".to_string() });
+            }
+            if let Some(properties) = schema.get("properties").and_then(|p| p.as_object()) {
+                for (k, property) in properties {
+                    if let Some(v) = o.get(k) {
+                        validate_value(violations, &nkey(k), property, v);
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(item) = schema.get("items") {
+                for (i, v) in items.iter().enumerate() {
+                    validate_value(violations, &nkey(&i.to_string()), item, v);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+fn has_type(t: &str, value: &serde_json::Value) -> bool {
+    match t {
+        "integer" => value.is_i64() || value.is_u64(),
+        t => type_name(value) == t || (t == "number" && value.is_number()),
+    }
+}
+
+fn type_name(value: &serde_json::Value) -> &'static str {
+    use serde_json::Value;
+
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
@@ -10,10 +10,13 @@ use std::net::SocketAddr;
 
 use error::Error;
 
+pub mod blobs;
 pub mod error;
 pub mod external_ip;
+pub mod fan_out;
 pub mod flow_status;
 pub mod flows;
+pub mod json_schema;
 pub mod more_serde;
 pub mod oauth2;
 pub mod scripts;
@@ -0,0 +1,109 @@
+/*
+ * Author: Ruben Fiszel
+ * Copyright: Windmill Labs, Inc 2022
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+//! The values a forloop iterates over (`itered`) are either kept inline in the iterator of the
+//! module status or, when `FlowStatus::iterators_in_table` is set, in the `flow_iterator` table,
+//! the iterator then only keeping how many there are. Large iterators otherwise make every update
+//! of `flow_status` rewrite all of their values.
+//!
+//! The engine reads and writes them through this module so it doesn't need to care where they
+//! live. They are only kept in the table while the flow runs.
+
+use std::ops::Range;
+
+use serde_json::Value;
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+use windmill_common::{error, flow_status::FlowStatus};
+
+lazy_static::lazy_static! {
+    pub static ref ITERATORS_IN_TABLE: bool = std::env::var("ITERATORS_IN_TABLE")
+        .ok()
+        .and_then(|x| x.parse::<bool>().ok())
+        .unwrap_or(false);
+}
+
+/// Records `itered` as the values iterated over by the forloop at `step` of `flow`, replacing
+/// those of a previous run of the module.
+///
+/// Returns the values to keep inline in the iterator of the module status, none when the flow
+/// keeps its iterators in the table.
+pub async fn set_itered<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    status: &FlowStatus,
+    flow: Uuid,
+    step: i32,
+    itered: Vec<Value>,
+) -> error::Result<Vec<Value>> {
+    if !status.iterators_in_table {
+        return Ok(itered);
+    }
+
+    sqlx::query!(
+        "DELETE FROM flow_iterator WHERE flow = $1 AND step = $2",
+        flow,
+        step
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO flow_iterator (flow, step, idx, value)
+              SELECT $1, $2, idx - 1, value FROM UNNEST($3::JSONB[]) WITH ORDINALITY AS t(value, idx)",
+        flow,
+        step,
+        &itered
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(vec![])
+}
+
+/// Returns the values at `range` iterated over by the forloop at `step` of `flow`, `itered`
+/// being the inline values of the iterator of its module status. The range is truncated to the
+/// values there are.
+pub async fn get_itered<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    status: &FlowStatus,
+    flow: Uuid,
+    step: i32,
+    itered: &[Value],
+    range: Range<usize>,
+) -> error::Result<Vec<Value>> {
+    if !status.iterators_in_table {
+        let end = range.end.min(itered.len());
+        return Ok(itered[range.start.min(end)..end].to_vec());
+    }
+
+    let values = sqlx::query_scalar!(
+        "SELECT value FROM flow_iterator WHERE flow = $1 AND step = $2 AND idx >= $3 AND idx < $4
+       ORDER BY idx",
+        flow,
+        step,
+        i32::try_from(range.start).unwrap_or(i32::MAX),
+        i32::try_from(range.end).unwrap_or(i32::MAX)
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    Ok(values)
+}
+
+/// Forgets the values iterated over by the forloops of `flow`, once it completed.
+pub async fn delete_itered<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    status: &FlowStatus,
+    flow: Uuid,
+) -> error::Result<()> {
+    if !status.iterators_in_table {
+        return Ok(());
+    }
+
+    sqlx::query!("DELETE FROM flow_iterator WHERE flow = $1", flow)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,209 @@
+/*
+ * Author: Ruben Fiszel
+ * Copyright: Windmill Labs, Inc 2022
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+//! The jobs spawned by forloop and branchall modules (`flow_jobs`) are either kept inline in
+//! the module status or, when `FlowStatus::flow_jobs_in_table` is set, in the `flow_job` table.
+//! Large loops otherwise make every update of `flow_status` rewrite an ever growing array.
+//!
+//! The engine reads and writes them through this module so it doesn't need to care where they
+//! live. Readers outside of the engine can use `inline_flow_jobs` to get back the inline shape.
+
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+use windmill_common::{error, flow_status::FlowStatus};
+
+lazy_static::lazy_static! {
+    pub static ref FLOW_JOBS_IN_TABLE: bool = std::env::var("FLOW_JOBS_IN_TABLE")
+        .ok()
+        .and_then(|x| x.parse::<bool>().ok())
+        .unwrap_or(false);
+}
+
+/// Records `job` as the `idx`-th job spawned by the module at `step` of `flow`.
+///
+/// `flow_jobs` is the inline list of the module status, it is only extended when the flow keeps
+/// its flow jobs inline and doesn't already record `job` at `idx`, pushed again with the same
+/// idempotency key. Any job previously recorded at or after `idx` (from a retried module) is forgotten.
+pub async fn add_flow_job<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    status: &FlowStatus,
+    flow: Uuid,
+    step: i32,
+    idx: i32,
+    flow_jobs: &mut Vec<Uuid>,
+    job: Uuid,
+) -> error::Result<()> {
+    if !status.flow_jobs_in_table {
+        if flow_jobs.get(idx as usize) != Some(&job) {
+            flow_jobs.push(job);
+        }
+        return Ok(());
+    }
+
+    sqlx::query!(
+        "DELETE FROM flow_job WHERE flow = $1 AND step = $2 AND idx >= $3",
+        flow,
+        step,
+        idx
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO flow_job (flow, step, idx, job) VALUES ($1, $2, $3, $4)",
+        flow,
+        step,
+        idx,
+        job
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/// Forgets the jobs spawned by the module at `step` of `flow` past the first `len` ones.
+pub async fn truncate_flow_jobs<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    status: &FlowStatus,
+    flow: Uuid,
+    step: i32,
+    len: i32,
+    flow_jobs: &mut Vec<Uuid>,
+) -> error::Result<()> {
+    if !status.flow_jobs_in_table {
+        flow_jobs.truncate(len.try_into().unwrap_or_default());
+        return Ok(());
+    }
+
+    sqlx::query!(
+        "DELETE FROM flow_job WHERE flow = $1 AND step = $2 AND idx >= $3",
+        flow,
+        step,
+        len
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/// Returns the jobs spawned by the module at `step` of `flow`, `flow_jobs` being the inline
+/// list of the module status.
+pub async fn get_flow_jobs<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    status: &FlowStatus,
+    flow: Uuid,
+    step: i32,
+    flow_jobs: &[Uuid],
+) -> error::Result<Vec<Uuid>> {
+    if !status.flow_jobs_in_table {
+        return Ok(flow_jobs.to_vec());
+    }
+
+    let jobs = sqlx::query_scalar!(
+        "SELECT job FROM flow_job WHERE flow = $1 AND step = $2 ORDER BY idx",
+        flow,
+        step
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    Ok(jobs)
+}
+
+/// Fills the `flow_jobs` of a serialized flow status from the `flow_job` table so that it looks
+/// the same as if they had been kept inline. Does nothing for flows keeping them inline.
+pub async fn inline_flow_jobs<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    flow: Uuid,
+    flow_status: &mut Value,
+) -> error::Result<()> {
+    if flow_status.get("flow_jobs_in_table") != Some(&json!(true)) {
+        return Ok(());
+    }
+
+    let rows = sqlx::query!(
+        "SELECT step, job FROM flow_job WHERE flow = $1 ORDER BY step, idx",
+        flow
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut by_step: HashMap<i32, Vec<Uuid>> = HashMap::new();
+    for row in rows {
+        by_step.entry(row.step).or_default().push(row.job);
+    }
+
+    let mut set_flow_jobs = |step: usize, module: &mut Value| {
+        if let Some(flow_jobs) = module.get_mut("flow_jobs") {
+            *flow_jobs = json!(by_step.remove(&(step as i32)).unwrap_or_default());
+        }
+    };
+    let mut nb_modules = 0;
+    if let Some(modules) = flow_status
+        .get_mut("modules")
+        .and_then(|m| m.as_array_mut())
+    {
+        nb_modules = modules.len();
+        for (step, module) in modules.iter_mut().enumerate() {
+            set_flow_jobs(step, module);
+        }
+    }
+    /* the failure module is recorded at the step following the last module */
+    if let Some(failure_module) = flow_status.get_mut("failure_module") {
+        set_flow_jobs(nb_modules, failure_module);
+    }
+    Ok(())
+}
+
+/// The results of the completed `jobs` spawned by a forloop, as an array in the order of their
+/// iteration, or by a branchall, as an object keyed by the label or else the index of their branch.
+/// The jobs that haven't completed yet are left out, giving the partial results of a running loop.
+pub async fn flow_jobs_results<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    jobs: &[Uuid],
+    branchall: bool,
+) -> error::Result<Value> {
+    if branchall {
+        let results = sqlx::query_as::<_, (Option<String>, Value)>(
+            "
+              SELECT COALESCE(args->'branch'->>'label', args->'branch'->>'index'), result
+                FROM completed_job
+               WHERE id = ANY($1)
+                 AND workspace_id = $2
+            ORDER BY (args->'branch'->>'index')::int, array_position($1, id)
+                ",
+        )
+        .bind(jobs)
+        .bind(w_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        Ok(Value::Object(
+            results
+                .into_iter()
+                .enumerate()
+                .map(|(i, (key, result))| (key.unwrap_or_else(|| i.to_string()), result))
+                .collect(),
+        ))
+    } else {
+        let results = sqlx::query_as::<_, (Value,)>(
+            "
+              SELECT result
+                FROM completed_job
+               WHERE id = ANY($1)
+                 AND workspace_id = $2
+            ORDER BY args->'iter'->'index', array_position($1, id)
+                ",
+        )
+        .bind(jobs)
+        .bind(w_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        Ok(json!(results.into_iter().map(|(v,)| v).collect::<Vec<_>>()))
+    }
+}
@@ -41,9 +41,61 @@ lazy_static::lazy_static! {
     .unwrap();
 }
 
+lazy_static::lazy_static! {
+    pub static ref MAX_TRIGGER_DEPTH: i32 = std::env::var("MAX_TRIGGER_DEPTH")
+        .ok()
+        .and_then(|x| x.parse::<i32>().ok())
+        .unwrap_or(10);
+}
+
 const MAX_NB_OF_JOBS_IN_Q_PER_USER: i64 = 10;
 const MAX_DURATION_LAST_1200: std::time::Duration = std::time::Duration::from_secs(900);
 
+/// How far each wave of `max_concurrent_jobs` flow steps pushed past the limit is deferred, and
+/// how far a deferred job is deferred again if the limit is still reached when it comes due
+const CONCURRENCY_DEFERRAL_SECS: i64 = 5;
+
+/// The jobs of the workspace counted against its `max_concurrent_jobs`: the running ones, except
+/// the flows which only wait for their steps.
+pub async fn count_concurrent_jobs<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    workspace_id: &str,
+) -> error::Result<i64> {
+    let count = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM queue WHERE workspace_id = $1 AND running = true AND job_kind != \
+         'flow' AND job_kind != 'flowpreview'",
+        workspace_id
+    )
+    .fetch_one(tx)
+    .await?
+    .unwrap_or(0);
+    Ok(count)
+}
+
+/// Whether the jobs running in the workspace of this deferred job, itself included, are still
+/// past its `max_concurrent_jobs`.
+async fn still_at_concurrency_limit(
+    db: &Pool<Postgres>,
+    job: &QueuedJob,
+) -> windmill_common::error::Result<bool> {
+    let mut tx = db.begin().await?;
+    let max_concurrent_jobs = sqlx::query_scalar!(
+        "SELECT max_concurrent_jobs FROM workspace_settings WHERE workspace_id = $1",
+        &job.workspace_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .flatten();
+    let at_limit = match max_concurrent_jobs {
+        Some(max) => {
+            count_concurrent_jobs(&mut tx, &job.workspace_id).await? > i64::from(max.max(1))
+        }
+        None => false,
+    };
+    tx.commit().await?;
+    Ok(at_limit)
+}
+
 pub async fn cancel_job<'c>(
     username: &str,
     reason: Option<String>,
@@ -80,41 +132,59 @@ pub async fn cancel_job<'c>(
 }
 
 pub async fn pull(db: &Pool<Postgres>) -> windmill_common::error::Result<Option<QueuedJob>> {
-    /* Jobs can be started if they:
-     * - haven't been started before,
-     *   running = false
-     * - are flows with a step that needed resume,
-     *   suspend_until is non-null
-     *   and suspend = 0 when the resume messages are received
-     *   or suspend_until <= now() if it has timed out */
-    let job: Option<QueuedJob> = sqlx::query_as::<_, QueuedJob>(
-        "UPDATE queue
-            SET running = true
-              , started_at = coalesce(started_at, now())
-              , last_ping = now()
-              , suspend_until = null
-            WHERE id = (
-                SELECT id
-                FROM queue
-                WHERE (    running = false
-                       AND scheduled_for <= now())
-                   OR (suspend_until IS NOT NULL
-                       AND (   suspend <= 0
-                            OR suspend_until <= now()))
-                ORDER BY scheduled_for
-                FOR UPDATE SKIP LOCKED
-                LIMIT 1
-            )
-            RETURNING *",
-    )
-    .fetch_optional(db)
-    .await?;
+    loop {
+        /* Jobs can be started if they:
+         * - haven't been started before,
+         *   running = false
+         * - are flows with a step that needed resume,
+         *   suspend_until is non-null
+         *   and suspend = 0 when the resume messages are received
+         *   or suspend_until <= now() if it has timed out */
+        let job: Option<QueuedJob> = sqlx::query_as::<_, QueuedJob>(
+            "UPDATE queue
+                SET running = true
+                  , started_at = coalesce(started_at, now())
+                  , last_ping = now()
+                  , suspend_until = null
+                WHERE id = (
+                    SELECT id
+                    FROM queue
+                    WHERE (    running = false
+                           AND scheduled_for <= now())
+                       OR (suspend_until IS NOT NULL
+                           AND (   suspend <= 0
+                                OR suspend_until <= now()))
+                    ORDER BY scheduled_for
+                    FOR UPDATE SKIP LOCKED
+                    LIMIT 1
+                )
+                RETURNING *",
+        )
+        .fetch_optional(db)
+        .await?;
 
-    if job.is_some() {
-        QUEUE_PULL_COUNT.inc();
-    }
+        /* a deferred job only runs once its workspace is back under its max_concurrent_jobs,
+         * otherwise it is deferred again and the next job is pulled instead */
+        if let Some(job) = job.as_ref().filter(|job| job.deferred) {
+            if still_at_concurrency_limit(db, job).await? {
+                sqlx::query!(
+                    "UPDATE queue SET running = false, started_at = null, scheduled_for = now() + \
+                     make_interval(secs => $1) WHERE id = $2",
+                    CONCURRENCY_DEFERRAL_SECS as f64,
+                    job.id
+                )
+                .execute(db)
+                .await?;
+                continue;
+            }
+        }
 
-    Ok(job)
+        if job.is_some() {
+            QUEUE_PULL_COUNT.inc();
+        }
+
+        return Ok(job);
+    }
 }
 
 pub async fn get_result_by_id(
@@ -220,6 +290,54 @@ pub async fn get_queued_job<'c>(
     Ok(r)
 }
 
+/// The job of `workspace_id`, queued or completed, pushed with the idempotency key `key`.
+pub async fn get_idempotent_job<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    workspace_id: &str,
+    key: &str,
+) -> error::Result<Option<Uuid>> {
+    let job = sqlx::query_scalar!(
+        "SELECT id FROM queue WHERE workspace_id = $1 AND idempotency_key = $2
+         UNION ALL
+         SELECT id FROM completed_job WHERE workspace_id = $1 AND idempotency_key = $2
+         LIMIT 1",
+        workspace_id,
+        key
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .flatten();
+    Ok(job)
+}
+
+/// Where a flow keeps the parts of its status that grow with its loops, see `flow_jobs` and
+/// `flow_iterators`.
+#[derive(Clone, Copy, Debug)]
+pub struct FlowStorage {
+    pub flow_jobs_in_table: bool,
+    pub iterators_in_table: bool,
+}
+
+impl Default for FlowStorage {
+    /// The storage set for the instance with `FLOW_JOBS_IN_TABLE` and `ITERATORS_IN_TABLE`.
+    fn default() -> Self {
+        FlowStorage {
+            flow_jobs_in_table: *crate::flow_jobs::FLOW_JOBS_IN_TABLE,
+            iterators_in_table: *crate::flow_iterators::ITERATORS_IN_TABLE,
+        }
+    }
+}
+
+impl FlowStorage {
+    /// The storage of the flow with the status `status`, for the flows it spawns.
+    pub fn of(status: &FlowStatus) -> Self {
+        FlowStorage {
+            flow_jobs_in_table: status.flow_jobs_in_table,
+            iterators_in_table: status.iterators_in_table,
+        }
+    }
+}
+
 #[instrument(level = "trace", skip_all)]
 pub async fn push<'c>(
     mut tx: Transaction<'c, Postgres>,
@@ -233,8 +351,18 @@ pub async fn push<'c>(
     parent_job: Option<Uuid>,
     is_flow_step: bool,
     mut same_worker: bool,
+    idempotency_key: Option<String>,
+    flow_storage: FlowStorage,
 ) -> Result<(Uuid, Transaction<'c, Postgres>), Error> {
-    let scheduled_for = scheduled_for_o.unwrap_or_else(chrono::Utc::now);
+    /* a job was already pushed with this key, e.g. by a worker that crashed before it could
+     * carry on with it, it is the one returned */
+    if let Some(key) = idempotency_key.as_deref() {
+        if let Some(job) = get_idempotent_job(&mut tx, workspace_id, key).await? {
+            return Ok((job, tx));
+        }
+    }
+
+    let mut scheduled_for = scheduled_for_o.unwrap_or_else(chrono::Utc::now);
     let args_json = args.map(serde_json::Value::Object);
     let job_id: Uuid = Ulid::new().into();
 
@@ -289,7 +417,7 @@ pub async fn push<'c>(
         }
     }
 
-    let (script_hash, script_path, raw_code, job_kind, raw_flow, language) = match job_payload {
+    let (script_hash, script_path, raw_code, job_kind, mut raw_flow, language) = match job_payload {
         JobPayload::ScriptHash { hash, path } => {
             let language = sqlx::query_scalar!(
                 "SELECT language as \"language: ScriptLang\" FROM script WHERE hash = $1 AND \
@@ -371,8 +499,44 @@ pub async fn push<'c>(
     };
 
     let is_running = same_worker;
-    if let Some(flow) = raw_flow.as_ref() {
-        same_worker = same_worker || flow.same_worker;
+
+    /* past the limit of the workspace, flow steps are still pushed, but deferred by waves of the
+     * size of the limit so that they don't starve the other workspaces, see `pull` */
+    let mut deferred = false;
+    if is_flow_step && !is_running && job_kind != JobKind::Flow && job_kind != JobKind::FlowPreview
+    {
+        let max_concurrent_jobs = sqlx::query_scalar!(
+            "SELECT max_concurrent_jobs FROM workspace_settings WHERE workspace_id = $1",
+            workspace_id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .flatten();
+        if let Some(max_concurrent_jobs) = max_concurrent_jobs {
+            let waves = count_concurrent_jobs(&mut tx, workspace_id).await?
+                / i64::from(max_concurrent_jobs.max(1));
+            if waves > 0 {
+                let deferred_to = chrono::Utc::now()
+                    + chrono::Duration::seconds(CONCURRENCY_DEFERRAL_SECS * waves);
+                scheduled_for = scheduled_for.max(deferred_to);
+                deferred = true;
+            }
+        }
+    }
+
+    if let Some(flow) = raw_flow.as_mut() {
+        /* flows that don't set same_worker follow the default of their workspace */
+        if flow.same_worker.is_none() {
+            let flow_same_worker = sqlx::query_scalar!(
+                "SELECT flow_same_worker FROM workspace_settings WHERE workspace_id = $1",
+                workspace_id
+            )
+            .fetch_optional(&mut tx)
+            .await?
+            .unwrap_or(false);
+            flow.same_worker = Some(flow_same_worker);
+        }
+        same_worker = same_worker || flow.same_worker == Some(true);
 
         for module in flow.modules.iter() {
             if let Some(retry) = &module.retry {
@@ -392,13 +556,19 @@ pub async fn push<'c>(
         }
     }
 
-    let flow_status = raw_flow.as_ref().map(FlowStatus::new);
+    let flow_status = raw_flow.as_ref().map(|f| FlowStatus {
+        flow_jobs_in_table: flow_storage.flow_jobs_in_table,
+        iterators_in_table: flow_storage.iterators_in_table,
+        ..FlowStatus::new(f)
+    });
     let uuid = sqlx::query_scalar!(
         "INSERT INTO queue
             (workspace_id, id, running, parent_job, created_by, permissioned_as, scheduled_for, 
                 script_hash, script_path, raw_code, args, job_kind, schedule_path, raw_flow, \
-         flow_status, is_flow_step, language, started_at, same_worker)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, CASE WHEN $3 THEN now() END, $18) \
+         flow_status, is_flow_step, language, started_at, same_worker, root_job, deferred, \
+         idempotency_key)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, CASE WHEN $3 THEN now() END, $18, \
+         (SELECT COALESCE(root_job, id) FROM queue WHERE id = $4), $19, $20) \
          RETURNING id",
         workspace_id,
         job_id,
@@ -417,7 +587,9 @@ pub async fn push<'c>(
         flow_status.map(|f| serde_json::json!(f)),
         is_flow_step,
         language: ScriptLang,
-        same_worker
+        same_worker,
+        deferred,
+        idempotency_key
     )
     .fetch_one(&mut tx)
     .await
@@ -459,6 +631,47 @@ pub async fn push<'c>(
     Ok((uuid, tx))
 }
 
+/// Records `job` as triggered by the run that owns `token`, if any: the runs started from the
+/// token of a job form a chain, `job` then joining the chain of the top-level run of that job one
+/// level deeper. Fails when that is deeper than `MAX_TRIGGER_DEPTH`, so that runs triggering
+/// themselves stop at some point. Flow steps, being part of their flow run, aren't concerned.
+pub async fn chain_trigger<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    job: Uuid,
+    token: &str,
+) -> error::Result<()> {
+    let trigger = sqlx::query!(
+        "SELECT COALESCE(root.trigger_chain, root.id) AS \"chain!\", root.trigger_depth
+           FROM token
+           JOIN queue j ON j.id = token.job
+           JOIN queue root ON root.id = COALESCE(j.root_job, j.id)
+          WHERE token.token = $1",
+        token
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(trigger) = trigger {
+        let depth = trigger.trigger_depth + 1;
+        if depth > *MAX_TRIGGER_DEPTH {
+            return Err(Error::BadRequest(format!(
+                "Run not started: it would be at depth {depth} of the chain of runs started by {}, \
+                 more than the max trigger depth of {}",
+                trigger.chain, *MAX_TRIGGER_DEPTH
+            )));
+        }
+        sqlx::query!(
+            "UPDATE queue SET trigger_chain = $1, trigger_depth = $2 WHERE id = $3",
+            trigger.chain,
+            depth,
+            job
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
 pub fn canceled_job_to_result(job: &QueuedJob) -> String {
     let reason = job
         .canceled_reason
@@ -514,6 +727,30 @@ pub struct QueuedJob {
     pub is_flow_step: bool,
     pub language: Option<ScriptLang>,
     pub same_worker: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root_job: Option<Uuid>,
+    /// the id of the flow module this flow step job was pushed for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flow_step_id: Option<String>,
+    /// the completed flow this job is a rerun of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rerun_of: Option<Uuid>,
+    /// why the flow was parked, it then stays in the queue until canceled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub needs_intervention: Option<String>,
+    /// the first run of the chain of runs this run was triggered by, see `chain_trigger`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trigger_chain: Option<Uuid>,
+    pub trigger_depth: i32,
+    /// seconds this flow step job may run for, the timeout of its module
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<i32>,
+    /// the job was pushed while its workspace was at its limit of concurrent jobs, its
+    /// `scheduled_for` is pushed out until it can run
+    pub deferred: bool,
+    /// pushing a job with the key of a job already pushed returns that job instead, see `push`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
 }
 
 impl QueuedJob {
@@ -523,6 +760,29 @@ impl QueuedJob {
             .map(String::as_str)
             .unwrap_or("NO_FLOW_PATH")
     }
+
+    /// The id shared by all the jobs spawned, directly or not, by the same top-level job.
+    /// Used as the trace id of the spans of this job so a whole flow run can be followed
+    /// across workers.
+    pub fn trace_id(&self) -> Uuid {
+        self.root_job.unwrap_or(self.id)
+    }
+
+    /// The span this job runs under: the `flow_step` span of the step of its parent flow that
+    /// pushed it, identified like `step_span_id` does. The span of the job itself is identified by
+    /// its id.
+    pub fn parent_span_id(&self) -> Option<String> {
+        let parent_job = self.parent_job?;
+        Some(match self.flow_step_id.as_deref() {
+            Some(module_id) => step_span_id(parent_job, module_id),
+            None => parent_job.to_string(),
+        })
+    }
+}
+
+/// The id of the `flow_step` span of the module `module_id` of the flow `flow`.
+pub fn step_span_id(flow: Uuid, module_id: &str) -> String {
+    format!("{flow}/{module_id}")
 }
 
 impl QueuedJob {
@@ -6,6 +6,8 @@
  * LICENSE-AGPL for a copy of the license.
  */
 
+pub mod flow_iterators;
+pub mod flow_jobs;
 mod jobs;
 pub mod schedule;
 
@@ -17,7 +17,7 @@ use windmill_common::{
     utils::{get_owner_from_path, not_found_if_none, now_from_db, paginate, Pagination, StripPath},
 };
 
-use crate::{push, JobPayload};
+use crate::{push, FlowStorage, JobPayload};
 
 #[derive(FromRow, Serialize, Deserialize, Debug)]
 pub struct Schedule {
@@ -32,6 +32,8 @@ pub struct Schedule {
     pub is_flow: bool,
     pub args: Option<serde_json::Value>,
     pub extra_perms: serde_json::Value,
+    /// when the last job of the schedule was pushed for, so that it is only pushed once
+    pub last_scheduled_for: Option<DateTime<chrono::Utc>>,
 }
 
 #[derive(Deserialize)]
@@ -74,6 +76,24 @@ pub async fn push_scheduled_job<'c>(
         return Ok(tx);
     }
 
+    /* claiming the run on the schedule row serializes the workers re-arming it concurrently, or
+     * again after a crash, only the first one pushes it */
+    let claimed = sqlx::query_scalar!(
+        "UPDATE schedule SET last_scheduled_for = $3
+          WHERE workspace_id = $1 AND path = $2
+            AND last_scheduled_for IS DISTINCT FROM $3
+         RETURNING path",
+        &schedule.workspace_id,
+        &schedule.path,
+        next
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+
+    if claimed.is_none() {
+        return Ok(tx);
+    }
+
     let mut args: Option<serde_json::Map<String, serde_json::Value>> = None;
 
     if let Some(args_v) = schedule.args {
@@ -112,6 +132,8 @@ pub async fn push_scheduled_job<'c>(
         None,
         false,
         false,
+        None,
+        FlowStorage::default(),
     )
     .await?;
     Ok(tx)
@@ -206,11 +228,23 @@ pub struct EditSchedule {
     pub args: Option<serde_json::Value>,
 }
 
-async fn clear_schedule<'c>(db: &mut Transaction<'c, Postgres>, path: &str) -> Result<()> {
+async fn clear_schedule<'c>(
+    db: &mut Transaction<'c, Postgres>,
+    path: &str,
+    w_id: &str,
+) -> Result<()> {
     sqlx::query!(
         "DELETE FROM queue WHERE schedule_path = $1 AND running = false",
         path
     )
+    .execute(&mut *db)
+    .await?;
+    /* the run that was cleared can be pushed again */
+    sqlx::query!(
+        "UPDATE schedule SET last_scheduled_for = NULL WHERE path = $1 AND workspace_id = $2",
+        path,
+        w_id
+    )
     .execute(db)
     .await?;
     Ok(())
@@ -229,7 +263,7 @@ pub async fn edit_schedule(
 
     check_flow_conflict(&mut tx, &w_id, &path, es.is_flow, &es.script_path).await?;
 
-    clear_schedule(&mut tx, path).await?;
+    clear_schedule(&mut tx, path, &w_id).await?;
     let schedule = sqlx::query_as!(
         Schedule,
         "UPDATE schedule SET schedule = $1, script_path = $2, is_flow = $3, args = $4 WHERE path \
@@ -344,6 +378,29 @@ pub fn preview_schedule(
     Ok(upcoming)
 }
 
+/// The occurrences of the cron `schedule` between `start` and `end`, both included. Fails if
+/// there are more than `max` of them.
+pub fn schedule_occurrences(
+    schedule: &str,
+    start: DateTime<chrono::Utc>,
+    end: DateTime<chrono::Utc>,
+    max: usize,
+) -> Result<Vec<DateTime<chrono::Utc>>> {
+    let schedule = cron::Schedule::from_str(schedule)
+        .map_err(|e| Error::ExecutionErr(format!("invalid cron schedule: {e}")))?;
+    let occurrences: Vec<DateTime<chrono::Utc>> = schedule
+        .after(&(start - Duration::seconds(1)))
+        .take_while(|x| x <= &end)
+        .take(max + 1)
+        .collect();
+    if occurrences.len() > max {
+        return Err(Error::ExecutionErr(format!(
+            "schedule has more than {max} occurrences between {start} and {end}"
+        )));
+    }
+    Ok(occurrences)
+}
+
 fn get_offset(offset: Option<i32>) -> FixedOffset {
     FixedOffset::west(offset.unwrap_or(0) * 60)
 }
@@ -373,7 +430,7 @@ pub async fn set_enabled(
 
     let schedule = not_found_if_none(schedule_o, "Schedule", path)?;
 
-    clear_schedule(&mut tx, path).await?;
+    clear_schedule(&mut tx, path, &w_id).await?;
 
     if enabled {
         tx = push_scheduled_job(tx, schedule).await?;
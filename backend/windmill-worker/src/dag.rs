@@ -0,0 +1,569 @@
+/*
+ * Author: Ruben Fiszel
+ * Copyright: Windmill Labs, Inc 2022
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+//! Runs the flows opted in with `dag`, whose modules don't run one after the other by `step` but
+//! as soon as the modules of their `depends_on` have succeeded, the independent ones
+//! concurrently.
+//!
+//! A module gets the result of the module it depends on as `previous_result`, or an object of
+//! the results of the modules it depends on keyed by their id when there are several, and the
+//! flow input when it depends on none. The results of any completed module are available with
+//! `result_by_id`. The result of the flow is built the same way from the results of the modules
+//! no other one depends on.
+//!
+//! The modules of a dag flow are scripts, raw scripts and identities, each run as a job of the
+//! flow. Once one fails no other one starts, and the flow fails with its error when those
+//! running are done.
+
+use std::collections::{HashMap, HashSet};
+
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+use windmill_common::{
+    error::{self, Error},
+    flow_status::{FailureReason, FlowStatus, FlowStatusModule, FlowStepEvent},
+    flows::{FlowModule, FlowModuleValue, FlowValue},
+};
+use windmill_queue::{
+    canceled_job_to_result, get_queued_job, push, FlowStorage, JobPayload, QueuedJob, RawCode,
+};
+
+use crate::jobs::{add_completed_job, add_completed_job_error, schedule_again_if_scheduled};
+use crate::worker_flow::{
+    get_transform_context, identity_args, notify_flow_step, script_path_to_payload,
+    transform_input, transform_output,
+};
+
+type DB = sqlx::Pool<sqlx::Postgres>;
+
+/// Starts the modules of the dag flow `flow_job` that depend on none.
+pub(crate) async fn handle_dag_flow(
+    flow_job: &QueuedJob,
+    mut status: FlowStatus,
+    flow: &FlowValue,
+    db: &DB,
+    base_internal_url: &str,
+) -> error::Result<()> {
+    validate_dag(flow)?;
+
+    let mut tx = db.begin().await?;
+    lock_flow(&mut tx, flow_job.id).await?;
+    tx = push_ready_modules(db, tx, flow_job, &mut status, flow, base_internal_url).await?;
+    update_modules_status(&mut tx, flow_job.id, &status).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Records the completion of the job `job` of the dag flow `flow` and starts the modules that
+/// were waiting for it, or completes the flow once none is left running. `job` is nil when the
+/// flow itself failed.
+pub(crate) async fn update_dag_flow_status_after_job_completion(
+    db: &DB,
+    client: &windmill_api_client::Client,
+    flow: Uuid,
+    job: &Uuid,
+    w_id: &str,
+    success: bool,
+    result: Value,
+    base_internal_url: &str,
+) -> error::Result<()> {
+    let mut tx = db.begin().await?;
+
+    /* Lock the flow so that the modules completing concurrently are recorded, and their
+     * dependents started, one after the other */
+    lock_flow(&mut tx, flow).await?;
+    let mut flow_job = get_queued_job(flow, w_id, &mut tx)
+        .await?
+        .ok_or_else(|| Error::InternalErr(format!("requiring the queued flow {flow}")))?;
+
+    if job.is_nil() {
+        tx.commit().await?;
+        let logs = format!(
+            "{}Flow job failed",
+            flow_job.logs.as_deref().unwrap_or_default()
+        );
+        add_completed_job(db, client, &flow_job, false, false, result, logs).await?;
+        return Ok(());
+    }
+
+    let raw_flow = flow_job
+        .parse_raw_flow()
+        .ok_or_else(|| Error::InternalErr(format!("requiring a raw flow value")))?;
+    let mut status =
+        serde_json::from_value::<FlowStatus>(flow_job.flow_status.clone().unwrap_or_default())
+            .map_err(|e| Error::InternalErr(format!("unparsable status of flow {flow}: {e}")))?;
+
+    /* the module may have been recorded already, by a canceled flow for instance */
+    let i = match status
+        .modules
+        .iter()
+        .position(|m| matches!(m, FlowStatusModule::InProgress { .. }) && m.job() == Some(*job))
+    {
+        Some(i) => i,
+        None => {
+            tx.commit().await?;
+            return Ok(());
+        }
+    };
+
+    let id = status.modules[i].id();
+    /* the modules are in progress once pushed, the timing of the step is the one of its job */
+    let (started_at, ended_at) = sqlx::query_as::<
+        _,
+        (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>),
+    >("SELECT started_at, now() FROM completed_job WHERE id = $1")
+    .bind(job)
+    .fetch_optional(&mut tx)
+    .await?
+    .map_or((None, None), |(s, e)| (Some(s), Some(e)));
+    status.modules[i] = if success {
+        FlowStatusModule::Success {
+            id: id.clone(),
+            job: *job,
+            flow_jobs: None,
+            branch_chosen: None,
+            approvers: vec![],
+            warning: None,
+            skipped: false,
+            started_at,
+            ended_at,
+        }
+    } else {
+        FlowStatusModule::Failure {
+            id: id.clone(),
+            job: *job,
+            flow_jobs: None,
+            branch_chosen: None,
+            reason: FailureReason::of_result(&result),
+            started_at,
+            ended_at,
+        }
+    };
+
+    let failed = flow_job.canceled
+        || status
+            .modules
+            .iter()
+            .any(|m| matches!(m, FlowStatusModule::Failure { .. }));
+    if !failed {
+        tx = push_ready_modules(db, tx, &flow_job, &mut status, &raw_flow, base_internal_url)
+            .await?;
+    }
+    let done = !status
+        .modules
+        .iter()
+        .any(|m| matches!(m, FlowStatusModule::InProgress { .. }));
+
+    update_modules_status(&mut tx, flow, &status).await?;
+    notify_flow_step(
+        &mut tx,
+        FlowStepEvent {
+            flow,
+            root: flow_job.trace_id(),
+            module_id: id,
+            job: *job,
+            success,
+            result: Some(result.clone()),
+            result_truncated: false,
+            done,
+        },
+    )
+    .await?;
+
+    let first_completed = status
+        .modules
+        .iter()
+        .filter(|m| {
+            matches!(
+                m,
+                FlowStatusModule::Success { .. } | FlowStatusModule::Failure { .. }
+            )
+        })
+        .count()
+        == 1;
+    if let (true, Some(schedule_path), Some(script_path)) = (
+        first_completed,
+        &flow_job.schedule_path,
+        &flow_job.script_path,
+    ) {
+        tx = schedule_again_if_scheduled(tx, client, schedule_path, script_path, w_id).await?;
+    }
+
+    if !done {
+        tx.commit().await?;
+        return Ok(());
+    }
+
+    let first_failure = status
+        .modules
+        .iter()
+        .find(|m| matches!(m, FlowStatusModule::Failure { .. }))
+        .and_then(|m| m.job());
+    let (success, result) = match first_failure {
+        Some(failed) if failed == *job => (false, result),
+        Some(failed) => (false, job_result(&mut tx, w_id, failed).await?),
+        None => {
+            let depended_on: HashSet<&String> = raw_flow
+                .modules
+                .iter()
+                .flat_map(|m| &m.depends_on)
+                .collect();
+            let sinks = raw_flow
+                .modules
+                .iter()
+                .map(|m| &m.id)
+                .filter(|id| !depended_on.contains(id))
+                .cloned()
+                .collect::<Vec<_>>();
+            (
+                true,
+                results_of(&mut tx, w_id, &raw_flow, &status, &sinks).await?,
+            )
+        }
+    };
+    tx.commit().await?;
+
+    flow_job.flow_status = Some(json!(status));
+    let (success, result) = match raw_flow.output_transform.as_ref() {
+        Some(output_transform) if success && !flow_job.canceled => {
+            transform_output(output_transform, &mut flow_job, result, base_internal_url).await
+        }
+        _ => (success, result),
+    };
+
+    let logs = flow_job.logs.clone().unwrap_or_default();
+    if flow_job.canceled {
+        let logs = format!("{logs}Flow job canceled");
+        add_completed_job_error(
+            db,
+            client,
+            &flow_job,
+            logs,
+            &canceled_job_to_result(&flow_job),
+            None,
+        )
+        .await?;
+    } else {
+        let logs = format!("{logs}Flow job completed");
+        add_completed_job(db, client, &flow_job, success, false, result, logs).await?;
+    }
+    Ok(())
+}
+
+/// Checks that the modules of `flow` form a dag the engine can run: they are all supported,
+/// their ids are unique, and their dependencies exist and have no cycle.
+pub(crate) fn validate_dag(flow: &FlowValue) -> error::Result<()> {
+    let invalid = |msg: String| Err(Error::ExecutionErr(format!("Invalid dag flow: {msg}")));
+
+    if flow.failure_module.is_some() {
+        return invalid("dag flows don't support failure modules".to_string());
+    }
+    if flow.on_cancel.is_some() {
+        return invalid("dag flows don't support on_cancel".to_string());
+    }
+
+    let mut ids = HashSet::new();
+    for module in &flow.modules {
+        if !ids.insert(&module.id) {
+            return invalid(format!("the id `{}` is used by several modules", module.id));
+        }
+        if !matches!(
+            module.value,
+            FlowModuleValue::Script { .. }
+                | FlowModuleValue::RawScript { .. }
+                | FlowModuleValue::Identity { .. }
+        ) {
+            return invalid(format!(
+                "module `{}` is not a script, a raw script or an identity",
+                module.id
+            ));
+        }
+        let unsupported = unsupported_features(module);
+        if !unsupported.is_empty() {
+            return invalid(format!(
+                "module `{}` uses {} which dag flows don't support",
+                module.id,
+                unsupported.join(", ")
+            ));
+        }
+    }
+
+    for module in &flow.modules {
+        if let Some(unknown) = module.depends_on.iter().find(|id| !ids.contains(id)) {
+            return invalid(format!(
+                "module `{}` depends on `{unknown}` which does not exist",
+                module.id
+            ));
+        }
+    }
+
+    /* a module is sorted once all of its dependencies are, the ones never sorted are on or
+     * after a cycle */
+    let mut sorted: HashSet<&String> = HashSet::new();
+    loop {
+        let ready = flow
+            .modules
+            .iter()
+            .filter(|m| !sorted.contains(&m.id) && m.depends_on.iter().all(|d| sorted.contains(d)))
+            .map(|m| &m.id)
+            .collect::<Vec<_>>();
+        if ready.is_empty() {
+            break;
+        }
+        sorted.extend(ready);
+    }
+    if let Some(module) = flow.modules.iter().find(|m| !sorted.contains(&m.id)) {
+        return invalid(format!(
+            "the dependencies of module `{}` form a cycle",
+            module.id
+        ));
+    }
+
+    Ok(())
+}
+
+fn unsupported_features(module: &FlowModule) -> Vec<&'static str> {
+    [
+        ("suspend", module.suspend.is_some()),
+        ("sleep", module.sleep.is_some()),
+        ("retry", module.retry.is_some()),
+        ("poll", module.poll.is_some()),
+        ("stop_after_if", module.stop_after_if.is_some()),
+        ("skip_if", module.skip_if.is_some()),
+        ("assert", module.assert.is_some()),
+        ("result_schema", module.result_schema.is_some()),
+        ("partial_result", module.partial_result.is_some()),
+        ("group", module.group.is_some()),
+        ("compensation", module.compensation.is_some()),
+        ("catch", !module.catch.is_empty()),
+        ("cache_ttl", module.cache_ttl.is_some()),
+    ]
+    .into_iter()
+    .filter(|(_, used)| *used)
+    .map(|(feature, _)| feature)
+    .collect()
+}
+
+async fn lock_flow<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    flow: Uuid,
+) -> error::Result<()> {
+    sqlx::query_scalar!("SELECT null FROM queue WHERE id = $1 FOR UPDATE", flow)
+        .fetch_one(tx)
+        .await
+        .map_err(|e| Error::InternalErr(format!("lock flow {flow} in queue: {e}")))?;
+    Ok(())
+}
+
+async fn update_modules_status<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    flow: Uuid,
+    status: &FlowStatus,
+) -> error::Result<()> {
+    sqlx::query(
+        "UPDATE queue SET flow_status = JSONB_SET(flow_status, ARRAY['modules'], $1) WHERE id = $2",
+    )
+    .bind(json!(status.modules))
+    .bind(flow)
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+/// Pushes the jobs of the modules of `flow` whose dependencies have all succeeded and marks
+/// them in progress in `status`.
+async fn push_ready_modules<'c>(
+    db: &DB,
+    mut tx: sqlx::Transaction<'c, sqlx::Postgres>,
+    flow_job: &QueuedJob,
+    status: &mut FlowStatus,
+    flow: &FlowValue,
+    base_internal_url: &str,
+) -> error::Result<sqlx::Transaction<'c, sqlx::Postgres>> {
+    let succeeded: HashSet<String> = status
+        .modules
+        .iter()
+        .filter(|m| matches!(m, FlowStatusModule::Success { .. }))
+        .map(|m| m.id())
+        .collect();
+    let ready = flow
+        .modules
+        .iter()
+        .enumerate()
+        .filter(|(i, m)| {
+            matches!(
+                status.modules.get(*i),
+                Some(FlowStatusModule::WaitingForPriorSteps { .. })
+            ) && m.depends_on.iter().all(|d| succeeded.contains(d))
+        })
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    if ready.is_empty() {
+        return Ok(tx);
+    }
+
+    let (new_tx, (token, steps, by_id, trigger)) =
+        get_transform_context(tx, flow_job, status, &flow.modules).await?;
+    tx = new_tx;
+
+    for i in ready {
+        let module = &flow.modules[i];
+        let previous_result = if module.depends_on.is_empty() {
+            flow_job.args.clone().unwrap_or_else(|| json!({}))
+        } else {
+            results_of(
+                &mut tx,
+                &flow_job.workspace_id,
+                flow,
+                status,
+                &module.depends_on,
+            )
+            .await?
+        };
+
+        let (payload, args) = match &module.value {
+            FlowModuleValue::Script { input_transforms, .. }
+            | FlowModuleValue::RawScript { input_transforms, .. } => {
+                let args = transform_input(
+                    db,
+                    flow_job,
+                    previous_result,
+                    if !input_transforms.is_empty() {
+                        input_transforms
+                    } else {
+                        &module.input_transforms
+                    },
+                    &token,
+                    steps.clone(),
+                    &[],
+                    &by_id,
+                    &trigger,
+                    base_internal_url,
+                )
+                .await?;
+                let payload = match &module.value {
+                    FlowModuleValue::Script { path, hash, .. } => {
+                        script_path_to_payload(path, *hash, &mut tx, &flow_job.workspace_id).await?
+                    }
+                    FlowModuleValue::RawScript { path, content, language, .. } => {
+                        JobPayload::Code(RawCode {
+                            path: path.clone().or_else(|| {
+                                Some(format!("{}/{}", flow_job.script_path(), module.id))
+                            }),
+                            content: content.clone(),
+                            language: language.clone(),
+                        })
+                    }
+                    _ => unreachable!(),
+                };
+                (payload, args)
+            }
+            FlowModuleValue::Identity { expr } => {
+                let args = identity_args(
+                    expr.as_deref(),
+                    previous_result,
+                    flow_job,
+                    base_internal_url,
+                )
+                .await?;
+                (JobPayload::Identity, args)
+            }
+            _ => {
+                return Err(Error::ExecutionErr(format!(
+                    "module `{}` can't run in a dag flow",
+                    module.id
+                )))
+            }
+        };
+
+        let (uuid, new_tx) = push(
+            tx,
+            &flow_job.workspace_id,
+            payload,
+            Some(args),
+            &flow_job.created_by,
+            flow_job.permissioned_as.to_owned(),
+            None,
+            flow_job.schedule_path.clone(),
+            Some(flow_job.id),
+            true,
+            false,
+            None,
+            FlowStorage::default(),
+        )
+        .await?;
+        tx = new_tx;
+        sqlx::query!(
+            "UPDATE queue SET flow_step_id = $1, timeout = $2 WHERE id = $3",
+            module.id,
+            module.timeout.map(|t| t as i32),
+            uuid
+        )
+        .execute(&mut tx)
+        .await?;
+
+        status.modules[i] = FlowStatusModule::InProgress {
+            id: module.id.clone(),
+            job: uuid,
+            iterator: None,
+            flow_jobs: None,
+            branch_chosen: None,
+            branchall: None,
+            catching: None,
+            started_at: None,
+        };
+    }
+
+    Ok(tx)
+}
+
+/// The result of the completed module `ids[0]` when there is only one, otherwise an object of
+/// the results of the completed modules `ids` keyed by their id.
+async fn results_of<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    w_id: &str,
+    flow: &FlowValue,
+    status: &FlowStatus,
+    ids: &[String],
+) -> error::Result<Value> {
+    let jobs: HashMap<&String, Option<Uuid>> = flow
+        .modules
+        .iter()
+        .zip(&status.modules)
+        .map(|(m, s)| (&m.id, s.job()))
+        .collect();
+    let mut results = Map::new();
+    for id in ids {
+        let job = jobs
+            .get(id)
+            .copied()
+            .flatten()
+            .ok_or_else(|| Error::InternalErr(format!("module `{id}` has not completed")))?;
+        results.insert(id.clone(), job_result(tx, w_id, job).await?);
+    }
+    match (ids, results.len()) {
+        ([id], 1) => Ok(results.remove(id).unwrap_or(Value::Null)),
+        _ => Ok(Value::Object(results)),
+    }
+}
+
+async fn job_result<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    w_id: &str,
+    job: Uuid,
+) -> error::Result<Value> {
+    Ok(sqlx::query_scalar!(
+        "SELECT result FROM completed_job WHERE id = $1 AND workspace_id = $2",
+        job,
+        w_id,
+    )
+    .fetch_optional(tx)
+    .await?
+    .flatten()
+    .unwrap_or(Value::Null))
+}
@@ -0,0 +1,76 @@
+/*
+ * Author: Ruben Fiszel
+ * Copyright: Windmill Labs, Inc 2022
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+//! Evaluates the iterators and the predicates of a flow for `windmill_common::fan_out`, the
+//! javascript ones with the flow input only, like when the flow runs.
+
+use futures::{future::BoxFuture, FutureExt};
+use serde_json::Value;
+use windmill_common::{
+    fan_out::FanOutEval,
+    flows::{InputTransform, LoopIterator},
+};
+
+use crate::js_eval::eval_timeout;
+use crate::worker_flow::{evaluate_iterator, predicate_value};
+
+pub struct JsFanOutEval;
+
+impl FanOutEval for JsFanOutEval {
+    fn iterate<'a>(
+        &'a self,
+        iterator: &'a LoopIterator,
+        flow_input: Option<&'a Value>,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<Value>>> {
+        async move {
+            Ok(evaluate_iterator(iterator, |transform| evaluate(transform, flow_input)).await?)
+        }
+        .boxed()
+    }
+
+    fn predicate<'a>(
+        &'a self,
+        expr: &'a str,
+        flow_input: Option<&'a Value>,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        evaluate_predicate(expr, flow_input).boxed()
+    }
+}
+
+/// Coerced to a boolean like the predicates of the branches when the flow runs.
+async fn evaluate_predicate(expr: &str, flow_input: Option<&Value>) -> anyhow::Result<bool> {
+    let value = evaluate(
+        InputTransform::Javascript { expr: expr.to_string() },
+        flow_input,
+    )
+    .await?;
+    Ok(predicate_value(value, "")?)
+}
+
+async fn evaluate(transform: InputTransform, flow_input: Option<&Value>) -> anyhow::Result<Value> {
+    match (transform, flow_input) {
+        (InputTransform::Static { value }, _) => Ok(value),
+        (InputTransform::Javascript { expr }, Some(flow_input)) => {
+            eval_timeout(
+                expr,
+                vec![("flow_input".to_string(), flow_input.clone())],
+                None,
+                vec![],
+                None,
+                String::new(),
+            )
+            .await
+        }
+        (InputTransform::Javascript { .. }, None) => Err(anyhow::anyhow!(
+            "the input of sub-flows is only known at runtime"
+        )),
+        (InputTransform::Resource { .. }, _) => Err(anyhow::anyhow!(
+            "a resource transform is only supported as the input of a step"
+        )),
+    }
+}
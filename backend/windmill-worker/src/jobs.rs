@@ -10,7 +10,7 @@ use serde_json::{Map, Value};
 use sqlx::{Pool, Postgres, Transaction};
 use tracing::instrument;
 use uuid::Uuid;
-use windmill_common::error::Error;
+use windmill_common::{error::Error, flow_status::StepError};
 use windmill_queue::{delete_job, JobKind, QueuedJob};
 
 #[instrument(level = "trace", skip_all)]
@@ -38,14 +38,17 @@ pub async fn add_completed_job_error<E: ToString + std::fmt::Debug>(
     Ok((a, output_map))
 }
 
+/// The error is also structured as a `StepError`, so that every failed job has the same error
+/// fields whether or not a failure module or catch modules get it.
 pub fn error_to_result<E: ToString + std::fmt::Debug>(
     output_map: &mut Map<String, Value>,
     err: &E,
 ) {
-    output_map.insert(
-        "error".to_string(),
-        serde_json::Value::String(err.to_string()),
-    );
+    let error = serde_json::Value::String(err.to_string());
+    if let Value::Object(fields) = serde_json::json!(StepError::of_result(&error, None)) {
+        output_map.extend(fields);
+    }
+    output_map.insert("error".to_string(), error);
 }
 
 #[instrument(level = "trace", skip_all)]
@@ -86,9 +89,15 @@ pub async fn add_completed_job(
                    , raw_flow
                    , is_flow_step
                    , is_skipped
-                   , language )
+                   , language
+                   , flow_step_id
+                   , rerun_of
+                   , trigger_chain
+                   , trigger_depth
+                   , idempotency_key )
             VALUES ($1, $2, $3, $4, $5, $6, EXTRACT(milliseconds FROM (now() - $6)), $7, $8, $9,\
-                    $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24)
+                    $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24, $25, $26,\
+                    $27, $28, $29)
          ON CONFLICT (id) DO UPDATE SET success = $7, result = $11, logs = concat(cj.logs, $12)",
         queued_job.workspace_id,
         queued_job.id,
@@ -114,6 +123,11 @@ pub async fn add_completed_job(
         queued_job.is_flow_step,
         skipped,
         queued_job.language: ScriptLang,
+        queued_job.flow_step_id,
+        queued_job.rerun_of,
+        queued_job.trigger_chain,
+        queued_job.trigger_depth,
+        queued_job.idempotency_key,
     )
     .execute(&mut tx)
     .await
@@ -174,6 +188,7 @@ pub async fn schedule_again_if_scheduled<'c>(
                     .args
                     .and_then(|e| serde_json::to_value(e).map_or(None, |v| Some(v))),
                 extra_perms: serde_json::to_value(schedule.extra_perms).expect("hashmap -> json"),
+                last_scheduled_for: None,
             },
         )
         .await?;
@@ -46,6 +46,7 @@ pub async fn eval_timeout(
                     // and serializes the returned Result & value
                     op_variable::decl(),
                     op_resource::decl(),
+                    op_get_blob::decl(),
                 ])
             }
 
@@ -209,6 +210,9 @@ async function variable(path) {{
 }}
 async function resource(path) {{
     return await Deno.core.opAsync("op_resource", [workspace, path, token, base_url]);
+}}
+async function blob(ref) {{
+    return await Deno.core.opAsync("op_get_blob", [workspace, ref["$blob"], token, base_url]);
 }}
         "#,
             base_internal_url,
@@ -271,6 +275,18 @@ async fn op_variable(args: Vec<String>) -> Result<String, anyhow::Error> {
     Ok(result.into_inner().value.unwrap_or_else(|| "".to_owned()))
 }
 
+/// Resolves a blob reference returned by a previous step, the content is base64 encoded.
+#[op]
+async fn op_get_blob(args: Vec<String>) -> Result<String, anyhow::Error> {
+    let workspace = &args[0];
+    let id = &args[1];
+    let token = &args[2];
+    let base_url = &args[3];
+    let client = windmill_api_client::create_client(base_url, token.clone());
+    let result = client.get_job_blob_base64(workspace, &id.parse()?).await?;
+    Ok(result.into_inner())
+}
+
 #[op]
 async fn op_get_result(
     args: Vec<String>,
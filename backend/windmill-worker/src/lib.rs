@@ -1,6 +1,10 @@
+mod dag;
+mod fan_out;
 mod jobs;
 mod js_eval;
 mod worker;
 mod worker_flow;
 
+pub use fan_out::JsFanOutEval;
 pub use worker::*;
+pub use worker_flow::{cancel_timed_out_flows, trigger_failure_module};
@@ -13,6 +13,7 @@ use tracing::{trace_span, Instrument};
 use uuid::Uuid;
 use windmill_common::{
     error::{self, to_anyhow, Error},
+    flow_status::{FailureReason, StepError},
     scripts::{ScriptHash, ScriptLang},
     utils::rd_string,
     variables,
@@ -44,7 +45,8 @@ use async_recursion::async_recursion;
 use crate::{
     jobs::{add_completed_job, add_completed_job_error, error_to_result},
     worker_flow::{
-        handle_flow, update_flow_status_after_job_completion, update_flow_status_in_progress,
+        cancel_timed_out_flows, handle_flow, remove_flow_dir_unless_continued,
+        update_flow_status_after_job_completion, update_flow_status_in_progress,
     },
 };
 
@@ -56,6 +58,7 @@ pub async fn create_token_for_owner<'c>(
     label: &str,
     expires_in: i32,
     username: &str,
+    job: Option<Uuid>,
 ) -> error::Result<(Transaction<'c, Postgres>, String)> {
     // TODO: Bad implementation. We should not have access to this DB here.
     let token: String = rd_string(30);
@@ -70,14 +73,15 @@ pub async fn create_token_for_owner<'c>(
 
     let expiration = sqlx::query_scalar!(
         "INSERT INTO token
-            (workspace_id, token, owner, label, expiration, super_admin)
-            VALUES ($1, $2, $3, $4, now() + ($5 || ' seconds')::interval, $6) RETURNING expiration",
+            (workspace_id, token, owner, label, expiration, super_admin, job)
+            VALUES ($1, $2, $3, $4, now() + ($5 || ' seconds')::interval, $6, $7) RETURNING expiration",
         &w_id,
         token,
         owner,
         label,
         expires_in.to_string(),
-        is_super_admin
+        is_super_admin,
+        job
     )
     .fetch_one(&mut tx)
     .await?;
@@ -363,10 +367,12 @@ pub async fn run_worker(
                         .await
                         .expect("could not create job dir");
 
-                    let same_worker = job.same_worker;
+                    let (job_id, same_worker) = (job.id, job.same_worker);
                     let is_flow = job.job_kind == JobKind::Flow || job.job_kind == JobKind::FlowPreview;
 
-                    if is_flow && same_worker {
+                    /* steps may be continued on this worker without their flow having been,
+                     * the shared dir of the flow is then created on the first of them */
+                    if same_worker {
                         let target = &format!("{job_dir}/shared");
                         if let Some(parent_flow) = job.parent_job {
                             let parent_shared_dir = format!("{worker_dir}/{parent_flow}/shared");
@@ -377,10 +383,12 @@ pub async fn run_worker(
                                     .await
                                     .expect("could not create parent shared dir");
                             }
-                            symlink(&parent_shared_dir, target)
-                                .await
-                                .expect("could not symlink target");
-                        } else {
+                            if is_flow {
+                                symlink(&parent_shared_dir, target)
+                                    .await
+                                    .expect("could not symlink target");
+                            }
+                        } else if is_flow {
                             DirBuilder::new()
                                 .create(target)
                                 .await
@@ -395,6 +403,7 @@ pub async fn run_worker(
                         "ephemeral-script",
                         timeout * 2,
                         &job.created_by,
+                        Some(job.id),
                     )
                     .await.expect("could not create job token");
                     tx.commit().await.expect("could not commit job token");
@@ -433,8 +442,12 @@ pub async fn run_worker(
                         .await;
                     };
 
-                    if !worker_config.keep_job_dir && !(is_flow && same_worker) {
-                        let _ = tokio::fs::remove_dir_all(job_dir).await;
+                    if !worker_config.keep_job_dir {
+                        if is_flow {
+                            remove_flow_dir_unless_continued(db, job_id, &worker_dir).await;
+                        } else {
+                            let _ = tokio::fs::remove_dir_all(job_dir).await;
+                        }
                     }
                 }
                 Ok(None) => {
@@ -562,7 +575,44 @@ struct Envs {
     pip_trusted_host: Option<String>,
 }
 
-#[tracing::instrument(level = "trace", skip_all)]
+/// Fails the flow step `job` if it waited in the queue for longer than the `max_queue_wait` of
+/// its module. Like any other failure of the step, it can then be retried.
+async fn check_max_queue_wait(
+    db: &Pool<Postgres>,
+    job: &QueuedJob,
+) -> windmill_common::error::Result<()> {
+    let parent_job = job
+        .parent_job
+        .ok_or_else(|| Error::InternalErr("expected parent job".to_string()))?;
+    let max_queue_wait = sqlx::query_as::<_, (Option<i64>,)>(
+        "SELECT (raw_flow->'modules'->((flow_status->>'step')::int)->>'max_queue_wait')::bigint
+           FROM queue WHERE id = $1",
+    )
+    .bind(parent_job)
+    .fetch_optional(db)
+    .await?
+    .and_then(|(max_queue_wait,)| max_queue_wait);
+
+    let waited = (chrono::Utc::now() - job.scheduled_for).num_seconds();
+    match max_queue_wait {
+        Some(max_queue_wait) if waited > max_queue_wait => Err(Error::ExecutionErr(format!(
+            "Job was queued for too long: waited {waited}s, more than the max_queue_wait of {max_queue_wait}s of its step"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[tracing::instrument(
+    level = "trace",
+    skip_all,
+    fields(
+        job_id = %job.id,
+        parent_job = ?job.parent_job,
+        trace_id = %job.trace_id(),
+        span_id = %job.id,
+        parent_span_id = ?job.parent_span_id()
+    )
+)]
 async fn handle_queued_job(
     job: QueuedJob,
     db: &sqlx::Pool<sqlx::Postgres>,
@@ -581,6 +631,9 @@ async fn handle_queued_job(
     if job.canceled {
         return Err(Error::ExecutionErr(canceled_job_to_result(&job)))?;
     }
+    if job.is_flow_step {
+        check_max_queue_wait(db, &job).await?;
+    }
     match job.job_kind {
         JobKind::FlowPreview | JobKind::Flow => {
             let args = job.args.clone().unwrap_or(Value::Null);
@@ -618,6 +671,10 @@ async fn handle_queued_job(
             );
 
             logs.push_str(&format!("job {} on worker {}\n", &job.id, &worker_name));
+            /* the job of a step is killed after the timeout of its module, unless the workers'
+             * comes first */
+            let step_timeout = job.timeout.filter(|t| *t < timeout);
+            let timeout = step_timeout.unwrap_or(timeout);
             let result = match job.job_kind {
                 JobKind::Dependencies => {
                     handle_dependency_job(&job, &mut logs, job_dir, db, timeout, &envs).await
@@ -666,6 +723,9 @@ async fn handle_queued_job(
                     }
                 }
                 Err(e) => {
+                    /* only the step timeout, not the workers', fails the step as timed out */
+                    let timed_out =
+                        step_timeout.filter(|_| matches!(e, Error::ExecutionTimeout(_)));
                     let error_message = match e {
                         Error::ExitStatus(_) => {
                             let last_10_log_lines = logs
@@ -684,15 +744,32 @@ async fn handle_queued_job(
                         err @ _ => format!("error before termination: {err:#?}"),
                     };
 
-                    let (_, output_map) = add_completed_job_error(
-                        db,
-                        client,
-                        &job,
-                        logs,
-                        error_message,
-                        Some(metrics.clone()),
-                    )
-                    .await?;
+                    let (_, output_map) = match timed_out {
+                        Some(t) => {
+                            metrics.worker_execution_failed.inc();
+                            let result = json!({
+                                "error": format!("Job timed out after the {t}s timeout of its step"),
+                                "reason": FailureReason::Timeout,
+                            });
+                            let result = StepError::of_result(&result, None).into_result(result);
+                            let output_map = result.as_object().cloned().unwrap_or_default();
+                            let id =
+                                add_completed_job(db, client, &job, false, false, result, logs)
+                                    .await?;
+                            (id, output_map)
+                        }
+                        None => {
+                            add_completed_job_error(
+                                db,
+                                client,
+                                &job,
+                                logs,
+                                error_message,
+                                Some(metrics.clone()),
+                            )
+                            .await?
+                        }
+                    };
                     if job.is_flow_step {
                         if let Some(parent_job) = job.parent_job {
                             update_flow_status_after_job_completion(
@@ -731,7 +808,7 @@ async fn write_file(dir: &str, path: &str, content: &str) -> error::Result<File>
 }
 
 #[async_recursion]
-async fn transform_json_value(
+pub(crate) async fn transform_json_value(
     client: &windmill_api_client::Client,
     workspace: &str,
     v: Value,
@@ -759,7 +836,7 @@ async fn transform_json_value(
                 ));
             }
             let v = client
-                .get_resource_value(workspace, path)
+                .get_resource_value(workspace, path, None, None)
                 .await
                 .map_err(to_anyhow)?
                 .into_inner();
@@ -2000,6 +2077,7 @@ async fn handle_child(
                 ))
             }
         }
+        Ok(Err(KillReason::Timeout)) => Err(Error::ExecutionTimeout(timeout.as_secs())),
         Ok(Err(kill_reason)) => Err(Error::ExecutionErr(format!(
             "job process killed because {kill_reason:#?}"
         ))),
@@ -2116,8 +2194,12 @@ pub async fn handle_zombie_jobs_periodically(
 }
 
 async fn handle_zombie_jobs(db: &Pool<Postgres>, timeout: i32, base_url: &str) {
+    if let Err(err) = cancel_timed_out_flows(db).await {
+        tracing::error!("error canceling timed out flows: {err}");
+    }
+
     let restarted = sqlx::query!(
-            "UPDATE queue SET running = false WHERE last_ping < now() - ($1 || ' seconds')::interval AND running = true AND job_kind != $2 AND same_worker = false RETURNING id, workspace_id, last_ping",
+            "UPDATE queue SET running = false WHERE last_ping < now() - ($1 || ' seconds')::interval AND running = true AND job_kind != $2 AND same_worker = false AND needs_intervention IS NULL RETURNING id, workspace_id, last_ping",
             (timeout * 5).to_string(),
             JobKind::Flow: JobKind,
         )
@@ -2165,6 +2247,7 @@ async fn handle_zombie_jobs(db: &Pool<Postgres>, timeout: i32, base_url: &str) {
             "ephemeral-zombie-jobs",
             timeout * 2,
             &job.created_by,
+            Some(job.id),
         )
         .await
         .expect("could not create job token");
@@ -6,36 +6,174 @@
  * LICENSE-AGPL for a copy of the license.
  */
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
 
 use crate::jobs::{add_completed_job, add_completed_job_error, schedule_again_if_scheduled};
 use crate::js_eval::{eval_timeout, EvalCreds, IdContext};
-use crate::worker;
+use crate::{dag, worker};
 use anyhow::Context;
 use async_recursion::async_recursion;
-use futures::TryStreamExt;
 use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
 use tokio::sync::mpsc::Sender;
 use tracing::instrument;
 use uuid::Uuid;
 use windmill_common::{
     error::{self, to_anyhow, Error},
     flow_status::{
-        Approval, BranchAllStatus, BranchChosen, FlowStatus, FlowStatusModule, RetryStatus,
-        MAX_RETRY_ATTEMPTS, MAX_RETRY_INTERVAL,
+        Approval, ApprovalResult, BranchAllStatus, BranchChosen, FailureReason, FlowStatus,
+        FlowStatusModule, FlowStepEvent, RetryStatus, StepError, FLOW_STEP_CHANNEL,
+        MAX_FLOW_STEP_EVENT_RESULT_SIZE, MAX_RETRY_ATTEMPTS, MAX_RETRY_INTERVAL,
     },
-    flows::{FlowModule, FlowModuleValue, FlowValue, InputTransform, Retry, Suspend},
+    flows::{
+        BranchOneModules, CronIterator, FlowModule, FlowModuleValue, FlowValue, InputTransform,
+        LoopIterator, OrderBy, OutputTransform, Poll, Retry, RetryIf, Suspend,
+        DEFAULT_ON_CANCEL_TIMEOUT, MAX_CRON_ITERATIONS,
+    },
+    json_schema::{validate_value, Violation},
+    scripts::ScriptHash,
+    utils::now_from_db,
 };
 
 type DB = sqlx::Pool<sqlx::Postgres>;
 
 use windmill_queue::{
-    canceled_job_to_result, get_queued_job, push, JobPayload, QueuedJob, RawCode,
+    canceled_job_to_result,
+    flow_iterators::{delete_itered, get_itered, set_itered},
+    flow_jobs::{add_flow_job, flow_jobs_results, get_flow_jobs, truncate_flow_jobs},
+    get_idempotent_job, get_queued_job, push,
+    schedule::schedule_occurrences,
+    step_span_id, FlowStorage, JobKind, JobPayload, QueuedJob, RawCode,
 };
 
+lazy_static::lazy_static! {
+    static ref FLOW_STATUS_RECOVERY: FlowStatusRecovery = std::env::var("FLOW_STATUS_RECOVERY")
+        .ok()
+        .and_then(|x| x.parse::<FlowStatusRecovery>().ok())
+        .unwrap_or(FlowStatusRecovery::Intervention);
+
+    /// The most levels flows may be nested in one another through loops and branches, set with
+    /// the `MAX_FLOW_DEPTH` env variable
+    static ref MAX_FLOW_DEPTH: u32 = std::env::var("MAX_FLOW_DEPTH")
+        .ok()
+        .and_then(|x| x.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_MAX_FLOW_DEPTH);
+
+    /// Whether the predicates of the flows (stop_after_if, branches, skip_if, ...) must evaluate
+    /// to a boolean, instead of the truthiness of their value, set with the `STRICT_PREDICATES`
+    /// env variable
+    static ref STRICT_PREDICATES: bool = std::env::var("STRICT_PREDICATES")
+        .ok()
+        .and_then(|x| x.parse::<bool>().ok())
+        .unwrap_or(false);
+
+    /// The steps of flows that started, succeeded, failed, were retried or suspended, by the
+    /// `type` of their module
+    static ref FLOW_STEP_TRANSITIONS: prometheus::IntCounterVec =
+        prometheus::register_int_counter_vec!(
+            "flow_step_transitions",
+            "Number of flow steps that started, succeeded, failed, were retried or suspended",
+            &["transition", "module_kind"]
+        )
+        .unwrap();
+    static ref FLOW_STEP_DURATION: prometheus::HistogramVec = prometheus::register_histogram_vec!(
+        "flow_step_duration",
+        "Duration in seconds of the flow steps, from the start of their first job to their completion",
+        &["module_kind"]
+    )
+    .unwrap();
+}
+
+const DEFAULT_MAX_FLOW_DEPTH: u32 = 100;
+
+/// What the engine does with a flow whose `flow_status` can't be parsed, set with the
+/// `FLOW_STATUS_RECOVERY` env variable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlowStatusRecovery {
+    /// `fail`: the flow fails
+    Fail,
+    /// `repair`: the status is rebuilt with `FlowStatus::repair` and the flow goes on, it needs
+    /// intervention when it can't be
+    Repair,
+    /// `intervention` (default): the flow is parked, with its status left as is, until it is
+    /// canceled
+    Intervention,
+}
+
+impl std::str::FromStr for FlowStatusRecovery {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(FlowStatusRecovery::Fail),
+            "repair" => Ok(FlowStatusRecovery::Repair),
+            "intervention" => Ok(FlowStatusRecovery::Intervention),
+            _ => Err(format!("unknown flow status recovery: {s}")),
+        }
+    }
+}
+
+/// Applies `FLOW_STATUS_RECOVERY` to the flow whose `raw` status failed to parse with `err`.
+/// Returns the repaired status, or `None` when the flow has been parked: it then keeps running
+/// with `needs_intervention` set and is only pulled again once canceled.
+async fn recover_flow_status<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    flow: Uuid,
+    w_id: &str,
+    raw: &Value,
+    err: serde_json::Error,
+) -> error::Result<Option<FlowStatus>> {
+    let reason = format!("flow status could not be parsed: {err}");
+    let job = sqlx::query!(
+        "SELECT raw_flow, canceled FROM queue WHERE id = $1 AND workspace_id = $2",
+        flow,
+        w_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    /* a canceled flow is failed so that it completes as canceled */
+    if job.canceled || *FLOW_STATUS_RECOVERY == FlowStatusRecovery::Fail {
+        return Err(Error::InternalErr(reason));
+    }
+
+    if *FLOW_STATUS_RECOVERY == FlowStatusRecovery::Repair {
+        let repaired = job
+            .raw_flow
+            .and_then(|f| serde_json::from_value::<FlowValue>(f).ok())
+            .and_then(|f| FlowStatus::repair(raw, &f));
+        if let Some(status) = repaired {
+            tracing::warn!(flow_id = %flow, "repaired the flow status: {reason}");
+            sqlx::query!(
+                "UPDATE queue SET flow_status = $1 WHERE id = $2",
+                json!(status),
+                flow
+            )
+            .execute(&mut *tx)
+            .await?;
+            return Ok(Some(status));
+        }
+    }
+
+    tracing::error!(flow_id = %flow, "flow needs manual intervention: {reason}");
+    sqlx::query!(
+        "UPDATE queue
+            SET needs_intervention = $1
+              , running = true
+              , suspend = 1
+              , suspend_until = 'infinity'
+          WHERE id = $2",
+        reason,
+        flow
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(None)
+}
+
 #[async_recursion]
-#[instrument(level = "trace", skip_all)]
+#[instrument(level = "trace", skip_all, fields(flow_id = %flow, job_id = %job_id_for_status))]
 pub async fn update_flow_status_after_job_completion(
     db: &DB,
     client: &windmill_api_client::Client,
@@ -54,10 +192,26 @@ pub async fn update_flow_status_after_job_completion(
 ) -> error::Result<()> {
     tracing::debug!("UPDATE FLOW STATUS: {flow:?} {success} {result:?} {w_id}");
 
+    if is_dag_flow(db, flow).await? {
+        return dag::update_dag_flow_status_after_job_completion(
+            db,
+            client,
+            flow,
+            job_id_for_status,
+            w_id,
+            success,
+            result,
+            base_internal_url,
+        )
+        .await;
+    }
+
     let mut tx = db.begin().await?;
 
+    /* Lock the flow so that the iterations of a window of a parallel forloop completing at the
+     * same time update its status one after the other */
     let old_status_json = sqlx::query_scalar!(
-        "SELECT flow_status FROM queue WHERE id = $1 AND workspace_id = $2",
+        "SELECT flow_status FROM queue WHERE id = $1 AND workspace_id = $2 FOR UPDATE",
         flow,
         w_id
     )
@@ -70,19 +224,70 @@ pub async fn update_flow_status_after_job_completion(
     })?
     .ok_or_else(|| Error::InternalErr(format!("requiring a previous status")))?;
 
-    let old_status = serde_json::from_value::<FlowStatus>(old_status_json)
-        .ok()
-        .ok_or_else(|| {
-            Error::InternalErr(format!("requiring status to be parsabled as FlowStatus"))
-        })?;
+    /* a flow that ran for longer than its timeout is canceled, the rest of this
+     * update then follows the semantics of canceled flows */
+    tx = cancel_flow_if_timed_out(tx, flow, w_id).await?;
+
+    let old_status = match serde_json::from_value::<FlowStatus>(old_status_json.clone()) {
+        Ok(old_status) => old_status,
+        Err(e) => match recover_flow_status(&mut tx, flow, w_id, &old_status_json, e).await? {
+            Some(old_status) => old_status,
+            None => {
+                tx.commit().await?;
+                return Ok(());
+            }
+        },
+    };
 
     let module_index = usize::try_from(old_status.step).ok();
     let module_status = module_index
         .and_then(|i| old_status.modules.get(i))
         .unwrap_or(&old_status.failure_module);
 
+    /* the jobs of a step interrupted by `trigger_failure_module` report once the failure module
+     * already runs, they have nothing left to update */
+    if module_index.map_or(true, |i| i >= old_status.modules.len())
+        && !job_id_for_status.is_nil()
+        && module_status
+            .job()
+            .map_or(false, |job| job != *job_id_for_status)
+    {
+        tx.commit().await?;
+        return Ok(());
+    }
+
     tracing::debug!("UPDATE FLOW STATUS 2: {module_index:#?} {module_status:#?} {old_status:#?} ");
 
+    /* with `log_iterations`, the iterations of a loop are logged in the logs of the flow as they
+     * complete, the iteration of a job being its rank in the jobs of the step */
+    if let FlowStatusModule::InProgress { iterator: Some(iterator), flow_jobs, .. } = module_status
+    {
+        if compute_log_iterations(flow, &mut tx).await? {
+            let index = get_flow_jobs(
+                &mut tx,
+                &old_status,
+                flow,
+                old_status.step,
+                flow_jobs.as_deref().unwrap_or_default(),
+            )
+            .await?
+            .iter()
+            .position(|job| job == job_id_for_status)
+            .unwrap_or(iterator.index);
+            let outcome = if success { "completed" } else { "failed" };
+            sqlx::query!(
+                "UPDATE queue SET logs = concat(logs, $1::text) WHERE id = $2",
+                format!(
+                    "Step '{}' iteration {index} (job {job_id_for_status}) {outcome}\n",
+                    old_status.step_name(&module_status.id())
+                ),
+                flow
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+    }
+
     let skip_loop_failures = if matches!(
         module_status,
         FlowStatusModule::InProgress { iterator: Some(_), .. }
@@ -94,37 +299,402 @@ pub async fn update_flow_status_after_job_completion(
         false
     };
 
+    /* the failures of the iterations are only skipped until that many failed, which can only be
+     * reached by an iteration that failed */
+    let max_failures_reached = match module_status {
+        FlowStatusModule::InProgress { iterator: Some(_), flow_jobs, .. }
+            if skip_loop_failures && !success =>
+        {
+            match compute_max_failures(flow, old_status.step, &mut tx).await? {
+                Some(max_failures) => {
+                    let jobs = get_flow_jobs(
+                        &mut tx,
+                        &old_status,
+                        flow,
+                        old_status.step,
+                        flow_jobs.as_deref().unwrap_or_default(),
+                    )
+                    .await?;
+                    let failures = sqlx::query_scalar::<_, i64>(
+                        "SELECT count(*) FROM completed_job
+                          WHERE id = ANY($1) AND id <> $2 AND success = false",
+                    )
+                    .bind(jobs.as_slice())
+                    .bind(job_id_for_status)
+                    .fetch_one(&mut tx)
+                    .await?
+                        + 1;
+                    (failures >= max_failures as i64).then(|| (failures, max_failures))
+                }
+                None => None,
+            }
+        }
+        _ => None,
+    };
+    let skip_loop_failures = skip_loop_failures && max_failures_reached.is_none();
+
+    let stop_at_first_success = match module_status {
+        FlowStatusModule::InProgress { branchall: Some(_), .. } => {
+            compute_stop_at_first_success(flow, old_status.step, &mut tx)
+                .await?
+                .unwrap_or(false)
+        }
+        _ => false,
+    };
+
     let skip_branch_failure = match module_status {
         FlowStatusModule::InProgress {
-            branchall: Some(BranchAllStatus { branch, .. }), ..
-        } => compute_skip_branchall_failure(flow, old_status.step, *branch, &mut tx)
-            .await?
-            .unwrap_or(false),
+            branchall: Some(BranchAllStatus { branch, running, .. }),
+            flow_jobs,
+            ..
+        } if !stop_at_first_success => {
+            let branch = rank_in_window(
+                &mut tx,
+                &old_status,
+                flow,
+                flow_jobs,
+                running,
+                job_id_for_status,
+                *branch,
+            )
+            .await?;
+            compute_skip_branchall_failure(flow, old_status.step, branch, &mut tx)
+                .await?
+                .unwrap_or(false)
+        }
         _ => false,
     };
 
-    let skip_failure = skip_branch_failure || skip_loop_failures;
+    /* with `stop_at_first_success`, a failed branch is skipped as long as there are others to
+     * try */
+    let try_next_branch = stop_at_first_success
+        && !success
+        && matches!(
+            module_status,
+            FlowStatusModule::InProgress { branchall: Some(BranchAllStatus { branch, len, .. }), .. }
+                if branch + 1 < *len
+        );
 
-    let (step_counter, new_status) = match module_status {
+    /* the job of a step that failed with an error from which the module's
+     * `partial_result` expression extracts a usable result is treated as a success of the step,
+     * with the extracted result as its output. Iterations and branches are not concerned. */
+    let (success, result, warning) = match module_status {
+        FlowStatusModule::InProgress { iterator: None, branchall: None, .. }
+        | FlowStatusModule::WaitingForExecutor { .. }
+            if !success && !unrecoverable =>
+        {
+            match compute_partial_result(flow, old_status.step, &result, &mut tx, base_internal_url)
+                .await?
+            {
+                Some(partial_result) => {
+                    tracing::warn!(
+                        flow = %flow,
+                        job = %job_id_for_status,
+                        "step '{}' failed, continuing with its partial result",
+                        old_status.step_name(&module_status.id())
+                    );
+                    let warning = format!(
+                        "job {job_id_for_status} failed, its partial result was used as output"
+                    );
+                    (true, partial_result, Some(warning))
+                }
+                None => (success, result, None),
+            }
+        }
+        _ => (success, result, None),
+    };
+
+    /* a step whose result doesn't satisfy its `assert` or its `result_schema` fails */
+    let (success, result) = match module_status {
+        FlowStatusModule::InProgress { iterator: None, branchall: None, .. }
+        | FlowStatusModule::WaitingForExecutor { .. }
+            if success && module_index.map_or(false, |i| i < old_status.modules.len()) =>
+        {
+            match compute_assertion(flow, old_status.step, &result, &mut tx, base_internal_url)
+                .await?
+            {
+                Some(error) => (false, json!({ "error": error })),
+                None => (success, result),
+            }
+        }
+        _ => (success, result),
+    };
+
+    /* a step whose result doesn't meet the condition of its `poll` yet is run again once
+     * the poll interval elapsed, the flow being suspended in the meantime */
+    let poll_next = match module_status {
+        FlowStatusModule::InProgress { iterator: None, branchall: None, .. }
+        | FlowStatusModule::WaitingForExecutor { .. }
+            if success && module_index.map_or(false, |i| i < old_status.modules.len()) =>
+        {
+            compute_poll(
+                flow,
+                old_status.step,
+                &old_status.retry,
+                &result,
+                &mut tx,
+                base_internal_url,
+            )
+            .await?
+        }
+        _ => None,
+    };
+    let (success, result) = match poll_next {
+        Some(PollNext::Again { interval, until }) => {
+            sqlx::query(
+                "
+                UPDATE queue
+                   SET flow_status = JSONB_SET(
+                                     JSONB_SET(flow_status, ARRAY['modules', $1::TEXT], $2),
+                                     ARRAY['retry'], COALESCE(flow_status->'retry', '{}'::jsonb) || $3)
+                     , suspend = 1
+                     , suspend_until = now() + $4
+                 WHERE id = $5
+                ",
+            )
+            .bind(old_status.step)
+            .bind(json!(FlowStatusModule::WaitingForCondition {
+                id: module_status.id(),
+                job: *job_id_for_status,
+            }))
+            .bind(json!({ "poll_until": until }))
+            .bind(interval)
+            .bind(flow)
+            .execute(&mut tx)
+            .await?;
+            tx.commit().await?;
+            return Ok(());
+        }
+        Some(PollNext::TimedOut) => (
+            false,
+            json!({
+                "error": format!("Timed out waiting for the poll condition of step '{}'", old_status.step_name(&module_status.id()))
+            }),
+        ),
+        None => (success, result),
+    };
+
+    /* the index of the iteration if its result meets the `break_if` of the loop */
+    let break_at = match module_status {
+        FlowStatusModule::InProgress { iterator: Some(iterator), flow_jobs, .. } if success => {
+            let index = rank_in_window(
+                &mut tx,
+                &old_status,
+                flow,
+                flow_jobs,
+                &iterator.running,
+                job_id_for_status,
+                iterator.index,
+            )
+            .await?;
+            compute_break_if(
+                flow,
+                old_status.step,
+                index,
+                &result,
+                &mut tx,
+                base_internal_url,
+            )
+            .await?
+            .then(|| index)
+        }
+        _ => None,
+    };
+
+    /* an iteration of a parallel forloop, or a branch of a parallel branchall,
+     * only leaves its window while other jobs of the window are still running, the step goes on
+     * once they all completed. A branch whose failure is skipped isn't recorded as failed. */
+    let mut new_status = module_status.clone();
+    let still_running = match &mut new_status {
+        FlowStatusModule::InProgress { iterator: Some(iterator), .. } => {
+            let still_running = leave_window(
+                &mut iterator.running,
+                &mut iterator.failed,
+                job_id_for_status,
+                !success,
+            );
+            if still_running {
+                iterator.break_at = iterator.break_at.into_iter().chain(break_at).min();
+            }
+            still_running
+        }
+        FlowStatusModule::InProgress { branchall: Some(branchall), .. } => {
+            let failed = !success && !skip_branch_failure;
+            leave_window(
+                &mut branchall.running,
+                &mut branchall.failed,
+                job_id_for_status,
+                failed,
+            )
+        }
+        _ => false,
+    };
+    if still_running {
+        sqlx::query(
+            "UPDATE queue SET flow_status = JSONB_SET(flow_status, ARRAY['modules', $1::TEXT], $2) \
+             WHERE id = $3",
+        )
+        .bind(old_status.step)
+        .bind(json!(new_status))
+        .bind(flow)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        return Ok(());
+    }
+    let (success, result, skip_branch_failure) = match module_status {
         FlowStatusModule::InProgress {
-            iterator: Some(windmill_common::flow_status::Iterator { index, itered, .. }),
+            iterator: Some(windmill_common::flow_status::Iterator { failed: Some(failed), .. }),
             ..
-        } if (*index + 1 < itered.len() && (success || skip_loop_failures)) => {
+        } if success && !skip_loop_failures => {
+            let result =
+                sqlx::query_scalar!("SELECT result FROM completed_job WHERE id = $1", failed)
+                    .fetch_one(&mut tx)
+                    .await?;
+            (false, result.unwrap_or(Value::Null), skip_branch_failure)
+        }
+        FlowStatusModule::InProgress {
+            branchall: Some(BranchAllStatus { failed: Some(failed), .. }),
+            ..
+        } => {
+            let result =
+                sqlx::query_scalar!("SELECT result FROM completed_job WHERE id = $1", failed)
+                    .fetch_one(&mut tx)
+                    .await?;
+            (false, result.unwrap_or(Value::Null), false)
+        }
+        _ => (success, result, skip_branch_failure),
+    };
+    let (success, result) = match max_failures_reached {
+        Some((failures, max_failures)) => {
+            let error = format!(
+                "Forloop '{}' failed: {failures} of its iterations failed, reaching its maximum of {max_failures} failures",
+                old_status.step_name(&module_status.id())
+            );
+            (false, json!({ "error": error, "failures": failures }))
+        }
+        None => (success, result),
+    };
+    let skip_failure = skip_branch_failure || skip_loop_failures || try_next_branch;
+
+    /* a pass of a whileloop is followed by another one until its stop condition,
+     * evaluated with the result of the pass, is met. Only the status of a whileloop records its
+     * `max_iterations`. */
+    let (success, result, next_pass) = match module_status {
+        FlowStatusModule::InProgress {
+            iterator:
+                Some(windmill_common::flow_status::Iterator {
+                    index,
+                    max_iterations: Some(max_iterations),
+                    ..
+                }),
+            ..
+        } if success => {
+            match compute_while_next(
+                flow,
+                old_status.step,
+                *index,
+                *max_iterations,
+                &result,
+                &mut tx,
+                base_internal_url,
+            )
+            .await?
+            {
+                Some(WhileNext::Again) => (success, result, true),
+                Some(WhileNext::MaxIterations(max_iterations)) => {
+                    let error = Error::ExecutionErr(format!(
+                        "Whileloop '{}' ran its maximum of {max_iterations} iterations without its stop condition being met",
+                        old_status.step_name(&module_status.id())
+                    ));
+                    (false, json!({ "error": error.to_string() }), false)
+                }
+                None => (success, result, false),
+            }
+        }
+        _ => (success, result, false),
+    };
+
+    let break_at = match module_status {
+        FlowStatusModule::InProgress { iterator: Some(iterator), .. } => {
+            iterator.break_at.into_iter().chain(break_at).min()
+        }
+        _ => None,
+    };
+
+    /* the failed job of the step whose catch modules just ran */
+    let catching = match module_status {
+        FlowStatusModule::InProgress { catching, .. } => *catching,
+        _ => None,
+    };
+
+    let (step_counter, new_status) = match module_status {
+        FlowStatusModule::InProgress { iterator: Some(iterator), .. }
+            if (iterator.index + 1 < iterator.length()
+                && (success || skip_loop_failures)
+                && break_at.is_none()) =>
+        {
+            (old_status.step, module_status.clone())
+        }
+        FlowStatusModule::InProgress { .. } if next_pass => {
             (old_status.step, module_status.clone())
         }
         FlowStatusModule::InProgress {
             branchall: Some(BranchAllStatus { branch, len, .. }),
             ..
-        } if branch.to_owned() < len - 1 && (success || skip_branch_failure) => {
+        } if branch.to_owned() < len - 1
+            && if stop_at_first_success {
+                !success
+            } else {
+                success || skip_branch_failure
+            } =>
+        {
             (old_status.step, module_status.clone())
         }
         _ => {
             let (flow_jobs, branch_chosen) = match module_status {
+                /* with `stop_at_first_success`, the branch that succeeded is the one chosen */
+                FlowStatusModule::InProgress {
+                    flow_jobs,
+                    branchall: Some(BranchAllStatus { branch, .. }),
+                    ..
+                } if stop_at_first_success && success => (
+                    flow_jobs.clone(),
+                    Some(BranchChosen::Branch {
+                        branch: *branch,
+                        label: compute_branch_label(flow, old_status.step, *branch, &mut tx)
+                            .await?,
+                    }),
+                ),
                 FlowStatusModule::InProgress { flow_jobs, branch_chosen, .. } => {
                     (flow_jobs.clone(), branch_chosen.clone())
                 }
                 _ => (None, None),
             };
+            /* the iterations past the one that broke the loop are left out */
+            let flow_jobs = match (flow_jobs, break_at) {
+                (Some(mut flow_jobs), Some(break_at)) => {
+                    truncate_flow_jobs(
+                        &mut tx,
+                        &old_status,
+                        flow,
+                        old_status.step,
+                        break_at as i32 + 1,
+                        &mut flow_jobs,
+                    )
+                    .await?;
+                    Some(flow_jobs)
+                }
+                (flow_jobs, _) => flow_jobs,
+            };
+            /* a sub-flow that stopped early with `skip_if_stopped` was skipped as a whole, and
+             * a step whose `skip_if` was true never ran */
+            let skipped = (stop_early_override == Some(true) && flow_jobs.is_none())
+                || module_status.is_skipped();
+            let (started_at, ended_at) = (
+                module_status.started_at(),
+                Some(now_from_db(&mut tx).await?),
+            );
             if success || (flow_jobs.is_some() && (skip_loop_failures || skip_branch_failure)) {
                 (
                     old_status.step + 1,
@@ -134,6 +704,14 @@ pub async fn update_flow_status_after_job_completion(
                         flow_jobs,
                         branch_chosen,
                         approvers: vec![],
+                        warning: warning.or_else(|| {
+                            catching.map(|job| {
+                                format!("job {job} failed, the result of the catch modules of the step was used as output")
+                            })
+                        }),
+                        skipped,
+                        started_at,
+                        ended_at,
                     },
                 )
             } else {
@@ -144,6 +722,13 @@ pub async fn update_flow_status_after_job_completion(
                         job: job_id_for_status.clone(),
                         flow_jobs,
                         branch_chosen,
+                        reason: if catching.is_some() {
+                            Some(FailureReason::Catch)
+                        } else {
+                            FailureReason::of_result(&result)
+                        },
+                        started_at,
+                        ended_at,
                     },
                 )
             }
@@ -224,6 +809,7 @@ pub async fn update_flow_status_after_job_completion(
             ))
         })?;
         let stop_early = success
+            && !module_status.is_skipped()
             && if let Some(expr) = stop_early_expr.clone() {
                 compute_bool_from_expr(expr, &flow_args, result.clone(), base_internal_url).await?
             } else {
@@ -232,24 +818,36 @@ pub async fn update_flow_status_after_job_completion(
         (stop_early, skip_if_stop_early.unwrap_or(false))
     };
 
-    let result = match &new_status {
-        FlowStatusModule::Success { flow_jobs: Some(jobs), .. } => {
-            let results = sqlx::query_as(
-                "
-                  SELECT result
-                    FROM completed_job
-                   WHERE id = ANY($1)
-                     AND workspace_id = $2
-                ORDER BY args->'iter'->'index'
-                    ",
+    /* the steps are logged in the logs of the flow as they complete */
+    match &new_status {
+        FlowStatusModule::Success { id, .. } | FlowStatusModule::Failure { id, .. } => {
+            let outcome = if new_status.is_skipped() {
+                "skipped"
+            } else if matches!(&new_status, FlowStatusModule::Success { .. }) {
+                "completed"
+            } else {
+                "failed"
+            };
+            sqlx::query!(
+                "UPDATE queue SET logs = concat(logs, $1::text) WHERE id = $2",
+                format!("Step '{}' {outcome}\n", old_status.step_name(id)),
+                flow
             )
-            .bind(jobs.as_slice())
-            .bind(w_id)
-            .fetch(&mut tx)
-            .map_ok(|(v,)| v)
-            .try_collect::<Vec<Value>>()
+            .execute(&mut tx)
             .await?;
-            json!(results)
+        }
+        _ => (),
+    }
+
+    let result = match &new_status {
+        /* the results of the branches of a branchall are keyed by their label, or their index */
+        FlowStatusModule::Success { flow_jobs: Some(jobs), branch_chosen: None, .. } => {
+            let jobs = get_flow_jobs(&mut tx, &old_status, flow, old_status.step, jobs).await?;
+            let branchall = matches!(
+                module_status,
+                FlowStatusModule::InProgress { branchall: Some(_), .. }
+            );
+            flow_jobs_results(&mut tx, w_id, &jobs, branchall).await?
         }
         _ => result,
     };
@@ -269,7 +867,7 @@ pub async fn update_flow_status_after_job_completion(
         .context("remove flow status retry")?;
     }
 
-    let flow_job = get_queued_job(flow, w_id, &mut tx)
+    let mut flow_job = get_queued_job(flow, w_id, &mut tx)
         .await?
         .ok_or_else(|| Error::InternalErr(format!("requiring flow to be in the queue")))?;
 
@@ -278,25 +876,107 @@ pub async fn update_flow_status_after_job_completion(
         module_index.and_then(|i| module.modules.get(i).or(module.failure_module.as_ref()))
     });
 
+    /* the result of a script step that succeeded is cached for its next runs with the same
+     * arguments */
+    if let (FlowStatusModule::Success { warning: None, .. }, Some(module)) = (&new_status, module) {
+        if matches!(
+            module_status,
+            FlowStatusModule::InProgress { iterator: None, branchall: None, catching: None, .. }
+                | FlowStatusModule::WaitingForExecutor { .. }
+        ) && module_index.map_or(false, |i| i < old_status.modules.len())
+        {
+            cache_result(
+                flow,
+                &flow_job.permissioned_as,
+                module,
+                *job_id_for_status,
+                w_id,
+                &result,
+                &mut tx,
+            )
+            .await?;
+        }
+    }
+
+    /* without a failure module, the failure module slot is only used to run the compensations of
+     * a transactional group, the flow still fails with the error of the step that failed */
+    let (success, result) = match &raw_flow {
+        Some(raw_flow)
+            if success
+                && module_index.map_or(false, |i| i >= old_status.modules.len())
+                && raw_flow.failure_module.is_none() =>
+        {
+            (false, get_failed_step_result(&old_status, &mut tx).await?)
+        }
+        _ => (success, result),
+    };
+    /* a step that failed for good runs its catch modules, unless they are what failed */
+    let has_catch = catching.is_none()
+        && module_index.map_or(false, |i| i < old_status.modules.len())
+        && module.map_or(false, |m| !m.catch.is_empty());
+    let needs_compensation = !success
+        && !unrecoverable
+        && matches!((&raw_flow, module_index), (Some(raw_flow), Some(i))
+            if !compensation_modules(raw_flow, &old_status, i).is_empty());
+
+    /* a canceled flow still runs its failure module if it is `run_on_cancel`, or its `on_cancel`
+     * cleanup modules in its place, unless it was running them */
+    let run_failure_module_on_cancel = flow_job.canceled
+        && module_index.map_or(false, |i| i < old_status.modules.len())
+        && raw_flow.as_ref().map_or(false, |f| {
+            f.on_cancel.is_some() || f.failure_module.as_ref().map_or(false, |m| m.run_on_cancel)
+        });
+
     let should_continue_flow = match success {
         _ if stop_early => false,
-        _ if flow_job.canceled => false,
+        _ if flow_job.canceled => run_failure_module_on_cancel,
         true => !is_last_step,
         false if unrecoverable => false,
         false if skip_failure => !is_last_step,
         false
-            if next_retry(
+            if next_retry_if(
                 &module.and_then(|m| m.retry.clone()).unwrap_or_default(),
                 &old_status.retry,
+                &result,
+                &flow_job.args,
+                base_internal_url,
             )
+            .await?
             .is_some() =>
         {
             true
         }
-        false if has_failure_module(flow, &mut tx).await? => true,
-        false => false,
+        false if has_catch => true,
+        false if needs_compensation || has_failure_module(flow, &mut tx).await? => true,
+        false => {
+            /* the flow failed for good, with nothing left to handle its error */
+            if flow_job.parent_job.is_none() {
+                dead_letter(&mut tx, &flow_job, &result).await?;
+            }
+            false
+        }
     };
 
+    match &new_status {
+        FlowStatusModule::Success { id, job, .. } | FlowStatusModule::Failure { id, job, .. } => {
+            notify_flow_step(
+                &mut tx,
+                FlowStepEvent {
+                    flow,
+                    root: flow_job.trace_id(),
+                    module_id: id.clone(),
+                    job: *job,
+                    success,
+                    result: Some(result.clone()),
+                    result_truncated: false,
+                    done: !should_continue_flow,
+                },
+            )
+            .await?
+        }
+        _ => (),
+    }
+
     if old_status.step == 0
         && !flow_job.is_flow_step
         && flow_job.schedule_path.is_some()
@@ -312,8 +992,41 @@ pub async fn update_flow_status_after_job_completion(
         .await?;
     }
 
+    /* the values iterated over kept in a table are only needed while the flow runs */
+    if !should_continue_flow {
+        delete_itered(&mut tx, &old_status, flow).await?;
+    }
+
     tx.commit().await?;
 
+    /* the metrics of the step are only recorded once committed, so that an update rolled back
+     * and retried is counted once */
+    if let FlowStatusModule::Success { started_at, ended_at, .. }
+    | FlowStatusModule::Failure { started_at, ended_at, .. } = &new_status
+    {
+        let kind = module.map_or("unknown", |m| m.value.kind());
+        let transition = if matches!(&new_status, FlowStatusModule::Success { .. }) {
+            "succeeded"
+        } else {
+            "failed"
+        };
+        FLOW_STEP_TRANSITIONS
+            .with_label_values(&[transition, kind])
+            .inc();
+        if let (Some(started_at), Some(ended_at)) = (started_at, ended_at) {
+            FLOW_STEP_DURATION
+                .with_label_values(&[kind])
+                .observe((*ended_at - *started_at).num_milliseconds() as f64 / 1000.0);
+        }
+    }
+
+    let (success, result) = match raw_flow.as_ref().and_then(|f| f.output_transform.as_ref()) {
+        Some(output_transform) if !should_continue_flow && success && !flow_job.canceled => {
+            transform_output(output_transform, &mut flow_job, result, base_internal_url).await
+        }
+        _ => (success, result),
+    };
+
     let done = if !should_continue_flow {
         let logs = if flow_job.canceled {
             "Flow job canceled".to_string()
@@ -322,6 +1035,7 @@ pub async fn update_flow_status_after_job_completion(
         } else {
             "Flow job completed".to_string()
         };
+        let logs = format!("{}{logs}", flow_job.logs.as_deref().unwrap_or_default());
         if flow_job.canceled {
             add_completed_job_error(
                 db,
@@ -373,11 +1087,11 @@ pub async fn update_flow_status_after_job_completion(
         }
     };
 
-    if done {
-        if flow_job.same_worker && !keep_job_dir {
-            let _ = tokio::fs::remove_dir_all(format!("{worker_dir}/{}", flow_job.id)).await;
-        }
+    if !keep_job_dir {
+        remove_flow_dir_unless_continued(db, flow, worker_dir).await;
+    }
 
+    if done {
         if let Some(parent_job) = flow_job.parent_job {
             return Ok(update_flow_status_after_job_completion(
                 db,
@@ -406,24 +1120,180 @@ pub async fn update_flow_status_after_job_completion(
     Ok(())
 }
 
-async fn compute_skip_loop_failures<'c>(
-    flow: Uuid,
-    step: i32,
-    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
-) -> Result<Option<bool>, Error> {
-    sqlx::query_as(
-        "
-    SELECT (raw_flow->'modules'->$1->'value'->>'skip_failures')::bool
-      FROM queue
-     WHERE id = $2
-        ",
+/// Removes the directory of `flow` from this worker unless the flow was continued
+/// on it, the step continued on it still needing the `shared` directory of the flow. Steps may
+/// set their own `same_worker`, so the flow can leave the worker before it's done.
+pub(crate) async fn remove_flow_dir_unless_continued(db: &DB, flow: Uuid, worker_dir: &str) {
+    let flow_dir = format!("{worker_dir}/{flow}");
+    if tokio::fs::metadata(&flow_dir).await.is_err() {
+        return;
+    }
+    let continued = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM queue WHERE parent_job = $1 AND same_worker = true)",
+        flow
     )
-    .bind(step)
-    .bind(flow)
-    .fetch_one(tx)
-    .await
-    .map(|(v,)| v)
-    .map_err(|e| Error::InternalErr(format!("error during retrieval of skip_loop_failures: {e}")))
+    .fetch_one(db)
+    .await;
+    match continued {
+        Ok(Some(true)) => (),
+        Ok(_) => {
+            let _ = tokio::fs::remove_dir_all(flow_dir).await;
+        }
+        Err(e) => tracing::error!(%flow, "could not check whether the flow was continued: {e}"),
+    }
+}
+
+/// Returns the success and the result of the flow once `output_transform`
+/// is applied to its `result`. The flow fails if the transform does.
+pub(crate) async fn transform_output(
+    output_transform: &OutputTransform,
+    flow_job: &mut QueuedJob,
+    result: Value,
+    base_internal_url: &str,
+) -> (bool, Value) {
+    let output = eval_timeout(
+        output_transform.expr.clone(),
+        vec![
+            ("result".to_string(), result.clone()),
+            (
+                "flow_input".to_string(),
+                flow_job.args.clone().unwrap_or_else(|| json!({})),
+            ),
+        ],
+        None,
+        vec![],
+        None,
+        base_internal_url.to_string(),
+    )
+    .await;
+    match output {
+        Ok(output) => {
+            if output_transform.keep_raw_result {
+                if let Some(Value::Object(status)) = flow_job.flow_status.as_mut() {
+                    status.insert("raw_result".to_string(), result);
+                }
+            }
+            (true, output)
+        }
+        Err(e) => (
+            false,
+            json!({ "error": format!("Error during the evaluation of the output transform `{}`:\n{e}", output_transform.expr) }),
+        ),
+    }
+}
+
+/// Lets the subscribers of `FLOW_STEP_CHANNEL` know that a module completed. This is only
+/// delivered on commit and never waits for the subscribers.
+pub(crate) async fn notify_flow_step<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    mut event: FlowStepEvent,
+) -> error::Result<()> {
+    let too_big = event
+        .result
+        .as_ref()
+        .map(|r| r.to_string().len() > MAX_FLOW_STEP_EVENT_RESULT_SIZE)
+        .unwrap_or(false);
+    if too_big {
+        event.result = None;
+        event.result_truncated = true;
+    }
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(FLOW_STEP_CHANNEL)
+        .bind(json!(event).to_string())
+        .execute(tx)
+        .await
+        .map_err(|e| Error::InternalErr(format!("error during notification of flow step: {e}")))?;
+    Ok(())
+}
+
+async fn compute_skip_loop_failures<'c>(
+    flow: Uuid,
+    step: i32,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+) -> Result<Option<bool>, Error> {
+    sqlx::query_as(
+        "
+    SELECT (raw_flow->'modules'->$1->'value'->>'skip_failures')::bool
+      FROM queue
+     WHERE id = $2
+        ",
+    )
+    .bind(step)
+    .bind(flow)
+    .fetch_one(tx)
+    .await
+    .map(|(v,)| v)
+    .map_err(|e| Error::InternalErr(format!("error during retrieval of skip_loop_failures: {e}")))
+}
+
+async fn compute_max_failures<'c>(
+    flow: Uuid,
+    step: i32,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+) -> Result<Option<u32>, Error> {
+    sqlx::query_as::<_, (Option<i32>,)>(
+        "
+    SELECT (raw_flow->'modules'->$1->'value'->>'max_failures')::int
+      FROM queue
+     WHERE id = $2
+        ",
+    )
+    .bind(step)
+    .bind(flow)
+    .fetch_one(tx)
+    .await
+    .map(|(v,)| v.and_then(|v| u32::try_from(v).ok()))
+    .map_err(|e| Error::InternalErr(format!("error during retrieval of max_failures: {e}")))
+}
+
+/// Evaluates the `partial_result` expression of the module at `step`, if any, against the error
+/// of its job. Returns `None` when there is no expression or when it returns `null`.
+async fn compute_partial_result<'c>(
+    flow: Uuid,
+    step: i32,
+    error: &serde_json::Value,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    base_internal_url: &str,
+) -> error::Result<Option<serde_json::Value>> {
+    let (expr, flow_args): (Option<String>, Option<serde_json::Value>) = sqlx::query_as(
+        "
+    SELECT raw_flow->'modules'->$1->'partial_result'->>'expr', args
+      FROM queue
+     WHERE id = $2
+        ",
+    )
+    .bind(step)
+    .bind(flow)
+    .fetch_one(tx)
+    .await
+    .map_err(|e| Error::InternalErr(format!("error during retrieval of partial_result: {e}")))?;
+
+    let expr = match expr {
+        Some(expr) => expr,
+        None => return Ok(None),
+    };
+    let partial_result = eval_timeout(
+        expr.clone(),
+        [
+            (
+                "flow_input".to_string(),
+                flow_args.unwrap_or_else(|| json!({})),
+            ),
+            ("result".to_string(), error.clone()),
+        ]
+        .into(),
+        None,
+        vec![],
+        None,
+        base_internal_url.to_string(),
+    )
+    .await
+    .map_err(|e| {
+        Error::ExecutionErr(format!(
+            "Error during isolated evaluation of partial result expression `{expr}`:\n{e}"
+        ))
+    })?;
+    Ok(Some(partial_result).filter(|r| !r.is_null()))
 }
 
 async fn compute_skip_branchall_failure<'c>(
@@ -448,6 +1318,130 @@ async fn compute_skip_branchall_failure<'c>(
     .map_err(|e| Error::InternalErr(format!("error during retrieval of skip_loop_failures: {e}")))
 }
 
+async fn compute_stop_at_first_success<'c>(
+    flow: Uuid,
+    step: i32,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+) -> Result<Option<bool>, Error> {
+    sqlx::query_as(
+        "
+    SELECT (raw_flow->'modules'->$1->'value'->>'stop_at_first_success')::bool
+      FROM queue
+     WHERE id = $2
+        ",
+    )
+    .bind(step)
+    .bind(flow)
+    .fetch_one(tx)
+    .await
+    .map(|(v,)| v)
+    .map_err(|e| {
+        Error::InternalErr(format!(
+            "error during retrieval of stop_at_first_success: {e}"
+        ))
+    })
+}
+
+/// The number of flows `flow` is nested in, following the `parent_job` of the
+/// queued jobs up to `max` levels.
+async fn flow_depth(db: &DB, flow: Uuid, max: u32) -> error::Result<u32> {
+    let depth = sqlx::query_scalar!(
+        "WITH RECURSIVE ancestor (id, depth) AS (
+                SELECT parent_job, 1 FROM queue WHERE id = $1
+             UNION ALL
+                SELECT q.parent_job, a.depth + 1 FROM queue q JOIN ancestor a ON q.id = a.id
+                 WHERE a.depth <= $2
+         )
+         SELECT max(depth) FROM ancestor WHERE id IS NOT NULL",
+        flow,
+        max as i32
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(depth.unwrap_or(0) as u32)
+}
+
+async fn compute_log_iterations<'c>(
+    flow: Uuid,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+) -> Result<bool, Error> {
+    sqlx::query_as::<_, (Option<bool>,)>(
+        "
+    SELECT (raw_flow->>'log_iterations')::bool
+      FROM queue
+     WHERE id = $1
+        ",
+    )
+    .bind(flow)
+    .fetch_one(tx)
+    .await
+    .map(|(v,)| v.unwrap_or(false))
+    .map_err(|e| Error::InternalErr(format!("error during retrieval of log_iterations: {e}")))
+}
+
+async fn compute_branch_label<'c>(
+    flow: Uuid,
+    step: i32,
+    branch: usize,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+) -> Result<Option<String>, Error> {
+    sqlx::query_as(
+        "
+    SELECT raw_flow->'modules'->$1->'value'->'branches'->$2->>'label'
+      FROM queue
+     WHERE id = $3
+        ",
+    )
+    .bind(step)
+    .bind(branch as i32)
+    .bind(flow)
+    .fetch_one(tx)
+    .await
+    .map(|(v,)| v)
+    .map_err(|e| Error::InternalErr(format!("error during retrieval of branch label: {e}")))
+}
+
+/// Keeps the payload of a top-level flow that failed for good, with its error, in
+/// `dead_letter_job` when its workspace enables `dead_letter`, to be inspected or replayed later.
+async fn dead_letter<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    flow_job: &QueuedJob,
+    error: &Value,
+) -> error::Result<()> {
+    sqlx::query(
+        "INSERT INTO dead_letter_job
+                (id, workspace_id, job_kind, script_path, raw_flow, args, flow_status, error, created_by)
+         SELECT $1, $2, $3, $4, $5, $6, $7, $8, $9
+          WHERE EXISTS (SELECT 1 FROM workspace_settings WHERE workspace_id = $2 AND dead_letter)
+         ON CONFLICT (id) DO NOTHING",
+    )
+    .bind(flow_job.id)
+    .bind(&flow_job.workspace_id)
+    .bind(&flow_job.job_kind)
+    .bind(&flow_job.script_path)
+    .bind(&flow_job.raw_flow)
+    .bind(&flow_job.args)
+    .bind(&flow_job.flow_status)
+    .bind(error)
+    .bind(&flow_job.created_by)
+    .execute(tx)
+    .await
+    .map_err(|e| Error::InternalErr(format!("dead lettering flow {}: {e}", flow_job.id)))?;
+    Ok(())
+}
+
+async fn is_dag_flow(db: &DB, flow: Uuid) -> error::Result<bool> {
+    let dag = sqlx::query_scalar!(
+        "SELECT (raw_flow->>'dag')::boolean FROM queue WHERE id = $1",
+        flow
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|e| Error::InternalErr(format!("error during retrieval of is_dag_flow: {e}")))?
+    .flatten();
+    Ok(dag.unwrap_or(false))
+}
+
 async fn has_failure_module<'c>(
     flow: Uuid,
     tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
@@ -465,40 +1459,393 @@ async fn has_failure_module<'c>(
     .map_err(|e| Error::InternalErr(format!("error during retrieval of has_failure_module: {e}")))
 }
 
+/// The compensations of the modules that succeeded before the module at
+/// `failed` in its group, the last one first
+fn compensation_modules(flow: &FlowValue, status: &FlowStatus, failed: usize) -> Vec<FlowModule> {
+    let group = match flow.modules.get(failed).and_then(|m| m.group.as_ref()) {
+        Some(group) => group,
+        None => return vec![],
+    };
+    flow.modules[..failed]
+        .iter()
+        .zip(status.modules.iter())
+        .rev()
+        .take_while(|(m, _)| m.group.as_ref() == Some(group))
+        .filter(|(_, s)| matches!(s, FlowStatusModule::Success { .. }))
+        .filter_map(|(m, _)| m.compensation.as_deref().cloned())
+        .collect()
+}
+
+async fn get_failed_step_result<'c>(
+    status: &FlowStatus,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+) -> error::Result<Value> {
+    let job = status.modules.iter().rev().find_map(|m| match m {
+        FlowStatusModule::Failure { job, .. } => Some(*job),
+        _ => None,
+    });
+    let result = match job {
+        Some(job) => sqlx::query_scalar!("SELECT result FROM completed_job WHERE id = $1", job)
+            .fetch_optional(tx)
+            .await?
+            .flatten(),
+        None => None,
+    };
+    Ok(result.unwrap_or_else(|| json!({ "error": "a step of the flow failed" })))
+}
+
+/// Checks the result of the job of the module at `step` against its `assert` and its
+/// `result_schema`, if any. Returns why the result is rejected, `None` when it's accepted.
+async fn compute_assertion<'c>(
+    flow: Uuid,
+    step: i32,
+    result: &serde_json::Value,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    base_internal_url: &str,
+) -> error::Result<Option<String>> {
+    let (assert, result_schema, flow_args): (
+        Option<serde_json::Value>,
+        Option<serde_json::Value>,
+        Option<serde_json::Value>,
+    ) = sqlx::query_as(
+        "
+    SELECT raw_flow->'modules'->$1->'assert', raw_flow->'modules'->$1->'result_schema', args
+      FROM queue
+     WHERE id = $2
+        ",
+    )
+    .bind(step)
+    .bind(flow)
+    .fetch_one(tx)
+    .await
+    .map_err(|e| Error::InternalErr(format!("error during retrieval of assert: {e}")))?;
+
+    if let Some(schema) = result_schema.filter(|s| !s.is_null()) {
+        let mut violations = vec![];
+        validate_value(&mut violations, "", &schema, result);
+        if !violations.is_empty() {
+            let violations = violations
+                .into_iter()
+                .map(|Violation { key, message }| format!("`{key}` {message}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Ok(Some(format!(
+                "The result does not satisfy the result schema: {violations}"
+            )));
+        }
+    }
+
+    let assert = match assert.filter(|a| !a.is_null()) {
+        Some(assert) => serde_json::from_value::<InputTransform>(assert)
+            .map_err(|e| Error::InternalErr(format!("invalid assert: {e}")))?,
+        None => return Ok(None),
+    };
+    let (holds, assertion) = match assert {
+        InputTransform::Static { value } => (value.clone(), value.to_string()),
+        InputTransform::Javascript { expr } => (
+            eval_timeout(
+                expr.clone(),
+                vec![
+                    (
+                        "flow_input".to_string(),
+                        flow_args.unwrap_or_else(|| json!({})),
+                    ),
+                    ("result".to_string(), result.clone()),
+                ],
+                None,
+                vec![],
+                None,
+                base_internal_url.to_string(),
+            )
+            .await
+            .map_err(|e| {
+                Error::ExecutionErr(format!(
+                    "Error during isolated evaluation of assert expression `{expr}`:\n{e}"
+                ))
+            })?,
+            expr,
+        ),
+        InputTransform::Resource { .. } => {
+            return Err(Error::ExecutionErr(
+                "A resource transform is only supported as the input of a step".to_string(),
+            ))
+        }
+    };
+    Ok((!predicate_value(holds, " from assert")?)
+        .then(|| format!("The result does not satisfy the assertion `{assertion}`")))
+}
+
+enum PollNext {
+    Again { interval: Duration, until: chrono::DateTime<chrono::Utc> },
+    TimedOut,
+}
+
+/// Evaluates the `condition` of the `poll` of the module at `step`, if any, against the result
+/// of its job. Returns `None` when the module isn't polled or when its condition is met.
+async fn compute_poll<'c>(
+    flow: Uuid,
+    step: i32,
+    retry: &RetryStatus,
+    result: &serde_json::Value,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    base_internal_url: &str,
+) -> error::Result<Option<PollNext>> {
+    let (poll, flow_args): (Option<serde_json::Value>, Option<serde_json::Value>) = sqlx::query_as(
+        "
+    SELECT raw_flow->'modules'->$1->'poll', args
+      FROM queue
+     WHERE id = $2
+        ",
+    )
+    .bind(step)
+    .bind(flow)
+    .fetch_one(tx)
+    .await
+    .map_err(|e| Error::InternalErr(format!("error during retrieval of poll: {e}")))?;
+
+    let poll = match poll.filter(|p| !p.is_null()) {
+        Some(poll) => serde_json::from_value::<Poll>(poll)
+            .map_err(|e| Error::InternalErr(format!("invalid poll: {e}")))?,
+        None => return Ok(None),
+    };
+    if compute_bool_from_expr(
+        poll.condition,
+        &flow_args,
+        result.clone(),
+        base_internal_url,
+    )
+    .await?
+    {
+        return Ok(None);
+    }
+
+    let now = chrono::Utc::now();
+    let until = retry.poll_until.unwrap_or_else(|| {
+        now + chrono::Duration::seconds(poll.timeout.map(i64::from).unwrap_or(30 * 60))
+    });
+    if now >= until {
+        return Ok(Some(PollNext::TimedOut));
+    }
+    let interval = Duration::from_secs(poll.interval.max(1).into());
+    Ok(Some(PollNext::Again { interval, until }))
+}
+
+/// Evaluates the `break_if` of the forloop at `step`, if any, against the result of
+/// its iteration `index`.
+async fn compute_break_if<'c>(
+    flow: Uuid,
+    step: i32,
+    index: usize,
+    result: &serde_json::Value,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    base_internal_url: &str,
+) -> error::Result<bool> {
+    let (break_if, flow_args): (Option<String>, Option<serde_json::Value>) = sqlx::query_as(
+        "
+    SELECT raw_flow->'modules'->$1->'value'->>'break_if', args
+      FROM queue
+     WHERE id = $2
+        ",
+    )
+    .bind(step)
+    .bind(flow)
+    .fetch_one(tx)
+    .await
+    .map_err(|e| Error::InternalErr(format!("error during retrieval of break_if: {e}")))?;
+
+    match break_if {
+        Some(break_if) => {
+            compute_bool_from_expr_with_iter(
+                break_if,
+                &flow_args,
+                result.clone(),
+                Some(json!({ "index": index })),
+                base_internal_url,
+            )
+            .await
+        }
+        None => Ok(false),
+    }
+}
+
+enum WhileNext {
+    Again,
+    MaxIterations(u32),
+}
+
+/// Evaluates the `stop_condition` of the module at `step`, if it's a whileloop, against the
+/// result of its pass `index`, the loop running at most `max_iterations` passes. Returns `None`
+/// when the module isn't a whileloop or when its condition is met.
+async fn compute_while_next<'c>(
+    flow: Uuid,
+    step: i32,
+    index: usize,
+    max_iterations: u32,
+    result: &serde_json::Value,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    base_internal_url: &str,
+) -> error::Result<Option<WhileNext>> {
+    let while_loop: Option<(Option<String>, Option<serde_json::Value>)> = sqlx::query_as(
+        "
+    SELECT raw_flow->'modules'->$1->'value'->>'stop_condition',
+           args
+      FROM queue
+     WHERE id = $2
+       AND raw_flow->'modules'->$1->'value'->>'type' = 'whileloopflow'
+        ",
+    )
+    .bind(step)
+    .bind(flow)
+    .fetch_optional(tx)
+    .await
+    .map_err(|e| Error::InternalErr(format!("error during retrieval of whileloop: {e}")))?;
+
+    let (stop_condition, flow_args) = match while_loop {
+        Some((Some(stop_condition), flow_args)) => (stop_condition, flow_args),
+        _ => return Ok(None),
+    };
+    if compute_bool_from_expr_with_iter(
+        stop_condition,
+        &flow_args,
+        result.clone(),
+        Some(json!({ "index": index })),
+        base_internal_url,
+    )
+    .await?
+    {
+        return Ok(None);
+    }
+
+    if index.saturating_add(1) >= max_iterations as usize {
+        return Ok(Some(WhileNext::MaxIterations(max_iterations)));
+    }
+    Ok(Some(WhileNext::Again))
+}
+
 fn next_retry(retry: &Retry, status: &RetryStatus) -> Option<(u16, Duration)> {
+    let since_first_failure = status
+        .first_failure_at
+        .and_then(|t| (chrono::Utc::now() - t).to_std().ok())
+        .unwrap_or_default();
     (status.fail_count <= MAX_RETRY_ATTEMPTS)
         .then(|| &retry)
-        .and_then(|retry| retry.interval(status.fail_count))
+        .and_then(|retry| {
+            retry
+                .interval_since_first_failure(status.fail_count, since_first_failure)
+                .map(|d| retry.jitter(status.fail_count, d))
+        })
         .map(|d| (status.fail_count + 1, std::cmp::min(d, MAX_RETRY_INTERVAL)))
 }
 
+/// Like [`next_retry`] for a step that failed with `result`, which isn't retried when the
+/// `retry_if` of `retry` doesn't match its error.
+async fn next_retry_if(
+    retry: &Retry,
+    status: &RetryStatus,
+    result: &Value,
+    flow_args: &Option<Value>,
+    base_internal_url: &str,
+) -> error::Result<Option<(u16, Duration)>> {
+    let next = next_retry(retry, status);
+    match &retry.retry_if {
+        Some(retry_if)
+            if next.is_some()
+                && !retry_if_matches(retry_if, result, flow_args, base_internal_url).await? =>
+        {
+            Ok(None)
+        }
+        _ => Ok(next),
+    }
+}
+
+/// Whether the error of a step that failed with `result` is one of the `errors` of `retry_if`
+/// or makes its `expr` return true. The expression gets the error structured like the
+/// `previous_result` of a failure module, with its `name` and `message`, as `error`, and may
+/// return any value, coerced to a boolean by [`predicate_value`].
+async fn retry_if_matches(
+    retry_if: &RetryIf,
+    result: &Value,
+    flow_args: &Option<Value>,
+    base_internal_url: &str,
+) -> error::Result<bool> {
+    let error = StepError::of_result(result, None);
+    if retry_if.errors.contains(&error.name) {
+        return Ok(true);
+    }
+    let expr = match retry_if.expr.clone() {
+        Some(expr) => expr,
+        None => return Ok(false),
+    };
+    let context = vec![
+        ("error".to_string(), error.into_result(result.clone())),
+        (
+            "flow_input".to_string(),
+            flow_args.clone().unwrap_or_else(|| json!({})),
+        ),
+    ];
+    let value = eval_timeout(
+        expr,
+        context,
+        None,
+        vec![],
+        None,
+        base_internal_url.to_string(),
+    )
+    .await?;
+    predicate_value(value, " from retry_if")
+}
+
 async fn compute_bool_from_expr(
     expr: String,
     flow_args: &Option<serde_json::Value>,
     result: serde_json::Value,
     base_internal_url: &str,
+) -> error::Result<bool> {
+    compute_bool_from_expr_with_iter(expr, flow_args, result, None, base_internal_url).await
+}
+
+/// Like [`compute_bool_from_expr`] with `iter` also available to the expression when given.
+async fn compute_bool_from_expr_with_iter(
+    expr: String,
+    flow_args: &Option<serde_json::Value>,
+    result: serde_json::Value,
+    iter: Option<serde_json::Value>,
+    base_internal_url: &str,
 ) -> error::Result<bool> {
     let flow_input = flow_args.clone().unwrap_or_else(|| json!({}));
-    match eval_timeout(
+    let mut context = vec![
+        ("flow_input".to_string(), flow_input),
+        ("result".to_string(), result.clone()),
+        ("previous_result".to_string(), result),
+    ];
+    context.extend(iter.map(|iter| ("iter".to_string(), iter)));
+    let value = eval_timeout(
         expr,
-        [
-            ("flow_input".to_string(), flow_input),
-            ("result".to_string(), result.clone()),
-            ("previous_result".to_string(), result),
-        ]
-        .into(),
+        context,
         None,
         vec![],
         None,
         base_internal_url.to_string(),
     )
-    .await?
-    {
-        serde_json::Value::Bool(true) => Ok(true),
-        serde_json::Value::Bool(false) => Ok(false),
-        a @ _ => Err(Error::ExecutionErr(format!(
-            "Expected a boolean value, found: {a:?}"
+    .await?;
+    predicate_value(value, "")
+}
+
+/// The boolean a predicate evaluated to. Unless `STRICT_PREDICATES` is set, a value that isn't a
+/// boolean is coerced: `null`, `0`, `""` and `[]` are false, anything else is true. Unlike in
+/// javascript, an empty array is false, like an empty string.
+pub(crate) fn predicate_value(value: Value, from: &str) -> error::Result<bool> {
+    match value {
+        Value::Bool(b) => Ok(b),
+        a if *STRICT_PREDICATES => Err(Error::ExecutionErr(format!(
+            "Expected a boolean value{from}, found: {a:?}. Unset STRICT_PREDICATES to treat null, \
+             0, \"\", [] and false as false and any other value as true"
         ))),
+        Value::Null => Ok(false),
+        Value::Number(n) => Ok(n.as_f64() != Some(0.0)),
+        Value::String(s) => Ok(!s.is_empty()),
+        Value::Array(a) => Ok(!a.is_empty()),
+        Value::Object(_) => Ok(true),
     }
 }
 
@@ -509,10 +1856,16 @@ pub async fn update_flow_status_in_progress(
     job_in_progress: Uuid,
 ) -> error::Result<()> {
     let step = get_step_of_flow_status(db, flow).await?;
-    if let Step::Step(step) = step {
+    if let Step::Dag = step {
+        /* the modules of dag flows are marked in progress when their job is pushed */
+        return Ok(());
+    } else if let Step::Step(step) = step {
+        /* the first job of the step to start sets when it started, the jobs of its iterations or
+         * branches keep it */
         sqlx::query(&format!(
             "UPDATE queue
-                SET flow_status = jsonb_set(jsonb_set(flow_status, '{{modules, {step}, job}}', $1), '{{modules, {step}, type}}', $2)
+                SET flow_status = jsonb_set(jsonb_set(jsonb_set(flow_status, '{{modules, {step}, job}}', $1), '{{modules, {step}, type}}', $2),
+                                  '{{modules, {step}, started_at}}', COALESCE(flow_status->'modules'->{step}->'started_at', to_jsonb(now())))
                 WHERE id = $3 AND workspace_id = $4",
         ))
         .bind(json!(job_in_progress.to_string()))
@@ -524,7 +1877,8 @@ pub async fn update_flow_status_in_progress(
     } else {
         sqlx::query(&format!(
             "UPDATE queue
-                SET flow_status = jsonb_set(jsonb_set(flow_status, '{{failure_module, job}}', $1), '{{failure_module, type}}', $2)
+                SET flow_status = jsonb_set(jsonb_set(jsonb_set(flow_status, '{{failure_module, job}}', $1), '{{failure_module, type}}', $2),
+                                  '{{failure_module, started_at}}', COALESCE(flow_status->'failure_module'->'started_at', to_jsonb(now())))
                 WHERE id = $3 AND workspace_id = $4",
         ))
         .bind(json!(job_in_progress.to_string()))
@@ -540,17 +1894,20 @@ pub async fn update_flow_status_in_progress(
 pub enum Step {
     Step(i32),
     FailureStep,
+    Dag,
 }
 #[instrument(level = "trace", skip_all)]
 pub async fn get_step_of_flow_status(db: &DB, id: Uuid) -> error::Result<Step> {
     let r = sqlx::query!(
-        "SELECT (flow_status->'step')::integer as step, jsonb_array_length(flow_status->'modules') as len  FROM queue WHERE id = $1",
+        "SELECT (flow_status->'step')::integer as step, jsonb_array_length(flow_status->'modules') as len, (raw_flow->>'dag')::boolean as dag FROM queue WHERE id = $1",
         id
     )
     .fetch_one(db)
     .await
     .map_err(|e| Error::InternalErr(format!("fetching step flow status: {e}")))?;
-    if r.step < r.len {
+    if r.dag == Some(true) {
+        Ok(Step::Dag)
+    } else if r.step < r.len {
         Ok(Step::Step(r.step.ok_or_else(|| {
             Error::InternalErr("step is null".to_string())
         })?))
@@ -559,19 +1916,38 @@ pub async fn get_step_of_flow_status(db: &DB, id: Uuid) -> error::Result<Step> {
     }
 }
 
-/// resumes should be in order of timestamp ascending, so that more recent are at the end
+/// approvals should be in order of timestamp ascending, so that more recent are at the end
 #[instrument(level = "trace", skip_all)]
-async fn transform_input(
-    flow_args: &Option<serde_json::Value>,
+pub(crate) async fn transform_input(
+    db: &DB,
+    flow_job: &QueuedJob,
     last_result: serde_json::Value,
     input_transforms: &HashMap<String, InputTransform>,
-    workspace: &str,
     token: &str,
     steps: Vec<Uuid>,
-    resumes: &[Value],
+    approvals: &[ApprovalResult],
     by_id: &IdContext,
+    trigger: &Value,
     base_internal_url: &str,
 ) -> anyhow::Result<Map<String, serde_json::Value>> {
+    let (flow_args, workspace) = (&flow_job.args, flow_job.workspace_id.as_str());
+    let resumes = approvals
+        .iter()
+        .map(|a| a.payload.clone())
+        .collect::<Vec<_>>();
+    /* the results of all the completed steps are fetched at once, and only when an expression
+     * may reference them */
+    let results = if input_transforms
+        .values()
+        .any(|t| matches!(t, InputTransform::Javascript { .. }))
+    {
+        let mut tx = db.begin().await?;
+        let results = step_results(&mut tx, workspace, by_id).await?;
+        tx.commit().await?;
+        Value::Object(results)
+    } else {
+        json!({})
+    };
     let mut mapped = serde_json::Map::new();
 
     for (key, val) in input_transforms.into_iter() {
@@ -590,11 +1966,14 @@ async fn transform_input(
                     ("params".to_string(), json!(mapped)),
                     ("previous_result".to_string(), previous_result),
                     ("flow_input".to_string(), flow_input),
+                    ("results".to_string(), results.clone()),
                     (
                         "resume".to_string(),
                         resumes.last().map(|v| json!(v)).unwrap_or_default(),
                     ),
                     ("resumes".to_string(), resumes.clone().into()),
+                    ("approvals".to_string(), json!(approvals)),
+                    ("trigger".to_string(), trigger.clone()),
                 ];
 
                 let v = eval_timeout(
@@ -614,12 +1993,180 @@ async fn transform_input(
                 mapped.insert(key.to_string(), v);
                 ()
             }
+            InputTransform::Resource { path, workspace, permissioned_as } => {
+                let v = resolve_resource_transform(
+                    db,
+                    flow_job,
+                    token,
+                    path,
+                    workspace.as_deref(),
+                    permissioned_as.as_deref(),
+                    base_internal_url,
+                )
+                .await?;
+                mapped.insert(key.to_string(), v);
+            }
         }
     }
 
     Ok(mapped)
 }
 
+/// Reads the resource at `path` for a step of `flow_job`. Without
+/// `permissioned_as`, the resource is read as the flow, with its `token`, and only from its own
+/// workspace. With it, the flow impersonates `permissioned_as` in `workspace` (its own by
+/// default), which requires a cross-workspace grant of that workspace to the path of the
+/// deployed flow the job belongs to. Every such resolution is audited in both workspaces.
+async fn resolve_resource_transform(
+    db: &DB,
+    flow_job: &QueuedJob,
+    token: &str,
+    path: &str,
+    workspace: Option<&str>,
+    permissioned_as: Option<&str>,
+    base_internal_url: &str,
+) -> error::Result<Value> {
+    let workspace = workspace.unwrap_or(&flow_job.workspace_id);
+    let token = match permissioned_as {
+        None if workspace == flow_job.workspace_id => token.to_string(),
+        None => {
+            return Err(Error::ExecutionErr(format!(
+                "Reading the resource {path} of workspace {workspace} requires a permissioned_as \
+                 granted by its admins"
+            )))
+        }
+        Some(permissioned_as) => {
+            let mut tx = db.begin().await?;
+            /* sub-flows are granted along with the deployed flow they were started from */
+            let flow_path = sqlx::query_scalar!(
+                "SELECT script_path FROM queue WHERE id = $1 AND job_kind = 'flow'",
+                flow_job.trace_id()
+            )
+            .fetch_optional(&mut tx)
+            .await?
+            .flatten();
+            let granted = match flow_path.as_ref() {
+                Some(flow_path) => sqlx::query_scalar!(
+                    "SELECT EXISTS(
+                        SELECT 1 FROM cross_workspace_grant
+                         WHERE workspace_id = $1 AND resource_path = $2 AND permissioned_as = $3
+                           AND grantee_workspace_id = $4 AND grantee_flow_path = $5)",
+                    workspace,
+                    path,
+                    permissioned_as,
+                    &flow_job.workspace_id,
+                    flow_path
+                )
+                .fetch_one(&mut tx)
+                .await?
+                .unwrap_or(false),
+                None => false,
+            };
+            let flow_path = match flow_path {
+                Some(flow_path) if granted => flow_path,
+                _ => {
+                    return Err(Error::ExecutionErr(format!(
+                        "No cross-workspace grant of workspace {workspace} allows this flow to \
+                         read the resource {path} as {permissioned_as}"
+                    )))
+                }
+            };
+
+            let (mut tx, token) = crate::create_token_for_owner(
+                tx,
+                workspace,
+                permissioned_as,
+                "cross-workspace-resource",
+                10,
+                &flow_job.created_by,
+                Some(flow_job.id),
+            )
+            .await?;
+            let job = flow_job.id.to_string();
+            let mut audited = vec![workspace];
+            if workspace != flow_job.workspace_id {
+                audited.push(&flow_job.workspace_id);
+            }
+            for w_id in audited {
+                windmill_audit::audit_log(
+                    &mut tx,
+                    &flow_job.created_by,
+                    "resources.cross_workspace_get",
+                    windmill_audit::ActionKind::Execute,
+                    w_id,
+                    Some(path),
+                    Some(
+                        [
+                            ("resource_workspace", workspace),
+                            ("flow_workspace", &flow_job.workspace_id[..]),
+                            ("flow", &flow_path[..]),
+                            ("permissioned_as", permissioned_as),
+                            ("job", &job[..]),
+                        ]
+                        .into(),
+                    ),
+                )
+                .await?;
+            }
+            tx.commit().await?;
+            token
+        }
+    };
+
+    let client = windmill_api_client::create_client(base_internal_url, token);
+    let value = client
+        .get_resource_value(workspace, path, None, None)
+        .await
+        .map_err(|e| {
+            Error::ExecutionErr(format!(
+                "Could not resolve the resource {path} of {workspace}: {e}"
+            ))
+        })?
+        .into_inner();
+    worker::transform_json_value(&client, workspace, value).await
+}
+
+/// The arguments of the job of an identity module, and so its result: the result of the previous
+/// module, reshaped by the `expr` of the module if any. A value that isn't an object is given as
+/// `previous_result`.
+pub(crate) async fn identity_args(
+    expr: Option<&str>,
+    previous_result: Value,
+    flow_job: &QueuedJob,
+    base_internal_url: &str,
+) -> error::Result<Map<String, Value>> {
+    let value = match expr {
+        Some(expr) => eval_timeout(
+            expr.to_string(),
+            vec![
+                (
+                    "previous_result".to_string(),
+                    flatten_previous_result(previous_result),
+                ),
+                (
+                    "flow_input".to_string(),
+                    flow_job.args.clone().unwrap_or_else(|| json!({})),
+                ),
+            ],
+            None,
+            vec![],
+            None,
+            base_internal_url.to_string(),
+        )
+        .await
+        .map_err(|e| {
+            Error::ExecutionErr(format!(
+                "Error during isolated evaluation of expression `{expr}`:\n{e}"
+            ))
+        })?,
+        None => previous_result,
+    };
+    Ok(match value {
+        Value::Object(m) => m,
+        v => Map::from_iter([("previous_result".to_string(), v)]),
+    })
+}
+
 fn flatten_previous_result(last_result: serde_json::Value) -> serde_json::Value {
     if last_result.is_object()
         && last_result
@@ -638,7 +2185,17 @@ fn flatten_previous_result(last_result: serde_json::Value) -> serde_json::Value
     }
 }
 
-#[instrument(level = "trace", skip_all)]
+#[instrument(
+    name = "flow",
+    level = "trace",
+    skip_all,
+    fields(
+        flow_id = %flow_job.id,
+        trace_id = %flow_job.trace_id(),
+        span_id = %flow_job.id,
+        parent_span_id = ?flow_job.parent_span_id()
+    )
+)]
 pub async fn handle_flow(
     flow_job: &QueuedJob,
     db: &sqlx::Pool<sqlx::Postgres>,
@@ -655,48 +2212,503 @@ pub async fn handle_flow(
         .to_owned();
     let flow = serde_json::from_value::<FlowValue>(value)?;
 
-    if flow.modules.is_empty() {
-        update_flow_status_after_job_completion(
-            db,
-            client,
-            flow_job.id,
-            &Uuid::nil(),
-            flow_job.workspace_id.as_str(),
-            true,
-            json!({}),
-            None,
-            true,
-            same_worker_tx,
-            worker_dir,
-            false,
-            base_internal_url,
-            None,
-        )
+    /* with `resolve_resources`, the worker passes the flow input as the last result of the first
+     * step */
+    let resolved_job;
+    let (flow_job, last_result) = if flow.resolve_resources {
+        let args = resolve_input_resources(
+            client,
+            &flow_job.workspace_id,
+            flow_job.args.clone().unwrap_or_else(|| json!({})),
+        )
+        .await?;
+        let last_result = if flow_job.args.as_ref() == Some(&last_result) {
+            args.clone()
+        } else {
+            last_result
+        };
+        resolved_job = QueuedJob { args: Some(args), ..flow_job.clone() };
+        (&resolved_job, last_result)
+    } else {
+        (flow_job, last_result)
+    };
+
+    if flow.modules.is_empty() {
+        update_flow_status_after_job_completion(
+            db,
+            client,
+            flow_job.id,
+            &Uuid::nil(),
+            flow_job.workspace_id.as_str(),
+            true,
+            json!({}),
+            None,
+            true,
+            same_worker_tx,
+            worker_dir,
+            false,
+            base_internal_url,
+            None,
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let raw_status = flow_job.flow_status.clone().unwrap_or_default();
+    let status = match serde_json::from_value::<FlowStatus>(raw_status.clone()) {
+        Ok(status) => status,
+        Err(e) => {
+            let mut tx = db.begin().await?;
+            let status =
+                recover_flow_status(&mut tx, flow_job.id, &flow_job.workspace_id, &raw_status, e)
+                    .await?;
+            tx.commit().await?;
+            match status {
+                Some(status) => status,
+                None => return Ok(()),
+            }
+        }
+    };
+
+    /* the `Script` modules of a run are pinned to the latest version of their
+     * script when it starts, so that every reference to a path, in each iteration of a loop
+     * included, runs the same version without looking it up again. Sub-flows are built from the
+     * modules of their parent and so are pinned as well. */
+    let flow = if flow_job.parent_job.is_none()
+        && status.step == 0
+        && status.retry.fail_count == 0
+        && matches!(
+            status.modules.first(),
+            Some(FlowStatusModule::WaitingForPriorSteps { .. })
+        ) {
+        pin_script_hashes(db, flow_job, flow).await?
+    } else {
+        flow
+    };
+
+    /* the modules of a dag flow run as soon as their dependencies have succeeded instead */
+    if flow.dag {
+        dag::handle_dag_flow(flow_job, status, &flow, db, base_internal_url).await?;
+        return Ok(());
+    }
+
+    tracing::debug!("handle_flow: {:#?}", flow_job.flow_status);
+    push_next_flow_job(
+        flow_job,
+        status,
+        flow,
+        db,
+        client,
+        last_result,
+        same_worker_tx,
+        base_internal_url,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Sets the hash of the `Script` modules of `flow` without one to the latest hash of their path,
+/// each path being looked up once, and saves the pinned flow as the `raw_flow` of `flow_job`.
+/// The modules run on failure, on cancel and as compensations are pinned too. The paths that
+/// don't resolve are left as is, to fail when their module is reached.
+async fn pin_script_hashes(
+    db: &DB,
+    flow_job: &QueuedJob,
+    mut flow: FlowValue,
+) -> error::Result<FlowValue> {
+    let paths = flow_script_paths(&flow);
+    if paths.is_empty() {
+        return Ok(flow);
+    }
+
+    let mut tx = db.begin().await?;
+    let mut hashes = HashMap::new();
+    for path in paths {
+        match windmill_common::get_latest_hash_for_path(&mut tx, &flow_job.workspace_id, &path)
+            .await
+        {
+            Ok(hash) => {
+                hashes.insert(path, hash);
+            }
+            Err(Error::NotFound(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    pin_flow(&mut flow, &hashes);
+    sqlx::query("UPDATE queue SET raw_flow = $1 WHERE id = $2")
+        .bind(json!(flow))
+        .bind(flow_job.id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(flow)
+}
+
+fn flow_script_paths(flow: &FlowValue) -> HashSet<String> {
+    let mut paths = HashSet::new();
+    flow.modules
+        .iter()
+        .chain(&flow.failure_module)
+        .chain(flow.on_cancel.iter().flat_map(|c| &c.modules))
+        .for_each(|m| script_paths(m, &mut paths));
+    paths
+}
+
+fn pin_flow(flow: &mut FlowValue, hashes: &HashMap<String, ScriptHash>) {
+    flow.modules
+        .iter_mut()
+        .chain(&mut flow.failure_module)
+        .chain(flow.on_cancel.iter_mut().flat_map(|c| &mut c.modules))
+        .for_each(|m| pin_module(m, hashes));
+}
+
+fn script_paths(module: &FlowModule, paths: &mut HashSet<String>) {
+    let children: Vec<&FlowModule> = match &module.value {
+        FlowModuleValue::Script { path, hash: None, .. } if !path.starts_with("hub/") => {
+            paths.insert(path.clone());
+            vec![]
+        }
+        FlowModuleValue::ForloopFlow { modules, .. }
+        | FlowModuleValue::WhileloopFlow { modules, .. } => modules.iter().collect(),
+        FlowModuleValue::BranchOne { branches, default, .. } => branches
+            .iter()
+            .flat_map(|b| &b.modules)
+            .chain(default)
+            .collect(),
+        FlowModuleValue::BranchAll { branches, .. } => {
+            branches.iter().flat_map(|b| &b.modules).collect()
+        }
+        _ => vec![],
+    };
+    children
+        .into_iter()
+        .chain(&module.catch)
+        .chain(module.compensation.as_deref())
+        .for_each(|m| script_paths(m, paths));
+}
+
+fn pin_module(module: &mut FlowModule, hashes: &HashMap<String, ScriptHash>) {
+    let children: Vec<&mut FlowModule> = match &mut module.value {
+        FlowModuleValue::Script { path, hash: hash @ None, .. } => {
+            *hash = hashes.get(path).copied();
+            vec![]
+        }
+        FlowModuleValue::ForloopFlow { modules, .. }
+        | FlowModuleValue::WhileloopFlow { modules, .. } => modules.iter_mut().collect(),
+        FlowModuleValue::BranchOne { branches, default, .. } => branches
+            .iter_mut()
+            .flat_map(|b| &mut b.modules)
+            .chain(default)
+            .collect(),
+        FlowModuleValue::BranchAll { branches, .. } => {
+            branches.iter_mut().flat_map(|b| &mut b.modules).collect()
+        }
+        _ => vec![],
+    };
+    children
+        .into_iter()
+        .chain(&mut module.catch)
+        .chain(module.compensation.as_deref_mut())
+        .for_each(|m| pin_module(m, hashes));
+}
+
+/// Replaces the `$res:<path>` strings of the flow input by the value of the resource at that
+/// path, as is done for the arguments of scripts. `client` being permissioned as the flow, a
+/// resource the flow can't read fails it just like a missing one.
+#[async_recursion]
+async fn resolve_input_resources(
+    client: &windmill_api_client::Client,
+    w_id: &str,
+    v: Value,
+) -> error::Result<Value> {
+    match v {
+        Value::String(s) if s.starts_with("$res:") => {
+            let path = s.strip_prefix("$res:").unwrap();
+            let value = client
+                .get_resource_value(w_id, path, None, None)
+                .await
+                .map_err(|e| {
+                    Error::ExecutionErr(format!(
+                        "Could not resolve the resource {path} of the flow input: {e}"
+                    ))
+                })?
+                .into_inner();
+            worker::transform_json_value(client, w_id, value).await
+        }
+        Value::Object(m) => {
+            let mut resolved = Map::new();
+            for (k, v) in m {
+                resolved.insert(k, resolve_input_resources(client, w_id, v).await?);
+            }
+            Ok(Value::Object(resolved))
+        }
+        Value::Array(a) => {
+            let mut resolved = Vec::with_capacity(a.len());
+            for v in a {
+                resolved.push(resolve_input_resources(client, w_id, v).await?);
+            }
+            Ok(Value::Array(resolved))
+        }
+        v => Ok(v),
+    }
+}
+
+/// Interrupts the running flow `flow` as if its current step had failed with
+/// `error`: the jobs of the step are canceled and the flow goes on with its failure module,
+/// preceded by the compensations of the step, just like after a failure past its retries.
+pub async fn trigger_failure_module(
+    db: &DB,
+    flow: Uuid,
+    w_id: &str,
+    username: &str,
+    error: Value,
+) -> error::Result<()> {
+    let mut tx = db.begin().await?;
+
+    /* Lock the flow so that its status doesn't move on while it is rewritten */
+    sqlx::query_scalar!(
+        "SELECT null FROM queue WHERE id = $1 AND workspace_id = $2 FOR UPDATE",
+        flow,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or_else(|| Error::NotFound(format!("queued flow {flow} does not exist")))?;
+
+    let mut flow_job = get_queued_job(flow, w_id, &mut tx)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("queued flow {flow} does not exist")))?;
+
+    if !matches!(flow_job.job_kind, JobKind::Flow | JobKind::FlowPreview) {
+        return Err(Error::BadRequest(format!("job {flow} is not a flow")));
+    }
+    if !flow_job.running || flow_job.canceled {
+        return Err(Error::BadRequest(format!("flow {flow} is not running")));
+    }
+    /* the failure module of a same_worker flow has to be run by the worker of the flow */
+    let failure_same_worker = flow_job
+        .raw_flow
+        .as_ref()
+        .and_then(|f| f.get("failure_module")?.get("same_worker")?.as_bool());
+    if failure_same_worker.unwrap_or(flow_job.same_worker) {
+        return Err(Error::BadRequest(format!(
+            "the failure module of the same_worker flow {flow} can't be triggered"
+        )));
+    }
+
+    let value = flow_job
+        .raw_flow
+        .clone()
+        .ok_or_else(|| Error::InternalErr("requiring a raw flow value".to_string()))?;
+    let raw_flow = serde_json::from_value::<FlowValue>(value).context("parse raw flow")?;
+    if raw_flow.failure_module.is_none() {
+        return Err(Error::BadRequest(format!(
+            "flow {flow} has no failure module"
+        )));
+    }
+
+    let mut status =
+        serde_json::from_value::<FlowStatus>(flow_job.flow_status.clone().unwrap_or_default())
+            .map_err(|e| Error::BadRequest(format!("unparsable status of flow {flow}: {e}")))?;
+    let i = usize::try_from(status.step)
+        .ok()
+        .filter(|i| *i < status.modules.len())
+        .ok_or_else(|| {
+            Error::BadRequest(format!(
+                "the failure module of flow {flow} is already running"
+            ))
+        })?;
+
+    let children = sqlx::query_scalar!("SELECT id FROM queue WHERE parent_job = $1", flow)
+        .fetch_all(&mut tx)
         .await?;
-        return Ok(());
+    let reason = format!("failure module of flow {flow} triggered by {username}");
+    for child in children {
+        tx = windmill_queue::cancel_job(username, Some(reason.clone()), child, w_id, tx)
+            .await?
+            .0;
     }
 
-    let status: FlowStatus =
-        serde_json::from_value::<FlowStatus>(flow_job.flow_status.clone().unwrap_or_default())
-            .with_context(|| format!("parse flow status {}", flow_job.id))?;
+    /* the retries of the step are exhausted so that push_next_flow_job goes straight to the
+     * failure module */
+    let module = &status.modules[i];
+    status.modules[i] = FlowStatusModule::Failure {
+        id: module.id(),
+        job: module.job().unwrap_or_else(Uuid::nil),
+        flow_jobs: None,
+        branch_chosen: None,
+        reason: None,
+        started_at: module.started_at(),
+        ended_at: Some(now_from_db(&mut tx).await?),
+    };
+    status.retry = RetryStatus { fail_count: MAX_RETRY_ATTEMPTS + 1, ..status.retry };
+    sqlx::query(
+        "UPDATE queue SET flow_status = $1, suspend = 0, suspend_until = NULL WHERE id = $2",
+    )
+    .bind(json!(status))
+    .bind(flow)
+    .execute(&mut tx)
+    .await?;
+    flow_job.flow_status = Some(json!(status));
 
-    tracing::debug!("handle_flow: {:#?}", flow_job.flow_status);
+    let base_internal_url =
+        std::env::var("BASE_INTERNAL_URL").unwrap_or_else(|_| "http://localhost:8000".to_string());
+    let (tx, token) = worker::create_token_for_owner(
+        tx,
+        w_id,
+        &flow_job.permissioned_as,
+        "ephemeral-failure-module",
+        windmill_common::DEFAULT_TIMEOUT,
+        &flow_job.created_by,
+        Some(flow),
+    )
+    .await?;
+    tx.commit().await?;
+    let client = windmill_api_client::create_client(&base_internal_url, token);
+
+    // the flow isn't same_worker, the same worker queue should never be sent anything
+    let (same_worker_tx_never_used, _same_worker_rx_never_used) =
+        tokio::sync::mpsc::channel::<Uuid>(1);
+    let last_result = json!({ "error": error, "reason": "triggered" });
     push_next_flow_job(
-        flow_job,
+        &flow_job,
         status,
-        flow,
+        raw_flow,
         db,
-        client,
+        &client,
         last_result,
-        same_worker_tx,
-        base_internal_url,
+        same_worker_tx_never_used,
+        &base_internal_url,
+    )
+    .await
+}
+
+/// Jobs a flow run may push, its sub-flows included, when the workspace doesn't set
+/// `max_flow_jobs`
+const DEFAULT_MAX_FLOW_JOBS: i32 = 10_000;
+/// Passes a whileloop may run without its stop condition being met when it doesn't set
+/// `max_iterations`
+const DEFAULT_MAX_WHILE_ITERATIONS: u32 = 1000;
+/// Seconds a suspended step waits for its resume messages when neither it nor the workspace
+/// sets a timeout
+const DEFAULT_SUSPEND_TIMEOUT: u64 = 30 * 60;
+
+/// The jobs a flow run of the workspace `w_id` may push.
+async fn get_max_flow_jobs(db: &DB, w_id: &str) -> error::Result<i32> {
+    let max_flow_jobs = sqlx::query_scalar!(
+        "SELECT max_flow_jobs FROM workspace_settings WHERE workspace_id = $1",
+        w_id
+    )
+    .fetch_optional(db)
+    .await?
+    .flatten()
+    .unwrap_or(DEFAULT_MAX_FLOW_JOBS);
+    Ok(max_flow_jobs)
+}
+
+/// Counts a job about to be pushed by `flow_job` in `tx` in the jobs pushed by its run. Past
+/// `max_flow_jobs`, the whole run is canceled, `tx` committed with the cancel, and the job must
+/// not be pushed.
+async fn count_flow_run_job<'c>(
+    mut tx: sqlx::Transaction<'c, sqlx::Postgres>,
+    flow_job: &QueuedJob,
+    max_flow_jobs: i32,
+) -> error::Result<sqlx::Transaction<'c, sqlx::Postgres>> {
+    let root = flow_job.root_job.unwrap_or(flow_job.id);
+    let pushed = sqlx::query_scalar!(
+        "UPDATE queue SET flow_jobs_pushed = flow_jobs_pushed + 1 WHERE id = $1 \
+         RETURNING flow_jobs_pushed",
+        root
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+
+    match pushed {
+        Some(pushed) if pushed > max_flow_jobs => {
+            let reason = format!("flow run {root} exceeded the maximum of {max_flow_jobs} jobs");
+            tx = windmill_queue::cancel_job(
+                "windmill",
+                Some(reason.clone()),
+                root,
+                &flow_job.workspace_id,
+                tx,
+            )
+            .await?
+            .0;
+            tx.commit().await?;
+            Err(Error::ExecutionErr(reason))
+        }
+        _ => Ok(tx),
+    }
+}
+
+/// Cancels `flow` with its in-flight jobs, nested flows included, if it has been
+/// running for longer than its `timeout`.
+async fn cancel_flow_if_timed_out<'c>(
+    mut tx: sqlx::Transaction<'c, sqlx::Postgres>,
+    flow: Uuid,
+    w_id: &str,
+) -> error::Result<sqlx::Transaction<'c, sqlx::Postgres>> {
+    let timeout = sqlx::query_scalar!(
+        "SELECT (raw_flow->>'timeout')::int FROM queue
+          WHERE id = $1 AND workspace_id = $2 AND canceled = false
+            AND started_at + ((raw_flow->>'timeout') || ' seconds')::interval < now()",
+        flow,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .flatten();
+    if let Some(timeout) = timeout {
+        tx = windmill_queue::cancel_job(
+            "timeout",
+            Some(format!("flow duration > {timeout}s")),
+            flow,
+            w_id,
+            tx,
+        )
+        .await?
+        .0;
+    }
+    Ok(tx)
+}
+
+/// Cancels the flows that have been running for longer than their `timeout`, for
+/// those stuck waiting to be resumed or on a long step. The flows are then completed as any
+/// canceled flow.
+pub async fn cancel_timed_out_flows(db: &DB) -> error::Result<()> {
+    let flows = sqlx::query!(
+        "SELECT id, workspace_id FROM queue
+          WHERE raw_flow->>'timeout' IS NOT NULL AND canceled = false
+            AND started_at + ((raw_flow->>'timeout') || ' seconds')::interval < now()"
     )
+    .fetch_all(db)
     .await?;
+    for flow in flows {
+        tracing::info!("canceling timed out flow {} {}", flow.id, flow.workspace_id);
+        let tx = cancel_flow_if_timed_out(db.begin().await?, flow.id, &flow.workspace_id).await?;
+        tx.commit().await?;
+    }
     Ok(())
 }
 
 #[async_recursion]
-#[instrument(level = "trace", skip_all)]
+#[instrument(
+    name = "flow_step",
+    level = "trace",
+    skip_all,
+    fields(
+        flow_id = %flow_job.id,
+        trace_id = %flow_job.trace_id(),
+        span_id = tracing::field::Empty,
+        parent_span_id = %flow_job.id,
+        step = tracing::field::Empty,
+        module_id = tracing::field::Empty,
+        module_summary = tracing::field::Empty
+    )
+)]
 async fn push_next_flow_job(
     flow_job: &QueuedJob,
     mut status: FlowStatus,
@@ -716,6 +2728,7 @@ async fn push_next_flow_job(
         .get(i)
         .or_else(|| flow.failure_module.as_ref())
         .with_context(|| format!("no module at index {}", status.step))?;
+    record_step_span(flow_job.id, i, module);
 
     // calculate sleep if any
     let mut scheduled_for_o = {
@@ -741,13 +2754,27 @@ async fn push_next_flow_job(
                         "Error during isolated evaluation of expression `{expr}`:\n{e}"
                     ))
                 })?,
+                InputTransform::Resource { .. } => Err(Error::ExecutionErr(
+                    "A resource transform is only supported as the input of a step".to_string(),
+                ))?,
             };
             match json_value {
                 serde_json::Value::Number(n) => {
                     n.as_u64().map(|x| from_now(Duration::from_secs(x)))
                 }
+                /* an absolute time to sleep until, the step running right away once it passed */
+                serde_json::Value::String(s) => {
+                    let until = chrono::DateTime::parse_from_rfc3339(&s)
+                        .map_err(|e| {
+                            Error::ExecutionErr(format!(
+                                "Expected the sleep to be an RFC 3339 datetime, found `{s}`: {e}"
+                            ))
+                        })?
+                        .with_timezone(&chrono::Utc);
+                    (until > chrono::Utc::now()).then(|| until)
+                }
                 _ => Err(Error::ExecutionErr(format!(
-                    "Expected an array value, found: {json_value}"
+                    "Expected a number of seconds or a datetime to sleep until, found: {json_value}"
                 )))?,
             }
         } else {
@@ -755,6 +2782,10 @@ async fn push_next_flow_job(
         }
     };
 
+    let compensation_module: FlowModule;
+    let cleanup_module: FlowModule;
+    let catch_module: FlowModule;
+    let mut catching = None;
     let mut status_module: FlowStatusModule = status
         .modules
         .get(i)
@@ -767,17 +2798,67 @@ async fn push_next_flow_job(
         status_module
     );
 
-    let mut resume_messages: Vec<Value> = vec![];
+    /* update_flow_status_after_job_completion only continues a canceled flow to run its
+     * failure module, or its `on_cancel` cleanup modules followed by it if it is
+     * `run_on_cancel`, all in a sub-flow bounded by the timeout of the cleanup */
+    let mut cleanup_timeout = None;
+    if flow_job.canceled && i < flow.modules.len() {
+        let step_id = status_module.id();
+        i = flow.modules.len();
+        module = match &flow.on_cancel {
+            Some(on_cancel) => {
+                cleanup_timeout = Some(on_cancel.timeout.unwrap_or(DEFAULT_ON_CANCEL_TIMEOUT));
+                cleanup_module = serde_json::from_value(json!({
+                    "id": status.failure_module.id(),
+                    "value": {
+                        "type": "branchone",
+                        "branches": [],
+                        "default": on_cancel
+                            .modules
+                            .iter()
+                            .chain(flow.failure_module.as_ref().filter(|m| m.run_on_cancel))
+                            .collect::<Vec<_>>(),
+                    },
+                }))
+                .context("build cleanup module")?;
+                &cleanup_module
+            }
+            None => flow
+                .failure_module
+                .as_ref()
+                .context("missing failure module")?,
+        };
+        record_step_span(flow_job.id, i, module);
+        status_module = status.failure_module.clone();
+        last_result = step_error(
+            json!({ "error": canceled_job_to_result(flow_job), "reason": "canceled" }),
+            step_id,
+        );
+    }
+
+    let mut resume_messages: Vec<ApprovalResult> = vec![];
 
     /* (suspend / resume), when starting a module, if previous module has a
      * non-zero `suspend` value, collect `resume_job`s for the previous module job.
      *
-     * If there aren't enough, try again later. */
-    if matches!(
-        &status_module,
-        FlowStatusModule::WaitingForPriorSteps { .. } | FlowStatusModule::WaitingForEvents { .. }
-    ) {
-        if let Some((suspend, last)) = needs_resume(&flow, &status) {
+     * If there aren't enough, try again later. The failure module never waits for them. */
+    if i < flow.modules.len()
+        && matches!(
+            &status_module,
+            FlowStatusModule::WaitingForPriorSteps { .. }
+                | FlowStatusModule::WaitingForEvents { .. }
+        )
+    {
+        if let Some((suspend, last)) = needs_resume(
+            &flow,
+            &status,
+            &status_module,
+            &flow_job.args,
+            &last_result,
+            base_internal_url,
+        )
+        .await?
+        {
             let mut tx = db.begin().await?;
 
             /* Lock this row to prevent the suspend column getting out out of sync
@@ -793,13 +2874,25 @@ async fn push_next_flow_job(
             .context("lock flow in queue")?;
 
             let resumes = sqlx::query!(
-                "SELECT value, approver, resume_id FROM resume_job WHERE job = $1 ORDER BY created_at ASC",
+                "SELECT value, approver, resume_id, created_at FROM resume_job WHERE job = $1 ORDER BY created_at ASC",
                 last
             )
             .fetch_all(&mut tx)
             .await?;
 
-            resume_messages.extend(resumes.iter().map(|r| r.value.clone()));
+            resume_messages.extend(resumes.into_iter().map(|r| ApprovalResult {
+                approval: Approval {
+                    resume_id: r.resume_id as u16,
+                    approver: r.approver.unwrap_or_else(|| "unknown".to_string()),
+                    approved_at: Some(r.created_at),
+                },
+                payload: r.value,
+            }));
+            /* only the first approval of each approver counts towards `required_events` */
+            if suspend.require_distinct_approvers {
+                let mut approvers = HashSet::new();
+                resume_messages.retain(|r| approvers.insert(r.approval.approver.clone()));
+            }
 
             let required_events = suspend.required_events.unwrap() as u16;
             if resume_messages.len() >= required_events as usize {
@@ -812,12 +2905,9 @@ async fn push_next_flow_job(
                       ",
                 )
                 .bind(status.step - 1)
-                .bind(json!(resumes
-                    .into_iter()
-                    .map(|r| Approval {
-                        resume_id: r.resume_id as u16,
-                        approver: r.approver.unwrap_or_else(|| "unknown".to_string())
-                    })
+                .bind(json!(resume_messages
+                    .iter()
+                    .map(|r| &r.approval)
                     .collect::<Vec<_>>()))
                 .bind(flow_job.id)
                 .execute(&mut tx)
@@ -841,6 +2931,52 @@ async fn push_next_flow_job(
                 &status_module,
                 FlowStatusModule::WaitingForPriorSteps { .. }
             ) {
+                let timeout = match suspend.timeout {
+                    Some(timeout) => timeout.as_secs(),
+                    None => sqlx::query_scalar!(
+                        "SELECT default_suspend_timeout FROM workspace_settings WHERE workspace_id = $1",
+                        flow_job.workspace_id
+                    )
+                    .fetch_optional(&mut tx)
+                    .await?
+                    .flatten()
+                    .map_or(DEFAULT_SUSPEND_TIMEOUT, |t| t as u64),
+                };
+                sqlx::query(
+                    "
+                    UPDATE queue
+                       SET flow_status = JSONB_SET(flow_status, ARRAY['modules', flow_status->>'step'::text], $1)
+                         , suspend = $2
+                         , suspend_until = now() + $3
+                     WHERE id = $4
+                    ",
+                )
+                .bind(json!(FlowStatusModule::WaitingForEvents { id: status_module.id(), count: required_events, job: last, in_grace_period: false, suspended_at: Some(chrono::Utc::now()) }))
+                .bind((required_events - resume_messages.len() as u16) as i32)
+                .bind(Duration::from_secs(timeout))
+                .bind(flow_job.id)
+                .execute(&mut tx)
+                .await?;
+
+                tx.commit().await?;
+                /* the suspend is the one of the previous step */
+                let kind = i
+                    .checked_sub(1)
+                    .and_then(|prev| flow.modules.get(prev))
+                    .map_or("unknown", |m| m.value.kind());
+                FLOW_STEP_TRANSITIONS
+                    .with_label_values(&["suspended", kind])
+                    .inc();
+                return Ok(());
+
+            /* timed out for the first time, give late messages a last chance during the grace
+             * period. They are counted again under the lock above once it's over. */
+            } else if let (
+                Some(grace_period),
+                FlowStatusModule::WaitingForEvents { in_grace_period: false, suspended_at, .. },
+                false,
+            ) = (suspend.grace_period, &status_module, flow_job.canceled)
+            {
                 sqlx::query(
                     "
                     UPDATE queue
@@ -850,9 +2986,9 @@ async fn push_next_flow_job(
                      WHERE id = $4
                     ",
                 )
-                .bind(json!(FlowStatusModule::WaitingForEvents { id: status_module.id(), count: required_events, job: last }))
+                .bind(json!(FlowStatusModule::WaitingForEvents { id: status_module.id(), count: required_events, job: last, in_grace_period: true, suspended_at: *suspended_at }))
                 .bind((required_events - resume_messages.len() as u16) as i32)
-                .bind(Duration::from_secs(suspend.timeout.map(|t| t.into()).unwrap_or_else(|| 30 * 60)))
+                .bind(Duration::from_secs(grace_period.into()))
                 .bind(flow_job.id)
                 .execute(&mut tx)
                 .await?;
@@ -864,29 +3000,85 @@ async fn push_next_flow_job(
             } else {
                 tx.commit().await?;
 
-                let success = false;
-                let skipped = false;
-                let logs = "Timed out waiting to be resumed".to_string();
-                let result = json!({ "error": logs });
-                let _uuid =
-                    add_completed_job(db, client, &flow_job, success, skipped, result, logs)
+                let waited = match &status_module {
+                    FlowStatusModule::WaitingForEvents {
+                        suspended_at: Some(suspended_at), ..
+                    } => {
+                        format!(
+                            " after {}",
+                            format_duration((chrono::Utc::now() - *suspended_at).num_seconds())
+                        )
+                    }
+                    _ => String::new(),
+                };
+                let approved_by = if resume_messages.is_empty() {
+                    "no one approved".to_string()
+                } else {
+                    format!(
+                        "approved by {} ({} of the {required_events} approvals required)",
+                        resume_messages
+                            .iter()
+                            .map(|r| r.approval.approver.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        resume_messages.len()
+                    )
+                };
+                let logs = format!("Timed out waiting to be resumed{waited}, {approved_by}");
+                match flow.failure_module.as_ref() {
+                    /* with `run_on_timeout`, clean up with the failure module instead of failing */
+                    Some(failure_module) if failure_module.run_on_timeout => {
+                        /* the suspended step is the previous one */
+                        let step_id = flow.modules[i - 1].id.clone();
+                        i = flow.modules.len();
+                        module = failure_module;
+                        record_step_span(flow_job.id, i, module);
+                        status_module = status.failure_module.clone();
+                        last_result =
+                            step_error(json!({ "error": logs, "reason": "timeout" }), step_id);
+                    }
+                    _ => {
+                        let success = false;
+                        let skipped = false;
+                        let result = json!({ "error": logs });
+                        let _uuid = add_completed_job(
+                            db, client, &flow_job, success, skipped, result, logs,
+                        )
                         .await?;
 
-                return Ok(());
+                        return Ok(());
+                    }
+                }
             }
         }
     }
 
+    /* the `poll` condition wasn't met, run the step again with the args of its first run */
+    if let FlowStatusModule::WaitingForCondition { .. } = &status_module {
+        if let Some(v) = &status.retry.previous_result {
+            last_result = v.clone();
+        }
+        scheduled_for_o = None;
+        status_module = FlowStatusModule::WaitingForPriorSteps { id: status_module.id() };
+    }
+
     match &status_module {
         FlowStatusModule::Failure { job, .. } => {
             let retry = &module.retry.clone().unwrap_or_default();
-            if let Some((fail_count, retry_in)) = next_retry(retry, &status.retry) {
+            if let Some((fail_count, retry_in)) = next_retry_if(
+                retry,
+                &status.retry,
+                &last_result,
+                &flow_job.args,
+                base_internal_url,
+            )
+            .await?
+            {
                 tracing::debug!(
                     retry_in_seconds = retry_in.as_secs(),
                     fail_count = fail_count,
                     "retrying"
                 );
-
                 scheduled_for_o = Some(from_now(retry_in));
                 status.retry.failed_jobs.push(job.clone());
                 sqlx::query(
@@ -896,11 +3088,22 @@ async fn push_next_flow_job(
                  WHERE id = $2
                 ",
                 )
-                .bind(json!(RetryStatus { fail_count, ..status.retry.clone() }))
+                .bind(json!(RetryStatus {
+                    fail_count,
+                    first_failure_at: status
+                        .retry
+                        .first_failure_at
+                        .or_else(|| Some(chrono::Utc::now())),
+                    poll_until: None,
+                    ..status.retry.clone()
+                }))
                 .bind(flow_job.id)
                 .execute(db)
                 .await
                 .context("update flow retry")?;
+                FLOW_STEP_TRANSITIONS
+                    .with_label_values(&["retried", module.value.kind()])
+                    .inc();
 
                 /* it might be better to retry the job using the previous args instead of determining
                  * them again from the last result, but that seemed to not play well with the forloop
@@ -910,8 +3113,38 @@ async fn push_next_flow_job(
                 }
                 status_module = FlowStatusModule::WaitingForPriorSteps { id: status_module.id() };
 
-            /* Start the failure module ... */
+            /* Run the catch modules of the step in its place, unless they failed too ... */
+            } else if i < flow.modules.len()
+                && !module.catch.is_empty()
+                && !matches!(
+                    status_module,
+                    FlowStatusModule::Failure { reason: Some(FailureReason::Catch), .. }
+                )
+            {
+                catching = Some(*job);
+                last_result = step_error(last_result, status_module.id());
+                catch_module = serde_json::from_value(json!({
+                    "id": module.id,
+                    "value": {
+                        "type": "branchone",
+                        "branches": [],
+                        "default": module.catch,
+                    },
+                }))
+                .context("build catch module")?;
+                module = &catch_module;
+                status_module = FlowStatusModule::WaitingForPriorSteps { id: status_module.id() };
+
+            /* ... or start the failure module ... */
             } else {
+                /* ... preceded by the compensations of the transactional group of the failed
+                 * module, all run in a sub-flow in place of the failure module */
+                let compensations = compensation_modules(&flow, &status, i);
+                /* the error of the failed step is structured, unless the
+                 * failure module itself failed and is retried with its own previous_result */
+                if i < flow.modules.len() {
+                    last_result = step_error(last_result, status_module.id());
+                }
                 /* push_next_flow_job is called with the current step on FlowStatusModule::Failure.
                  * This must update the step index to the end so that no subsequent steps are run after
                  * the failure module.
@@ -920,12 +3153,28 @@ async fn push_next_flow_job(
                  * In that case, `i` will index past `flow.modules`.  The above should handle that and
                  * re-run the failure module. */
                 i = flow.modules.len();
-                module = flow
-                    .failure_module
-                    .as_ref()
-                    /* If this fails, it's a update_flow_status_after_job_completion shouldn't have called
-                     * handle_flow to get here. */
-                    .context("missing failure module")?;
+                module = if compensations.is_empty() {
+                    flow.failure_module
+                        .as_ref()
+                        /* If this fails, it's a update_flow_status_after_job_completion shouldn't have called
+                         * handle_flow to get here. */
+                        .context("missing failure module")?
+                } else {
+                    compensation_module = serde_json::from_value(json!({
+                        "id": status.failure_module.id(),
+                        "value": {
+                            "type": "branchone",
+                            "branches": [],
+                            "default": compensations
+                                .into_iter()
+                                .chain(flow.failure_module.clone())
+                                .collect::<Vec<_>>(),
+                        },
+                    }))
+                    .context("build compensation module")?;
+                    &compensation_module
+                };
+                record_step_span(flow_job.id, i, module);
                 status_module = status.failure_module.clone();
 
                 /* (retry feature) save the previous_result the first time this step is run */
@@ -942,6 +3191,8 @@ async fn push_next_flow_job(
                         previous_result: Some(last_result.clone()),
                         fail_count: 0,
                         failed_jobs: vec![],
+                        first_failure_at: None,
+                        poll_until: None,
                     }))
                     .bind(flow_job.id)
                     .execute(db)
@@ -953,12 +3204,14 @@ async fn push_next_flow_job(
             /* (retry feature) save the previous_result the first time this step is run */
         }
         FlowStatusModule::WaitingForPriorSteps { .. }
-            if module
+            if (module
                 .retry
                 .as_ref()
                 .map(|x| x.has_attempts())
                 .unwrap_or(false)
-                && status.retry.fail_count == 0 =>
+                || module.poll.is_some())
+                && status.retry.fail_count == 0
+                && status.retry.poll_until.is_none() =>
         {
             sqlx::query(
                 "
@@ -971,6 +3224,8 @@ async fn push_next_flow_job(
                 previous_result: Some(last_result.clone()),
                 fail_count: 0,
                 failed_jobs: vec![],
+                first_failure_at: None,
+                poll_until: None,
             }))
             .bind(flow_job.id)
             .execute(db)
@@ -980,40 +3235,98 @@ async fn push_next_flow_job(
         _ => (),
     }
 
+    /* a step whose `skip_if` is true completes as skipped without running, passing the
+     * previous result through to the next step as if it were an identity */
+    if let (Some(skip_if), FlowStatusModule::WaitingForPriorSteps { .. }, None) =
+        (&module.skip_if, &status_module, catching)
+    {
+        if i < flow.modules.len()
+            && compute_bool_from_expr(
+                skip_if.expr.clone(),
+                &flow_job.args,
+                last_result.clone(),
+                base_internal_url,
+            )
+            .await?
+        {
+            sqlx::query(
+                "UPDATE queue SET flow_status = JSONB_SET(flow_status, ARRAY['modules', $1::TEXT], $2) \
+                 WHERE id = $3",
+            )
+            .bind(status.step)
+            .bind(json!(FlowStatusModule::Success {
+                id: status_module.id(),
+                job: Uuid::nil(),
+                flow_jobs: None,
+                branch_chosen: None,
+                approvers: vec![],
+                warning: None,
+                skipped: true,
+                started_at: None,
+                ended_at: None,
+            }))
+            .bind(flow_job.id)
+            .execute(db)
+            .await?;
+            return update_flow_status_after_job_completion(
+                db,
+                client,
+                flow_job.id,
+                &Uuid::nil(),
+                &flow_job.workspace_id,
+                true,
+                last_result,
+                None,
+                false,
+                same_worker_tx,
+                "",
+                true,
+                base_internal_url,
+                None,
+            )
+            .await;
+        }
+    }
+
     let mut transform_context: Option<TransformContext> = None;
-    let mut args = match &module.value {
+    let args = match &module.value {
         FlowModuleValue::Script { input_transforms, .. }
         | FlowModuleValue::RawScript { input_transforms, .. } => {
             let tx = db.begin().await?;
             let (tx, ctx) = get_transform_context(tx, &flow_job, &status, &flow.modules).await?;
             transform_context = Some(ctx);
             tx.commit().await?;
-            let (token, steps, by_id) = transform_context.as_ref().unwrap();
+            let (token, steps, by_id, trigger) = transform_context.as_ref().unwrap();
             transform_input(
-                &flow_job.args,
+                db,
+                &flow_job,
                 last_result.clone(),
                 if !input_transforms.is_empty() {
                     input_transforms
                 } else {
                     &module.input_transforms
                 },
-                &flow_job.workspace_id,
                 &token,
                 steps.to_vec(),
                 resume_messages.as_slice(),
                 by_id,
+                trigger,
+                base_internal_url,
+            )
+            .await?
+        }
+        FlowModuleValue::Collector { ids } => {
+            collect_results(db, &flow_job.workspace_id, &flow, &status, i, ids).await?
+        }
+        FlowModuleValue::Identity { expr } => {
+            identity_args(
+                expr.as_deref(),
+                last_result.clone(),
+                &flow_job,
                 base_internal_url,
             )
             .await?
         }
-        FlowModuleValue::Identity => match last_result.clone() {
-            Value::Object(m) => m,
-            v @ _ => {
-                let mut m = Map::new();
-                m.insert("previous_result".to_string(), v);
-                m
-            }
-        },
         _ => {
             /* embedded flow input is augmented with embedding flow input */
             if let Some(value) = &flow_job.args {
@@ -1029,6 +3342,58 @@ async fn push_next_flow_job(
         }
     };
 
+    /* with `cache_ttl`, a script step run recently enough with the same script and arguments
+     * completes with the result of the job that ran it then, as if it had just returned it
+     * again */
+    if let (Some(ttl), FlowModuleValue::Script { .. } | FlowModuleValue::RawScript { .. }) =
+        (module.cache_ttl, &module.value)
+    {
+        if i < flow.modules.len() && module.poll.is_none() {
+            let cached = get_cached_result(
+                db,
+                &flow_job.workspace_id,
+                &flow_job.permissioned_as,
+                &module.value,
+                &args,
+                ttl,
+            )
+            .await?;
+            if let Some((job, cached)) = cached {
+                tracing::info!(
+                    flow = %flow_job.id,
+                    "step '{}' completed with the cached result of job {job}",
+                    status.step_name(&module.id)
+                );
+                sqlx::query(
+                    "UPDATE queue SET flow_status = JSONB_SET(flow_status, ARRAY['modules', $1::TEXT], $2) \
+                     WHERE id = $3",
+                )
+                .bind(status.step)
+                .bind(json!(FlowStatusModule::WaitingForExecutor { id: status_module.id(), job }))
+                .bind(flow_job.id)
+                .execute(db)
+                .await?;
+                return update_flow_status_after_job_completion(
+                    db,
+                    client,
+                    flow_job.id,
+                    &job,
+                    &flow_job.workspace_id,
+                    true,
+                    cached,
+                    None,
+                    false,
+                    same_worker_tx,
+                    "",
+                    true,
+                    base_internal_url,
+                    None,
+                )
+                .await;
+            }
+        }
+    }
+
     let tx = db.begin().await?;
     let (tx, next_flow_transform) = compute_next_flow_transform(
         flow_job,
@@ -1045,6 +3410,12 @@ async fn push_next_flow_job(
     tx.commit().await?;
 
     let (job_payload, next_status) = match next_flow_transform {
+        NextFlowTransform::Continue(JobPayload::RawFlow { mut value, path }, next_state)
+            if cleanup_timeout.is_some() =>
+        {
+            value.timeout = cleanup_timeout;
+            (JobPayload::RawFlow { value, path }, next_state)
+        }
         NextFlowTransform::Continue(job_payload, next_state) => (job_payload, next_state),
         NextFlowTransform::EmptyInnerFlows => {
             return jump_to_next_step(
@@ -1060,8 +3431,17 @@ async fn push_next_flow_job(
                     flow_jobs: Some(vec![]),
                     branch_chosen: None,
                     approvers: vec![],
+                    warning: None,
+                    skipped: true,
+                    started_at: None,
+                    ended_at: None,
+                },
+                /* the results of the branches of a branchall are keyed by their label */
+                if matches!(module.value, FlowModuleValue::BranchAll { .. }) {
+                    json!({})
+                } else {
+                    json!([])
                 },
-                json!([]),
                 same_worker_tx,
                 base_internal_url,
             )
@@ -1069,79 +3449,279 @@ async fn push_next_flow_job(
         }
     };
 
-    let continue_on_same_worker =
-        flow.same_worker && module.suspend.is_none() && module.sleep.is_none();
+    let continue_on_same_worker = module.same_worker.or(flow.same_worker).unwrap_or(false)
+        && module.suspend.is_none()
+        && module.sleep.is_none()
+        && module.poll.is_none();
 
-    match &next_status {
-        NextStatus::NextLoopIteration(NextIteration { new_args, .. }) => {
-            args.extend(new_args.clone())
-        }
-        NextStatus::BranchChosen(_) => {
-            args.insert(
-                "previous_result".to_string(),
-                flatten_previous_result(last_result),
-            );
-        }
-        NextStatus::NextBranchStep(NextBranch { status, .. }) => {
-            args.insert(
+    let injected = match &next_status {
+        NextStatus::NextLoopIteration(NextIteration { new_args, .. }) => new_args.clone(),
+        NextStatus::BranchChosen(_) => Map::from_iter([(
+            "previous_result".to_string(),
+            flatten_previous_result(last_result),
+        )]),
+        NextStatus::NextBranchStep(NextBranch { status, .. }) => Map::from_iter([
+            (
                 "previous_result".to_string(),
                 flatten_previous_result(status.previous_result.clone()),
-            );
+            ),
+            ("branch".to_string(), branch_arg(module, status.branch)),
+        ]),
+        _ => Map::new(),
+    };
+    /* the next iteration waits for the `min_iteration_interval` to elapse since the
+     * start of the previous one, on top of any sleep or retry delay */
+    if let (
+        NextStatus::NextLoopIteration(NextIteration { index, .. }),
+        FlowModuleValue::ForloopFlow { min_iteration_interval: Some(interval), .. },
+    ) = (&next_status, &module.value)
+    {
+        if *index > 0 {
+            let previous_start = sqlx::query_scalar!(
+                "SELECT max(started_at) FROM completed_job WHERE parent_job = $1 AND flow_step_id = $2",
+                flow_job.id,
+                module.id
+            )
+            .fetch_one(db)
+            .await?;
+            let earliest = previous_start
+                .and_then(|s| s.checked_add_signed(chrono::Duration::seconds(*interval as i64)));
+            scheduled_for_o = scheduled_for_o.max(earliest);
+        }
+    }
+
+    /* a parallel forloop pushes the iterations of a whole window at once, each
+     * with its own `iter` */
+    let mut jobs = vec![(job_payload, injected)];
+    match (&next_status, &module.value) {
+        (
+            NextStatus::NextLoopIteration(NextIteration { index, itered, itered_len, .. }),
+            FlowModuleValue::ForloopFlow { parallelism: Some(parallelism), .. },
+        ) => {
+            let len = itered_len.unwrap_or(itered.len());
+            let end = index.saturating_add(usize::from(*parallelism)).min(len);
+            let mut tx = db.begin().await?;
+            let values = get_itered(
+                &mut tx,
+                &status,
+                flow_job.id,
+                i as i32,
+                itered,
+                index + 1..end,
+            )
+            .await?;
+            tx.commit().await?;
+            let job_payload = jobs[0].0.clone();
+            jobs.extend((index + 1..).zip(values).map(|(k, value)| {
+                (
+                    job_payload.clone(),
+                    Map::from_iter([("iter".to_string(), iter_arg(k, value, len))]),
+                )
+            }));
+        }
+        /* a parallel branchall pushes all its branches at once */
+        (
+            NextStatus::NextBranchStep(NextBranch { status: branch_status, .. }),
+            FlowModuleValue::BranchAll { parallel: true, stop_at_first_success: false, .. },
+        ) => {
+            let injected = jobs[0].1.clone();
+            for branch in branch_status.branch + 1..branch_status.len {
+                let payload = branchall_payload(flow_job, &flow, module, branch)
+                    .context("missing branch of branchall")?;
+                let mut injected = injected.clone();
+                injected.insert("branch".to_string(), branch_arg(module, branch));
+                jobs.push((payload, injected));
+            }
         }
         _ => (),
+    }
+    /* the iterations of a window run at the same time, so not all on the same worker */
+    let continue_on_same_worker = continue_on_same_worker && jobs.len() == 1;
+    /* only the job of a step is bounded by its timeout, not the flows of its iterations or
+     * branches */
+    let timeout = match &next_status {
+        NextStatus::NextStep => module.timeout.map(|t| t as i32),
+        _ => None,
     };
 
-    /* Finally, push the job into the queue */
-    let tx = db.begin().await?;
+    /* flows nested past `MAX_FLOW_DEPTH`, by accident or on purpose, fail instead of
+     * nesting without end */
+    if matches!(jobs.first(), Some((JobPayload::RawFlow { .. }, _)))
+        && flow_job.parent_job.is_some()
+    {
+        let depth = flow_depth(db, flow_job.id, *MAX_FLOW_DEPTH).await? + 1;
+        if depth > *MAX_FLOW_DEPTH {
+            return Err(Error::ExecutionErr(format!(
+                "Step '{}' would nest a flow {depth} levels deep, past the maximum of {} levels",
+                status.step_name(&module.id),
+                *MAX_FLOW_DEPTH
+            )));
+        }
+    }
 
-    let (uuid, mut tx) = push(
-        tx,
-        &flow_job.workspace_id,
-        job_payload,
-        Some(args.clone()),
-        &flow_job.created_by,
-        flow_job.permissioned_as.to_owned(),
-        scheduled_for_o,
-        flow_job.schedule_path.clone(),
-        Some(flow_job.id),
-        true,
-        continue_on_same_worker,
-    )
-    .await?;
+    /* the failure module still runs once the run has been canceled for its `max_flow_jobs` */
+    let max_flow_jobs = if i < flow.modules.len() {
+        Some(get_max_flow_jobs(db, &flow_job.workspace_id).await?)
+    } else {
+        None
+    };
+
+    /* Finally, push the jobs into the queue */
+    let mut tx = db.begin().await?;
+    let mut uuids = vec![];
+
+    for (k, (job_payload, injected)) in jobs.into_iter().enumerate() {
+        let mut args = args.clone();
+        let collisions = module.input_merge.merge(&mut args, injected)?;
+        if !collisions.is_empty() {
+            tracing::warn!(
+                flow = %flow_job.id,
+                "injected arguments {collisions:?} of step '{}' collide with existing arguments, merged with {:?}",
+                status.step_name(&module.id),
+                module.input_merge
+            );
+        }
+
+        /* the job of an iteration or a branch is only pushed once per attempt of the step, even
+         * if this is run again after a crash */
+        let idempotency_key = match &next_status {
+            NextStatus::NextLoopIteration(NextIteration { index, .. }) => Some(index + k),
+            NextStatus::NextBranchStep(NextBranch { status, .. }) => Some(status.branch + k),
+            _ => None,
+        }
+        .map(|idx| format!("{}:{i}:{idx}:{}", flow_job.id, status.retry.fail_count));
+
+        /* a job already pushed with the key was counted when it was */
+        let pushed_before = match idempotency_key.as_deref() {
+            Some(key) => get_idempotent_job(&mut tx, &flow_job.workspace_id, key)
+                .await?
+                .is_some(),
+            None => false,
+        };
+        if let Some(max_flow_jobs) = max_flow_jobs.filter(|_| !pushed_before) {
+            tx = count_flow_run_job(tx, flow_job, max_flow_jobs).await?;
+        }
+
+        let (uuid, new_tx) = push(
+            tx,
+            &flow_job.workspace_id,
+            job_payload,
+            Some(args),
+            &flow_job.created_by,
+            flow_job.permissioned_as.to_owned(),
+            scheduled_for_o,
+            flow_job.schedule_path.clone(),
+            Some(flow_job.id),
+            true,
+            continue_on_same_worker,
+            idempotency_key,
+            FlowStorage::of(&status),
+        )
+        .await?;
+        tx = new_tx;
+
+        /* the jobs of iterations and branches are correlated to the module that spawned them,
+         * their `parent_job` being the flow running that module */
+        sqlx::query!(
+            "UPDATE queue SET flow_step_id = $1, timeout = $2 WHERE id = $3",
+            module.id,
+            timeout,
+            uuid
+        )
+        .execute(&mut tx)
+        .await?;
+        uuids.push(uuid);
+    }
+    let uuid = *uuids.last().context("no job pushed")?;
 
+    /* a step spawning flows starts with them, a step running a script with its job */
+    let started_at = match (&next_status, status_module.started_at()) {
+        (NextStatus::NextStep, _) => None,
+        (_, Some(started_at)) => Some(started_at),
+        _ => Some(now_from_db(&mut tx).await?),
+    };
     let new_status = match next_status {
-        NextStatus::NextLoopIteration(NextIteration { index, itered, mut flow_jobs, .. }) => {
-            flow_jobs.push(uuid);
+        NextStatus::NextLoopIteration(NextIteration {
+            index,
+            itered,
+            itered_len,
+            max_iterations,
+            mut flow_jobs,
+            ..
+        }) => {
+            add_window_jobs(
+                &mut tx,
+                &status,
+                flow_job.id,
+                i,
+                index,
+                &mut flow_jobs,
+                &uuids,
+            )
+            .await?;
+            let running = if uuids.len() > 1 {
+                uuids.clone()
+            } else {
+                vec![]
+            };
 
             FlowStatusModule::InProgress {
                 job: uuid,
-                iterator: Some(windmill_common::flow_status::Iterator { index, itered }),
+                iterator: Some(windmill_common::flow_status::Iterator {
+                    index: index + uuids.len() - 1,
+                    itered,
+                    itered_len,
+                    running,
+                    failed: None,
+                    break_at: None,
+                    max_iterations,
+                }),
                 flow_jobs: Some(flow_jobs),
                 branch_chosen: None,
                 branchall: None,
                 id: status_module.id(),
+                catching: None,
+                started_at,
             }
         }
-        NextStatus::NextBranchStep(NextBranch { mut flow_jobs, status, .. }) => {
-            flow_jobs.push(uuid);
+        NextStatus::NextBranchStep(NextBranch { mut flow_jobs, status: mut branch_status }) => {
+            add_window_jobs(
+                &mut tx,
+                &status,
+                flow_job.id,
+                i,
+                branch_status.branch,
+                &mut flow_jobs,
+                &uuids,
+            )
+            .await?;
+            if uuids.len() > 1 {
+                branch_status.branch += uuids.len() - 1;
+                branch_status.running = uuids.clone();
+            }
 
             FlowStatusModule::InProgress {
                 job: uuid,
                 iterator: None,
                 flow_jobs: Some(flow_jobs),
                 branch_chosen: None,
-                branchall: Some(status),
+                branchall: Some(branch_status),
                 id: status_module.id(),
+                catching: None,
+                started_at,
             }
         }
 
+        /* the catch modules run as the default branch of a branchone */
         NextStatus::BranchChosen(branch) => FlowStatusModule::InProgress {
             job: uuid,
             iterator: None,
             flow_jobs: None,
-            branch_chosen: Some(branch),
+            branch_chosen: catching.is_none().then(|| branch),
             branchall: None,
             id: status_module.id(),
+            catching,
+            started_at,
         },
         NextStatus::NextStep => {
             FlowStatusModule::WaitingForExecutor { id: status_module.id(), job: uuid }
@@ -1173,12 +3753,30 @@ async fn push_next_flow_job(
 
     tx.commit().await?;
 
+    if matches!(status_module, FlowStatusModule::WaitingForPriorSteps { .. }) {
+        FLOW_STEP_TRANSITIONS
+            .with_label_values(&["started", module.value.kind()])
+            .inc();
+    }
+
     if continue_on_same_worker {
         same_worker_tx.send(uuid).await.map_err(to_anyhow)?;
     }
     return Ok(());
 }
 
+/// Sets the step attributes of the current `flow_step` span of `flow` once the module to run is
+/// known, its `span_id` being the parent span of the jobs the module pushes.
+fn record_step_span(flow: Uuid, step: usize, module: &FlowModule) {
+    let span = tracing::Span::current();
+    span.record("span_id", step_span_id(flow, &module.id).as_str());
+    span.record("step", step);
+    span.record("module_id", module.id.as_str());
+    if let Some(summary) = &module.summary {
+        span.record("module_summary", summary.as_str());
+    }
+}
+
 async fn jump_to_next_step(
     status_step: i32,
     i: usize,
@@ -1238,7 +3836,7 @@ async fn jump_to_next_step(
         let skipped = false;
         let logs = "Forloop completed without iteration".to_string();
         let _uuid =
-            add_completed_job(db, client, &new_job, success, skipped, json!([]), logs).await?;
+            add_completed_job(db, client, &new_job, success, skipped, last_result, logs).await?;
         return Ok(());
     }
 }
@@ -1248,6 +3846,8 @@ async fn jump_to_next_step(
 struct NextIteration {
     index: usize,
     itered: Vec<Value>,
+    itered_len: Option<usize>,
+    max_iterations: Option<u32>,
     flow_jobs: Vec<Uuid>,
     new_args: Map<String, serde_json::Value>,
 }
@@ -1276,21 +3876,25 @@ enum NextFlowTransform {
 
 // a similar function exists on the backend
 // TODO: rewrite this to use an endpoint in the backend directly, instead of checking for hub itself, and then using the API
-async fn script_path_to_payload<'c>(
+pub(crate) async fn script_path_to_payload<'c>(
     script_path: &str,
+    script_hash: Option<ScriptHash>,
     db: &mut sqlx::Transaction<'c, sqlx::Postgres>,
     w_id: &String,
 ) -> Result<JobPayload, Error> {
     let job_payload = if script_path.starts_with("hub/") {
         JobPayload::ScriptHub { path: script_path.to_owned() }
     } else {
-        let script_hash = windmill_common::get_latest_hash_for_path(db, w_id, script_path).await?;
+        let script_hash = match script_hash {
+            Some(script_hash) => script_hash,
+            None => windmill_common::get_latest_hash_for_path(db, w_id, script_path).await?,
+        };
         JobPayload::ScriptHash { hash: script_hash, path: script_path.to_owned() }
     };
     Ok(job_payload)
 }
 
-type TransformContext = (String, Vec<Uuid>, IdContext);
+pub(crate) type TransformContext = (String, Vec<Uuid>, IdContext, Value);
 
 async fn compute_next_flow_transform<'c>(
     flow_job: &QueuedJob,
@@ -1304,13 +3908,14 @@ async fn compute_next_flow_transform<'c>(
     base_internal_url: &str,
 ) -> error::Result<(sqlx::Transaction<'c, sqlx::Postgres>, NextFlowTransform)> {
     match &module.value {
-        FlowModuleValue::Identity => Ok((
+        /* the collected results are the arguments of the identity job */
+        FlowModuleValue::Identity { .. } | FlowModuleValue::Collector { .. } => Ok((
             tx,
             NextFlowTransform::Continue(JobPayload::Identity, NextStatus::NextStep),
         )),
-        FlowModuleValue::Script { path: script_path, .. } => {
+        FlowModuleValue::Script { path: script_path, hash, .. } => {
             let payload =
-                script_path_to_payload(script_path, &mut tx, &flow_job.workspace_id).await?;
+                script_path_to_payload(script_path, *hash, &mut tx, &flow_job.workspace_id).await?;
             Ok((
                 tx,
                 NextFlowTransform::Continue(payload, NextStatus::NextStep),
@@ -1332,13 +3937,14 @@ async fn compute_next_flow_transform<'c>(
                 ),
             ))
         }
-        /* forloop modules are expected set `iter: { value: Value, index: usize }` as job arguments */
-        FlowModuleValue::ForloopFlow { modules, iterator, .. } => {
+        /* forloop modules are expected set `iter: { value: Value, index: usize, length: usize, last: bool }`
+         * as job arguments */
+        FlowModuleValue::ForloopFlow { modules, iterator, order_by, error_if_empty, .. } => {
             let new_args: &mut Map<String, serde_json::Value> = &mut Map::new();
 
             let next_loop_status = match status_module {
                 FlowStatusModule::WaitingForPriorSteps { .. } => {
-                    let (token, steps, by_id) = if let Some(x) = transform_context {
+                    let (token, steps, by_id, trigger) = if let Some(x) = transform_context {
                         x
                     } else {
                         let (tx_new, res) =
@@ -1347,63 +3953,97 @@ async fn compute_next_flow_transform<'c>(
                         res
                     };
                     let flow_input = flow_job.args.clone().unwrap_or_else(|| json!({}));
-                    /* Iterator is an InputTransform, evaluate it into an array. */
-                    let itered = evaluate_with(
-                        iterator.clone(),
-                        || {
-                            vec![
-                                ("flow_input".to_string(), flow_input),
-                                ("result".to_string(), last_result.clone()),
-                                ("previous_result".to_string(), last_result.clone()),
-                            ]
-                        },
-                        token,
-                        flow_job.workspace_id.clone(),
-                        steps,
-                        Some(by_id),
-                        base_internal_url,
-                    )
-                    .await?
-                    .into_array()
-                    .map_err(|not_array| {
-                        Error::ExecutionErr(format!("Expected an array value, found: {not_array}"))
-                    })?;
+                    let results =
+                        Value::Object(step_results(&mut tx, &flow_job.workspace_id, &by_id).await?);
+                    let evaluate = |transform: InputTransform| {
+                        evaluate_with(
+                            transform,
+                            || {
+                                vec![
+                                    ("flow_input".to_string(), flow_input.clone()),
+                                    ("result".to_string(), last_result.clone()),
+                                    ("previous_result".to_string(), last_result.clone()),
+                                    ("results".to_string(), results.clone()),
+                                    ("trigger".to_string(), trigger.clone()),
+                                ]
+                            },
+                            token.clone(),
+                            flow_job.workspace_id.clone(),
+                            steps.clone(),
+                            Some(by_id.clone()),
+                            base_internal_url,
+                        )
+                    };
+                    let itered = evaluate_iterator(iterator, evaluate).await?;
+
+                    let itered = match order_by {
+                        Some(order_by) => {
+                            sort_itered(order_by, itered, &flow_input, base_internal_url).await?
+                        }
+                        None => itered,
+                    };
 
                     if let Some(first) = itered.first() {
-                        new_args.insert("iter".to_string(), json!({ "index": 0, "value": first }));
+                        let len = itered.len();
+                        new_args.insert("iter".to_string(), iter_arg(0, first.clone(), len));
+                        /* with `iterators_in_table`, only their number is kept in the status */
+                        let itered =
+                            set_itered(&mut tx, status, flow_job.id, status.step, itered).await?;
 
                         LoopStatus::NextIteration(NextIteration {
                             index: 0,
                             itered,
+                            itered_len: status.iterators_in_table.then(|| len),
+                            max_iterations: None,
                             flow_jobs: vec![],
                             new_args: new_args.clone(),
                         })
+                    } else if *error_if_empty {
+                        Err(Error::ExecutionErr(format!(
+                            "Expected at least one element to iterate over in forloop {}, the iterator is empty",
+                            module.id
+                        )))?
                     } else {
                         LoopStatus::EmptyIterator
                     }
                 }
 
                 FlowStatusModule::InProgress {
-                    iterator: Some(windmill_common::flow_status::Iterator { itered, index }),
+                    iterator: Some(iterator),
                     flow_jobs: Some(flow_jobs),
                     ..
                 } => {
-                    let (index, next) = index
+                    let len = iterator.length();
+                    let index = iterator
+                        .index
                         .checked_add(1)
-                        .and_then(|i| itered.get(i).map(|next| (i, next)))
+                        .filter(|i| *i < len)
                         /* we shouldn't get here because update_flow_status_after_job_completion
                          * should leave this state if there iteration is complete, but also it should
                          * be reasonable to just enter a completed state instead of failing, similar to
                          * iterating an empty list above */
                         .with_context(|| {
-                            format!("could not iterate index {index} of {itered:?}")
+                            format!("could not iterate index {} of {len} values", iterator.index)
                         })?;
+                    let value = get_itered(
+                        &mut tx,
+                        status,
+                        flow_job.id,
+                        status.step,
+                        &iterator.itered,
+                        index..index + 1,
+                    )
+                    .await?
+                    .pop()
+                    .with_context(|| format!("missing value {index} of the iterator"))?;
 
-                    new_args.insert("iter".to_string(), json!({ "index": index, "value": next }));
+                    new_args.insert("iter".to_string(), iter_arg(index, value, len));
 
                     LoopStatus::NextIteration(NextIteration {
                         index,
-                        itered: itered.clone(),
+                        itered: iterator.itered.clone(),
+                        itered_len: iterator.itered_len,
+                        max_iterations: None,
                         flow_jobs: flow_jobs.clone(),
                         new_args: new_args.clone(),
                     })
@@ -1423,7 +4063,14 @@ async fn compute_next_flow_transform<'c>(
                             value: FlowValue {
                                 modules: (*modules).clone(),
                                 failure_module: flow.failure_module.clone(),
-                                same_worker: flow.same_worker,
+                                same_worker: module.same_worker.or(flow.same_worker),
+                                /* their input is the one of the flow, already resolved */
+                                resolve_resources: false,
+                                output_transform: None,
+                                dag: false,
+                                timeout: None,
+                                log_iterations: flow.log_iterations,
+                                on_cancel: None,
                             },
                             path: Some(format!("{}/loop-{}", flow_job.script_path(), status.step)),
                         },
@@ -1432,10 +4079,57 @@ async fn compute_next_flow_transform<'c>(
                 )),
             }
         }
-        FlowModuleValue::BranchOne { branches, default, .. } => {
+        /* whileloop passes only get `iter: { index: usize }`, there is nothing iterated over */
+        FlowModuleValue::WhileloopFlow { modules, max_iterations, .. } => {
+            let (index, flow_jobs) = match status_module {
+                FlowStatusModule::WaitingForPriorSteps { .. } => (0, vec![]),
+                /* update_flow_status_after_job_completion only stays in this state while the
+                 * stop condition isn't met */
+                FlowStatusModule::InProgress {
+                    iterator: Some(windmill_common::flow_status::Iterator { index, .. }),
+                    flow_jobs: Some(flow_jobs),
+                    ..
+                } => (index + 1, flow_jobs.clone()),
+                _ => Err(Error::BadRequest(format!(
+                    "Unrecognized module status for WhileloopFlow {status_module:?}"
+                )))?,
+            };
+            let new_args = Map::from_iter([("iter".to_string(), json!({ "index": index }))]);
+
+            Ok((
+                tx,
+                NextFlowTransform::Continue(
+                    JobPayload::RawFlow {
+                        value: FlowValue {
+                            modules: (*modules).clone(),
+                            failure_module: flow.failure_module.clone(),
+                            same_worker: module.same_worker.or(flow.same_worker),
+                            resolve_resources: false,
+                            output_transform: None,
+                            dag: false,
+                            timeout: None,
+                            log_iterations: flow.log_iterations,
+                            on_cancel: None,
+                        },
+                        path: Some(format!("{}/while-{}", flow_job.script_path(), status.step)),
+                    },
+                    NextStatus::NextLoopIteration(NextIteration {
+                        index,
+                        itered: vec![],
+                        itered_len: None,
+                        max_iterations: Some(
+                            max_iterations.unwrap_or(DEFAULT_MAX_WHILE_ITERATIONS),
+                        ),
+                        flow_jobs,
+                        new_args,
+                    }),
+                ),
+            ))
+        }
+        FlowModuleValue::BranchOne { branches, default, match_all } => {
             let branch = match status_module {
                 FlowStatusModule::WaitingForPriorSteps { .. } => {
-                    let mut branch_chosen = BranchChosen::Default;
+                    let mut matched = vec![];
                     for (i, b) in branches.iter().enumerate() {
                         let pred = compute_bool_from_expr(
                             b.expr.to_string(),
@@ -1446,28 +4140,42 @@ async fn compute_next_flow_transform<'c>(
                         .await?;
 
                         if pred {
-                            branch_chosen = BranchChosen::Branch { branch: i };
-                            break;
+                            matched.push(i);
+                            if !match_all {
+                                break;
+                            }
+                        }
+                    }
+                    match matched.first() {
+                        None => BranchChosen::default_branch(),
+                        Some(_) if *match_all => BranchChosen::Branches { branches: matched },
+                        Some(&branch) => {
+                            BranchChosen::Branch { branch, label: branches[branch].label.clone() }
                         }
                     }
-                    branch_chosen
                 }
                 _ => Err(Error::BadRequest(format!(
                     "Unrecognized module status for BranchOne {status_module:?}"
                 )))?,
             };
 
-            let modules = if let BranchChosen::Branch { branch } = branch {
-                branches
-                    .get(branch)
-                    .map(|b| b.modules.clone())
-                    .ok_or_else(|| {
-                        Error::BadRequest(format!(
-                            "Unrecognized branch for BranchAll {status_module:?}"
-                        ))
-                    })?
-            } else {
-                default.clone()
+            let (modules, output_transform) = match &branch {
+                BranchChosen::Branch { branch, .. } => (
+                    branches
+                        .get(*branch)
+                        .map(|b| b.modules.clone())
+                        .ok_or_else(|| {
+                            Error::BadRequest(format!(
+                                "Unrecognized branch for BranchAll {status_module:?}"
+                            ))
+                        })?,
+                    None,
+                ),
+                BranchChosen::Branches { branches: matched } => {
+                    let (modules, output_transform) = match_all_modules(module, branches, matched)?;
+                    (modules, Some(output_transform))
+                }
+                BranchChosen::Default { .. } => (default.clone(), None),
             };
 
             Ok((
@@ -1477,7 +4185,13 @@ async fn compute_next_flow_transform<'c>(
                         value: FlowValue {
                             modules,
                             failure_module: flow.failure_module.clone(),
-                            same_worker: flow.same_worker,
+                            same_worker: module.same_worker.or(flow.same_worker),
+                            resolve_resources: false,
+                            output_transform,
+                            dag: false,
+                            timeout: None,
+                            log_iterations: flow.log_iterations,
+                            on_cancel: None,
                         },
                         path: Some(format!(
                             "{}/branchone-{}",
@@ -1500,13 +4214,15 @@ async fn compute_next_flow_transform<'c>(
                                 branch: 0,
                                 previous_result: last_result,
                                 len: branches.len(),
+                                running: vec![],
+                                failed: None,
                             },
                             vec![],
                         )
                     }
                 }
                 FlowStatusModule::InProgress {
-                    branchall: Some(BranchAllStatus { branch, previous_result, len }),
+                    branchall: Some(BranchAllStatus { branch, previous_result, len, .. }),
                     flow_jobs: Some(flow_jobs),
                     ..
                 } => (
@@ -1514,6 +4230,8 @@ async fn compute_next_flow_transform<'c>(
                         branch: branch + 1,
                         previous_result: previous_result.clone(),
                         len: len.clone(),
+                        running: vec![],
+                        failed: None,
                     },
                     flow_jobs.clone(),
                 ),
@@ -1523,10 +4241,8 @@ async fn compute_next_flow_transform<'c>(
                 )))?,
             };
 
-            let modules = branches
-                .get(status.branch)
-                .map(|b| b.modules.clone())
-                .ok_or_else(|| {
+            let payload =
+                branchall_payload(flow_job, flow, module, status.branch).ok_or_else(|| {
                     Error::BadRequest(format!(
                         "Unrecognized branch for BranchAll {status_module:?}"
                     ))
@@ -1535,18 +4251,7 @@ async fn compute_next_flow_transform<'c>(
             Ok((
                 tx,
                 NextFlowTransform::Continue(
-                    JobPayload::RawFlow {
-                        value: FlowValue {
-                            modules,
-                            failure_module: flow.failure_module.clone(),
-                            same_worker: flow.same_worker,
-                        },
-                        path: Some(format!(
-                            "{}/branchall-{}",
-                            flow_job.script_path(),
-                            status.branch
-                        )),
-                    },
+                    payload,
                     NextStatus::NextBranchStep(NextBranch { status, flow_jobs }),
                 ),
             ))
@@ -1554,19 +4259,156 @@ async fn compute_next_flow_transform<'c>(
     }
 }
 
-async fn get_transform_context<'c>(
+/// The modules of the sub-flow of the branchone `module` running its `matched`
+/// branches: a branchall of them keeping their label, or labelled by their index, along with the
+/// output transform adding the indexes of the branches that matched and were skipped to their
+/// results.
+fn match_all_modules(
+    module: &FlowModule,
+    branches: &[BranchOneModules],
+    matched: &[usize],
+) -> error::Result<(Vec<FlowModule>, OutputTransform)> {
+    let skipped = (0..branches.len())
+        .filter(|i| !matched.contains(i))
+        .collect::<Vec<_>>();
+    let branchall = serde_json::from_value(json!({
+        "id": module.id,
+        "value": {
+            "type": "branchall",
+            "branches": matched.iter().filter_map(|&i| branches.get(i).map(|b| json!({
+                "summary": b.summary,
+                "label": b.label.clone().unwrap_or_else(|| i.to_string()),
+                "modules": b.modules,
+                "skip_failure": false,
+            }))).collect::<Vec<_>>(),
+        },
+    }))
+    .context("build match_all module")?;
+    let output_transform = OutputTransform {
+        expr: format!(
+            "({{ results: result, matched: {}, skipped: {} }})",
+            json!(matched),
+            json!(skipped)
+        ),
+        keep_raw_result: false,
+    };
+    Ok((vec![branchall], output_transform))
+}
+
+/// The `branch` arg of the flow of the branch `branch` of the branchall `module`, by which its
+/// result is keyed in the result of the step.
+fn branch_arg(module: &FlowModule, branch: usize) -> Value {
+    let label = match &module.value {
+        FlowModuleValue::BranchAll { branches, .. } => {
+            branches.get(branch).and_then(|b| b.label.clone())
+        }
+        _ => None,
+    };
+    json!({ "index": branch, "label": label })
+}
+
+/// The rank of `job` in the jobs spawned by the module at the current step of `flow`. The jobs
+/// of a window (`running`) all run at once, while a step running one job at a time is at `current`.
+async fn rank_in_window<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    status: &FlowStatus,
+    flow: Uuid,
+    flow_jobs: &Option<Vec<Uuid>>,
+    running: &[Uuid],
+    job: &Uuid,
+    current: usize,
+) -> error::Result<usize> {
+    if running.is_empty() {
+        return Ok(current);
+    }
+    let jobs = get_flow_jobs(
+        tx,
+        status,
+        flow,
+        status.step,
+        flow_jobs.as_deref().unwrap_or_default(),
+    )
+    .await?;
+    Ok(jobs.iter().position(|j| j == job).unwrap_or(current))
+}
+
+/// Takes the completed `job` out of the `running` jobs of its window, recording it as the
+/// `failed` job of the window if it is the first to fail. Returns whether other jobs of the
+/// window are still running.
+fn leave_window(
+    running: &mut Vec<Uuid>,
+    failed: &mut Option<Uuid>,
+    job: &Uuid,
+    job_failed: bool,
+) -> bool {
+    if !running.iter().any(|j| j != job) {
+        return false;
+    }
+    running.retain(|j| j != job);
+    if job_failed && failed.is_none() {
+        *failed = Some(*job);
+    }
+    true
+}
+
+/// Records the `jobs` of a window pushed by the module at `step` of `flow`, the first one being
+/// its `first`-th job.
+async fn add_window_jobs<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    status: &FlowStatus,
+    flow: Uuid,
+    step: usize,
+    first: usize,
+    flow_jobs: &mut Vec<Uuid>,
+    jobs: &[Uuid],
+) -> error::Result<()> {
+    for (k, job) in (first..).zip(jobs) {
+        add_flow_job(tx, status, flow, step as i32, k as i32, flow_jobs, *job).await?;
+    }
+    Ok(())
+}
+
+/// The sub-flow running the branch `branch` of the branchall `module`, if it has such a branch.
+fn branchall_payload(
+    flow_job: &QueuedJob,
+    flow: &FlowValue,
+    module: &FlowModule,
+    branch: usize,
+) -> Option<JobPayload> {
+    let modules = match &module.value {
+        FlowModuleValue::BranchAll { branches, .. } => branches.get(branch)?.modules.clone(),
+        _ => return None,
+    };
+    Some(JobPayload::RawFlow {
+        value: FlowValue {
+            modules,
+            failure_module: flow.failure_module.clone(),
+            same_worker: module.same_worker.or(flow.same_worker),
+            resolve_resources: false,
+            output_transform: None,
+            dag: false,
+            timeout: None,
+            log_iterations: flow.log_iterations,
+            on_cancel: None,
+        },
+        path: Some(format!("{}/branchall-{}", flow_job.script_path(), branch)),
+    })
+}
+
+pub(crate) async fn get_transform_context<'c>(
     tx: sqlx::Transaction<'c, sqlx::Postgres>,
     flow_job: &QueuedJob,
     status: &FlowStatus,
     modules: &Vec<FlowModule>,
 ) -> error::Result<(sqlx::Transaction<'c, sqlx::Postgres>, TransformContext)> {
-    let (tx, new_token) = crate::create_token_for_owner(
+    let (mut tx, new_token) = crate::create_token_for_owner(
         tx,
         &flow_job.workspace_id,
         &flow_job.permissioned_as,
         "transform-input",
         10,
         &flow_job.created_by,
+        Some(flow_job.id),
     )
     .await?;
     let new_steps: Vec<Uuid> = status
@@ -1580,7 +4422,221 @@ async fn get_transform_context<'c>(
         .zip(new_steps.clone())
         .collect();
 
-    Ok((tx, (new_token, new_steps, IdContext(flow_job.id, id_map))))
+    /* sub-flows were triggered along with the top-level flow */
+    let triggered_at = match flow_job.root_job {
+        Some(root_job) => {
+            sqlx::query_scalar!("SELECT created_at FROM queue WHERE id = $1", root_job)
+                .fetch_optional(&mut tx)
+                .await?
+                .unwrap_or(flow_job.created_at)
+        }
+        None => flow_job.created_at,
+    };
+    let trigger = json!({
+        "kind": if flow_job.schedule_path.is_some() { "schedule" } else { "manual" },
+        "created_by": flow_job.created_by,
+        "created_at": triggered_at,
+        "schedule_path": flow_job.schedule_path,
+    });
+
+    Ok((
+        tx,
+        (
+            new_token,
+            new_steps,
+            IdContext(flow_job.id, id_map),
+            trigger,
+        ),
+    ))
+}
+
+/// The result of the failed job of a step with its error structured and the id of the step set,
+/// for its failure module or its catch modules.
+fn step_error(result: Value, step_id: String) -> Value {
+    StepError::of_result(&result, Some(step_id)).into_result(result)
+}
+
+/// The hashes keying the cached result of a script step: of its script and of its arguments,
+/// with the keys of their objects sorted so that equal values hash the same. A `Script` module is
+/// keyed on the version of its script it runs, and so isn't cached until it is pinned to one.
+fn cache_hashes(value: &FlowModuleValue, args: &Map<String, Value>) -> Option<(String, String)> {
+    fn sorted(value: Value) -> Value {
+        match value {
+            Value::Object(m) => {
+                let mut entries = m.into_iter().collect::<Vec<_>>();
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                Value::Object(entries.into_iter().map(|(k, v)| (k, sorted(v))).collect())
+            }
+            Value::Array(a) => Value::Array(a.into_iter().map(sorted).collect()),
+            v => v,
+        }
+    }
+    let hash = |value: Value| hex::encode(Sha256::digest(sorted(value).to_string()));
+    let module_hash = match value {
+        FlowModuleValue::Script { hash: Some(script_hash), .. } => {
+            hash(json!({ "hash": script_hash }))
+        }
+        FlowModuleValue::Script { hash: None, .. } => return None,
+        value => hash(json!(value)),
+    };
+    Some((module_hash, hash(Value::Object(args.clone()))))
+}
+
+/// The result cached for a script step run by `permissioned_as` with the same script and
+/// arguments less than `ttl` seconds ago, with the job that computed it.
+async fn get_cached_result(
+    db: &DB,
+    w_id: &str,
+    permissioned_as: &str,
+    value: &FlowModuleValue,
+    args: &Map<String, Value>,
+    ttl: u32,
+) -> error::Result<Option<(Uuid, Value)>> {
+    let (module_hash, args_hash) = match cache_hashes(value, args) {
+        Some(hashes) => hashes,
+        None => return Ok(None),
+    };
+    let result = sqlx::query_as(
+        "SELECT job, result FROM flow_module_cache
+          WHERE workspace_id = $1 AND permissioned_as = $2 AND module_hash = $3 AND args_hash = $4
+            AND created_at > now() - make_interval(secs => $5) AND expiration > now()",
+    )
+    .bind(w_id)
+    .bind(permissioned_as)
+    .bind(module_hash)
+    .bind(args_hash)
+    .bind(ttl as f64)
+    .fetch_optional(db)
+    .await?;
+    Ok(result)
+}
+
+/// Caches the result of the job of `flow` that just completed the script step `module`, if it has
+/// a `cache_ttl`, keyed on who the flow runs as and on the arguments the job ran with. A cached
+/// result the step completed with isn't cached again, its job being one of another run.
+async fn cache_result<'c>(
+    flow: Uuid,
+    permissioned_as: &str,
+    module: &FlowModule,
+    job: Uuid,
+    w_id: &str,
+    result: &Value,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+) -> error::Result<()> {
+    let ttl = match (module.cache_ttl, &module.value) {
+        (Some(ttl), FlowModuleValue::Script { .. } | FlowModuleValue::RawScript { .. }) => ttl,
+        _ => return Ok(()),
+    };
+    let args = sqlx::query_scalar::<_, Option<Value>>(
+        "SELECT args FROM completed_job WHERE id = $1 AND parent_job = $2",
+    )
+    .bind(job)
+    .bind(flow)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let args = match args {
+        Some(Some(Value::Object(args))) => args,
+        Some(_) => Map::new(),
+        None => return Ok(()),
+    };
+    let (module_hash, args_hash) = match cache_hashes(&module.value, &args) {
+        Some(hashes) => hashes,
+        None => return Ok(()),
+    };
+    sqlx::query(
+        "INSERT INTO flow_module_cache
+                (workspace_id, permissioned_as, module_hash, args_hash, job, result, expiration)
+              VALUES ($1, $2, $3, $4, $5, $6, now() + make_interval(secs => $7))
+         ON CONFLICT (workspace_id, permissioned_as, module_hash, args_hash)
+           DO UPDATE SET job = EXCLUDED.job, result = EXCLUDED.result, created_at = now(),
+                         expiration = EXCLUDED.expiration",
+    )
+    .bind(w_id)
+    .bind(permissioned_as)
+    .bind(module_hash)
+    .bind(args_hash)
+    .bind(job)
+    .bind(result)
+    .bind(ttl as f64)
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/// The results of the steps of the flow that completed, keyed by their id. The steps that have
+/// not run yet, or were skipped, have no job and are left out.
+async fn step_results<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    w_id: &str,
+    by_id: &IdContext,
+) -> error::Result<Map<String, Value>> {
+    let jobs = by_id
+        .1
+        .values()
+        .filter(|job| !job.is_nil())
+        .copied()
+        .collect::<Vec<_>>();
+    let rows = sqlx::query_as::<_, (Uuid, Option<Value>)>(
+        "SELECT id, result FROM completed_job WHERE id = ANY($1) AND workspace_id = $2",
+    )
+    .bind(&jobs)
+    .bind(w_id)
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .collect::<HashMap<_, _>>();
+    Ok(by_id
+        .1
+        .iter()
+        .filter_map(|(id, job)| {
+            rows.get(job)
+                .map(|result| (id.clone(), result.clone().unwrap_or(Value::Null)))
+        })
+        .collect())
+}
+
+/// The results of the modules `ids`, which must have completed before the module at `step`,
+/// keyed by module id
+async fn collect_results(
+    db: &DB,
+    w_id: &str,
+    flow: &FlowValue,
+    status: &FlowStatus,
+    step: usize,
+    ids: &[String],
+) -> error::Result<Map<String, Value>> {
+    let mut results = Map::new();
+    for id in ids {
+        let idx = flow
+            .modules
+            .iter()
+            .position(|m| &m.id == id)
+            .ok_or_else(|| {
+                Error::ExecutionErr(format!("Collected step `{id}` does not exist in the flow"))
+            })?;
+        let job =
+            match status.modules.get(idx) {
+                Some(
+                    FlowStatusModule::Success { job, .. } | FlowStatusModule::Failure { job, .. },
+                ) if idx < step => *job,
+                _ => {
+                    return Err(Error::ExecutionErr(format!(
+                        "Collected step `{id}` has not completed before step {step}"
+                    )))
+                }
+            };
+        let result = sqlx::query_scalar!(
+            "SELECT result FROM completed_job WHERE id = $1 AND workspace_id = $2",
+            job,
+            w_id,
+        )
+        .fetch_optional(db)
+        .await?
+        .flatten()
+        .unwrap_or(Value::Null);
+        results.insert(id.clone(), result);
+    }
+    Ok(results)
 }
 
 async fn evaluate_with<F>(
@@ -1608,8 +4664,41 @@ where
             )
             .await
         }
+        InputTransform::Resource { .. } => Err(anyhow::anyhow!(
+            "A resource transform is only supported as the input of a step"
+        )),
     }
 }
+/// Evaluates the elements a forloop iterates over, `evaluate` evaluating its transforms.
+pub(crate) async fn evaluate_iterator<F, Fut>(
+    iterator: &LoopIterator,
+    evaluate: F,
+) -> error::Result<Vec<Value>>
+where
+    F: Fn(InputTransform) -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<Value>>,
+{
+    let itered = match iterator {
+        /* Iterator is an InputTransform, evaluate it into an array. */
+        LoopIterator::Transform(iterator) => evaluate(iterator.clone())
+            .await?
+            .into_array()
+            .map_err(|not_array| {
+                Error::ExecutionErr(format!("Expected an array value, found: {not_array}"))
+            })?,
+        /* Iterator is a cron schedule, expand it into its occurrences. */
+        LoopIterator::Cron(CronIterator { schedule, start, end }) => {
+            let start = into_datetime(evaluate(start.clone()).await?)?;
+            let end = into_datetime(evaluate(end.clone()).await?)?;
+            schedule_occurrences(schedule, start, end, MAX_CRON_ITERATIONS)?
+                .into_iter()
+                .map(|occurrence| json!(occurrence))
+                .collect()
+        }
+    };
+    Ok(itered)
+}
+
 trait IntoArray: Sized {
     fn into_array(self) -> Result<Vec<Value>, Self>;
 }
@@ -1623,6 +4712,103 @@ impl IntoArray for Value {
     }
 }
 
+/// The `iter` argument of the iteration `index` of a forloop over `length` values, `value` being
+/// the one at `index`.
+fn iter_arg(index: usize, value: Value, length: usize) -> Value {
+    json!({
+        "index": index,
+        "value": value,
+        "length": length,
+        "last": index + 1 == length,
+    })
+}
+
+/// Sorts the elements a forloop iterates over by the value the expression of
+/// `order_by` evaluates to for each of them.
+async fn sort_itered(
+    order_by: &OrderBy,
+    itered: Vec<Value>,
+    flow_input: &Value,
+    base_internal_url: &str,
+) -> error::Result<Vec<Value>> {
+    let mut keyed = Vec::with_capacity(itered.len());
+    for (index, value) in itered.into_iter().enumerate() {
+        let key = eval_timeout(
+            order_by.expr.clone(),
+            vec![
+                ("flow_input".to_string(), flow_input.clone()),
+                (
+                    "iter".to_string(),
+                    json!({ "index": index, "value": value }),
+                ),
+            ],
+            None,
+            vec![],
+            None,
+            base_internal_url.to_string(),
+        )
+        .await
+        .map_err(|e| {
+            Error::ExecutionErr(format!(
+                "Error during isolated evaluation of order_by expression `{}`:\n{e}",
+                order_by.expr
+            ))
+        })?;
+        keyed.push((key, value));
+    }
+
+    if !keyed.iter().all(|(key, _)| key.is_number())
+        && !keyed.iter().all(|(key, _)| key.is_string())
+    {
+        return Err(Error::ExecutionErr(format!(
+            "Expected the order_by expression `{}` to return either numbers or strings for all iterations",
+            order_by.expr
+        )));
+    }
+
+    /* sort_by is stable, equal keys keep the order of the iterator in both directions */
+    keyed.sort_by(|(a, _), (b, _)| {
+        let ordering = match (a, b) {
+            (Value::Number(a), Value::Number(b)) => a
+                .as_f64()
+                .partial_cmp(&b.as_f64())
+                .unwrap_or(std::cmp::Ordering::Equal),
+            _ => a.as_str().cmp(&b.as_str()),
+        };
+        if order_by.desc {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+    Ok(keyed.into_iter().map(|(_, value)| value).collect())
+}
+
+fn into_datetime(value: Value) -> error::Result<chrono::DateTime<chrono::Utc>> {
+    serde_json::from_value(value.clone())
+        .map_err(|_| Error::ExecutionErr(format!("Expected a RFC 3339 datetime, found: {value}")))
+}
+
+/// `seconds` as days, hours, minutes and seconds, e.g. `1d 2h 30s`.
+fn format_duration(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    let parts = [
+        (seconds / 86400, "d"),
+        (seconds % 86400 / 3600, "h"),
+        (seconds % 3600 / 60, "m"),
+        (seconds % 60, "s"),
+    ]
+    .into_iter()
+    .filter(|(n, _)| *n > 0)
+    .map(|(n, unit)| format!("{n}{unit}"))
+    .collect::<Vec<_>>();
+    if parts.is_empty() {
+        "0s".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
+
 fn from_now(duration: Duration) -> chrono::DateTime<chrono::Utc> {
     // "This function errors when original duration is larger than
     // the maximum value supported for this type."
@@ -1632,25 +4818,184 @@ fn from_now(duration: Duration) -> chrono::DateTime<chrono::Utc> {
         .unwrap_or(chrono::DateTime::<chrono::Utc>::MAX_UTC)
 }
 
-/// returns previous module non-zero suspend count and job
-fn needs_resume(flow: &FlowValue, status: &FlowStatus) -> Option<(Suspend, Uuid)> {
-    let prev = usize::try_from(status.step)
+/// The suspend of the previous module and its job, if the flow has to wait for resume messages
+/// before running the current module: the previous module succeeded, requires events and, if it
+/// has a `condition`, that condition holds for its result.
+///
+/// The `condition` is only evaluated when first reaching the current module. Once the module is
+/// `WaitingForEvents` the flow already decided to suspend, so it keeps waiting.
+async fn needs_resume(
+    flow: &FlowValue,
+    status: &FlowStatus,
+    status_module: &FlowStatusModule,
+    flow_args: &Option<serde_json::Value>,
+    last_result: &serde_json::Value,
+    base_internal_url: &str,
+) -> error::Result<Option<(Suspend, Uuid)>> {
+    let prev = match usize::try_from(status.step)
         .ok()
-        .and_then(|s| s.checked_sub(1))?;
+        .and_then(|s| s.checked_sub(1))
+    {
+        Some(prev) => prev,
+        None => return Ok(None),
+    };
 
-    let suspend = flow.modules.get(prev)?.suspend.clone();
-    if suspend
-        .as_ref()
-        .and_then(|s| s.required_events)
-        .unwrap_or(0)
-        == 0
+    let suspend = match flow.modules.get(prev).and_then(|m| m.suspend.clone()) {
+        Some(suspend) if suspend.required_events.unwrap_or(0) > 0 => suspend,
+        _ => return Ok(None),
+    };
+
+    let job = match status.modules.get(prev) {
+        Some(&FlowStatusModule::Success { job, .. }) => job,
+        _ => return Ok(None),
+    };
+
+    if let (Some(expr), FlowStatusModule::WaitingForPriorSteps { .. }) =
+        (&suspend.condition, status_module)
     {
-        return None;
+        if !compute_bool_from_expr(
+            expr.clone(),
+            flow_args,
+            last_result.clone(),
+            base_internal_url,
+        )
+        .await?
+        {
+            return Ok(None);
+        }
     }
 
-    if let &FlowStatusModule::Success { job, .. } = status.modules.get(prev)? {
-        Some((suspend.unwrap(), job))
-    } else {
-        None
+    Ok(Some((suspend, job)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(path: &str) -> Value {
+        json!({ "value": { "type": "script", "path": path } })
+    }
+
+    fn pinned(module: &FlowModule) -> Option<ScriptHash> {
+        match &module.value {
+            FlowModuleValue::Script { hash, .. } => *hash,
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_script_hashes_looked_up_once_per_path() {
+        let mut step = script("u/test-user/step");
+        step["catch"] = json!([script("u/test-user/catch")]);
+        step["compensation"] = script("u/test-user/undo");
+        let mut flow: FlowValue = serde_json::from_value(json!({
+            "modules": [{
+                "value": {
+                    "type": "forloopflow",
+                    "iterator": { "type": "static", "value": [] },
+                    "skip_failures": false,
+                    "modules": vec![step; 1000],
+                },
+            }],
+            "failure_module": script("u/test-user/failure"),
+            "on_cancel": { "modules": [script("u/test-user/cleanup")] },
+        }))
+        .unwrap();
+
+        /* the 3000 modules of the loop need only 3 lookups */
+        let paths = flow_script_paths(&flow);
+        let mut sorted = paths.iter().map(String::as_str).collect::<Vec<_>>();
+        sorted.sort();
+        assert_eq!(
+            sorted,
+            vec![
+                "u/test-user/catch",
+                "u/test-user/cleanup",
+                "u/test-user/failure",
+                "u/test-user/step",
+                "u/test-user/undo",
+            ]
+        );
+
+        let hashes = paths
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| (p, ScriptHash(i as i64)))
+            .collect::<HashMap<_, _>>();
+        pin_flow(&mut flow, &hashes);
+        let steps = match &flow.modules[0].value {
+            FlowModuleValue::ForloopFlow { modules, .. } => modules,
+            _ => unreachable!(),
+        };
+        for step in steps {
+            assert_eq!(pinned(step), hashes.get("u/test-user/step").copied());
+            assert_eq!(
+                pinned(&step.catch[0]),
+                hashes.get("u/test-user/catch").copied()
+            );
+            assert_eq!(
+                pinned(step.compensation.as_ref().unwrap()),
+                hashes.get("u/test-user/undo").copied()
+            );
+        }
+        assert_eq!(
+            pinned(flow.failure_module.as_ref().unwrap()),
+            hashes.get("u/test-user/failure").copied()
+        );
+        assert_eq!(
+            pinned(&flow.on_cancel.as_ref().unwrap().modules[0]),
+            hashes.get("u/test-user/cleanup").copied()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_needs_resume_condition() -> anyhow::Result<()> {
+        let flow: FlowValue = serde_json::from_value(json!({
+            "modules": [{
+                "id": "a",
+                "value": { "type": "identity" },
+                "suspend": { "required_events": 1, "condition": "flow_input.wait && result.x > 0" },
+            }, {
+                "id": "b",
+                "value": { "type": "identity" },
+            }],
+        }))?;
+        let job = Uuid::new_v4();
+        let mut status = FlowStatus::new(&flow);
+        status.step = 1;
+        status.modules[0] =
+            serde_json::from_value(json!({ "type": "Success", "id": "a", "job": job }))?;
+        let waiting_for_prior_steps = status.modules[1].clone();
+        let waiting_for_events = serde_json::from_value(
+            json!({ "type": "WaitingForEvents", "id": "b", "count": 1, "job": job }),
+        )?;
+        let result = json!({ "x": 1 });
+
+        /* the condition is evaluated when reaching the next module ... */
+        let args = Some(json!({ "wait": false }));
+        let resume =
+            needs_resume(&flow, &status, &waiting_for_prior_steps, &args, &result, "").await?;
+        assert!(resume.is_none());
+
+        let args = Some(json!({ "wait": true }));
+        let resume =
+            needs_resume(&flow, &status, &waiting_for_prior_steps, &args, &result, "").await?;
+        assert_eq!(resume.map(|(_, last)| last), Some(job));
+        let resume = needs_resume(
+            &flow,
+            &status,
+            &waiting_for_prior_steps,
+            &args,
+            &json!({ "x": 0 }),
+            "",
+        )
+        .await?;
+        assert!(resume.is_none());
+
+        /* ... not once the flow decided to wait for the resume messages */
+        let args = Some(json!({ "wait": false }));
+        let resume = needs_resume(&flow, &status, &waiting_for_events, &args, &result, "").await?;
+        assert_eq!(resume.map(|(_, last)| last), Some(job));
+        Ok(())
     }
 }